@@ -1,12 +1,14 @@
 use std::process::Stdio;
 
-use nix::unistd::User;
+use nix::unistd::{Group, User};
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind};
 use crate::execute_command;
+use crate::os::linux::UserDatabase;
+use crate::settings::UserBackend;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -20,8 +22,12 @@ pub struct AddUserToGroup {
     pub(crate) uid: u32,
     pub(crate) groupname: String,
     pub(crate) gid: u32,
+    #[serde(default)]
+    pub(crate) backend: UserBackend,
 }
 
+const GROUP_MEMBERSHIP_COMMANDS: &[&[&str]] = &[&["addgroup", "gpasswd"], &["delgroup", "gpasswd"]];
+
 impl AddUserToGroup {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
@@ -29,24 +35,29 @@ impl AddUserToGroup {
         uid: u32,
         groupname: String,
         gid: u32,
+        backend: UserBackend,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self {
+        let mut this = Self {
             name: name.clone(),
             uid,
             groupname,
             gid,
+            backend,
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("addgroup").is_ok() || which::which("gpasswd").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingAddUserToGroupCommand));
-                }
-                if !(which::which("delgroup").is_ok() || which::which("gpasswd").is_ok()) {
-                    return Err(Self::error(
-                        ActionErrorKind::MissingRemoveUserFromGroupCommand,
-                    ));
+                this.backend = backend.resolve(GROUP_MEMBERSHIP_COMMANDS);
+                if this.backend == UserBackend::Tools {
+                    if !(which::which("addgroup").is_ok() || which::which("gpasswd").is_ok()) {
+                        return Err(Self::error(ActionErrorKind::MissingAddUserToGroupCommand));
+                    }
+                    if !(which::which("delgroup").is_ok() || which::which("gpasswd").is_ok()) {
+                        return Err(Self::error(
+                            ActionErrorKind::MissingRemoveUserFromGroupCommand,
+                        ));
+                    }
                 }
             },
         }
@@ -134,6 +145,20 @@ impl AddUserToGroup {
                         },
                     };
                 },
+                _ if this.backend == UserBackend::Files => {
+                    // `groups` may be missing as well, and the primary group was checked above
+                    let group = Group::from_name(this.groupname.as_str())
+                        .map_err(|e| ActionErrorKind::GettingGroupId(this.groupname.clone(), e))
+                        .map_err(Self::error)?;
+                    if group.is_some_and(|group| group.mem.contains(&this.name)) {
+                        tracing::debug!(
+                            "Adding user `{}` to group `{}` already complete",
+                            this.name,
+                            this.groupname
+                        );
+                        return Ok(StatefulAction::completed(this));
+                    }
+                },
                 _ => {
                     let output = execute_command(
                         Command::new("groups")
@@ -232,7 +257,12 @@ impl Action for AddUserToGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("gpasswd").is_ok() {
+                if self.backend.resolve(GROUP_MEMBERSHIP_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .add_user_to_group(&self.name, &self.groupname)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("gpasswd").is_ok() {
                     execute_command(
                         Command::new("gpasswd")
                             .process_group(0)
@@ -281,6 +311,7 @@ impl Action for AddUserToGroup {
             uid: _,
             groupname,
             gid: _,
+            backend,
         } = self;
 
         use target_lexicon::OperatingSystem;
@@ -302,7 +333,12 @@ impl Action for AddUserToGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("gpasswd").is_ok() {
+                if backend.resolve(GROUP_MEMBERSHIP_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .remove_user_from_group(name, groupname)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("gpasswd").is_ok() {
                     execute_command(
                         Command::new("gpasswd")
                             .process_group(0)
@@ -112,6 +112,7 @@ impl CreateDirectory {
                 force_prune_on_revert,
             },
             state: action_state,
+            timing: None,
         })
     }
 }
@@ -12,6 +12,7 @@ use tokio::{
 
 use crate::{
     action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    backup::{backup_file, FileBackup},
     util::OnMissing,
 };
 
@@ -30,6 +31,12 @@ pub struct CreateFile {
     mode: Option<u32>,
     buf: String,
     force: bool,
+    /// The original file, saved before the first write
+    #[serde(default)]
+    backup: Option<FileBackup>,
+    /// Where to back up the original file before the first write, `None` to not back it up
+    #[serde(skip, default = "crate::backup::default_backup_root")]
+    backup_root: Option<PathBuf>,
 }
 
 impl CreateFile {
@@ -53,6 +60,8 @@ impl CreateFile {
             mode,
             buf,
             force,
+            backup: None,
+            backup_root: crate::backup::default_backup_root(),
         };
 
         if this.path.exists() {
@@ -184,6 +193,12 @@ impl Action for CreateFile {
             span.record("buf", &self.buf);
         }
 
+        if self.backup.is_none() {
+            self.backup = backup_file(self.backup_root.as_deref(), &self.path)
+                .await
+                .map_err(Self::error)?;
+        }
+
         let mut options = OpenOptions::new();
         options.create_new(true).write(true).read(true);
 
@@ -241,6 +256,8 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            backup: _,
+            backup_root: _,
         } = &self;
 
         vec![ActionDescription::new(
@@ -258,6 +275,8 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            backup: _,
+            backup_root: _,
         } = self;
 
         crate::util::remove_file(path, OnMissing::Ignore)
@@ -282,6 +301,7 @@ mod test {
         let mut action =
             CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -300,6 +320,7 @@ mod test {
         let mut action =
             CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         write(test_file.as_path(), "More content").await?;
@@ -331,6 +352,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -444,6 +466,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -5,6 +5,8 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::linux::{is_busybox_applet, UserDatabase};
+use crate::settings::UserBackend;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -16,24 +18,36 @@ Create an operating system level user group
 pub struct CreateGroup {
     name: String,
     gid: u32,
+    #[serde(default)]
+    backend: UserBackend,
 }
 
+const GROUP_COMMANDS: &[&[&str]] = &[&["groupadd", "addgroup"], &["groupdel", "delgroup"]];
+
 impl CreateGroup {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn plan(name: String, gid: u32) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self {
+    pub fn plan(
+        name: String,
+        gid: u32,
+        backend: UserBackend,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut this = Self {
             name: name.clone(),
             gid,
+            backend,
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
-                }
-                if !(which::which("groupdel").is_ok() || which::which("delgroup").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingGroupDeletionCommand));
+                this.backend = backend.resolve(GROUP_COMMANDS);
+                if this.backend == UserBackend::Tools {
+                    if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
+                        return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
+                    }
+                    if !(which::which("groupdel").is_ok() || which::which("delgroup").is_ok()) {
+                        return Err(Self::error(ActionErrorKind::MissingGroupDeletionCommand));
+                    }
                 }
             },
         }
@@ -68,13 +82,18 @@ impl Action for CreateGroup {
         format!("Create group `{}` (GID {})", self.name, self.gid)
     }
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { name: _, gid: _ } = &self;
-        vec![ActionDescription::new(
-            self.tracing_synopsis(),
-            vec![format!(
-                "The nix daemon requires a system user group its system users can be part of"
-            )],
-        )]
+        let Self {
+            name: _,
+            gid: _,
+            backend,
+        } = &self;
+        let mut explanation = vec![format!(
+            "The nix daemon requires a system user group its system users can be part of"
+        )];
+        if *backend == UserBackend::Files {
+            explanation.push("Edit `/etc/group` and `/etc/gshadow` directly".to_string());
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     fn tracing_span(&self) -> Span {
@@ -88,7 +107,7 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid } = self;
+        let Self { name, gid, backend } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -116,7 +135,12 @@ impl Action for CreateGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("groupadd").is_ok() {
+                if backend.resolve(GROUP_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .create_group(name, *gid)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("groupadd").is_ok() {
                     execute_command(
                         Command::new("groupadd")
                             .process_group(0)
@@ -125,6 +149,15 @@ impl Action for CreateGroup {
                     )
                     .await
                     .map_err(Self::error)?;
+                } else if is_busybox_applet("addgroup") {
+                    execute_command(
+                        Command::new("addgroup")
+                            .process_group(0)
+                            .args(busybox_addgroup_args(name, *gid))
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    .map_err(Self::error)?;
                 } else if which::which("addgroup").is_ok() {
                     execute_command(
                         Command::new("addgroup")
@@ -144,7 +177,11 @@ impl Action for CreateGroup {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
-        let Self { name, gid } = &self;
+        let Self {
+            name,
+            gid,
+            backend: _,
+        } = &self;
         vec![ActionDescription::new(
             format!("Delete group `{name}` (GID {gid})"),
             vec![format!(
@@ -155,7 +192,11 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid: _ } = self;
+        let Self {
+            name,
+            gid: _,
+            backend,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -174,7 +215,12 @@ impl Action for CreateGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("groupdel").is_ok() {
+                if backend.resolve(GROUP_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .delete_group(name)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("groupdel").is_ok() {
                     execute_command(
                         Command::new("groupdel")
                             .process_group(0)
@@ -201,3 +247,26 @@ impl Action for CreateGroup {
         Ok(())
     }
 }
+
+/// The arguments for busybox's `addgroup` (as on Alpine), which only takes short flags
+fn busybox_addgroup_args(name: &str, gid: u32) -> [String; 4] {
+    [
+        "-g".to_string(),
+        gid.to_string(),
+        "-S".to_string(),
+        name.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::busybox_addgroup_args;
+
+    #[test]
+    fn busybox_addgroup_uses_short_flags() {
+        assert_eq!(
+            busybox_addgroup_args("nixbld", 30000),
+            ["-g", "30000", "-S", "nixbld"]
+        );
+    }
+}
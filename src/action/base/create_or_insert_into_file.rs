@@ -2,6 +2,7 @@ use nix::unistd::{chown, Group, User};
 
 use crate::{
     action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    backup::{backup_file, FileBackup},
     util::OnMissing,
 };
 use rand::Rng;
@@ -20,6 +21,13 @@ use tracing::{span, Span};
 pub enum Position {
     Beginning,
     End,
+    /// The `buf` is wrapped in the marker lines, replacing any existing block between those markers in place
+    ///
+    /// If the file contains no such block, it is inserted at the beginning.
+    ManagedBlock {
+        begin_marker: String,
+        end_marker: String,
+    },
 }
 
 /** Create a file at the given location with the provided `buf` as
@@ -27,19 +35,36 @@ contents, optionally with an owning user, group, and mode.
 
 If the file exists, the provided `buf` will be inserted at its
 beginning or end, depending on the position field.
+
+With [`Position::ManagedBlock`] an existing block between the markers
+(such as one written by an older version of `nix-installer`) is updated
+in place, duplicate blocks are dropped, and revert removes only the
+block as it was written, leaving any other lines (or a block edited
+since) alone.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_or_insert_into_file")]
 pub struct CreateOrInsertIntoFile {
-    path: PathBuf,
+    pub(crate) path: PathBuf,
     user: Option<String>,
     group: Option<String>,
     mode: Option<u32>,
     buf: String,
     position: Position,
+    /// The original file, saved before the first write
+    #[serde(default)]
+    backup: Option<FileBackup>,
+    /// Where to back up the original file before the first write, `None` to not back it up
+    #[serde(skip, default = "crate::backup::default_backup_root")]
+    backup_root: Option<PathBuf>,
 }
 
 impl CreateOrInsertIntoFile {
+    /// Back up the original file under `root` instead, or not at all without one
+    pub(crate) fn set_backup_root(&mut self, root: Option<PathBuf>) {
+        self.backup_root = root;
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         path: impl AsRef<Path>,
@@ -60,6 +85,8 @@ impl CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            backup: None,
+            backup_root: crate::backup::default_backup_root(),
         };
         if this.path.exists() {
             // If the path exists, perhaps we can just skip this
@@ -130,14 +157,28 @@ impl CreateOrInsertIntoFile {
                 }
             }
 
-            // Does it have the right content?
-            let mut discovered_buf = String::new();
-            file.read_to_string(&mut discovered_buf)
+            // Does it have the right content? Profiles needn't be UTF-8, so compare bytes
+            let mut discovered_buf = Vec::new();
+            file.read_to_end(&mut discovered_buf)
                 .await
                 .map_err(|e| ActionErrorKind::Read(this.path.clone(), e))
                 .map_err(Self::error)?;
 
-            if discovered_buf.contains(&this.buf) {
+            let already_complete = match &this.position {
+                Position::ManagedBlock {
+                    begin_marker,
+                    end_marker,
+                } => {
+                    let blocks = find_managed_blocks(&discovered_buf, begin_marker, end_marker);
+                    blocks.len() == 1
+                        && discovered_buf[blocks[0].clone()]
+                            == *render_managed_block(&this.buf, begin_marker, end_marker).as_bytes()
+                },
+                Position::Beginning | Position::End => {
+                    rfind_bytes(&discovered_buf, this.buf.as_bytes()).is_some()
+                },
+            };
+            if already_complete {
                 tracing::debug!("Inserting into `{}` already complete", this.path.display(),);
                 return Ok(StatefulAction::completed(this));
             }
@@ -191,8 +232,16 @@ impl Action for CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            backup,
+            backup_root,
         } = self;
 
+        if backup.is_none() {
+            *backup = backup_file(backup_root.as_deref(), path)
+                .await
+                .map_err(Self::error)?;
+        }
+
         let mut orig_file = match OpenOptions::new().read(true).open(&path).await {
             Ok(f) => Some(f),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
@@ -242,8 +291,42 @@ impl Action for CreateOrInsertIntoFile {
             }
         }
 
+        let new_buf = match position {
+            Position::ManagedBlock {
+                begin_marker,
+                end_marker,
+            } => {
+                let mut orig_contents = Vec::new();
+                if let Some(ref mut orig_file) = orig_file {
+                    orig_file
+                        .read_to_end(&mut orig_contents)
+                        .await
+                        .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
+                        .map_err(Self::error)?;
+                }
+                let block = render_managed_block(buf, begin_marker, end_marker);
+                let blocks = find_managed_blocks(&orig_contents, begin_marker, end_marker);
+                match blocks.split_first() {
+                    Some((first, duplicates)) => {
+                        // Work from the end, so the earlier ranges stay valid
+                        for duplicate in duplicates.iter().rev() {
+                            orig_contents.drain(duplicate.clone());
+                        }
+                        orig_contents.splice(first.clone(), block.into_bytes());
+                        orig_contents
+                    },
+                    None => {
+                        let mut new_buf = block.into_bytes();
+                        new_buf.append(&mut orig_contents);
+                        new_buf
+                    },
+                }
+            },
+            Position::Beginning | Position::End => buf.clone().into_bytes(),
+        };
+
         temp_file
-            .write_all(buf.as_bytes())
+            .write_all(&new_buf)
             .await
             .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))
             .map_err(Self::error)?;
@@ -328,14 +411,26 @@ impl Action for CreateOrInsertIntoFile {
             group: _,
             mode: _,
             buf,
-            position: _,
+            position,
+            backup: _,
+            backup_root: _,
         } = &self;
-        vec![ActionDescription::new(
-            format!("Delete Nix related fragment from file `{}`", path.display()),
-            vec![format!(
+        let explanation = match position {
+            Position::ManagedBlock {
+                begin_marker,
+                end_marker,
+            } => format!(
+                "Delete the lines from `{begin_marker}` to `{end_marker}` from file `{}`",
+                path.display()
+            ),
+            Position::Beginning | Position::End => format!(
                 "Delete Nix related fragment from file `{}`. Fragment: `{buf}`",
                 path.display()
-            )],
+            ),
+        };
+        vec![ActionDescription::new(
+            format!("Delete Nix related fragment from file `{}`", path.display()),
+            vec![explanation],
         )]
     }
 
@@ -347,7 +442,9 @@ impl Action for CreateOrInsertIntoFile {
             group: _,
             mode: _,
             buf,
-            position: _,
+            position,
+            backup: _,
+            backup_root: _,
         } = self;
         // The user already deleted it
         if !path.exists() {
@@ -363,15 +460,37 @@ impl Action for CreateOrInsertIntoFile {
             .map_err(|e| ActionErrorKind::Open(path.to_owned(), e))
             .map_err(Self::error)?;
 
-        let mut file_contents = String::default();
-        file.read_to_string(&mut file_contents)
+        let mut file_contents = Vec::new();
+        file.read_to_end(&mut file_contents)
             .await
             .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
             .map_err(Self::error)?;
 
-        if let Some(start) = file_contents.rfind(buf.as_str()) {
-            let end = start + buf.len();
-            file_contents.replace_range(start..end, "")
+        match position {
+            Position::ManagedBlock {
+                begin_marker,
+                end_marker,
+            } => {
+                // Only remove the block as it was written, not others which happen to use the markers
+                let block = render_managed_block(buf, begin_marker, end_marker);
+                let written = find_managed_blocks(&file_contents, begin_marker, end_marker)
+                    .into_iter()
+                    .find(|range| file_contents[range.clone()] == *block.as_bytes());
+                match written {
+                    Some(range) => {
+                        file_contents.drain(range);
+                    },
+                    None => tracing::warn!(
+                        "The block from `{begin_marker}` to `{end_marker}` in `{}` was changed since it was written, leaving it in place",
+                        path.display()
+                    ),
+                }
+            },
+            Position::Beginning | Position::End => {
+                if let Some(start) = rfind_bytes(&file_contents, buf.as_bytes()) {
+                    file_contents.drain(start..start + buf.len());
+                }
+            },
         }
 
         if file_contents.is_empty() {
@@ -388,7 +507,7 @@ impl Action for CreateOrInsertIntoFile {
                 .await
                 .map_err(|e| ActionErrorKind::Truncate(path.to_owned(), e))
                 .map_err(Self::error)?;
-            file.write_all(file_contents.as_bytes())
+            file.write_all(&file_contents)
                 .await
                 .map_err(|e| ActionErrorKind::Write(path.to_owned(), e))
                 .map_err(Self::error)?;
@@ -401,6 +520,50 @@ impl Action for CreateOrInsertIntoFile {
     }
 }
 
+/// Wrap `buf` in the marker lines, as written for [`Position::ManagedBlock`]
+fn render_managed_block(buf: &str, begin_marker: &str, end_marker: &str) -> String {
+    let mut block = format!("{begin_marker}\n{buf}");
+    if !block.ends_with('\n') {
+        block.push('\n');
+    }
+    block.push_str(end_marker);
+    block.push('\n');
+    block
+}
+
+/// The byte ranges of each block from a `begin_marker` line to the following `end_marker` line, inclusive
+fn find_managed_blocks(
+    contents: &[u8],
+    begin_marker: &str,
+    end_marker: &str,
+) -> Vec<std::ops::Range<usize>> {
+    let mut blocks = vec![];
+    let mut block_start = None;
+    let mut offset = 0;
+    for line in contents.split_inclusive(|byte| *byte == b'\n') {
+        let trimmed = line.trim_ascii_end();
+        if trimmed == begin_marker.as_bytes() {
+            block_start = Some(offset);
+        } else if trimmed == end_marker.as_bytes() {
+            if let Some(start) = block_start.take() {
+                blocks.push(start..offset + line.len());
+            }
+        }
+        offset += line.len();
+    }
+    blocks
+}
+
+/// The start of the last occurrence of `needle` in `haystack`, like [`str::rfind`] for bytes
+fn rfind_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(haystack.len());
+    }
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -421,6 +584,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -450,6 +614,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -491,6 +656,7 @@ mod test {
             )
             .await?;
 
+            action.action.backup_root = Some(temp_dir.path().join("backups"));
             action.try_execute().await?;
 
             action.try_revert().await?;
@@ -526,6 +692,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -555,6 +722,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
@@ -564,6 +732,219 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn managed_block_migrates_legacy_blocks() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("managed_block_migrates_legacy_blocks");
+
+        write(
+            test_file.as_path(),
+            "Before\n# Nix\nold\n# End Nix\nBetween\n# Nix\nolder\n# End Nix\nAfter\n",
+        )
+        .await?;
+
+        let position = Position::ManagedBlock {
+            begin_marker: "# Nix".into(),
+            end_marker: "# End Nix".into(),
+        };
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "new\n".into(),
+            position.clone(),
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+        assert_eq!(
+            read_to_string(&test_file).await?,
+            "Before\n# Nix\nnew\n# End Nix\nBetween\nAfter\n"
+        );
+
+        let replanned = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "new\n".into(),
+            position,
+        )
+        .await?;
+        assert_eq!(replanned.state, crate::action::ActionState::Completed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn managed_block_reverts_exactly_the_block() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("managed_block_reverts_exactly_the_block");
+
+        write(test_file.as_path(), "Some content\n").await?;
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test\n".into(),
+            Position::ManagedBlock {
+                begin_marker: "# Nix".into(),
+                end_marker: "# End Nix".into(),
+            },
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+
+        // The user edits around the block
+        let edited = format!(
+            "Added by the user\n{}More from the user\n",
+            read_to_string(&test_file).await?
+        );
+        write(test_file.as_path(), &edited).await?;
+
+        action.try_revert().await?;
+
+        assert_eq!(
+            read_to_string(&test_file).await?,
+            "Added by the user\nSome content\nMore from the user\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn managed_block_handles_non_utf8_files() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("managed_block_handles_non_utf8_files");
+
+        // A Latin-1 comment, as found in older profiles
+        let original = b"# Gr\xfc\xdfe\nexport LANG=de_DE\n".to_vec();
+        write(test_file.as_path(), &original).await?;
+
+        let position = Position::ManagedBlock {
+            begin_marker: "# Nix".into(),
+            end_marker: "# End Nix".into(),
+        };
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test\n".into(),
+            position.clone(),
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+        let mut expected = b"# Nix\nTest\n# End Nix\n".to_vec();
+        expected.extend(&original);
+        assert_eq!(tokio::fs::read(&test_file).await?, expected);
+
+        let replanned = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test\n".into(),
+            position,
+        )
+        .await?;
+        assert_eq!(replanned.state, crate::action::ActionState::Completed);
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read(&test_file).await?, original);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn managed_block_reverts_only_the_written_block() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("managed_block_reverts_only_the_written_block");
+
+        write(test_file.as_path(), "Some content\n").await?;
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test\n".into(),
+            Position::ManagedBlock {
+                begin_marker: "# Nix".into(),
+                end_marker: "# End Nix".into(),
+            },
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+
+        // Another tool adds a block with the same markers
+        let other_block = "# Nix\nexport NIX_PATH=nixpkgs=channel:nixos-unstable\n# End Nix\n";
+        let edited = format!("{}{other_block}", read_to_string(&test_file).await?);
+        write(test_file.as_path(), &edited).await?;
+
+        action.try_revert().await?;
+
+        assert_eq!(
+            read_to_string(&test_file).await?,
+            format!("Some content\n{other_block}")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn managed_block_leaves_an_edited_block() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("managed_block_leaves_an_edited_block");
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test\n".into(),
+            Position::ManagedBlock {
+                begin_marker: "# Nix".into(),
+                end_marker: "# End Nix".into(),
+            },
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+
+        let edited = "# Nix\nTest\nEdited by the user\n# End Nix\n";
+        write(test_file.as_path(), edited).await?;
+
+        action.try_revert().await?;
+
+        assert_eq!(read_to_string(&test_file).await?, edited);
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_bytes_from_the_end() {
+        assert_eq!(rfind_bytes(b"abcabc", b"bc"), Some(4));
+        assert_eq!(rfind_bytes(b"abc", b"abcd"), None);
+        assert_eq!(rfind_bytes(b"\xff\xfeab", b"ab"), Some(2));
+        assert_eq!(rfind_bytes(b"abc", b""), Some(3));
+    }
+
     #[tokio::test]
     async fn errors_on_dir() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
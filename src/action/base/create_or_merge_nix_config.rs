@@ -10,6 +10,7 @@ use tracing::{span, Span};
 
 use crate::{
     action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    backup::{backup_file, FileBackup},
     util::OnMissing,
 };
 
@@ -43,7 +44,12 @@ impl From<CreateOrMergeNixConfigError> for ActionErrorKind {
     }
 }
 
-/// Create or merge an existing `nix.conf` at the specified path.
+/** Create or merge an existing `nix.conf` at the specified path.
+
+On revert, only the settings (or, for mergeable settings like `experimental-features`, the values)
+this action contributed are removed. Settings the user added or changed after install are kept,
+and the file is only deleted if nothing else remains in it.
+ */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_or_merge_nix_config")]
 pub struct CreateOrMergeNixConfig {
@@ -51,6 +57,29 @@ pub struct CreateOrMergeNixConfig {
     pending_nix_config: NixConfig,
     header: String,
     footer: Option<String>,
+    /// The original file, saved before the first write
+    #[serde(default)]
+    backup: Option<FileBackup>,
+    /// Where to back up the original file before the first write, `None` to not back it up
+    #[serde(skip, default = "crate::backup::default_backup_root")]
+    backup_root: Option<PathBuf>,
+    /// The full value of each setting as written, to detect edits made after install
+    ///
+    /// `None` in receipts from versions which deleted the whole file on revert.
+    #[serde(default)]
+    written_nix_config: Option<NixConfig>,
+    /// The values of each setting which were not present before install
+    #[serde(default)]
+    contributed_nix_config: Option<NixConfig>,
+}
+
+/// The result of removing the contributed settings from a `nix.conf`
+struct NixConfigRevert {
+    /// Whether any line was removed or rewritten
+    changed: bool,
+    contents: String,
+    removed: Vec<String>,
+    kept: Vec<String>,
 }
 
 impl CreateOrMergeNixConfig {
@@ -68,6 +97,10 @@ impl CreateOrMergeNixConfig {
             pending_nix_config,
             header,
             footer,
+            backup: None,
+            backup_root: crate::backup::default_backup_root(),
+            written_nix_config: Some(NixConfig::new()),
+            contributed_nix_config: Some(NixConfig::new()),
         };
 
         if this.path.exists() {
@@ -183,6 +216,81 @@ impl CreateOrMergeNixConfig {
         Ok((merged_nix_config, existing_nix_config))
     }
 
+    /// Remove the contributed settings from `contents`, keeping anything the user added or changed
+    fn revert_contents(
+        &self,
+        contents: &str,
+        written_nix_config: &NixConfig,
+        contributed_nix_config: &NixConfig,
+    ) -> NixConfigRevert {
+        let mut ours = self.header.lines().map(str::trim).collect::<Vec<_>>();
+        if let Some(footer) = &self.footer {
+            ours.extend(footer.lines().map(str::trim));
+        }
+
+        let mut changed = false;
+        let mut lines = vec![];
+        let mut removed = vec![];
+        let mut kept = vec![];
+        for line in contents.lines() {
+            let trimmed = line.trim();
+            if !trimmed.is_empty() && ours.contains(&trimmed) {
+                changed = true;
+                continue;
+            }
+            let Some((name, value, inline_comment)) = parse_setting_line(trimmed) else {
+                lines.push(line.to_string());
+                continue;
+            };
+            let (Some(written), Some(contributed)) = (
+                written_nix_config.settings().get(name),
+                contributed_nix_config.settings().get(name),
+            ) else {
+                lines.push(line.to_string());
+                continue;
+            };
+
+            let value_tokens = value.split_whitespace().collect::<Vec<_>>();
+            if value_tokens != written.split_whitespace().collect::<Vec<_>>() {
+                kept.push(format!("{name}=\"{value}\""));
+                lines.push(line.to_string());
+                continue;
+            }
+
+            let mut remaining = value_tokens;
+            for token in contributed.split_whitespace() {
+                if let Some(idx) = remaining.iter().position(|v| *v == token) {
+                    remaining.remove(idx);
+                }
+            }
+            changed = true;
+            removed.push(format!("{name}=\"{contributed}\""));
+            if !remaining.is_empty() {
+                let mut line = format!("{name} = {}", remaining.join(" "));
+                if let Some(inline_comment) = inline_comment {
+                    line.push(' ');
+                    line.push_str(inline_comment);
+                }
+                lines.push(line);
+            }
+        }
+
+        while lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+        let mut contents = lines.join("\n");
+        if !contents.trim().is_empty() {
+            contents.push('\n');
+        }
+
+        NixConfigRevert {
+            changed,
+            contents,
+            removed,
+            kept,
+        }
+    }
+
     async fn maybe_comment_out_invalid_conf(
         path: &Path,
     ) -> Result<Result<NixConfig, nix_config_parser::ParseError>, ActionError> {
@@ -288,6 +396,12 @@ impl Action for CreateOrMergeNixConfig {
             );
         }
 
+        if self.backup.is_none() {
+            self.backup = backup_file(self.backup_root.as_deref(), &self.path)
+                .await
+                .map_err(Self::error)?;
+        }
+
         // Create a temporary file in the same directory as the one
         // that the final file goes in, so that we can rename it
         // atomically
@@ -322,6 +436,28 @@ impl Action for CreateOrMergeNixConfig {
             (self.pending_nix_config.clone(), None)
         };
 
+        let mut contributed_nix_config = NixConfig::new();
+        for (name, value) in merged_nix_config.settings() {
+            let existing_value = existing_nix_config
+                .as_ref()
+                .and_then(|existing| existing.settings().get(name));
+            let existing_tokens = existing_value
+                .map(|existing| existing.split_whitespace().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let contributed = value
+                .split_whitespace()
+                .filter(|token| !existing_tokens.contains(token))
+                .collect::<Vec<_>>()
+                .join(" ");
+            if !contributed.is_empty() {
+                contributed_nix_config
+                    .settings_mut()
+                    .insert(name.clone(), contributed);
+            }
+        }
+        self.written_nix_config = Some(merged_nix_config.clone());
+        self.contributed_nix_config = Some(contributed_nix_config);
+
         let mut new_config = String::new();
 
         if let Some(existing_nix_config) = existing_nix_config.as_mut() {
@@ -499,22 +635,116 @@ impl Action for CreateOrMergeNixConfig {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        let (Some(written_nix_config), Some(contributed_nix_config)) =
+            (&self.written_nix_config, &self.contributed_nix_config)
+        else {
+            return vec![ActionDescription::new(
+                format!("Delete file `{}`", self.path.display()),
+                vec![format!("Delete file `{}`", self.path.display())],
+            )];
+        };
+
+        let mut explanation = vec![];
+        if let Ok(contents) = std::fs::read_to_string(&self.path) {
+            let NixConfigRevert {
+                changed: _,
+                contents,
+                removed,
+                kept,
+            } = self.revert_contents(&contents, written_nix_config, contributed_nix_config);
+            if !removed.is_empty() {
+                explanation.push(format!("Remove settings: {}", removed.join(", ")));
+            }
+            if !kept.is_empty() {
+                explanation.push(format!(
+                    "Keep settings changed after install: {}",
+                    kept.join(", ")
+                ));
+            }
+            if contents.is_empty() {
+                explanation.push(format!("Delete file `{}`", self.path.display()));
+            } else {
+                explanation.push(format!(
+                    "Keep file `{}`, it contains other configuration",
+                    self.path.display()
+                ));
+            }
+        }
+
         vec![ActionDescription::new(
-            format!("Delete file `{}`", self.path.display()),
-            vec![format!("Delete file `{}`", self.path.display())],
+            format!(
+                "Remove the Nix configuration added to `{}`",
+                self.path.display()
+            ),
+            explanation,
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        crate::util::remove_file(&self.path, OnMissing::Ignore)
-            .await
-            .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.to_owned(), e)))?;
+        let (Some(written_nix_config), Some(contributed_nix_config)) =
+            (&self.written_nix_config, &self.contributed_nix_config)
+        else {
+            crate::util::remove_file(&self.path, OnMissing::Ignore)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.to_owned(), e)))?;
+
+            return Ok(());
+        };
+
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            // The user already deleted it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Self::error(ActionErrorKind::Read(self.path.to_owned(), e))),
+        };
+
+        let NixConfigRevert {
+            changed,
+            contents,
+            removed: _,
+            kept,
+        } = self.revert_contents(&contents, written_nix_config, contributed_nix_config);
+
+        if !changed {
+            return Ok(());
+        }
+
+        if !kept.is_empty() {
+            tracing::debug!(
+                "Keeping settings changed after install in `{}`: {}",
+                self.path.display(),
+                kept.join(", ")
+            );
+        }
+
+        if contents.is_empty() {
+            crate::util::remove_file(&self.path, OnMissing::Ignore)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.to_owned(), e)))?;
+        } else {
+            crate::util::write_atomic(&self.path, &contents)
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
 }
 
+/// Split a `name = value # comment` line, returning `None` for comments and directives like `include`
+fn parse_setting_line(line: &str) -> Option<(&str, &str, Option<&str>)> {
+    if line.starts_with(NIX_CONF_COMMENT_CHAR) {
+        return None;
+    }
+    let (setting, inline_comment) = match line.find(NIX_CONF_COMMENT_CHAR) {
+        Some(idx) => (&line[..idx], Some(&line[idx..])),
+        None => (line, None),
+    };
+    let (name, value) = setting.split_once('=')?;
+    Some((name.trim(), value.trim(), inline_comment))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -537,6 +767,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         let s = std::fs::read_to_string(&test_file)?;
@@ -554,11 +785,9 @@ mod test {
     }
 
     #[tokio::test]
-    async fn creates_and_deletes_file_even_if_edited() -> eyre::Result<()> {
+    async fn creates_and_keeps_user_edits() -> eyre::Result<()> {
         let temp_dir = tempfile::TempDir::new()?;
-        let test_file = temp_dir
-            .path()
-            .join("creates_and_deletes_file_even_if_edited");
+        let test_file = temp_dir.path().join("creates_and_keeps_user_edits");
         let mut nix_config = NixConfig::new();
         nix_config
             .settings_mut()
@@ -571,13 +800,63 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
-        write(test_file.as_path(), "More content").await?;
+        let s = std::fs::read_to_string(&test_file)?;
+        write(
+            test_file.as_path(),
+            format!("{s}\nwarn-dirty = false\n# A comment from the user\n"),
+        )
+        .await?;
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        let s = std::fs::read_to_string(&test_file)?;
+        assert_eq!(s, "\nwarn-dirty = false\n# A comment from the user\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_settings_changed_after_install() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("keeps_settings_changed_after_install");
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "ca-references".into());
+        nix_config
+            .settings_mut()
+            .insert("max-jobs".into(), "auto".into());
+        let mut action = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config,
+            "# Generated by".to_string(),
+            None,
+        )
+        .await?;
+
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
+        action.try_execute().await?;
+
+        let s = std::fs::read_to_string(&test_file)?;
+        write(
+            test_file.as_path(),
+            s.replace("max-jobs = auto", "max-jobs = 4"),
+        )
+        .await?;
+
+        let description = action.inner().revert_description();
+        assert!(description[0]
+            .explanation
+            .iter()
+            .any(|line| line.contains("max-jobs=\"4\"")));
+
+        action.try_revert().await?;
+
+        let s = std::fs::read_to_string(&test_file)?;
+        assert_eq!(s, "max-jobs = 4\n");
 
         Ok(())
     }
@@ -605,11 +884,13 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        let s = std::fs::read_to_string(&test_file)?;
+        assert_eq!(s, test_content, "The pre-existing file should be unchanged");
 
         Ok(())
     }
@@ -643,6 +924,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         let s = std::fs::read_to_string(&test_file)?;
@@ -660,7 +942,12 @@ mod test {
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        let s = std::fs::read_to_string(&test_file)?;
+        assert!(s.contains("warn-dirty = true"));
+        assert!(s.contains("experimental-features = flakes"));
+        assert!(!s.contains("nix-command"));
+        assert!(!s.contains("allow-dirty"));
+        assert!(!s.contains("# Generated by"));
 
         Ok(())
     }
@@ -736,6 +1023,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         let s = std::fs::read_to_string(&test_file)?;
@@ -750,7 +1038,10 @@ mod test {
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        let s = std::fs::read_to_string(&test_file)?;
+        assert!(s.contains("# this is an inline comment"));
+        assert!(s.contains("# some inline comment about experimental-features"));
+        assert!(!s.contains("ca-references"));
 
         Ok(())
     }
@@ -774,6 +1065,7 @@ mod test {
         )
         .await?;
 
+        action.action.backup_root = Some(temp_dir.path().join("backups"));
         action.try_execute().await?;
 
         let s = std::fs::read_to_string(&test_file)?;
@@ -784,7 +1076,9 @@ mod test {
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        let s = std::fs::read_to_string(&test_file)?;
+        assert_eq!(s.matches("a = b").count(), 1);
+        assert!(!s.contains("ca-references"));
 
         Ok(())
     }
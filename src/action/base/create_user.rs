@@ -7,6 +7,8 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::linux::{is_busybox_applet, UserDatabase};
+use crate::settings::UserBackend;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -24,8 +26,12 @@ pub struct CreateUser {
     pub(crate) groupname: String,
     pub(crate) gid: u32,
     comment: String,
+    #[serde(default)]
+    backend: UserBackend,
 }
 
+const USER_COMMANDS: &[&[&str]] = &[&["useradd", "adduser"], &["userdel", "deluser"]];
+
 impl CreateUser {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
@@ -35,23 +41,28 @@ impl CreateUser {
         gid: u32,
         comment: String,
         check_completed: bool,
+        backend: UserBackend,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self {
+        let mut this = Self {
             name: name.clone(),
             uid,
             groupname,
             gid,
             comment,
+            backend,
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("useradd").is_ok() || which::which("adduser").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
-                }
-                if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
+                this.backend = backend.resolve(USER_COMMANDS);
+                if this.backend == UserBackend::Tools {
+                    if !(which::which("useradd").is_ok() || which::which("adduser").is_ok()) {
+                        return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
+                    }
+                    if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
+                        return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
+                    }
                 }
             },
         }
@@ -112,12 +123,13 @@ impl Action for CreateUser {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(
-            self.tracing_synopsis(),
-            vec![format!(
-                "The Nix daemon requires system users it can act as in order to build"
-            )],
-        )]
+        let mut explanation = vec![format!(
+            "The Nix daemon requires system users it can act as in order to build"
+        )];
+        if self.backend == UserBackend::Files {
+            explanation.push("Edit `/etc/passwd` and `/etc/shadow` directly".to_string());
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -128,6 +140,7 @@ impl Action for CreateUser {
             groupname,
             gid,
             comment,
+            backend,
         } = self;
 
         match OperatingSystem::host() {
@@ -137,7 +150,12 @@ impl Action for CreateUser {
                     .map_err(Self::error)?;
             },
             _ => {
-                if which::which("useradd").is_ok() {
+                if backend.resolve(USER_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .create_user(name, *uid, *gid, comment)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("useradd").is_ok() {
                     execute_command(
                         Command::new("useradd")
                             .process_group(0)
@@ -164,6 +182,15 @@ impl Action for CreateUser {
                     )
                     .await
                     .map_err(Self::error)?;
+                } else if is_busybox_applet("adduser") {
+                    execute_command(
+                        Command::new("adduser")
+                            .process_group(0)
+                            .args(busybox_adduser_args(name, *uid, groupname, comment))
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    .map_err(Self::error)?;
                 } else if which::which("adduser").is_ok() {
                     execute_command(
                         Command::new("adduser")
@@ -216,7 +243,12 @@ impl Action for CreateUser {
                 delete_user_macos(&self.name).await.map_err(Self::error)?;
             },
             _ => {
-                if which::which("userdel").is_ok() {
+                if self.backend.resolve(USER_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .delete_user(&self.name)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("userdel").is_ok() {
                     execute_command(
                         Command::new("userdel")
                             .process_group(0)
@@ -244,6 +276,29 @@ impl Action for CreateUser {
     }
 }
 
+/// The arguments for busybox's `adduser` (as on Alpine), which only takes short flags
+fn busybox_adduser_args(name: &str, uid: u32, groupname: &str, comment: &str) -> Vec<String> {
+    [
+        "-h",
+        "/var/empty",
+        "-H", // Don't create a home.
+        "-g",
+        comment,
+        "-G",
+        groupname,
+        "-S",
+        "-s",
+        "/sbin/nologin",
+        "-u",
+        &uid.to_string(),
+        "-D", // Don't assign a password.
+        name,
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
 #[tracing::instrument]
 async fn execute_dscl_retry_on_specific_errors(dscl_args: &[&str]) -> Result<(), ActionErrorKind> {
     let mut retry_tokens: usize = 10;
@@ -394,3 +449,31 @@ pub async fn delete_user_macos(name: &str) -> Result<(), ActionErrorKind> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::busybox_adduser_args;
+
+    #[test]
+    fn busybox_adduser_uses_short_flags() {
+        assert_eq!(
+            busybox_adduser_args("nixbld1", 30001, "nixbld", "Nix build user 1"),
+            [
+                "-h",
+                "/var/empty",
+                "-H",
+                "-g",
+                "Nix build user 1",
+                "-G",
+                "nixbld",
+                "-S",
+                "-s",
+                "/sbin/nologin",
+                "-u",
+                "30001",
+                "-D",
+                "nixbld1",
+            ]
+        );
+    }
+}
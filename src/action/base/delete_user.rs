@@ -6,6 +6,8 @@ use tracing::{span, Span};
 use crate::action::base::create_user::delete_user_macos;
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::linux::UserDatabase;
+use crate::settings::UserBackend;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -16,19 +18,24 @@ Delete an operating system level user
 #[serde(tag = "action_name", rename = "delete_user")]
 pub struct DeleteUser {
     name: String,
+    #[serde(default)]
+    backend: UserBackend,
 }
 
+const USER_DELETION_COMMANDS: &[&[&str]] = &[&["userdel", "deluser"]];
+
 impl DeleteUser {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(name: String) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self { name: name.clone() };
+        let mut this = Self {
+            name: name.clone(),
+            backend: UserBackend::Auto,
+        };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
-                    return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
-                }
+                this.backend = this.backend.resolve(USER_DELETION_COMMANDS);
             },
         }
 
@@ -79,7 +86,12 @@ impl Action for DeleteUser {
                 delete_user_macos(&self.name).await.map_err(Self::error)?;
             },
             _ => {
-                if which::which("userdel").is_ok() {
+                if self.backend.resolve(USER_DELETION_COMMANDS) == UserBackend::Files {
+                    UserDatabase::system()
+                        .delete_user(&self.name)
+                        .await
+                        .map_err(Self::error)?;
+                } else if which::which("userdel").is_ok() {
                     execute_command(
                         Command::new("userdel")
                             .process_group(0)
@@ -22,6 +22,7 @@ impl RemoveDirectory {
         Ok(StatefulAction {
             action: Self { path },
             state: ActionState::Uncompleted,
+            timing: None,
         })
     }
 }
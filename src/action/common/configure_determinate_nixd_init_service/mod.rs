@@ -60,7 +60,7 @@ impl ConfigureDeterminateNixdInitService {
                 Some(DARWIN_NIXD_DAEMON_DEST.into())
             },
             InitSystem::Systemd => Some(LINUX_NIXD_DAEMON_DEST.into()),
-            InitSystem::None => None,
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => None,
         };
         let service_name: Option<String> = match init {
             InitSystem::Launchd => Some(DARWIN_NIXD_SERVICE_NAME.into()),
@@ -83,7 +83,7 @@ impl ConfigureDeterminateNixdInitService {
             InitSystem::Systemd => Some(UnitSrc::Literal(
                 include_str!("./nix-daemon.determinate-nixd.service").to_string(),
             )),
-            InitSystem::None => None {},
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => None {},
         };
 
         let configure_init_service = ConfigureInitService::plan(
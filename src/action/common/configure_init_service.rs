@@ -140,7 +140,7 @@ impl ConfigureInitService {
                     return Err(Self::error(ActionErrorKind::SystemdMissing));
                 }
             },
-            InitSystem::None => {
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => {
                 // Nothing here, no init system
             },
         };
@@ -170,6 +170,8 @@ impl Action for ConfigureInitService {
                 "Configure Nix daemon related settings with launchctl".to_string()
             },
             InitSystem::None => "Leave the Nix daemon unconfigured".to_string(),
+            InitSystem::Wsl => "Leave the Nix daemon to the WSL boot command".to_string(),
+            InitSystem::Openrc => "Leave the Nix daemon to its OpenRC service".to_string(),
         }
     }
 
@@ -262,7 +264,7 @@ impl Action for ConfigureInitService {
                 }
                 vec.push(ActionDescription::new(self.tracing_synopsis(), explanation))
             },
-            InitSystem::None => (),
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => (),
         }
         vec
     }
@@ -479,7 +481,7 @@ impl Action for ConfigureInitService {
                     }
                 }
             },
-            InitSystem::None => {
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => {
                 // Nothing here, no init system
             },
         };
@@ -516,7 +518,7 @@ impl Action for ConfigureInitService {
                     )],
                 )]
             },
-            InitSystem::None => Vec::new(),
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => Vec::new(),
         }
     }
 
@@ -652,7 +654,7 @@ impl Action for ConfigureInitService {
                     errors.push(err);
                 }
             },
-            InitSystem::None => {
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => {
                 // Nothing here, no init
             },
         };
@@ -1,6 +1,6 @@
 use crate::action::base::{create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction,
 };
 use crate::planner::ShellProfileLocations;
 
@@ -11,6 +11,17 @@ use tracing::{span, Instrument, Span};
 
 const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
 const PROFILE_NIX_FILE_FISH: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish";
+/// Marks the block managed by `nix-installer` in each shell profile, matching the blocks written by earlier versions
+const PROFILE_BLOCK_BEGIN: &str = "# Nix";
+const PROFILE_BLOCK_END: &str = "# End Nix";
+/// The certificate bundles `nix-daemon.sh` considers, for shells which can't source it
+const SSL_CERT_FILE_CANDIDATES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/certs/ca-bundle.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
+];
 
 /**
 Configure any detected shell profiles to include Nix support
@@ -32,53 +43,30 @@ impl ConfigureShellProfile {
         let mut create_directories = Vec::default();
 
         let shell_buf = format!(
-            "\n\
-            # Nix\n\
+            "\
             if [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n\
             {inde}. '{PROFILE_NIX_FILE_SHELL}'\n\
             fi\n\
-            # End Nix\n
-        \n",
+        ",
             inde = "    ", // indent
         );
 
         for profile_target in locations.bash.iter().chain(locations.zsh.iter()) {
-            let profile_target_path = Path::new(profile_target);
-            if let Some(parent) = profile_target_path.parent() {
-                // Some tools (eg `nix-darwin`) create symlinks to these files, don't write to them if that's the case.
-                if !profile_target_path.is_symlink() {
-                    if !parent.exists() {
-                        create_directories.push(
-                            CreateDirectory::plan(parent, None, None, 0o0755, false)
-                                .await
-                                .map_err(Self::error)?,
-                        );
-                    }
-
-                    create_or_insert_files.push(
-                        CreateOrInsertIntoFile::plan(
-                            profile_target_path,
-                            None,
-                            None,
-                            0o644,
-                            shell_buf.to_string(),
-                            create_or_insert_into_file::Position::Beginning,
-                        )
-                        .await
-                        .map_err(Self::error)?,
-                    );
-                }
-            }
+            plan_profile_target(
+                profile_target,
+                &shell_buf,
+                &mut create_directories,
+                &mut create_or_insert_files,
+            )
+            .await?;
         }
 
         let fish_buf = format!(
-            "\n\
-            # Nix\n\
+            "\
             if test -e '{PROFILE_NIX_FILE_FISH}'\n\
             {inde}. '{PROFILE_NIX_FILE_FISH}'\n\
             end\n\
-            # End Nix\n\
-        \n",
+        ",
             inde = "    ", // indent
         );
 
@@ -109,7 +97,10 @@ impl ConfigureShellProfile {
                         None,
                         0o644,
                         fish_buf.to_string(),
-                        create_or_insert_into_file::Position::Beginning,
+                        create_or_insert_into_file::Position::ManagedBlock {
+                            begin_marker: PROFILE_BLOCK_BEGIN.to_string(),
+                            end_marker: PROFILE_BLOCK_END.to_string(),
+                        },
                     )
                     .await?,
                 );
@@ -139,12 +130,67 @@ impl ConfigureShellProfile {
                     None,
                     0o644,
                     fish_buf.to_string(),
-                    create_or_insert_into_file::Position::Beginning,
+                    create_or_insert_into_file::Position::ManagedBlock {
+                        begin_marker: PROFILE_BLOCK_BEGIN.to_string(),
+                        end_marker: PROFILE_BLOCK_END.to_string(),
+                    },
                 )
                 .await?,
             );
         }
 
+        let nushell_buf = nushell_buf();
+        let nushell_installed = which::which("nu").is_ok();
+        let mut nushell_prefixes = locations
+            .nushell
+            .vendor_autoload_prefixes
+            .iter()
+            .filter(|prefix| prefix.exists())
+            .collect::<Vec<_>>();
+        if nushell_prefixes.is_empty() && nushell_installed {
+            // Nushell installed outside of a package manager (eg via `cargo`) still reads the vendor
+            // autoload directories of `$XDG_DATA_DIRS`, so create the first one.
+            nushell_prefixes.extend(locations.nushell.vendor_autoload_prefixes.first());
+        }
+        for nushell_prefix in nushell_prefixes {
+            let profile_target = nushell_prefix.join(&locations.nushell.vendor_autoload_suffix);
+            plan_profile_target(
+                &profile_target,
+                &nushell_buf,
+                &mut create_directories,
+                &mut create_or_insert_files,
+            )
+            .await?;
+        }
+
+        let xonsh_buf = xonsh_buf();
+        let elvish_buf = elvish_buf();
+        let csh_buf = csh_buf();
+        let csh_installed = which::which("tcsh").is_ok() || which::which("csh").is_ok();
+        for (targets, buf, installed) in [
+            (&locations.xonsh, &xonsh_buf, which::which("xonsh").is_ok()),
+            (
+                &locations.elvish,
+                &elvish_buf,
+                which::which("elvish").is_ok(),
+            ),
+            (&locations.csh, &csh_buf, csh_installed),
+        ] {
+            for profile_target in targets {
+                // Unlike the bash/zsh profiles, don't litter `/etc` with files for shells which aren't there.
+                if !installed && !profile_target.exists() {
+                    continue;
+                }
+                plan_profile_target(
+                    profile_target,
+                    buf,
+                    &mut create_directories,
+                    &mut create_or_insert_files,
+                )
+                .await?;
+            }
+        }
+
         // If the `$GITHUB_PATH` environment exists, we're almost certainly running on Github
         // Actions, and almost certainly wants the relevant `$PATH` additions added.
         if let Ok(github_path) = std::env::var("GITHUB_PATH") {
@@ -179,6 +225,145 @@ impl ConfigureShellProfile {
         }
         .into())
     }
+
+    /// Back up the original shell profiles under `root` instead, or not at all without one
+    pub(crate) fn set_backup_root(&mut self, root: Option<PathBuf>) {
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            create_or_insert_into_file
+                .action
+                .set_backup_root(root.clone());
+        }
+    }
+
+    /// The shell profiles which are planned to receive the Nix hook, but do not yet contain it
+    pub(crate) fn missing_hooks(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .filter(|create_or_insert_into_file| {
+                create_or_insert_into_file.state != ActionState::Completed
+            })
+            .map(|create_or_insert_into_file| create_or_insert_into_file.inner().path.clone())
+            .collect()
+    }
+}
+
+/// The Nushell hook, which sets up the environment `nix-daemon.sh` would, as Nushell cannot source it
+fn nushell_buf() -> String {
+    format!(
+        "\
+        if ('{PROFILE_NIX_FILE_SHELL}' | path exists) {{\n\
+        {inde}$env.NIX_PROFILES = $\"/nix/var/nix/profiles/default ($env.HOME)/.nix-profile\"\n\
+        {inde}if 'NIX_SSL_CERT_FILE' not-in $env {{\n\
+        {inde}{inde}let certs = [{certs}] | where {{|cert| $cert | path exists }}\n\
+        {inde}{inde}if ($certs | length) > 0 {{\n\
+        {inde}{inde}{inde}$env.NIX_SSL_CERT_FILE = ($certs | first)\n\
+        {inde}{inde}}}\n\
+        {inde}}}\n\
+        {inde}$env.PATH = ($env.PATH | prepend [$\"($env.HOME)/.nix-profile/bin\" '/nix/var/nix/profiles/default/bin'] | uniq)\n\
+        }}\n\
+    ",
+        inde = "    ", // indent
+        certs = SSL_CERT_FILE_CANDIDATES
+            .iter()
+            .map(|cert| format!("'{cert}'"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// The xonsh hook, which sources `nix-daemon.sh` through `source-bash`
+fn xonsh_buf() -> String {
+    format!(
+        "\
+        if p'{PROFILE_NIX_FILE_SHELL}'.exists():\n\
+        {inde}source-bash '{PROFILE_NIX_FILE_SHELL}'\n\
+    ",
+        inde = "    ", // indent
+    )
+}
+
+/// The elvish hook, which sets up the environment `nix-daemon.sh` would
+fn elvish_buf() -> String {
+    format!(
+        "\
+        use os\n\
+        if (os:exists '{PROFILE_NIX_FILE_SHELL}') {{\n\
+        {inde}set-env NIX_PROFILES '/nix/var/nix/profiles/default '$E:HOME'/.nix-profile'\n\
+        {inde}if (not (has-env NIX_SSL_CERT_FILE)) {{\n\
+        {inde}{inde}for cert [{certs}] {{\n\
+        {inde}{inde}{inde}if (os:exists $cert) {{ set-env NIX_SSL_CERT_FILE $cert; break }}\n\
+        {inde}{inde}}}\n\
+        {inde}}}\n\
+        {inde}set paths = [$E:HOME'/.nix-profile/bin' /nix/var/nix/profiles/default/bin $@paths]\n\
+        }}\n\
+    ",
+        inde = "    ", // indent
+        certs = SSL_CERT_FILE_CANDIDATES.join(" "),
+    )
+}
+
+/// The `csh`/`tcsh` hook, which sets up the environment `nix-daemon.sh` would
+fn csh_buf() -> String {
+    format!(
+        "\
+        if ( -e '{PROFILE_NIX_FILE_SHELL}' ) then\n\
+        {inde}setenv NIX_PROFILES \"/nix/var/nix/profiles/default $HOME/.nix-profile\"\n\
+        {inde}if ( ! $?NIX_SSL_CERT_FILE ) then\n\
+        {inde}{inde}foreach cert ( {certs} )\n\
+        {inde}{inde}{inde}if ( ! $?NIX_SSL_CERT_FILE && -e $cert ) setenv NIX_SSL_CERT_FILE $cert\n\
+        {inde}{inde}end\n\
+        {inde}endif\n\
+        {inde}set path = ( $HOME/.nix-profile/bin /nix/var/nix/profiles/default/bin $path )\n\
+        endif\n\
+    ",
+        inde = "    ", // indent
+        certs = SSL_CERT_FILE_CANDIDATES.join(" "),
+    )
+}
+
+/// Plan inserting `buf` into `profile_target`, creating its parent directory if required
+async fn plan_profile_target(
+    profile_target: &Path,
+    buf: &str,
+    create_directories: &mut Vec<StatefulAction<CreateDirectory>>,
+    create_or_insert_files: &mut Vec<StatefulAction<CreateOrInsertIntoFile>>,
+) -> Result<(), ActionError> {
+    // Some tools (eg `nix-darwin`) create symlinks to these files, don't write to them if that's the case.
+    if profile_target.is_symlink() {
+        return Ok(());
+    }
+    let Some(parent) = profile_target.parent() else {
+        return Ok(());
+    };
+    if !parent.exists()
+        && !create_directories
+            .iter()
+            .any(|create_directory| create_directory.inner().path == parent)
+    {
+        create_directories.push(
+            CreateDirectory::plan(parent, None, None, 0o0755, false)
+                .await
+                .map_err(ConfigureShellProfile::error)?,
+        );
+    }
+
+    create_or_insert_files.push(
+        CreateOrInsertIntoFile::plan(
+            profile_target,
+            None,
+            None,
+            0o644,
+            buf.to_string(),
+            create_or_insert_into_file::Position::ManagedBlock {
+                begin_marker: PROFILE_BLOCK_BEGIN.to_string(),
+                end_marker: PROFILE_BLOCK_END.to_string(),
+            },
+        )
+        .await
+        .map_err(ConfigureShellProfile::error)?,
+    );
+
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -300,3 +485,135 @@ impl Action for ConfigureShellProfile {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::planner::{FishShellProfileLocations, NushellProfileLocations};
+
+    const CERTS: &str = "/etc/ssl/certs/ca-certificates.crt /etc/ssl/ca-bundle.pem /etc/ssl/certs/ca-bundle.crt /etc/pki/tls/certs/ca-bundle.crt /nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt";
+
+    #[test]
+    fn nushell_hook() {
+        let quoted_certs = CERTS
+            .split(' ')
+            .map(|cert| format!("'{cert}'"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(
+            nushell_buf(),
+            format!(
+                r#"if ('/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' | path exists) {{
+    $env.NIX_PROFILES = $"/nix/var/nix/profiles/default ($env.HOME)/.nix-profile"
+    if 'NIX_SSL_CERT_FILE' not-in $env {{
+        let certs = [{quoted_certs}] | where {{|cert| $cert | path exists }}
+        if ($certs | length) > 0 {{
+            $env.NIX_SSL_CERT_FILE = ($certs | first)
+        }}
+    }}
+    $env.PATH = ($env.PATH | prepend [$"($env.HOME)/.nix-profile/bin" '/nix/var/nix/profiles/default/bin'] | uniq)
+}}
+"#
+            )
+        );
+    }
+
+    #[test]
+    fn xonsh_hook() {
+        assert_eq!(
+            xonsh_buf(),
+            r#"if p'/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'.exists():
+    source-bash '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'
+"#
+        );
+    }
+
+    #[test]
+    fn elvish_hook() {
+        assert_eq!(
+            elvish_buf(),
+            format!(
+                r#"use os
+if (os:exists '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh') {{
+    set-env NIX_PROFILES '/nix/var/nix/profiles/default '$E:HOME'/.nix-profile'
+    if (not (has-env NIX_SSL_CERT_FILE)) {{
+        for cert [{CERTS}] {{
+            if (os:exists $cert) {{ set-env NIX_SSL_CERT_FILE $cert; break }}
+        }}
+    }}
+    set paths = [$E:HOME'/.nix-profile/bin' /nix/var/nix/profiles/default/bin $@paths]
+}}
+"#
+            )
+        );
+    }
+
+    #[test]
+    fn csh_hook() {
+        assert_eq!(
+            csh_buf(),
+            format!(
+                r#"if ( -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ) then
+    setenv NIX_PROFILES "/nix/var/nix/profiles/default $HOME/.nix-profile"
+    if ( ! $?NIX_SSL_CERT_FILE ) then
+        foreach cert ( {CERTS} )
+            if ( ! $?NIX_SSL_CERT_FILE && -e $cert ) setenv NIX_SSL_CERT_FILE $cert
+        end
+    endif
+    set path = ( $HOME/.nix-profile/bin /nix/var/nix/profiles/default/bin $path )
+endif
+"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_existing_profiles_of_other_shells() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let xonshrc = temp_dir.path().join("xonshrc");
+        std::fs::write(&xonshrc, "$XONSH_SHOW_TRACEBACK = True\n")?;
+        let csh_login = temp_dir.path().join("csh/csh.login");
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![],
+            zsh: vec![],
+            nushell: NushellProfileLocations {
+                vendor_autoload_prefixes: vec![temp_dir.path().join("nushell")],
+                ..Default::default()
+            },
+            xonsh: vec![xonshrc.clone()],
+            elvish: vec![],
+            csh: vec![csh_login.clone()],
+        };
+        let nu_installed = which::which("nu").is_ok();
+        let csh_installed = which::which("tcsh").is_ok() || which::which("csh").is_ok();
+
+        let action = ConfigureShellProfile::plan(locations).await?.action;
+        let targets = action
+            .create_or_insert_into_files
+            .iter()
+            .map(|create_or_insert_into_file| create_or_insert_into_file.inner().path.clone())
+            .filter(|path| path.starts_with(temp_dir.path()))
+            .collect::<Vec<_>>();
+
+        // The xonsh profile exists, so it is written even without `xonsh` installed
+        assert!(targets.contains(&xonshrc));
+        assert_eq!(targets.contains(&csh_login), csh_installed);
+        assert_eq!(
+            targets.contains(&temp_dir.path().join("nushell/vendor/autoload/nix.nu")),
+            nu_installed
+        );
+        // Missing parent directories are created along with the profile
+        assert_eq!(
+            action.create_directories.iter().any(
+                |create_directory| create_directory.inner().path == temp_dir.path().join("csh")
+            ),
+            csh_installed
+        );
+        Ok(())
+    }
+}
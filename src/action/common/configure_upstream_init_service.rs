@@ -37,7 +37,7 @@ impl ConfigureUpstreamInitService {
         let service_src: Option<UnitSrc> = match init {
             InitSystem::Launchd => Some(UnitSrc::Path(DARWIN_NIX_DAEMON_SOURCE.into())),
             InitSystem::Systemd => Some(UnitSrc::Path(SERVICE_SRC.into())),
-            InitSystem::None => None,
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => None,
         };
         let service_dest: Option<PathBuf> = match init {
             InitSystem::Launchd => {
@@ -64,7 +64,7 @@ impl ConfigureUpstreamInitService {
                 Some(DARWIN_NIX_DAEMON_DEST.into())
             },
             InitSystem::Systemd => Some(SERVICE_DEST.into()),
-            InitSystem::None => None,
+            InitSystem::None | InitSystem::Wsl | InitSystem::Openrc => None,
         };
         let service_name: Option<String> = match init {
             InitSystem::Launchd => Some(DARWIN_LAUNCHD_SERVICE_NAME.into()),
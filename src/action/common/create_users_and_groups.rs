@@ -26,6 +26,7 @@ impl CreateUsersAndGroups {
         let create_group = CreateGroup::plan(
             settings.nix_build_group_name.clone(),
             settings.nix_build_group_id,
+            settings.user_backend,
         )?;
         let mut create_users = Vec::with_capacity(settings.nix_build_user_count as usize);
         let mut add_users_to_groups = Vec::with_capacity(settings.nix_build_user_count as usize);
@@ -38,6 +39,7 @@ impl CreateUsersAndGroups {
                     settings.nix_build_group_id,
                     format!("Nix build user {index}"),
                     true,
+                    settings.user_backend,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -48,6 +50,7 @@ impl CreateUsersAndGroups {
                     settings.nix_build_user_id_base + index,
                     settings.nix_build_group_name.clone(),
                     settings.nix_build_group_id,
+                    settings.user_backend,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -0,0 +1,271 @@
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+
+pub const OPENRC_SERVICE_NAME: &str = "nix-daemon";
+pub const OPENRC_SERVICE_DEST: &str = "/etc/init.d/nix-daemon";
+const OPENRC_RUNLEVEL: &str = "default";
+const OPENRC_SOFTLEVEL: &str = "/run/openrc/softlevel";
+const OPENRC_DAEMON_PID_FILE: &str = "/run/nix-daemon.pid";
+const OPENRC_DAEMON_LOG: &str = "/var/log/nix-daemon.log";
+
+/**
+Run the Nix daemon as an OpenRC service, as on Alpine
+
+The service is added to the `default` runlevel. Containers usually aren't booted by OpenRC, in which
+case the service is only started by the next boot.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_openrc_service")]
+pub struct ConfigureOpenrcService {
+    start_daemon: bool,
+    create_service: StatefulAction<CreateFile>,
+}
+
+impl ConfigureOpenrcService {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        daemon_command: &str,
+        start_daemon: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        if which::which("openrc-run").is_err() || which::which("rc-update").is_err() {
+            return Err(Self::error(ActionErrorKind::OpenrcMissing));
+        }
+
+        let create_service = CreateFile::plan(
+            OPENRC_SERVICE_DEST,
+            None,
+            None,
+            0o0755,
+            service_script(daemon_command),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            start_daemon,
+            create_service,
+        }
+        .into())
+    }
+}
+
+/// The `openrc-run` script which runs `daemon_command` in the background
+fn service_script(daemon_command: &str) -> String {
+    let (command, command_args) = daemon_command
+        .split_once(' ')
+        .unwrap_or((daemon_command, ""));
+    format!(
+        "\
+        #!/sbin/openrc-run\n\
+        # Installed by nix-installer.\n\
+        \n\
+        description=\"Nix package manager daemon\"\n\
+        command=\"{command}\"\n\
+        command_args=\"{command_args}\"\n\
+        command_background=true\n\
+        pidfile=\"{OPENRC_DAEMON_PID_FILE}\"\n\
+        output_log=\"{OPENRC_DAEMON_LOG}\"\n\
+        error_log=\"{OPENRC_DAEMON_LOG}\"\n\
+        \n\
+        depend() {{\n\
+        \x20   need localmount\n\
+        \x20   after net\n\
+        }}\n\
+    "
+    )
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_openrc_service")]
+impl Action for ConfigureOpenrcService {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_openrc_service")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Configure OpenRC to run the Nix daemon".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_openrc_service",
+            start_daemon = self.start_daemon,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            format!("Create `{OPENRC_SERVICE_DEST}`"),
+            format!("Run `rc-update add {OPENRC_SERVICE_NAME} {OPENRC_RUNLEVEL}`"),
+        ];
+        if self.start_daemon {
+            explanation.push(format!("Run `rc-service {OPENRC_SERVICE_NAME} start`"));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_service.try_execute().await?;
+
+        execute_command(
+            Command::new("rc-update")
+                .process_group(0)
+                .args(["add", OPENRC_SERVICE_NAME, OPENRC_RUNLEVEL])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        if self.start_daemon {
+            execute_command(
+                Command::new("rc-service")
+                    .process_group(0)
+                    .args([OPENRC_SERVICE_NAME, "start"])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Remove the Nix daemon's OpenRC service".to_string(),
+            vec![
+                format!("Run `rc-service {OPENRC_SERVICE_NAME} stop`"),
+                format!("Run `rc-update del {OPENRC_SERVICE_NAME} {OPENRC_RUNLEVEL}`"),
+                format!("Remove `{OPENRC_SERVICE_DEST}`"),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        // `rc-service` refuses to run on systems OpenRC didn't boot, where the service can't be running anyway
+        if Path::new(OPENRC_SOFTLEVEL).exists() {
+            if let Err(err) = execute_command(
+                Command::new("rc-service")
+                    .process_group(0)
+                    .args([OPENRC_SERVICE_NAME, "stop"])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            {
+                errors.push(Self::error(err));
+            }
+        }
+
+        if Path::new("/etc/runlevels")
+            .join(OPENRC_RUNLEVEL)
+            .join(OPENRC_SERVICE_NAME)
+            .exists()
+        {
+            if let Err(err) = execute_command(
+                Command::new("rc-update")
+                    .process_group(0)
+                    .args(["del", OPENRC_SERVICE_NAME, OPENRC_RUNLEVEL])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            {
+                errors.push(Self::error(err));
+            }
+        }
+
+        if let Err(err) = self.create_service.try_revert().await {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn service_script_runs_daemon_in_background() {
+        assert_eq!(
+            service_script("/nix/var/nix/profiles/default/bin/nix-daemon --daemon"),
+            "#!/sbin/openrc-run\n\
+            # Installed by nix-installer.\n\
+            \n\
+            description=\"Nix package manager daemon\"\n\
+            command=\"/nix/var/nix/profiles/default/bin/nix-daemon\"\n\
+            command_args=\"--daemon\"\n\
+            command_background=true\n\
+            pidfile=\"/run/nix-daemon.pid\"\n\
+            output_log=\"/var/log/nix-daemon.log\"\n\
+            error_log=\"/var/log/nix-daemon.log\"\n\
+            \n\
+            depend() {\n\
+            \x20   need localmount\n\
+            \x20   after net\n\
+            }\n"
+        );
+        assert!(service_script("/usr/local/bin/determinate-nixd")
+            .contains("command=\"/usr/local/bin/determinate-nixd\"\ncommand_args=\"\"\n"));
+    }
+
+    #[tokio::test]
+    async fn describes_starting_only_when_asked() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let create_service = CreateFile::plan(
+            temp_dir.path().join("nix-daemon"),
+            None,
+            None,
+            0o0755,
+            service_script("nix-daemon"),
+            false,
+        )
+        .await?;
+        let mut action = ConfigureOpenrcService {
+            start_daemon: false,
+            create_service,
+        };
+        let explanation = |action: &ConfigureOpenrcService| {
+            action
+                .execute_description()
+                .into_iter()
+                .flat_map(|description| description.explanation)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            explanation(&action),
+            [
+                "Create `/etc/init.d/nix-daemon`",
+                "Run `rc-update add nix-daemon default`"
+            ]
+        );
+        action.start_daemon = true;
+        assert_eq!(
+            explanation(&action).last().map(String::as_str),
+            Some("Run `rc-service nix-daemon start`")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+
+pub const REPAIR_HOOKS_SERVICE_DEST: &str =
+    "/etc/systemd/system/nix-installer-repair-hooks.service";
+pub const REPAIR_HOOKS_TIMER_DEST: &str = "/etc/systemd/system/nix-installer-repair-hooks.timer";
+pub const REPAIR_HOOKS_TIMER_NAME: &str = "nix-installer-repair-hooks.timer";
+const REPAIR_HOOKS_SERVICE_UNIT: &str = "\
+[Unit]
+Description=Restore the Nix shell profile hooks if an OS upgrade removed them
+Documentation=https://github.com/DeterminateSystems/nix-installer
+RequiresMountsFor=/nix
+ConditionPathExists=/nix/nix-installer
+ConditionPathExists=/nix/receipt.json
+
+[Service]
+Type=oneshot
+Environment=NIX_INSTALLER_DIAGNOSTIC_ENDPOINT=
+ExecStart=/nix/nix-installer repair hooks --no-confirm
+StandardOutput=journal
+StandardError=journal
+";
+const REPAIR_HOOKS_TIMER_UNIT: &str = "\
+[Unit]
+Description=Check the Nix shell profile hooks after boot
+
+[Timer]
+OnBootSec=1min
+
+[Install]
+WantedBy=timers.target
+";
+
+/**
+Install a systemd service and timer which run `nix-installer repair hooks` shortly after boot
+
+Some distributions (such as SteamOS) reset `/etc` on every update, wiping the shell profile
+hooks added by [`ConfigureShellProfile`](crate::action::common::ConfigureShellProfile). The
+service checks the hooks on each boot and restores any that are missing, logging to the journal.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_repair_hooks_service")]
+pub struct ConfigureRepairHooksService {
+    start_timer: bool,
+    create_service: StatefulAction<CreateFile>,
+    create_timer: StatefulAction<CreateFile>,
+}
+
+impl ConfigureRepairHooksService {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(start_timer: bool) -> Result<StatefulAction<Self>, ActionError> {
+        if which::which("systemctl").is_err() {
+            return Err(Self::error(ActionErrorKind::SystemdMissing));
+        }
+
+        Self::plan_at(
+            Path::new(REPAIR_HOOKS_SERVICE_DEST),
+            Path::new(REPAIR_HOOKS_TIMER_DEST),
+            start_timer,
+        )
+        .await
+    }
+
+    /// Plan writing the service and timer units to the given paths
+    async fn plan_at(
+        service_dest: &Path,
+        timer_dest: &Path,
+        start_timer: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let create_service = CreateFile::plan(
+            service_dest,
+            None,
+            None,
+            0o0644,
+            REPAIR_HOOKS_SERVICE_UNIT.to_string(),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+        let create_timer = CreateFile::plan(
+            timer_dest,
+            None,
+            None,
+            0o0644,
+            REPAIR_HOOKS_TIMER_UNIT.to_string(),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            start_timer,
+            create_service,
+            create_timer,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_repair_hooks_service")]
+impl Action for ConfigureRepairHooksService {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_repair_hooks_service")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Configure a systemd timer to repair the shell profile hooks at boot".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_repair_hooks_service",
+            start_timer = self.start_timer,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            format!("Create `{}`", self.create_service.inner().path.display()),
+            format!("Create `{}`", self.create_timer.inner().path.display()),
+        ];
+        if self.start_timer {
+            explanation.push(format!(
+                "Run `systemctl enable --now {REPAIR_HOOKS_TIMER_NAME}`"
+            ));
+        } else {
+            explanation.push(format!("Run `systemctl enable {REPAIR_HOOKS_TIMER_NAME}`"));
+        }
+        explanation.push(
+            "At boot, missing shell profile hooks are restored with `/nix/nix-installer repair hooks`, logging to the journal".to_string(),
+        );
+
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_service.try_execute().await?;
+        self.create_timer.try_execute().await?;
+
+        if Path::new("/run/systemd/system").exists() {
+            execute_command(
+                Command::new("systemctl")
+                    .process_group(0)
+                    .arg("daemon-reload")
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        let mut command = Command::new("systemctl");
+        command.process_group(0).arg("enable");
+        if self.start_timer {
+            command.arg("--now");
+        }
+        execute_command(
+            command
+                .arg(REPAIR_HOOKS_TIMER_NAME)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Remove the systemd timer which repairs the shell profile hooks at boot".to_string(),
+            vec![
+                format!("Run `systemctl disable --now {REPAIR_HOOKS_TIMER_NAME}`"),
+                format!("Remove `{}`", self.create_timer.inner().path.display()),
+                format!("Remove `{}`", self.create_service.inner().path.display()),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let Err(err) = execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .args(["disable", "--now", REPAIR_HOOKS_TIMER_NAME])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        {
+            errors.push(Self::error(err));
+        }
+
+        if let Err(err) = self.create_timer.try_revert().await {
+            errors.push(err);
+        }
+        if let Err(err) = self.create_service.try_revert().await {
+            errors.push(err);
+        }
+
+        if Path::new("/run/systemd/system").exists() {
+            if let Err(err) = execute_command(
+                Command::new("systemctl")
+                    .process_group(0)
+                    .arg("daemon-reload")
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            {
+                errors.push(Self::error(err));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::plan::RECEIPT_LOCATION;
+
+    /// Plan the units under a temporary directory, with their usual file names
+    async fn plan_in(
+        dir: &Path,
+        start_timer: bool,
+    ) -> eyre::Result<StatefulAction<ConfigureRepairHooksService>> {
+        let file_name = |dest: &str| Path::new(dest).file_name().map(|name| dir.join(name));
+        Ok(ConfigureRepairHooksService::plan_at(
+            &file_name(REPAIR_HOOKS_SERVICE_DEST).expect("The service has a file name"),
+            &file_name(REPAIR_HOOKS_TIMER_DEST).expect("The timer has a file name"),
+            start_timer,
+        )
+        .await?)
+    }
+
+    #[tokio::test]
+    async fn timer_starts_the_service() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut action = plan_in(temp_dir.path(), false).await?;
+        action.action.create_service.try_execute().await?;
+        action.action.create_timer.try_execute().await?;
+
+        let timer_path = temp_dir.path().join(REPAIR_HOOKS_TIMER_NAME);
+        let timer = tokio::fs::read_to_string(&timer_path).await?;
+        assert!(timer.contains("\n[Timer]\nOnBootSec=1min\n"));
+        assert!(timer.ends_with("[Install]\nWantedBy=timers.target\n"));
+        // Without `Unit=`, a timer activates the service of the same name
+        assert!(!timer.lines().any(|line| line.starts_with("Unit=")));
+        let service = tokio::fs::read_to_string(timer_path.with_extension("service")).await?;
+        assert!(service.lines().any(|line| line == "[Service]"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_repairs_hooks_of_an_install() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut action = plan_in(temp_dir.path(), false).await?;
+        action.action.create_service.try_execute().await?;
+
+        let service =
+            tokio::fs::read_to_string(&action.inner().create_service.inner().path).await?;
+        let lines = service.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"Type=oneshot"));
+        assert!(lines.contains(&"ExecStart=/nix/nix-installer repair hooks --no-confirm"));
+        // Only runs where the installer and its receipt were left in place
+        assert!(lines.contains(&"ConditionPathExists=/nix/nix-installer"));
+        assert!(lines.contains(&format!("ConditionPathExists={RECEIPT_LOCATION}").as_str()));
+        assert!(lines.contains(&"RequiresMountsFor=/nix"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plans_and_describes_units() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let service_dest = temp_dir.path().join("nix-installer-repair-hooks.service");
+        let timer_dest = temp_dir.path().join("nix-installer-repair-hooks.timer");
+
+        let mut action =
+            ConfigureRepairHooksService::plan_at(&service_dest, &timer_dest, false).await?;
+        assert_eq!(action.inner().create_service.inner().path, service_dest);
+        assert_eq!(action.inner().create_timer.inner().path, timer_dest);
+
+        let explanation = |action: &ConfigureRepairHooksService| {
+            action
+                .execute_description()
+                .into_iter()
+                .flat_map(|description| description.explanation)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            explanation(action.inner())[..3],
+            [
+                format!("Create `{}`", service_dest.display()),
+                format!("Create `{}`", timer_dest.display()),
+                format!("Run `systemctl enable {REPAIR_HOOKS_TIMER_NAME}`"),
+            ]
+        );
+
+        action.action.start_timer = true;
+        assert!(explanation(action.inner()).contains(&format!(
+            "Run `systemctl enable --now {REPAIR_HOOKS_TIMER_NAME}`"
+        )));
+        assert_eq!(
+            action.inner().revert_description()[0].explanation,
+            [
+                format!("Run `systemctl disable --now {REPAIR_HOOKS_TIMER_NAME}`"),
+                format!("Remove `{}`", timer_dest.display()),
+                format!("Remove `{}`", service_dest.display()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::backup::{backup_file, FileBackup};
+use crate::execute_command;
+
+pub const WSL_CONF: &str = "/etc/wsl.conf";
+pub const WSL_DAEMON_SUPERVISOR_DEST: &str = "/nix/var/nix-installer/wsl-nix-daemon";
+const WSL_DAEMON_PID_FILE: &str = "/run/nix-daemon-wsl.pid";
+const WSL_DAEMON_LOG: &str = "/var/log/nix-daemon.log";
+
+/**
+Run the Nix daemon on WSL2 distributions which don't boot with systemd
+
+A small supervisor script is started by the `[boot] command` of `/etc/wsl.conf` each time the
+distribution starts, keeping the daemon running. Any existing boot command is kept, running
+after the supervisor, and is restored on revert.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_wsl_daemon")]
+pub struct ConfigureWslDaemon {
+    start_daemon: bool,
+    boot_command: String,
+    previous_boot_command: Option<String>,
+    create_supervisor: StatefulAction<CreateFile>,
+    #[serde(default)]
+    backup: Option<FileBackup>,
+    /// Where to back up `/etc/wsl.conf` before the first write, `None` to not back it up
+    #[serde(skip, default = "crate::backup::default_backup_root")]
+    backup_root: Option<PathBuf>,
+}
+
+impl ConfigureWslDaemon {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        daemon_command: &str,
+        start_daemon: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let supervisor_buf = format!(
+            "\
+            #!/bin/sh\n\
+            # Keeps the Nix daemon running on WSL2 without systemd, installed by nix-installer.\n\
+            # WSL runs this as root when the distribution starts, see `[boot] command` in {WSL_CONF}.\n\
+            \n\
+            if [ \"$1\" != \"--supervise\" ]; then\n\
+            \x20   if [ -f {WSL_DAEMON_PID_FILE} ] && kill -0 \"$(cat {WSL_DAEMON_PID_FILE})\" 2>/dev/null; then\n\
+            \x20       exit 0\n\
+            \x20   fi\n\
+            \x20   setsid \"$0\" --supervise </dev/null >>{WSL_DAEMON_LOG} 2>&1 &\n\
+            \x20   exit 0\n\
+            fi\n\
+            \n\
+            echo $$ > {WSL_DAEMON_PID_FILE}\n\
+            while true; do\n\
+            \x20   {daemon_command}\n\
+            \x20   echo \"The Nix daemon exited with status $?, restarting it\" >&2\n\
+            \x20   sleep 1\n\
+            done\n\
+        "
+        );
+        let create_supervisor = CreateFile::plan(
+            WSL_DAEMON_SUPERVISOR_DEST,
+            None,
+            None,
+            0o0755,
+            supervisor_buf,
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        let previous_boot_command = match tokio::fs::read_to_string(WSL_CONF).await {
+            Ok(contents) => boot_command(&contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Self::error(ActionErrorKind::Read(WSL_CONF.into(), e))),
+        };
+        // Planning again over our own install shouldn't chain the supervisor twice
+        let previous_boot_command = previous_boot_command
+            .map(|command| without_supervisor(&command))
+            .filter(|command| !command.is_empty());
+        let boot_command = match &previous_boot_command {
+            Some(previous) => format!("{WSL_DAEMON_SUPERVISOR_DEST}; {previous}"),
+            None => WSL_DAEMON_SUPERVISOR_DEST.to_string(),
+        };
+
+        Ok(Self {
+            start_daemon,
+            boot_command,
+            previous_boot_command,
+            create_supervisor,
+            backup: None,
+            backup_root: crate::backup::default_backup_root(),
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_wsl_daemon")]
+impl Action for ConfigureWslDaemon {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_wsl_daemon")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Configure WSL to start the Nix daemon when the distribution starts".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_wsl_daemon",
+            start_daemon = self.start_daemon,
+            boot_command = self.boot_command,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "WSL2 distributions without systemd have nothing to run the Nix daemon, which non-root users need to use Nix".to_string(),
+            format!("Create `{WSL_DAEMON_SUPERVISOR_DEST}`, which runs the daemon and restarts it if it exits"),
+            format!("Set `command = {}` in the `[boot]` section of `{WSL_CONF}`", self.boot_command),
+        ];
+        if self.start_daemon {
+            explanation.push(format!("Run `{WSL_DAEMON_SUPERVISOR_DEST}`"));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if let Some(parent) = Path::new(WSL_DAEMON_SUPERVISOR_DEST).parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(parent.into(), e))
+                .map_err(Self::error)?;
+        }
+        self.create_supervisor.try_execute().await?;
+
+        if self.backup.is_none() {
+            self.backup = backup_file(self.backup_root.as_deref(), Path::new(WSL_CONF))
+                .await
+                .map_err(Self::error)?;
+        }
+        edit_wsl_conf(|contents| with_boot_command(contents, Some(&self.boot_command)))
+            .await
+            .map_err(Self::error)?;
+
+        if self.start_daemon {
+            execute_command(
+                Command::new(WSL_DAEMON_SUPERVISOR_DEST)
+                    .process_group(0)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let boot_command = match &self.previous_boot_command {
+            Some(previous) => {
+                format!("Restore `command = {previous}` in the `[boot]` section of `{WSL_CONF}`")
+            },
+            None => format!("Remove `command` from the `[boot]` section of `{WSL_CONF}`"),
+        };
+        vec![ActionDescription::new(
+            "Stop starting the Nix daemon when the WSL distribution starts".to_string(),
+            vec![
+                "Stop the Nix daemon and its supervisor".to_string(),
+                boot_command,
+                format!("Remove `{WSL_DAEMON_SUPERVISOR_DEST}`"),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let Err(err) = stop_supervisor().await {
+            errors.push(Self::error(err));
+        }
+
+        if let Err(err) = edit_wsl_conf(|contents| match boot_command(contents) {
+            Some(current) if current == self.boot_command => {
+                with_boot_command(contents, self.previous_boot_command.as_deref())
+            },
+            Some(current) => {
+                // Someone changed the command since, only take the supervisor out of it
+                let remaining = without_supervisor(&current);
+                with_boot_command(
+                    contents,
+                    Some(remaining.as_str()).filter(|remaining| !remaining.is_empty()),
+                )
+            },
+            None => contents.to_string(),
+        })
+        .await
+        {
+            errors.push(Self::error(err));
+        }
+
+        if let Err(err) = self.create_supervisor.try_revert().await {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+/// Rewrite `/etc/wsl.conf`, creating it if needed
+async fn edit_wsl_conf(edit: impl FnOnce(&str) -> String) -> Result<(), ActionErrorKind> {
+    let contents = match tokio::fs::read_to_string(WSL_CONF).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(ActionErrorKind::Read(WSL_CONF.into(), e)),
+    };
+    let new_contents = edit(&contents);
+    if new_contents == contents {
+        return Ok(());
+    }
+
+    let temp = PathBuf::from(format!("{WSL_CONF}.nix-installer"));
+    tokio::fs::write(&temp, &new_contents)
+        .await
+        .map_err(|e| ActionErrorKind::Write(temp.clone(), e))?;
+    tokio::fs::rename(&temp, WSL_CONF)
+        .await
+        .map_err(|e| ActionErrorKind::Rename(temp.clone(), WSL_CONF.into(), e))?;
+    Ok(())
+}
+
+/// Stop the supervisor and the daemon it started, which share its process group
+async fn stop_supervisor() -> Result<(), ActionErrorKind> {
+    let pid = match tokio::fs::read_to_string(WSL_DAEMON_PID_FILE).await {
+        Ok(pid) => pid.trim().to_string(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(ActionErrorKind::Read(WSL_DAEMON_PID_FILE.into(), e)),
+    };
+
+    if Path::new("/proc").join(&pid).exists() {
+        execute_command(
+            Command::new("kill")
+                .process_group(0)
+                .args(["-TERM", "--", &format!("-{pid}")])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+    }
+
+    crate::util::remove_file(
+        Path::new(WSL_DAEMON_PID_FILE),
+        crate::util::OnMissing::Ignore,
+    )
+    .await
+    .map_err(|e| ActionErrorKind::Remove(WSL_DAEMON_PID_FILE.into(), e))?;
+    Ok(())
+}
+
+fn is_section_header(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('[') && line.ends_with(']')
+}
+
+/// The `command` key of the `[boot]` section, if any
+fn boot_command(contents: &str) -> Option<String> {
+    let mut in_boot = false;
+    for line in contents.lines() {
+        if is_section_header(line) {
+            in_boot = line.trim() == "[boot]";
+        } else if in_boot {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "command" {
+                    return Some(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Set (or, given `None`, remove) the `command` key of the `[boot]` section
+fn with_boot_command(contents: &str, command: Option<&str>) -> String {
+    let mut lines = contents.lines().map(String::from).collect::<Vec<_>>();
+    let new_line = command.map(|command| format!("command = \"{command}\""));
+
+    let boot_section = lines.iter().position(|line| line.trim() == "[boot]");
+    match boot_section {
+        Some(section) => {
+            let end = lines[section + 1..]
+                .iter()
+                .position(|line| is_section_header(line))
+                .map(|offset| section + 1 + offset)
+                .unwrap_or(lines.len());
+            let existing = lines[section + 1..end].iter().position(|line| {
+                line.split_once('=')
+                    .is_some_and(|(key, _)| key.trim() == "command")
+            });
+            match (existing, new_line) {
+                (Some(offset), Some(new_line)) => lines[section + 1 + offset] = new_line,
+                (Some(offset), None) => {
+                    lines.remove(section + 1 + offset);
+                },
+                (None, Some(new_line)) => lines.insert(section + 1, new_line),
+                (None, None) => (),
+            }
+        },
+        None => {
+            if let Some(new_line) = new_line {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push("[boot]".to_string());
+                lines.push(new_line);
+            }
+        },
+    }
+
+    let mut new_contents = lines.join("\n");
+    if !new_contents.is_empty() {
+        new_contents.push('\n');
+    }
+    new_contents
+}
+
+/// A boot command with our supervisor taken out of the chain
+fn without_supervisor(command: &str) -> String {
+    command
+        .split(';')
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty() && *part != WSL_DAEMON_SUPERVISOR_DEST)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sets_and_restores_boot_command() {
+        let original = "[automount]\nenabled = true\n\n[boot]\ncommand = \"service docker start\"\n\n[network]\nhostname = dev\n";
+        assert_eq!(
+            boot_command(original).as_deref(),
+            Some("service docker start")
+        );
+
+        let chained = format!("{WSL_DAEMON_SUPERVISOR_DEST}; service docker start");
+        let edited = with_boot_command(original, Some(&chained));
+        assert_eq!(boot_command(&edited), Some(chained.clone()));
+        assert!(edited.contains("[network]\nhostname = dev\n"));
+        assert_eq!(without_supervisor(&chained), "service docker start");
+
+        let restored = with_boot_command(&edited, Some("service docker start"));
+        assert_eq!(restored, original);
+    }
+
+    #[test]
+    fn adds_and_removes_boot_section() {
+        let original = "[automount]\nenabled = true\n";
+        let edited = with_boot_command(original, Some(WSL_DAEMON_SUPERVISOR_DEST));
+        assert_eq!(
+            edited,
+            format!("[automount]\nenabled = true\n\n[boot]\ncommand = \"{WSL_DAEMON_SUPERVISOR_DEST}\"\n")
+        );
+        assert_eq!(boot_command(""), None);
+        assert_eq!(
+            with_boot_command(&edited, None),
+            "[automount]\nenabled = true\n\n[boot]\n"
+        );
+    }
+}
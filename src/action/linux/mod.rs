@@ -1,11 +1,21 @@
+pub(crate) mod configure_openrc_service;
+pub(crate) mod configure_repair_hooks_service;
+pub(crate) mod configure_wsl_daemon;
 pub(crate) mod ensure_steamos_nix_directory;
+pub(crate) mod provision_apparmor;
 pub(crate) mod provision_selinux;
 pub(crate) mod revert_clean_steamos_nix_offload;
 pub(crate) mod start_systemd_unit;
 pub(crate) mod systemctl_daemon_reload;
+pub(crate) mod verify_selinux_labels;
 
+pub use configure_openrc_service::ConfigureOpenrcService;
+pub use configure_repair_hooks_service::ConfigureRepairHooksService;
+pub use configure_wsl_daemon::ConfigureWslDaemon;
 pub use ensure_steamos_nix_directory::EnsureSteamosNixDirectory;
+pub use provision_apparmor::ProvisionAppArmor;
 pub use provision_selinux::ProvisionSelinux;
 pub use revert_clean_steamos_nix_offload::RevertCleanSteamosNixOffload;
 pub use start_systemd_unit::{StartSystemdUnit, StartSystemdUnitError};
 pub use systemctl_daemon_reload::SystemctlDaemonReload;
+pub use verify_selinux_labels::VerifySelinuxLabels;
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::execute_command;
+
+use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::util::OnMissing;
+
+pub const APPARMOR_PROFILE_CONTENT: &str = include_str!("apparmor/nix");
+
+/**
+Provision the apparmor/nix profile for AppArmor systems which restrict unprivileged user namespaces
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "provision_apparmor")]
+pub struct ProvisionAppArmor {
+    profile_path: PathBuf,
+    profile_content: String,
+}
+
+impl ProvisionAppArmor {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        profile_path: PathBuf,
+        profile_content: &str,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let this = Self {
+            profile_path,
+            profile_content: profile_content.to_string(),
+        };
+
+        // Note: Even if the profile is in place, it may not be loaded, so always (re)load it.
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "provision_apparmor")]
+impl Action for ProvisionAppArmor {
+    fn action_tag() -> ActionTag {
+        ActionTag("provision_apparmor")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Install an AppArmor profile for Nix".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "provision_apparmor",
+            profile_path = %self.profile_path.display()
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!(
+                    "On AppArmor systems which restrict unprivileged user namespaces (such as Ubuntu 23.10 and later) Nix and the tools it installs need a profile allowing them to create user namespaces."
+                ),
+                format!(
+                    "Write `{}` and load it with `apparmor_parser`",
+                    self.profile_path.display()
+                ),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if let Some(parent) = self.profile_path.parent() {
+            tokio::fs::create_dir_all(&parent)
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(parent.into(), e))
+                .map_err(Self::error)?;
+        }
+
+        tokio::fs::write(&self.profile_path, &self.profile_content)
+            .await
+            .map_err(|e| ActionErrorKind::Write(self.profile_path.clone(), e))
+            .map_err(Self::error)?;
+
+        execute_command(
+            Command::new("apparmor_parser")
+                .process_group(0)
+                .arg("--replace")
+                .arg(&self.profile_path)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Remove the AppArmor profile for Nix".into(),
+            vec![format!(
+                "Unload the profile with `apparmor_parser` and remove `{}`",
+                self.profile_path.display()
+            )],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if self.profile_path.exists() {
+            execute_command(
+                Command::new("apparmor_parser")
+                    .process_group(0)
+                    .arg("--remove")
+                    .arg(&self.profile_path)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+
+            crate::util::remove_file(&self.profile_path, OnMissing::Ignore)
+                .await
+                .map_err(|e| ActionErrorKind::Remove(self.profile_path.clone(), e))
+                .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profile_allows_user_namespaces_from_the_store() {
+        let lines = APPARMOR_PROFILE_CONTENT
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "abi <abi/4.0>,",
+                "include <tunables/global>",
+                "profile nix /nix/store/*/bin/** flags=(unconfined) {",
+                "userns,",
+                "include if exists <local/nix>",
+                "}",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn always_reloads_the_profile() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let profile_path = temp_dir.path().join("nix");
+        std::fs::write(&profile_path, APPARMOR_PROFILE_CONTENT)?;
+
+        // Even in place, the profile may not be loaded
+        let action =
+            ProvisionAppArmor::plan(profile_path.clone(), APPARMOR_PROFILE_CONTENT).await?;
+        assert_eq!(action.state, crate::action::ActionState::Uncompleted);
+        assert_eq!(
+            action.inner().execute_description()[0].explanation[1],
+            format!(
+                "Write `{}` and load it with `apparmor_parser`",
+                profile_path.display()
+            )
+        );
+        Ok(())
+    }
+}
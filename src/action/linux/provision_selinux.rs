@@ -38,6 +38,56 @@ impl ProvisionSelinux {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// Plan a site-specific policy, either a compiled `.pp` or a `.te` source
+    ///
+    /// Sources are compiled with `checkmodule` and `semodule_package`, along with the file
+    /// contexts in a `.fc` of the same name if there is one. The policy is written to
+    /// `policy_dir` as `<module name>.pp`.
+    #[tracing::instrument(level = "debug", skip_all, fields(source = %source.display()))]
+    pub async fn plan_from_file(
+        policy_dir: &Path,
+        source: &Path,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let file_stem = source
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .ok_or_else(|| ActionErrorKind::UnknownSelinuxPolicyFormat(source.into()))
+            .map_err(Self::error)?;
+
+        let (module_name, policy_content) =
+            match source.extension().and_then(|extension| extension.to_str()) {
+                Some("pp") => {
+                    let policy_content = tokio::fs::read(source)
+                        .await
+                        .map_err(|e| ActionErrorKind::Read(source.into(), e))
+                        .map_err(Self::error)?;
+                    (file_stem, policy_content)
+                },
+                Some("te") => compile_policy_source(source, &file_stem)
+                    .await
+                    .map_err(Self::error)?,
+                _ => {
+                    return Err(Self::error(ActionErrorKind::UnknownSelinuxPolicyFormat(
+                        source.into(),
+                    )))
+                },
+            };
+
+        Self::plan(
+            policy_dir.join(format!("{module_name}.pp")),
+            &policy_content,
+        )
+        .await
+    }
+
+    /// The SELinux module name, which `semodule` takes from the policy package file name
+    fn module_name(&self) -> String {
+        self.policy_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "nix".to_string())
+    }
 }
 
 #[async_trait::async_trait]
@@ -71,7 +121,7 @@ impl Action for ProvisionSelinux {
     async fn execute(&mut self) -> Result<(), ActionError> {
         if self.policy_path.exists() {
             // Rebuild it.
-            remove_existing_policy(&self.policy_path)
+            remove_existing_policy(&self.module_name(), &self.policy_path)
                 .await
                 .map_err(Self::error)?;
         }
@@ -113,7 +163,7 @@ impl Action for ProvisionSelinux {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         if self.policy_path.exists() {
-            remove_existing_policy(&self.policy_path)
+            remove_existing_policy(&self.module_name(), &self.policy_path)
                 .await
                 .map_err(Self::error)?;
         }
@@ -122,8 +172,11 @@ impl Action for ProvisionSelinux {
     }
 }
 
-async fn remove_existing_policy(policy_path: &Path) -> Result<(), ActionErrorKind> {
-    execute_command(Command::new("semodule").arg("--remove").arg("nix")).await?;
+async fn remove_existing_policy(
+    module_name: &str,
+    policy_path: &Path,
+) -> Result<(), ActionErrorKind> {
+    execute_command(Command::new("semodule").arg("--remove").arg(module_name)).await?;
 
     crate::util::remove_file(policy_path, OnMissing::Ignore)
         .await
@@ -133,3 +186,151 @@ async fn remove_existing_policy(policy_path: &Path) -> Result<(), ActionErrorKin
 
     Ok(())
 }
+
+/// Compile a `.te` policy source into a policy package, returning its module name and content
+async fn compile_policy_source(
+    source: &Path,
+    file_stem: &str,
+) -> Result<(String, Vec<u8>), ActionErrorKind> {
+    if which::which("checkmodule").is_err() || which::which("semodule_package").is_err() {
+        return Err(ActionErrorKind::MissingSelinuxPolicyCompiler);
+    }
+
+    let source_content = tokio::fs::read_to_string(source)
+        .await
+        .map_err(|e| ActionErrorKind::Read(source.into(), e))?;
+    let module_name = declared_module_name(&source_content)
+        .unwrap_or(file_stem)
+        .to_string();
+
+    let temp_dir = tempfile::tempdir().map_err(ActionErrorKind::CreateTempDir)?;
+    let module_path = temp_dir.path().join(format!("{module_name}.mod"));
+    let package_path = temp_dir.path().join(format!("{module_name}.pp"));
+
+    execute_command(
+        Command::new("checkmodule")
+            .process_group(0)
+            .args(["-M", "-m", "-o"])
+            .arg(&module_path)
+            .arg(source)
+            .stdin(std::process::Stdio::null()),
+    )
+    .await?;
+
+    let mut semodule_package = Command::new("semodule_package");
+    semodule_package
+        .process_group(0)
+        .arg("-o")
+        .arg(&package_path)
+        .arg("-m")
+        .arg(&module_path)
+        .stdin(std::process::Stdio::null());
+    let file_contexts = source.with_extension("fc");
+    if file_contexts.exists() {
+        semodule_package.arg("-f").arg(&file_contexts);
+    }
+    execute_command(&mut semodule_package).await?;
+
+    let policy_content = tokio::fs::read(&package_path)
+        .await
+        .map_err(|e| ActionErrorKind::Read(package_path.clone(), e))?;
+
+    Ok((module_name, policy_content))
+}
+
+/// The module name declared by the first statement of a policy source, e.g. `module nix 1.0;`
+fn declared_module_name(source: &str) -> Option<&str> {
+    source
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|line| line.strip_prefix("module "))
+        .and_then(|rest| rest.split([' ', '\t', ';']).next())
+        .filter(|name| !name.is_empty())
+}
+
+/// Check `path` carries the type the loaded policy expects, as `restorecon` can silently leave
+/// it alone (for example, if the policy module failed to load)
+pub(crate) async fn verify_label(path: &Path) -> Result<(), ActionErrorKind> {
+    let output = execute_command(
+        Command::new("matchpathcon")
+            .process_group(0)
+            .arg("-n")
+            .arg(path)
+            .stdin(std::process::Stdio::null()),
+    )
+    .await?;
+    let expected = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    // The equivalent of `ls -Zd`
+    let output = execute_command(
+        Command::new("stat")
+            .process_group(0)
+            .args(["--format", "%C"])
+            .arg(path)
+            .stdin(std::process::Stdio::null()),
+    )
+    .await?;
+    let actual = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    compare_labels(path, &expected, &actual)?;
+    tracing::trace!(path = %path.display(), context = actual, "SELinux label verified");
+
+    Ok(())
+}
+
+/// Check the `actual` context of `path` has the type of the `expected` one
+pub(crate) fn compare_labels(
+    path: &Path,
+    expected: &str,
+    actual: &str,
+) -> Result<(), ActionErrorKind> {
+    // Only the type is meaningful here, the user and role vary with who created the file
+    if selinux_type(expected) != selinux_type(actual) {
+        return Err(ActionErrorKind::SelinuxLabelMismatch {
+            path: path.into(),
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// The type of an SELinux context, e.g. `nix_store_t` of `system_u:object_r:nix_store_t:s0`
+fn selinux_type(context: &str) -> Option<&str> {
+    context.split(':').nth(2)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{declared_module_name, selinux_type};
+
+    #[test]
+    fn selinux_types() {
+        assert_eq!(
+            selinux_type("system_u:object_r:nix_store_t:s0"),
+            Some("nix_store_t")
+        );
+        assert_eq!(
+            selinux_type("unconfined_u:object_r:nix_var_run_t:s0-s0:c0.c1023"),
+            Some("nix_var_run_t")
+        );
+        assert_eq!(selinux_type("<<none>>"), None);
+        assert_eq!(selinux_type(""), None);
+    }
+
+    #[test]
+    fn module_names() {
+        assert_eq!(declared_module_name("module nix 1.0;\n"), Some("nix"));
+        assert_eq!(
+            declared_module_name("# Site policy\n\n  module site-nix\t2.1;\nrequire {}\n"),
+            Some("site-nix")
+        );
+        assert_eq!(declared_module_name("module nix;"), Some("nix"));
+        assert_eq!(
+            declared_module_name("require { type unconfined_t; }\nmodule nix 1.0;"),
+            None
+        );
+        assert_eq!(declared_module_name("# Only a comment\n"), None);
+    }
+}
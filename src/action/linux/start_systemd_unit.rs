@@ -44,6 +44,7 @@ impl StartSystemdUnit {
                 enable,
             },
             state,
+            timing: None,
         })
     }
 }
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use tracing::{span, Span};
+
+use crate::action::linux::provision_selinux::verify_label;
+use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
+
+/// The store, then the daemon socket, which only exists once the daemon is started
+pub(crate) const VERIFIED_PATHS: &[&str] = &["/nix/store", "/nix/var/nix/daemon-socket"];
+
+/**
+Check the SELinux labels of `/nix` match the loaded policy, once the daemon has created its socket
+
+`restorecon` can silently leave a label alone, for example if the policy module failed to load,
+and a mislabeled store only shows up later as denials.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "verify_selinux_labels")]
+pub struct VerifySelinuxLabels {
+    paths: Vec<PathBuf>,
+}
+
+impl VerifySelinuxLabels {
+    /// Plan checking the store, and the daemon socket if the daemon is started
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(start_daemon: bool) -> Result<StatefulAction<Self>, ActionError> {
+        let paths = if start_daemon {
+            VERIFIED_PATHS.iter().map(PathBuf::from).collect()
+        } else {
+            vec![PathBuf::from(VERIFIED_PATHS[0])]
+        };
+        Ok(StatefulAction::uncompleted(Self { paths }))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "verify_selinux_labels")]
+impl Action for VerifySelinuxLabels {
+    fn action_tag() -> ActionTag {
+        ActionTag("verify_selinux_labels")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Verify the SELinux labels of `/nix`".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(tracing::Level::DEBUG, "verify_selinux_labels",)
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.paths
+                .iter()
+                .map(|path| format!("Check `{}` has the type the policy expects", path.display()))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if which::which("matchpathcon").is_err() {
+            tracing::warn!(
+                "Not verifying the SELinux labels of `/nix`, could not find `matchpathcon` to look up the expected labels"
+            );
+            return Ok(());
+        }
+        for path in &self.paths {
+            verify_label(path).await.map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::VerifySelinuxLabels;
+    use crate::{
+        action::{linux::provision_selinux::compare_labels, Action, ActionErrorKind},
+        error::FailureClass,
+        NixInstallerError,
+    };
+
+    #[test]
+    fn mislabeled_store_fails_the_install() {
+        let path = PathBuf::from("/nix/store");
+        assert!(compare_labels(
+            &path,
+            "system_u:object_r:nix_store_t:s0",
+            "unconfined_u:object_r:nix_store_t:s0"
+        )
+        .is_ok());
+
+        let kind = compare_labels(
+            &path,
+            "system_u:object_r:nix_store_t:s0",
+            "system_u:object_r:default_t:s0",
+        )
+        .unwrap_err();
+        assert!(matches!(kind, ActionErrorKind::SelinuxLabelMismatch { .. }));
+
+        let err = NixInstallerError::Action(VerifySelinuxLabels::error(kind));
+        assert_eq!(err.failure_class(), FailureClass::Action);
+        assert_eq!(err.most_specific_error_code(), "NI-ACT-0062");
+    }
+
+    #[tokio::test]
+    async fn socket_is_verified_only_if_the_daemon_starts() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let started = VerifySelinuxLabels::plan(true).await?;
+        assert_eq!(
+            started.inner().paths,
+            vec![
+                PathBuf::from("/nix/store"),
+                PathBuf::from("/nix/var/nix/daemon-socket")
+            ]
+        );
+        let stopped = VerifySelinuxLabels::plan(false).await?;
+        assert_eq!(stopped.inner().paths, vec![PathBuf::from("/nix/store")]);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
 use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::{span, Instrument, Span};
 
 const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
@@ -54,6 +54,13 @@ fi
         }
         .into())
     }
+
+    /// Back up the original `/etc/zshenv` under `root` instead, or not at all without one
+    pub(crate) fn set_backup_root(&mut self, root: Option<PathBuf>) {
+        if let Some(create_or_insert_into_file) = &mut self.create_or_insert_into_file {
+            create_or_insert_into_file.action.set_backup_root(root);
+        }
+    }
 }
 
 #[async_trait::async_trait]
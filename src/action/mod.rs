@@ -197,12 +197,16 @@ pub mod linux;
 pub mod macos;
 mod stateful;
 
-pub use stateful::{ActionState, StatefulAction};
+pub use stateful::{ActionState, ActionTiming, StatefulAction};
 use std::{error::Error, os::unix::process::ExitStatusExt as _, process::Output};
 use tokio::task::JoinError;
 use tracing::Span;
 
-use crate::{error::HasExpectedErrors, settings::UrlOrPathError, CertificateError};
+use crate::{
+    error::{HasErrorCode, HasExpectedErrors},
+    settings::UrlOrPathError,
+    CertificateError,
+};
 
 /// An action which can be reverted or completed, with an action state
 ///
@@ -257,6 +261,7 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
         StatefulAction {
             action: self,
             state: ActionState::Uncompleted,
+            timing: None,
         }
     }
 
@@ -563,11 +568,34 @@ pub enum ActionErrorKind {
     MissingGroupDeletionCommand,
     #[error("Could not find a supported command to remove users from groups in PATH; please install `gpasswd` or `deluser`")]
     MissingRemoveUserFromGroupCommand,
+    #[error(
+        "Timed out waiting for the lock `{0}`, another program may be editing the user database"
+    )]
+    UserDatabaseLocked(std::path::PathBuf),
+    #[error("Creating a temporary directory")]
+    CreateTempDir(#[source] std::io::Error),
+    #[error("SELinux policy `{0}` is neither a `.te` source nor a compiled `.pp` package")]
+    UnknownSelinuxPolicyFormat(std::path::PathBuf),
+    #[error("Could not find the commands to compile SELinux policy sources in PATH; please install `checkmodule` and `semodule_package` (usually packaged as `checkpolicy` and `policycoreutils`), or pass a compiled `.pp` package")]
+    MissingSelinuxPolicyCompiler,
+    #[error("`{path}` has the SELinux context `{actual}`, but the loaded policy expects `{expected}`; check the Nix policy module is listed by `semodule --list-modules`, then relabel with `restorecon -FR /nix`")]
+    SelinuxLabelMismatch {
+        path: std::path::PathBuf,
+        expected: String,
+        actual: String,
+    },
     #[error("\
         Could not detect systemd; you may be able to get up and running without systemd with `nix-installer install linux --init none`.\n\
         See https://github.com/DeterminateSystems/nix-installer#without-systemd-linux-only for documentation on usage and drawbacks.\
         ")]
     SystemdMissing,
+    #[error(
+        "\
+        Could not find `openrc-run` and `rc-update`, which `--init openrc` requires.\n\
+        To use a `root`-only Nix install, consider passing `--init none`.\
+        "
+    )]
+    OpenrcMissing,
     #[error("`{command}` failed, message: {message}")]
     DiskUtilInfoError { command: String, message: String },
     #[error(transparent)]
@@ -580,6 +608,8 @@ pub enum ActionErrorKind {
     ),
     #[error("Unknown url scheme")]
     UnknownUrlScheme,
+    #[error("Sending a signal to process `{0}`")]
+    Signal(u32, #[source] nix::errno::Errno),
 }
 
 impl ActionErrorKind {
@@ -607,12 +637,104 @@ impl HasExpectedErrors for ActionErrorKind {
             Self::PathUserMismatch(_, _, _)
             | Self::PathGroupMismatch(_, _, _)
             | Self::PathModeMismatch(_, _, _) => Some(Box::new(self)),
-            Self::SystemdMissing => Some(Box::new(self)),
+            Self::SystemdMissing | Self::OpenrcMissing => Some(Box::new(self)),
+            Self::UnknownSelinuxPolicyFormat(_) | Self::MissingSelinuxPolicyCompiler => {
+                Some(Box::new(self))
+            },
             _ => None,
         }
     }
 }
 
+impl HasErrorCode for ActionErrorKind {
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::Custom(..) => "NI-ACT-0001",
+            Self::Certificate(..) => "NI-ACT-0002",
+            Self::Child(..) => "NI-ACT-0003",
+            Self::MultipleChildren(..) => "NI-ACT-0004",
+            Self::Multiple(..) => "NI-ACT-0005",
+            Self::DeterminateNixUnavailable => "NI-ACT-0006",
+            Self::DifferentContent(..) => "NI-ACT-0007",
+            Self::FileExists(..) => "NI-ACT-0008",
+            Self::DirExists(..) => "NI-ACT-0009",
+            Self::SymlinkExists(..) => "NI-ACT-0010",
+            Self::PathUserMismatch(..) => "NI-ACT-0011",
+            Self::PathGroupMismatch(..) => "NI-ACT-0012",
+            Self::PathModeMismatch(..) => "NI-ACT-0013",
+            Self::PathWasNotFile(..) => "NI-ACT-0014",
+            Self::PathWasNotDirectory(..) => "NI-ACT-0015",
+            Self::GettingMetadata(..) => "NI-ACT-0016",
+            Self::CreateDirectory(..) => "NI-ACT-0017",
+            Self::Symlink(..) => "NI-ACT-0018",
+            Self::GetMetadata(..) => "NI-ACT-0019",
+            Self::SetPermissions(..) => "NI-ACT-0020",
+            Self::Remove(..) => "NI-ACT-0021",
+            Self::Copy(..) => "NI-ACT-0022",
+            Self::Rename(..) => "NI-ACT-0023",
+            Self::Canonicalize(..) => "NI-ACT-0024",
+            Self::Read(..) => "NI-ACT-0025",
+            Self::ReadDir(..) => "NI-ACT-0026",
+            Self::ReadSymlink(..) => "NI-ACT-0027",
+            Self::Open(..) => "NI-ACT-0028",
+            Self::Write(..) => "NI-ACT-0029",
+            Self::Sync(..) => "NI-ACT-0030",
+            Self::Seek(..) => "NI-ACT-0031",
+            Self::Flush(..) => "NI-ACT-0032",
+            Self::Truncate(..) => "NI-ACT-0033",
+            Self::GettingUserId(..) => "NI-ACT-0034",
+            Self::UserUidMismatch(..) => "NI-ACT-0035",
+            Self::UserGidMismatch(..) => "NI-ACT-0036",
+            Self::NoUser(..) => "NI-ACT-0037",
+            Self::GettingGroupId(..) => "NI-ACT-0038",
+            Self::GroupGidMismatch(..) => "NI-ACT-0039",
+            Self::NoGroup(..) => "NI-ACT-0040",
+            Self::Chown(..) => "NI-ACT-0041",
+            Self::GlobGlobError(..) => "NI-ACT-0042",
+            Self::GlobPatternError(..) => "NI-ACT-0043",
+            Self::Command { .. } => "NI-ACT-0044",
+            Self::CommandOutput { .. } => "NI-ACT-0045",
+            Self::Join(..) => "NI-ACT-0046",
+            Self::FromUtf8(..) => "NI-ACT-0047",
+            Self::PathNoneString(..) => "NI-ACT-0048",
+            Self::Plist(..) => "NI-ACT-0049",
+            Self::MalformedBinaryTarball => "NI-ACT-0050",
+            Self::MissingSteamosBinary(..) => "NI-ACT-0051",
+            Self::MissingUserCreationCommand => "NI-ACT-0052",
+            Self::MissingGroupCreationCommand => "NI-ACT-0053",
+            Self::MissingAddUserToGroupCommand => "NI-ACT-0054",
+            Self::MissingUserDeletionCommand => "NI-ACT-0055",
+            Self::MissingGroupDeletionCommand => "NI-ACT-0056",
+            Self::MissingRemoveUserFromGroupCommand => "NI-ACT-0057",
+            Self::UserDatabaseLocked(..) => "NI-ACT-0058",
+            Self::CreateTempDir(..) => "NI-ACT-0059",
+            Self::UnknownSelinuxPolicyFormat(..) => "NI-ACT-0060",
+            Self::MissingSelinuxPolicyCompiler => "NI-ACT-0061",
+            Self::SelinuxLabelMismatch { .. } => "NI-ACT-0062",
+            Self::SystemdMissing => "NI-ACT-0063",
+            Self::OpenrcMissing => "NI-ACT-0064",
+            Self::DiskUtilInfoError { .. } => "NI-ACT-0065",
+            Self::UrlOrPathError(..) => "NI-ACT-0066",
+            Self::Reqwest(..) => "NI-ACT-0067",
+            Self::UnknownUrlScheme => "NI-ACT-0068",
+            Self::Signal(..) => "NI-ACT-0069",
+        }
+    }
+
+    fn error_codes(&self) -> Vec<&'static str> {
+        let mut codes = vec![self.error_code()];
+        match self {
+            Self::Child(child) => codes.extend(child.kind().error_codes()),
+            Self::MultipleChildren(children) => {
+                codes.extend(children.iter().flat_map(|child| child.kind().error_codes()))
+            },
+            Self::Multiple(kinds) => codes.extend(kinds.iter().flat_map(Self::error_codes)),
+            _ => (),
+        }
+        codes
+    }
+}
+
 #[cfg(feature = "diagnostics")]
 impl crate::diagnostics::ErrorDiagnostic for ActionErrorKind {
     fn diagnostic(&self) -> String {
@@ -630,6 +752,9 @@ impl crate::diagnostics::ErrorDiagnostic for ActionErrorKind {
             | Self::GettingMetadata(path, _)
             | Self::CreateDirectory(path, _)
             | Self::PathWasNotFile(path)
+            | Self::UserDatabaseLocked(path)
+            | Self::UnknownSelinuxPolicyFormat(path)
+            | Self::SelinuxLabelMismatch { path, .. }
             | Self::Remove(path, _) => {
                 vec![path.to_string_lossy().to_string()]
             },
@@ -1,7 +1,10 @@
+use std::time::SystemTime;
+
 use serde::{Deserialize, Serialize};
 use tracing::{Instrument, Span};
 
 use super::{Action, ActionDescription, ActionError, ActionTag};
+use crate::util::unix_millis;
 
 /// A wrapper around an [`Action`](crate::action::Action) which tracks the [`ActionState`] and
 /// handles some tracing output
@@ -9,6 +12,9 @@ use super::{Action, ActionDescription, ActionError, ActionTag};
 pub struct StatefulAction<A> {
     pub(crate) action: A,
     pub(crate) state: ActionState,
+    /// When the action last ran, receipts from before this was recorded have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timing: Option<ActionTiming>,
 }
 
 impl<A> From<A> for StatefulAction<A>
@@ -19,6 +25,7 @@ where
         Self {
             action,
             state: ActionState::Uncompleted,
+            timing: None,
         }
     }
 }
@@ -72,7 +79,10 @@ impl StatefulAction<Box<dyn Action>> {
             _ => {
                 self.state = ActionState::Progress;
                 tracing::debug!("Executing: {}", self.action.tracing_synopsis());
-                self.action.execute().await?;
+                self.timing = Some(ActionTiming::start());
+                let res = self.action.execute().await;
+                self.finish_timing();
+                res?;
                 self.state = ActionState::Completed;
                 tracing::debug!("Completed: {}", self.action.tracing_synopsis());
                 Ok(())
@@ -99,7 +109,10 @@ impl StatefulAction<Box<dyn Action>> {
             _ => {
                 self.state = ActionState::Progress;
                 tracing::debug!("Reverting: {}", self.action.tracing_synopsis());
-                self.action.revert().await?;
+                self.timing = Some(ActionTiming::start());
+                let res = self.action.revert().await;
+                self.finish_timing();
+                res?;
                 tracing::debug!("Reverted: {}", self.action.tracing_synopsis());
                 self.state = ActionState::Uncompleted;
                 Ok(())
@@ -138,6 +151,7 @@ where
         StatefulAction {
             action: Box::new(self.action),
             state: self.state,
+            timing: self.timing,
         }
     }
     /// A description of what this action would do during execution
@@ -179,7 +193,10 @@ where
                     "Executing: {}",
                     self.action.tracing_synopsis()
                 );
-                self.action.execute().instrument(span.clone()).await?;
+                self.timing = Some(ActionTiming::start());
+                let res = self.action.execute().instrument(span.clone()).await;
+                self.finish_timing();
+                res?;
                 self.state = ActionState::Completed;
                 tracing::debug!(
                     parent: &span,
@@ -215,7 +232,10 @@ where
                     "Reverting: {}",
                     self.action.tracing_synopsis()
                 );
-                self.action.revert().instrument(span.clone()).await?;
+                self.timing = Some(ActionTiming::start());
+                let res = self.action.revert().instrument(span.clone()).await;
+                self.finish_timing();
+                res?;
                 tracing::debug!(
                     parent: &span,
                     "Reverted: {}",
@@ -231,6 +251,7 @@ where
         Self {
             state: ActionState::Completed,
             action,
+            timing: None,
         }
     }
 
@@ -238,6 +259,7 @@ where
         Self {
             state: ActionState::Skipped,
             action,
+            timing: None,
         }
     }
 
@@ -245,6 +267,39 @@ where
         Self {
             state: ActionState::Uncompleted,
             action,
+            timing: None,
+        }
+    }
+}
+
+impl<A> StatefulAction<A> {
+    /// When the action last started and finished executing or reverting, if it ever ran
+    pub fn timing(&self) -> Option<&ActionTiming> {
+        self.timing.as_ref()
+    }
+
+    fn finish_timing(&mut self) {
+        if let Some(timing) = &mut self.timing {
+            timing.finished = Some(unix_millis(SystemTime::now()));
+        }
+    }
+}
+
+/// When a [`StatefulAction`] last started and finished, in milliseconds since the Unix epoch
+///
+/// Recorded whether the action succeeded or failed, `finished` is only missing if the installer
+/// stopped while the action was running.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Copy)]
+pub struct ActionTiming {
+    pub started: u64,
+    pub finished: Option<u64>,
+}
+
+impl ActionTiming {
+    fn start() -> Self {
+        Self {
+            started: unix_millis(SystemTime::now()),
+            finished: None,
         }
     }
 }
@@ -0,0 +1,357 @@
+/*! A store of the original contents of files `nix-installer` modifies
+
+Before their first write, actions such as [`CreateOrInsertIntoFile`](crate::action::base::CreateOrInsertIntoFile)
+copy any pre-existing file into `/nix/var/nix-installer/backups/<timestamp>/`, recording a
+[`FileBackup`] in the receipt. `nix-installer uninstall --restore-backups` uses those records to
+put the originals back byte-for-byte.
+*/
+
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use nix::unistd::{chown, Gid, Uid};
+use serde_json::Value;
+
+use crate::action::ActionErrorKind;
+
+pub(crate) const BACKUP_LOCATION: &str = "/nix/var/nix-installer/backups";
+
+/// The original of a file modified by an action, as recorded in the receipt
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct FileBackup {
+    pub original: PathBuf,
+    pub backup: PathBuf,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Where actions back up files unless told otherwise
+///
+/// Actions which back up files keep the root in an unserialized `backup_root` field defaulting to
+/// this, so tests can point it at a temporary directory and `repair` can turn backups off.
+pub(crate) fn default_backup_root() -> Option<PathBuf> {
+    Some(PathBuf::from(BACKUP_LOCATION))
+}
+
+/// The backup directory of this run under `root`, shared by every action so one install lands in
+/// one directory
+fn backup_dir(root: &Path) -> PathBuf {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    let started = STARTED.get_or_init(|| crate::util::unix_seconds(SystemTime::now()));
+    root.join(started.to_string())
+}
+
+/// Save the bytes, mode, and ownership of `path` into the backup store under `root`, if it exists
+///
+/// Nothing is saved without a `root`. If `path` was already saved during this run, the earlier
+/// (unmodified) copy is kept.
+#[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub(crate) async fn backup_file(
+    root: Option<&Path>,
+    path: &Path,
+) -> Result<Option<FileBackup>, ActionErrorKind> {
+    let Some(root) = root else {
+        return Ok(None);
+    };
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_path_buf(), e)),
+    };
+    if !metadata.is_file() {
+        return Err(ActionErrorKind::PathWasNotFile(path.to_path_buf()));
+    }
+
+    let backup = backup_dir(root).join(path.strip_prefix("/").unwrap_or(path));
+    if !backup.exists() {
+        if let Some(parent) = backup.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(parent.to_path_buf(), e))?;
+        }
+        tokio::fs::copy(path, &backup)
+            .await
+            .map_err(|e| ActionErrorKind::Copy(path.to_path_buf(), backup.clone(), e))?;
+        tracing::debug!("Backed up `{}` to `{}`", path.display(), backup.display());
+    }
+
+    Ok(Some(FileBackup {
+        original: path.to_path_buf(),
+        backup,
+        mode: metadata.permissions().mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }))
+}
+
+/// Every [`FileBackup`] recorded anywhere in a serialized receipt, including nested actions
+pub fn collect_backups(receipt: &Value) -> Vec<FileBackup> {
+    let mut backups = vec![];
+    collect_backups_into(receipt, &mut backups);
+    // The same original may be recorded by more than one action, the first write wins
+    let mut seen = std::collections::HashSet::new();
+    backups.retain(|backup| seen.insert(backup.original.clone()));
+    backups
+}
+
+fn collect_backups_into(value: &Value, backups: &mut Vec<FileBackup>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if key == "backup" {
+                    if let Ok(backup) = serde_json::from_value::<FileBackup>(value.clone()) {
+                        backups.push(backup);
+                        continue;
+                    }
+                }
+                collect_backups_into(value, backups);
+            }
+        },
+        Value::Array(values) => {
+            for value in values {
+                collect_backups_into(value, backups);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// The originals of the backups in a serialized receipt whose every owning action was reverted
+///
+/// Restoring a file under an action which failed to revert would undo a change still in effect.
+pub fn reverted_originals(receipt: &Value) -> std::collections::HashSet<PathBuf> {
+    let mut owners = std::collections::HashMap::<PathBuf, bool>::new();
+    collect_owners(receipt, &mut owners);
+    owners
+        .into_iter()
+        .filter_map(|(original, reverted)| reverted.then_some(original))
+        .collect()
+}
+
+fn collect_owners(value: &Value, owners: &mut std::collections::HashMap<PathBuf, bool>) {
+    match value {
+        Value::Object(map) => {
+            if let (Some(Value::Object(action)), Some(state)) =
+                (map.get("action"), map.get("state"))
+            {
+                let backup = action
+                    .get("backup")
+                    .and_then(|backup| serde_json::from_value::<FileBackup>(backup.clone()).ok());
+                if let Some(backup) = backup {
+                    let reverted = state == "Uncompleted";
+                    *owners.entry(backup.original).or_insert(true) &= reverted;
+                }
+            }
+            for value in map.values() {
+                collect_owners(value, owners);
+            }
+        },
+        Value::Array(values) => {
+            for value in values {
+                collect_owners(value, owners);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// A [`FileBackup`] read into memory, so it can be restored after `/nix` is removed
+#[derive(Debug, Clone)]
+pub struct LoadedBackup {
+    pub backup: FileBackup,
+    contents: Vec<u8>,
+}
+
+impl FileBackup {
+    pub async fn load(self) -> Result<LoadedBackup, ActionErrorKind> {
+        let contents = tokio::fs::read(&self.backup)
+            .await
+            .map_err(|e| ActionErrorKind::Read(self.backup.clone(), e))?;
+        Ok(LoadedBackup {
+            backup: self,
+            contents,
+        })
+    }
+}
+
+impl LoadedBackup {
+    /// Atomically replace the original path with the saved bytes, mode, and ownership
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %self.backup.original.display()))]
+    pub async fn restore(&self) -> Result<(), ActionErrorKind> {
+        let FileBackup {
+            original,
+            backup: _,
+            mode,
+            uid,
+            gid,
+        } = &self.backup;
+
+        if let Some(parent) = original.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(parent.to_path_buf(), e))?;
+        }
+
+        let temp = restore_path(original);
+        tokio::fs::write(&temp, &self.contents)
+            .await
+            .map_err(|e| ActionErrorKind::Write(temp.clone(), e))?;
+        chown(&temp, Some(Uid::from_raw(*uid)), Some(Gid::from_raw(*gid)))
+            .map_err(|e| ActionErrorKind::Chown(temp.clone(), e))?;
+        tokio::fs::set_permissions(&temp, PermissionsExt::from_mode(*mode))
+            .await
+            .map_err(|e| ActionErrorKind::SetPermissions(*mode, temp.clone(), e))?;
+        tokio::fs::rename(&temp, original)
+            .await
+            .map_err(|e| ActionErrorKind::Rename(temp.clone(), original.clone(), e))?;
+
+        tracing::debug!("Restored `{}`", original.display());
+        Ok(())
+    }
+}
+
+/// Where the backup is written before replacing `original`, like `/etc/nix/nix.conf.nix-installer-restore`
+fn restore_path(original: &Path) -> PathBuf {
+    let mut name = original.file_name().unwrap_or_default().to_os_string();
+    name.push(".nix-installer-restore");
+    original.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn backs_up_and_restores_file() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let backup_root = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("backs_up_and_restores_file");
+        tokio::fs::write(&test_file, "Original content").await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o640)).await?;
+
+        let backup = backup_file(Some(backup_root.path()), &test_file)
+            .await?
+            .expect("An existing file should be backed up");
+        assert!(backup.backup.starts_with(backup_root.path()));
+        tokio::fs::write(&test_file, "Modified content").await?;
+
+        let receipt = serde_json::json!({
+            "actions": [{ "action": { "action_name": "test", "backup": backup } }]
+        });
+        let collected = collect_backups(&receipt);
+        assert_eq!(collected, vec![backup]);
+
+        for backup in collected {
+            backup.load().await?.restore().await?;
+        }
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "Original content"
+        );
+        let mode = tokio::fs::metadata(&test_file).await?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_reverted_actions_restore() {
+        let backup = |original: &str| FileBackup {
+            original: original.into(),
+            backup: Path::new("/backups").join(original.trim_start_matches('/')),
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+        };
+        let action = |state: &str, original: &str| {
+            serde_json::json!({
+                "action": { "action_name": "test", "backup": backup(original) },
+                "state": state,
+            })
+        };
+        let receipt = serde_json::json!({
+            "actions": [
+                action("Uncompleted", "/etc/bashrc"),
+                action("Completed", "/etc/zshrc"),
+                {
+                    "action": {
+                        "action_name": "parent",
+                        "children": [
+                            action("Uncompleted", "/etc/profile"),
+                            action("Uncompleted", "/etc/zshenv"),
+                        ],
+                    },
+                    "state": "Completed",
+                },
+                action("Progress", "/etc/zshenv"),
+            ],
+        });
+
+        let mut reverted = reverted_originals(&receipt).into_iter().collect::<Vec<_>>();
+        reverted.sort();
+        assert_eq!(
+            reverted,
+            [PathBuf::from("/etc/bashrc"), PathBuf::from("/etc/profile")]
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_file_with_extension() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let backup_root = tempfile::tempdir()?;
+        let nix_conf = temp_dir.path().join("nix.conf");
+        // Would be clobbered if the suffix replaced the extension
+        let sibling = temp_dir.path().join("nix.nix-installer-restore");
+        tokio::fs::write(&nix_conf, "Original content").await?;
+        tokio::fs::write(&sibling, "Unrelated content").await?;
+
+        assert_eq!(
+            restore_path(&nix_conf),
+            temp_dir.path().join("nix.conf.nix-installer-restore")
+        );
+
+        let backup = backup_file(Some(backup_root.path()), &nix_conf)
+            .await?
+            .expect("An existing file should be backed up");
+        tokio::fs::write(&nix_conf, "Modified content").await?;
+        backup.load().await?.restore().await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(&nix_conf).await?,
+            "Original content"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(&sibling).await?,
+            "Unrelated content"
+        );
+        assert!(!restore_path(&nix_conf).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_missing_file() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("skips_missing_file");
+
+        assert_eq!(backup_file(Some(temp_dir.path()), &test_file).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_without_root() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("skips_without_root");
+        tokio::fs::write(&test_file, "Original content").await?;
+
+        assert_eq!(backup_file(None, &test_file).await?, None);
+
+        Ok(())
+    }
+}
@@ -20,7 +20,7 @@ async fn main() -> eyre::Result<ExitCode> {
 
     let cli = nix_installer::cli::NixInstallerCli::parse();
 
-    cli.instrumentation.setup()?;
+    cli.instrumentation.setup(cli.subcommand.log_name())?;
 
     tracing::info!("nix-installer v{}", env!("CARGO_PKG_VERSION"));
 
@@ -36,6 +36,8 @@ async fn main() -> eyre::Result<ExitCode> {
     )
     .await;
 
+    let feedback = cli.progress.feedback(feedback)?;
+
     let err = cli.execute(feedback).await;
 
     feedback_worker.submit().await;
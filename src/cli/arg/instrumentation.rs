@@ -1,11 +1,14 @@
 use eyre::WrapErr;
 use std::error::Error;
 use std::io::IsTerminal;
+use std::path::PathBuf;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{
-    filter::Directive, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    filter::Directive, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
+use crate::cli::log_file::{self, LogWriter};
+
 #[derive(Clone, Default, Debug, clap::ValueEnum)]
 pub enum Logger {
     #[default]
@@ -40,6 +43,9 @@ pub struct Instrumentation {
     /// See https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
     #[clap(long = "log-directive", global = true, env = "NIX_INSTALLER_LOG_DIRECTIVES", value_delimiter = ',', num_args = 0..)]
     pub log_directives: Vec<Directive>,
+    /// Where to write the full, trace-level, log of this run, instead of `/nix/var/log/nix-installer/<timestamp>-<subcommand>.log`
+    #[clap(long, env = "NIX_INSTALLER_LOG_FILE", global = true)]
+    pub log_file: Option<PathBuf>,
 }
 
 impl Instrumentation {
@@ -52,35 +58,79 @@ impl Instrumentation {
         .to_string()
     }
 
-    pub fn setup(&self) -> eyre::Result<()> {
-        let filter_layer = self.filter_layer()?;
+    /// Set up logging to the terminal, and to the log file of this run of `subcommand`
+    ///
+    /// `subcommand` is `None` for subcommands which only write a log file when given `--log-file`.
+    pub fn setup(&self, subcommand: Option<&str>) -> eyre::Result<()> {
+        let log_file = log_file::open(self.log_file.as_deref(), subcommand);
+        if let (Some(explicit), Err(err)) = (&self.log_file, &log_file) {
+            return Err(eyre::eyre!(
+                "Opening the log file `{}`: {err}",
+                explicit.display()
+            ));
+        }
 
+        // The terminal shows what was asked for, while the log file has everything
         let registry = tracing_subscriber::registry()
-            .with(filter_layer)
-            .with(ErrorLayer::default());
+            .with(ErrorLayer::default().with_filter(self.filter_layer()?))
+            .with(
+                self.fmt_layer_file()
+                    .with_filter(Self::file_filter_layer()?),
+            );
 
         match self.logger {
             Logger::Compact => {
                 let fmt_layer = self.fmt_layer_compact();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(self.filter_layer()?))
+                    .try_init()?
             },
             Logger::Full => {
                 let fmt_layer = self.fmt_layer_full();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(self.filter_layer()?))
+                    .try_init()?
             },
             Logger::Pretty => {
                 let fmt_layer = self.fmt_layer_pretty();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(self.filter_layer()?))
+                    .try_init()?
             },
             Logger::Json => {
                 let fmt_layer = self.fmt_layer_json();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(self.filter_layer()?))
+                    .try_init()?
             },
         }
 
+        match log_file {
+            Ok(Some(path)) => tracing::debug!("Writing the full log to `{}`", path.display()),
+            Ok(None) => (),
+            Err(err) => tracing::debug!("Not writing a log file: {err}"),
+        }
+
         Ok(())
     }
 
+    pub fn fmt_layer_file<S>(&self) -> impl tracing_subscriber::layer::Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        tracing_subscriber::fmt::Layer::new()
+            .with_ansi(false)
+            .with_writer(|| LogWriter)
+    }
+
+    /// Everything from `nix-installer`, regardless of `--verbose`, `--log-directive` or `RUST_LOG`
+    pub fn file_filter_layer() -> eyre::Result<EnvFilter> {
+        Ok(EnvFilter::try_new(format!(
+            "info,{}=trace",
+            env!("CARGO_PKG_NAME").replace('-', "_")
+        ))?)
+    }
+
     pub fn fmt_layer_full<S>(&self) -> impl tracing_subscriber::layer::Layer<S>
     where
         S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
@@ -1,2 +1,4 @@
 mod instrumentation;
+mod progress;
 pub(crate) use instrumentation::Instrumentation;
+pub(crate) use progress::ProgressEvents;
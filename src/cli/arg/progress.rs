@@ -0,0 +1,53 @@
+use std::fs::{File, OpenOptions};
+
+use eyre::WrapErr;
+
+use crate::feedback::progress::Progress;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// Newline-delimited JSON events
+    Json,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct ProgressEvents {
+    /// Emit a progress event as each action starts, completes or fails (options are `json`)
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PROGRESS",
+        requires = "progress_fd",
+        global = true
+    )]
+    pub progress: Option<ProgressFormat>,
+    /// The file descriptor to write progress events to, which must already be open for writing
+    ///
+    /// Required by `--progress`, as stderr already carries the log.
+    #[clap(long, env = "NIX_INSTALLER_PROGRESS_FD", global = true)]
+    pub progress_fd: Option<u32>,
+}
+
+impl ProgressEvents {
+    /// Wrap `feedback` so it also writes progress events, if they were asked for
+    pub fn feedback<F>(&self, feedback: F) -> eyre::Result<Progress<F>> {
+        let sink = match (self.progress, self.progress_fd) {
+            (Some(ProgressFormat::Json), Some(fd)) => Some(open_sink(fd)?),
+            (Some(ProgressFormat::Json), None) => {
+                return Err(eyre::eyre!(
+                    "`--progress json` requires `--progress-fd`, stderr already carries the log"
+                ))
+            },
+            (None, _) => None,
+        };
+        Ok(Progress::new(feedback, sink))
+    }
+}
+
+fn open_sink(fd: u32) -> eyre::Result<File> {
+    // Reopening through `/dev/fd` leaves the inherited descriptor to its owner
+    let path = format!("/dev/fd/{fd}");
+    OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .wrap_err_with(|| format!("Opening file descriptor {fd} for progress events"))
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::io::{stdin, stdout, BufRead, Write};
+use std::io::{stderr, stdin, stdout, BufRead, Write};
 
 use eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
@@ -20,7 +20,12 @@ pub(crate) async fn prompt(
     default: PromptChoice,
     currently_explaining: bool,
 ) -> eyre::Result<PromptChoice> {
-    let stdout = stdout();
+    // With `--output json`, stdout is kept for the outcome document
+    let out: Box<dyn Write + Send> = if super::output::is_json() {
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    };
     let terminfo = term::terminfo::TermInfo::from_env().unwrap_or_else(|_| {
         tracing::warn!("Couldn't find terminfo, using empty fallback terminfo");
         term::terminfo::TermInfo {
@@ -30,7 +35,7 @@ pub(crate) async fn prompt(
             strings: HashMap::new(),
         }
     });
-    let mut term = term::terminfo::TerminfoTerminal::new_with_terminfo(stdout, terminfo);
+    let mut term = term::terminfo::TerminfoTerminal::new_with_terminfo(out, terminfo);
     let with_confirm = format!(
         "\
         {question}\n\
@@ -92,8 +97,3 @@ pub(crate) fn read_line() -> eyre::Result<String> {
     }
     .context("unable to read from stdin for confirmation")
 }
-
-pub(crate) async fn clean_exit_with_message(message: impl AsRef<str>) -> ! {
-    eprintln!("{}", message.as_ref());
-    std::process::exit(0)
-}
@@ -0,0 +1,281 @@
+/*! A full log of each run, kept on disk
+
+Whatever `--verbose`, `--logger` and `--log-directive` show on the terminal, every run of a
+subcommand which runs as `root` also writes a trace-level log to
+`/nix/var/log/nix-installer/<timestamp>-<subcommand>.log`, or to the path given by `--log-file`, so
+it survives the terminal scrolling away or `curl | sh` exiting. Other subcommands only write one
+when given `--log-file`.
+
+When it can not be written there yet (before `/nix` exists, or when running `uninstall`, which
+removes it), the log is written to a private directory under the system's temporary directory
+instead, and moved under `/nix` by [`settle`] if it exists once the subcommand has finished. Only
+the newest [`KEPT_LOGS`] logs are kept under `/nix`.
+*/
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+pub(crate) const LOG_LOCATION: &str = "/nix/var/log/nix-installer";
+/// How many logs are kept in each directory, older ones are removed
+pub(crate) const KEPT_LOGS: usize = 20;
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    /// Whether the log is in a private temporary directory, and should be moved to
+    /// [`LOG_LOCATION`] once it can be
+    staged: bool,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+fn with_log<R>(f: impl FnOnce(&mut Option<Log>) -> R) -> R {
+    // A poisoned lock only means another write panicked, the file is still usable
+    let mut log = LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(&mut log)
+}
+
+/// Writes to the log of this run, if there is one
+///
+/// Pass `|| LogWriter` as the writer of a `tracing_subscriber` layer.
+pub(crate) struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        with_log(|log| match log {
+            Some(log) => log.file.write(buf),
+            None => Ok(buf.len()),
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        with_log(|log| match log {
+            Some(log) => log.file.flush(),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Open the log of this run of `subcommand`, at `explicit` if given
+///
+/// `subcommand` is `None` for subcommands which do not run as `root`, which only write a log at
+/// `explicit`. Returns where the log is, if there is one.
+pub(crate) fn open(
+    explicit: Option<&Path>,
+    subcommand: Option<&str>,
+) -> std::io::Result<Option<PathBuf>> {
+    let log = match (explicit, subcommand) {
+        (Some(path), _) => {
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)?;
+            }
+            Log {
+                path: path.to_path_buf(),
+                file: OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .custom_flags(nix::libc::O_NOFOLLOW)
+                    .open(path)?,
+                staged: false,
+            }
+        },
+        (None, None) => return Ok(None),
+        // The subcommand escalates with `sudo` and is run again, which writes the log
+        (None, Some(_)) if !crate::cli::is_root() => return Ok(None),
+        (None, Some(subcommand)) => {
+            let name = log_name(SystemTime::now(), subcommand);
+            // `uninstall` removes `/nix`, and creating `/nix` before `install` would get in its way
+            if subcommand != "uninstall" && Path::new("/nix").exists() {
+                match open_in(Path::new(LOG_LOCATION), &name) {
+                    Ok((path, file)) => Log {
+                        path,
+                        file,
+                        staged: false,
+                    },
+                    Err(_) => staged_log(&name)?,
+                }
+            } else {
+                staged_log(&name)?
+            }
+        },
+    };
+    let path = log.path.clone();
+    with_log(|current| *current = Some(log));
+    Ok(Some(path))
+}
+
+/// Where the log of this run is, if there is one
+pub(crate) fn path() -> Option<PathBuf> {
+    with_log(|log| log.as_ref().map(|log| log.path.clone()))
+}
+
+/// Move a log written to the temporary directory under `/nix`, if it now exists
+///
+/// Returns where the log is.
+pub(crate) fn settle() -> Option<PathBuf> {
+    // Nothing may be logged while the log is locked, as logging writes to it
+    let (path, moved) = with_log(|log| {
+        let current = log.as_mut()?;
+        let mut moved = None;
+        if current.staged && Path::new("/nix/var").is_dir() {
+            let from = current.path.clone();
+            let result = move_log(&current.path, Path::new(LOG_LOCATION)).map(|(path, file)| {
+                current.path = path;
+                current.file = file;
+                current.staged = false;
+            });
+            moved = Some((from, result));
+        }
+        Some((current.path.clone(), moved))
+    })?;
+    match moved {
+        Some((from, Ok(()))) => {
+            if let Some(staging_dir) = from.parent() {
+                // Only fails if something else was put there, which is left be
+                let _ = std::fs::remove_dir(staging_dir);
+            }
+            rotate(Path::new(LOG_LOCATION), KEPT_LOGS)
+        },
+        Some((from, Err(err))) => tracing::debug!(
+            "Could not move the log `{}` to `{LOG_LOCATION}`: {err}",
+            from.display()
+        ),
+        None => (),
+    }
+    Some(path)
+}
+
+fn staged_log(name: &str) -> std::io::Result<Log> {
+    // A fresh directory only `root` may use, as anyone may create paths in the temporary directory
+    let dir = tempfile::Builder::new()
+        .prefix("nix-installer-logs-")
+        .permissions(std::fs::Permissions::from_mode(0o700))
+        .tempdir()?
+        .keep();
+    let path = dir.join(name);
+    let file = create_file(&path)?;
+    Ok(Log {
+        path,
+        file,
+        staged: true,
+    })
+}
+
+/// Like `1760789696123-install.log`, so logs sort by when they started
+fn log_name(started: SystemTime, subcommand: &str) -> String {
+    format!("{}.log", crate::util::run_name(started, subcommand))
+}
+
+/// Create a new log at `path`, never following a link or appending to an existing file
+fn create_file(path: &Path) -> std::io::Result<File> {
+    // Logs may include paths and settings the user would rather not share with everyone
+    OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .mode(0o600)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(path)
+}
+
+fn open_in(dir: &Path, name: &str) -> std::io::Result<(PathBuf, File)> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(name);
+    let file = create_file(&path)?;
+    rotate(dir, KEPT_LOGS);
+    Ok((path, file))
+}
+
+fn move_log(from: &Path, dir: &Path) -> std::io::Result<(PathBuf, File)> {
+    let name = from.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "The log has no file name")
+    })?;
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(name);
+    // The temporary directory is often on another filesystem, so copy rather than rename
+    let mut file = create_file(&path)?;
+    std::io::copy(&mut File::open(from)?, &mut file)?;
+    std::fs::remove_file(from)?;
+    Ok((path, file))
+}
+
+/// Remove all but the newest `keep` logs in `dir`
+fn rotate(dir: &Path, keep: usize) {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut logs = read_dir
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+        .filter_map(|path| Some((log_started(&path)?, path)))
+        .collect::<Vec<_>>();
+    if logs.len() <= keep {
+        return;
+    }
+    logs.sort();
+    for (_, path) in &logs[..logs.len() - keep] {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::debug!("Could not remove the old log `{}`: {err}", path.display());
+        }
+    }
+}
+
+/// The leading timestamp of a log's name, logs without one are never rotated
+fn log_started(path: &Path) -> Option<u128> {
+    let name = path.file_name()?.to_str()?;
+    name.split_once('-')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{create_file, log_name, rotate};
+
+    #[test]
+    fn keeps_the_newest_logs() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+        for millis in [5, 1000, 30, 200] {
+            let started = SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+            std::fs::write(temp_dir.path().join(log_name(started, "install")), "")?;
+        }
+        std::fs::write(temp_dir.path().join("notes.log"), "")?;
+
+        rotate(temp_dir.path(), 2);
+
+        let mut remaining = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            ["1000-install.log", "200-install.log", "notes.log"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn never_writes_through_links_or_to_existing_files() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+        let target = temp_dir.path().join("target");
+        std::fs::write(&target, "")?;
+        let link = temp_dir.path().join("1-install.log");
+        std::os::unix::fs::symlink(&target, &link)?;
+
+        assert!(create_file(&link).is_err());
+        assert!(create_file(&target).is_err());
+        assert_eq!(std::fs::read_to_string(&target)?, "");
+        assert!(create_file(&temp_dir.path().join("2-install.log")).is_ok());
+        Ok(())
+    }
+}
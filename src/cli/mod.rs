@@ -4,9 +4,12 @@
 
 pub(crate) mod arg;
 mod interaction;
+pub(crate) mod log_file;
+pub(crate) mod output;
 pub(crate) mod subcommand;
 
 use clap::Parser;
+use color_eyre::Section;
 use eyre::WrapErr;
 use owo_colors::OwoColorize;
 use std::{ffi::CString, path::PathBuf, process::ExitCode};
@@ -14,6 +17,7 @@ use tokio::sync::broadcast::{Receiver, Sender};
 use url::Url;
 
 use self::subcommand::NixInstallerSubcommand;
+use crate::error::{FailureClass, HasErrorCode};
 
 const FAIL_PKG_SUGGEST: &str = "\
 The Determinate Nix Installer failed.
@@ -79,6 +83,13 @@ pub struct NixInstallerCli {
     #[clap(flatten)]
     pub instrumentation: arg::Instrumentation,
 
+    #[clap(flatten)]
+    pub progress: arg::ProgressEvents,
+
+    /// How `install`, `uninstall`, `plan`, `repair`, `self-test` and `split-receipt` report their result (options are `text` and `json`)
+    #[clap(long = "output", id = "output_format", value_name = "FORMAT", env = "NIX_INSTALLER_OUTPUT", default_value_t = Default::default(), global = true)]
+    pub output: output::OutputFormat,
+
     #[clap(subcommand)]
     pub subcommand: NixInstallerSubcommand,
 }
@@ -93,6 +104,13 @@ impl CommandExecute for NixInstallerCli {
         let feedback_clone = feedback.clone();
 
         let is_install_subcommand = matches!(self.subcommand, NixInstallerSubcommand::Install(_));
+        let history_entry = self
+            .subcommand
+            .history_name()
+            .map(crate::history::PendingEntry::start);
+        if self.output == output::OutputFormat::Json {
+            output::start(self.subcommand.name());
+        }
 
         let ret = match self.subcommand {
             NixInstallerSubcommand::Plan(plan) => plan.execute(feedback_clone).await,
@@ -103,23 +121,66 @@ impl CommandExecute for NixInstallerCli {
             NixInstallerSubcommand::SplitReceipt(split_receipt) => {
                 split_receipt.execute(feedback_clone).await
             },
+            NixInstallerSubcommand::Detect(detect) => detect.execute(feedback_clone).await,
+            NixInstallerSubcommand::Adopt(adopt) => adopt.execute(feedback_clone).await,
+            NixInstallerSubcommand::History(history) => history.execute(feedback_clone).await,
         };
 
-        let maybe_cancelled = ret.as_ref().err().and_then(|err| {
-            err.root_cause()
-                .downcast_ref::<crate::NixInstallerError>()
-                .and_then(|err| {
-                    if matches!(err, crate::NixInstallerError::Cancelled) {
-                        return Some(err);
+        if let Some(history_entry) = history_entry {
+            let exit_status = match &ret {
+                Ok(code) if *code == ExitCode::SUCCESS => crate::history::ExitStatus::Success,
+                Ok(_) => crate::history::ExitStatus::Failure,
+                Err(err) => crate::history::ExitStatus::Error(
+                    err.chain()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(": "),
+                ),
+            };
+            history_entry.finish(exit_status).await;
+        }
+
+        let log = log_file::settle();
+        output::finish(&ret, log.as_deref())?;
+
+        // Errors of the installer exit with the status of their class, and show their codes
+        let ret = match ret {
+            Err(err) => match err
+                .chain()
+                .find_map(|err| err.downcast_ref::<crate::NixInstallerError>())
+            {
+                Some(installer_err) => {
+                    let class = installer_err.failure_class();
+                    let codes = installer_err.error_codes().join(", ");
+                    match class {
+                        FailureClass::Cancelled => {
+                            let code = installer_err.most_specific_error_code();
+                            eprintln!("{}", format!("{code}: {installer_err}").red());
+                            Ok(class.into())
+                        },
+                        FailureClass::Other => Err(err.note(format!("Error code: {codes}"))),
+                        _ => {
+                            eprintln!("{:?}", err.note(format!("Error code: {codes}")));
+                            Ok(class.into())
+                        },
                     }
-                    None
-                })
-        });
+                },
+                None => Err(err),
+            },
+            ok => ok,
+        };
 
-        if let Some(cancelled) = maybe_cancelled {
-            eprintln!("{}", cancelled.red());
-            return Ok(ExitCode::FAILURE);
-        }
+        let ret = match (ret, log) {
+            (Err(err), Some(log)) => Err(err.note(format!(
+                "The full log of this run is at `{}`",
+                log.display()
+            ))),
+            (Ok(code), Some(log)) if code != ExitCode::SUCCESS => {
+                eprintln!("The full log of this run is at `{}`", log.display());
+                Ok(code)
+            },
+            (ret, _) => ret,
+        };
 
         let is_macos = matches!(
             target_lexicon::OperatingSystem::host(),
@@ -128,7 +189,7 @@ impl CommandExecute for NixInstallerCli {
         );
 
         if is_install_subcommand && is_macos {
-            let is_ok_but_failed = ret.as_ref().is_ok_and(|code| code == &ExitCode::FAILURE);
+            let is_ok_but_failed = ret.as_ref().is_ok_and(|code| code != &ExitCode::SUCCESS);
             let is_error = ret.as_ref().is_err();
 
             if is_error || is_ok_but_failed {
@@ -144,7 +205,7 @@ impl CommandExecute for NixInstallerCli {
 
                 tracing::warn!("{}\n", msg.trim());
 
-                return Ok(ExitCode::FAILURE);
+                return Ok(ret.unwrap_or(ExitCode::FAILURE));
             }
         }
 
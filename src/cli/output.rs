@@ -0,0 +1,282 @@
+/*! The final JSON document of `--output json`
+
+With `--output json`, every subcommand prints a single [`Outcome`] to stdout when it finishes, for
+wrappers which would otherwise scrape the colored text. Messages still go to stderr, but what would
+be printed to stdout (like the plan, the detected host or the history) goes in the [`Outcome`]'s
+`report` instead.
+
+The subcommands report to a process-wide recorder through the functions of this module, which print
+as before when the output is text.
+*/
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use owo_colors::OwoColorize;
+
+use crate::{
+    action::{Action, StatefulAction},
+    error::{FailureClass, HasErrorCode, HasExpectedErrors},
+    feedback::ActionPhase,
+    util::unix_millis,
+    InstallPlan, NixInstallerError,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Colored messages
+    #[default]
+    Text,
+    /// A single JSON document once the subcommand finishes
+    Json,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The result of a subcommand, as printed by `--output json`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Outcome {
+    pub subcommand: String,
+    pub success: bool,
+    /// The process exit status, see `docs/error-codes.md`
+    pub exit_code: u8,
+    /// The class of the failure, which decided the exit status
+    pub failure_class: Option<FailureClass>,
+    /// The message shown when the subcommand finished, or the reason it failed
+    pub message: Option<String>,
+    /// The explanation of a failure the installer anticipated, like an unsupported system
+    pub expected_error: Option<String>,
+    /// An unexpected error, followed by its causes
+    pub error_chain: Vec<String>,
+    /// The diagnostic names of the error and its causes, as sent with diagnostics
+    pub diagnostic_chain: Vec<String>,
+    /// The stable codes of the error and of the errors it wraps, like `NI-PLAN-0016`
+    pub error_codes: Vec<&'static str>,
+    /// The actions which changed the system during this run
+    pub changed: Vec<Change>,
+    /// Where the receipt describing the install now is, if anywhere
+    pub receipt: Option<PathBuf>,
+    /// What the subcommand would otherwise print to stdout, like the plan or the self-test results
+    pub report: Option<serde_json::Value>,
+    /// Where the full log of this run is
+    pub log: Option<PathBuf>,
+    /// Milliseconds since the Unix epoch, actions which started before this are not `changed`
+    #[serde(skip)]
+    started: u64,
+}
+
+/// An action executed or reverted during the run
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Change {
+    pub phase: ActionPhase,
+    pub tag: String,
+    pub synopsis: String,
+}
+
+static OUTCOME: Mutex<Option<Outcome>> = Mutex::new(None);
+
+fn with_outcome<R>(f: impl FnOnce(&mut Outcome) -> R) -> Option<R> {
+    let mut outcome = OUTCOME
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    outcome.as_mut().map(f)
+}
+
+/// Start recording the outcome of `subcommand`, to be printed by [`finish`]
+pub(crate) fn start(subcommand: impl Into<String>) {
+    let outcome = Outcome {
+        subcommand: subcommand.into(),
+        success: false,
+        exit_code: 0,
+        failure_class: None,
+        message: None,
+        expected_error: None,
+        error_chain: vec![],
+        diagnostic_chain: vec![],
+        error_codes: vec![],
+        changed: vec![],
+        receipt: None,
+        report: None,
+        log: None,
+        started: unix_millis(SystemTime::now()),
+    };
+    *OUTCOME
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(outcome);
+}
+
+/// Whether the outcome is being recorded, so nothing else should be printed to stdout
+pub(crate) fn is_json() -> bool {
+    with_outcome(|_| ()).is_some()
+}
+
+/// Print the recorded outcome, given how the subcommand returned and where its log is
+pub(crate) fn finish(ret: &eyre::Result<ExitCode>, log: Option<&Path>) -> eyre::Result<()> {
+    let Some(mut outcome) = OUTCOME
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+    else {
+        return Ok(());
+    };
+
+    outcome.log = log.map(Path::to_path_buf);
+    match ret {
+        Ok(code) => {
+            outcome.success = *code == ExitCode::SUCCESS;
+            if !outcome.success {
+                outcome.exit_code = outcome
+                    .failure_class
+                    .unwrap_or(FailureClass::Other)
+                    .exit_code();
+            }
+        },
+        Err(err) => {
+            outcome.success = false;
+            outcome.error_chain = err.chain().map(ToString::to_string).collect();
+            if let Some(err) = err
+                .chain()
+                .find_map(|err| err.downcast_ref::<NixInstallerError>())
+            {
+                record_error(&mut outcome, err);
+            }
+            outcome.exit_code = outcome
+                .failure_class
+                .unwrap_or(FailureClass::Other)
+                .exit_code();
+        },
+    }
+
+    println!("{}", serde_json::to_string_pretty(&outcome)?);
+    Ok(())
+}
+
+fn record_error(outcome: &mut Outcome, err: &NixInstallerError) {
+    if outcome.expected_error.is_none() {
+        outcome.expected_error = err.expected().map(|expected| expected.to_string());
+    }
+    if outcome.error_codes.is_empty() {
+        outcome.error_codes = err.error_codes();
+    }
+    if outcome.failure_class.is_none() {
+        outcome.failure_class = Some(err.failure_class());
+    }
+    #[cfg(feature = "diagnostics")]
+    if outcome.diagnostic_chain.is_empty() {
+        outcome.diagnostic_chain = crate::diagnostics::failure_chain(err);
+    }
+}
+
+/// Report a failure the installer anticipated, with the explanation from [`HasExpectedErrors`]
+///
+/// Returns the exit status for the failure.
+pub(crate) fn expected_failure(expected: &dyn Display, err: &NixInstallerError) -> ExitCode {
+    eprintln!(
+        "{}",
+        format!("{}: {expected}", err.most_specific_error_code()).red()
+    );
+    with_outcome(|outcome| {
+        outcome.expected_error = Some(expected.to_string());
+        record_error(outcome, err);
+    });
+    err.failure_class().into()
+}
+
+/// Report an error which needs no more explanation than its message, like a cancellation
+///
+/// Returns the exit status for the failure.
+pub(crate) fn error(err: &NixInstallerError) -> ExitCode {
+    failure(format!("{}: {err}", err.most_specific_error_code()));
+    with_outcome(|outcome| record_error(outcome, err));
+    err.failure_class().into()
+}
+
+/// Report why the subcommand is failing, when there is no error to return
+pub(crate) fn failure(message: impl Display) {
+    eprintln!("{}", message.red());
+    with_outcome(|outcome| outcome.message = Some(message.to_string()));
+}
+
+/// Report why the subcommand is failing, as a failure of `class`
+///
+/// Returns the exit status for the failure.
+pub(crate) fn classified_failure(class: FailureClass, message: impl Display) -> ExitCode {
+    failure(message);
+    with_outcome(|outcome| outcome.failure_class = Some(class));
+    class.into()
+}
+
+/// Report that the user declined to continue, which cancels the run without an error
+///
+/// Returns the exit status for a cancelled run.
+pub(crate) fn cancelled(message: impl Display) -> ExitCode {
+    eprintln!("{message}");
+    with_outcome(|outcome| {
+        outcome.message = Some(message.to_string());
+        outcome.failure_class = Some(FailureClass::Cancelled);
+    });
+    FailureClass::Cancelled.into()
+}
+
+/// Report the message the subcommand finished with
+pub(crate) fn success(message: impl Display) {
+    let recorded = with_outcome(|outcome| outcome.message = Some(message.to_string()));
+    if recorded.is_none() {
+        println!("{}\n", message.green().bold());
+    }
+}
+
+/// Set what the subcommand would otherwise print to stdout
+pub(crate) fn report(report: &impl serde::Serialize) -> eyre::Result<()> {
+    let report = serde_json::to_value(report)?;
+    with_outcome(|outcome| outcome.report = Some(report));
+    Ok(())
+}
+
+/// Set where the receipt is, or `None` if there no longer is one
+pub(crate) fn receipt(receipt: Option<&Path>) {
+    with_outcome(|outcome| outcome.receipt = receipt.map(Path::to_path_buf));
+}
+
+/// Record an action executed or reverted during this run
+pub(crate) fn changed(action: &StatefulAction<Box<dyn Action>>, phase: ActionPhase) {
+    with_outcome(|outcome| {
+        outcome.changed.push(Change {
+            phase,
+            tag: action.inner_typetag_name().to_string(),
+            synopsis: action.tracing_synopsis(),
+        })
+    });
+}
+
+/// Record the actions of `plan` which ran since the outcome started being recorded
+pub(crate) fn changed_in(plan: &InstallPlan, phase: ActionPhase) {
+    let Some(started) = with_outcome(|outcome| outcome.started) else {
+        return;
+    };
+    let actions = plan.actions.iter();
+    let actions: Box<dyn Iterator<Item = _>> = match phase {
+        ActionPhase::Install => Box::new(actions),
+        ActionPhase::Uninstall => Box::new(actions.rev()),
+    };
+    for action in actions {
+        if action
+            .timing()
+            .is_some_and(|timing| timing.started >= started)
+        {
+            changed(action, phase);
+        }
+    }
+}
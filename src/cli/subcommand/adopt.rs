@@ -0,0 +1,497 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{ArgAction, Parser};
+use eyre::WrapErr;
+use nix::unistd::{Group, User};
+use owo_colors::OwoColorize;
+use serde_json::{Map, Value};
+
+use crate::{
+    action::{
+        common::provision_determinate_nixd::DETERMINATE_NIXD_BINARY_PATH, Action, ActionState,
+        StatefulAction,
+    },
+    cli::{ensure_root, interaction::PromptChoice, output, CommandExecute},
+    plan::{current_version, RECEIPT_LOCATION, RECEIPT_MIRROR_LOCATION},
+    planner::BuiltinPlanner,
+    settings::CommonSettings,
+    FailureClass, InstallPlan,
+};
+
+/// Actions with nothing on the system to find, which are recorded as completed when their parent is
+///
+/// Their reverts only clean up after themselves, or do nothing at all.
+const UNPROBED_ACTIONS: &[&str] = &[
+    "fetch_and_unpack_nix",
+    "move_unpacked_nix",
+    "setup_default_profile",
+    "systemctl_daemon_reload",
+    "kickstart_launchctl_service",
+    "ensure_steamos_nix_directory",
+    "revert_clean_steamos_nix_offload",
+    "verify_selinux_labels",
+];
+
+/**
+Write a receipt for an existing Nix install which doesn't have one
+
+Installs made by the upstream install script, or whose receipt was lost, can't be uninstalled or
+repaired. This plans an install matching the build users and group found on the system, then
+records every action whose results are present as completed, so `uninstall` and `repair` work.
+*/
+#[derive(Debug, Parser)]
+pub struct Adopt {
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_NO_CONFIRM",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub no_confirm: bool,
+
+    /// Where to keep a copy of the receipt outside of `/nix`, for `uninstall` and `repair` to fall back to
+    #[clap(long, env = "NIX_INSTALLER_RECEIPT_MIRROR", default_value = RECEIPT_MIRROR_LOCATION)]
+    pub receipt_mirror: PathBuf,
+
+    #[clap(subcommand)]
+    pub planner: Option<BuiltinPlanner>,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Adopt {
+    #[tracing::instrument(level = "debug", skip_all, fields())]
+    async fn execute<T>(self, _feedback: T) -> eyre::Result<ExitCode>
+    where
+        T: crate::feedback::Feedback,
+    {
+        let Self {
+            no_confirm,
+            receipt_mirror,
+            planner,
+        } = self;
+
+        ensure_root()?;
+
+        if Path::new(RECEIPT_LOCATION).exists() {
+            return Ok(output::classified_failure(
+                FailureClass::Precondition,
+                format!("This Nix install already has a receipt at `{RECEIPT_LOCATION}`, there is nothing to adopt."),
+            ));
+        }
+        if !Path::new("/nix/store").exists() {
+            return Ok(output::classified_failure(
+                FailureClass::Precondition,
+                "No Nix install was found, `/nix/store` does not exist.",
+            ));
+        }
+
+        let mut planner = match planner {
+            Some(planner) => planner,
+            None => BuiltinPlanner::default().await?,
+        };
+        if let Err(reason) = adopt_settings(planner.common_settings_mut()) {
+            return Ok(output::classified_failure(
+                FailureClass::Precondition,
+                reason,
+            ));
+        }
+
+        let planner = planner.boxed();
+        planner.platform_check().await?;
+        let planned = planner
+            .plan()
+            .await
+            .wrap_err("Planning an install matching the existing one")?;
+
+        let mut actions = Vec::with_capacity(planned.len());
+        for action in planned {
+            actions.push(adopt_action(action)?);
+        }
+
+        let plan = InstallPlan {
+            version: current_version()?,
+            actions,
+            planner,
+            receipt_mirror: Some(receipt_mirror),
+        };
+
+        let mut summary = format!(
+            "Adopting the existing Nix install, with the `{}` planner.\n",
+            plan.planner.typetag_name()
+        );
+        for action in &plan.actions {
+            let (marker, note) = match action.state {
+                ActionState::Skipped => ("-".red().to_string(), " (not found, skipped)"),
+                _ => ("*".green().to_string(), ""),
+            };
+            summary.push_str(&format!("{marker} {}{note}\n", action.tracing_synopsis()));
+        }
+        summary.push_str(&format!(
+            "\nThe completed actions will be recorded in `{RECEIPT_LOCATION}`, nothing else is changed."
+        ));
+
+        if no_confirm {
+            tracing::info!("{summary}");
+        } else {
+            loop {
+                match crate::cli::interaction::prompt(&summary, PromptChoice::Yes, true).await? {
+                    PromptChoice::Yes => break,
+                    PromptChoice::No => {
+                        return Ok(output::cancelled("Okay, not adopting the install. Bye!"))
+                    },
+                    PromptChoice::Explain => (),
+                }
+            }
+        }
+
+        plan.write_receipt().await?;
+        output::receipt(Some(Path::new(RECEIPT_LOCATION)));
+
+        output::success(
+            "Adopted the Nix install, `nix-installer uninstall` and `nix-installer repair` can now be used",
+        );
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// Set the build users and group, and the distribution, to those of the existing install
+fn adopt_settings(settings: &mut CommonSettings) -> Result<(), String> {
+    let group = Group::from_name(&settings.nix_build_group_name)
+        .map_err(|e| format!("Looking up group `{}`: {e}", settings.nix_build_group_name))?
+        .ok_or_else(|| {
+            format!(
+                "No build group `{}` was found, pass the group of the existing install with `--nix-build-group-name`.",
+                settings.nix_build_group_name
+            )
+        })?;
+    settings.nix_build_group_id = group.gid.as_raw();
+
+    let mut uids = vec![];
+    loop {
+        let name = format!("{}{}", settings.nix_build_user_prefix, uids.len() + 1);
+        match User::from_name(&name).map_err(|e| format!("Looking up user `{name}`: {e}"))? {
+            Some(user) => uids.push(user.uid.as_raw()),
+            None => break,
+        }
+    }
+    let (base, count) = build_user_range(&settings.nix_build_user_prefix, &uids)?;
+    settings.nix_build_user_id_base = base;
+    settings.nix_build_user_count = count;
+
+    let determinate = Path::new(DETERMINATE_NIXD_BINARY_PATH).exists();
+    settings.determinate_nix = determinate;
+    settings.prefer_upstream = !determinate;
+    // Planning shouldn't refuse files which are already in place, they are what's being adopted
+    settings.force = true;
+
+    Ok(())
+}
+
+/// The UID base and count of the build users `<prefix>1`, `<prefix>2`… with the UIDs `uids`
+fn build_user_range(prefix: &str, uids: &[u32]) -> Result<(u32, u32), String> {
+    let Some(first_uid) = uids.first().copied() else {
+        return Err(format!(
+            "No build user `{prefix}1` was found, pass the prefix of the existing install's build users with `--nix-build-user-prefix`."
+        ));
+    };
+    // Build user `N` has the UID `base + N`
+    let base = first_uid.saturating_sub(1);
+    if let Some((index, uid)) = uids
+        .iter()
+        .enumerate()
+        .find(|(index, uid)| **uid != base + 1 + *index as u32)
+    {
+        return Err(format!(
+            "Build user `{prefix}{}` has UID {uid} rather than {}, run `nix-installer repair build-users` on a receipted install to renumber them.",
+            index + 1,
+            base + 1 + index as u32,
+        ));
+    }
+    Ok((base, uids.len() as u32))
+}
+
+/// Mark an action, and those nested in it, completed, or skipped if something it creates is missing
+fn adopt_action(
+    action: StatefulAction<Box<dyn Action>>,
+) -> eyre::Result<StatefulAction<Box<dyn Action>>> {
+    let mut value = serde_json::to_value(&action).wrap_err("Serializing a planned action")?;
+    mark_adopted(&mut value)?;
+    serde_json::from_value(value).wrap_err("Deserializing an adopted action")
+}
+
+/// Mark every action in `value` completed if what it does is found on the system, or skipped
+///
+/// Actions nesting others are completed if any of them is, unless they can be probed themselves.
+/// Actions which can't be probed are skipped, unless listed in [`UNPROBED_ACTIONS`].
+fn mark_adopted(value: &mut Value) -> eyre::Result<()> {
+    match value {
+        Value::Object(map) => {
+            for child in map.values_mut() {
+                mark_adopted(child)?;
+            }
+            let Some(Value::Object(action)) = map.get("action") else {
+                return Ok(());
+            };
+            if map.get("state").is_none() {
+                return Ok(());
+            }
+            let nested = nested_states(action);
+            let present = match probe(action) {
+                Some(present) => present,
+                None if !nested.is_empty() => nested.contains(&ActionState::Completed),
+                None => action
+                    .get("action_name")
+                    .and_then(Value::as_str)
+                    .is_some_and(|name| UNPROBED_ACTIONS.contains(&name)),
+            };
+            let state = if present {
+                ActionState::Completed
+            } else {
+                ActionState::Skipped
+            };
+            map.insert("state".to_string(), serde_json::to_value(state)?);
+        },
+        Value::Array(items) => {
+            for item in items {
+                mark_adopted(item)?;
+            }
+        },
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Whether what `action` does is found on the system, `None` if it can't be told by itself
+fn probe(action: &Map<String, Value>) -> Option<bool> {
+    let string = |field: &str| action.get(field).and_then(Value::as_str);
+    let exists = |field: &str| string(field).is_some_and(|path| Path::new(path).exists());
+    match string("action_name")? {
+        "create_directory" => Some(string("path").is_some_and(|path| Path::new(path).is_dir())),
+        "remove_directory" => Some(!exists("path")),
+        "create_file"
+        | "create_or_merge_nix_config"
+        | "bootstrap_launchctl_service"
+        | "create_nix_hook_service"
+        | "create_volume_service"
+        | "create_determinate_volume_service" => Some(exists("path")),
+        "provision_apparmor" => Some(exists("profile_path")),
+        "provision_determinate_nixd" => Some(exists("binary_location")),
+        "create_or_insert_into_file" => {
+            let contents = std::fs::read(string("path")?).ok()?;
+            let buf = string("buf")?.trim();
+            Some(
+                contents
+                    .windows(buf.len().max(1))
+                    .any(|window| window == buf.as_bytes()),
+            )
+        },
+        "create_user" => Some(User::from_name(string("name")?).ok()?.is_some()),
+        "create_group" => Some(Group::from_name(string("name")?).ok()?.is_some()),
+        "add_user_to_group" => {
+            let group = Group::from_name(string("groupname")?).ok()?;
+            let name = string("name")?;
+            Some(group.is_some_and(|group| group.mem.iter().any(|member| member == name)))
+        },
+        "configure_init_service" => {
+            let sockets = action
+                .get("socket_files")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|socket| socket.get("dest").and_then(Value::as_str));
+            let mut units = string("service_dest").into_iter().chain(sockets);
+            Some(units.all(|unit| Path::new(unit).exists()))
+        },
+        "start_systemd_unit" => {
+            let unit = string("unit")?;
+            let query = if action.get("enable") == Some(&Value::Bool(true)) {
+                "is-enabled"
+            } else {
+                "is-active"
+            };
+            Some(command_succeeds("systemctl", &[query, "--quiet", unit]))
+        },
+        "provision_selinux" => {
+            let policy_path = Path::new(string("policy_path")?);
+            let module = policy_path.file_stem()?.to_str()?;
+            Some(policy_path.exists() && selinux_module_loaded(module))
+        },
+        _ => None,
+    }
+}
+
+fn command_succeeds(command: &str, args: &[&str]) -> bool {
+    std::process::Command::new(command)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn selinux_module_loaded(module: &str) -> bool {
+    let Ok(output) = std::process::Command::new("semodule")
+        .arg("--list-modules")
+        .stdin(std::process::Stdio::null())
+        .output()
+    else {
+        return false;
+    };
+    // Lines like `nix 1.0`, or `nix` alone on newer releases
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.split_whitespace().next() == Some(module))
+}
+
+/// The states of the actions directly nested in `action`
+fn nested_states(action: &Map<String, Value>) -> Vec<ActionState> {
+    fn collect(value: &Value, states: &mut Vec<ActionState>) {
+        match value {
+            Value::Object(map) => match (map.get("action"), map.get("state")) {
+                (Some(Value::Object(_)), Some(state)) => {
+                    if let Ok(state) = serde_json::from_value(state.clone()) {
+                        states.push(state);
+                    }
+                },
+                _ => map.values().for_each(|value| collect(value, states)),
+            },
+            Value::Array(items) => items.iter().for_each(|item| collect(item, states)),
+            _ => (),
+        }
+    }
+    let mut states = vec![];
+    for value in action.values() {
+        collect(value, &mut states);
+    }
+    states
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::{adopt_settings, build_user_range, mark_adopted};
+    use crate::settings::CommonSettings;
+
+    fn action(fields: Value) -> Value {
+        json!({ "action": fields, "state": "Uncompleted" })
+    }
+
+    fn states(receipt: &Value) -> Vec<&str> {
+        receipt["actions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|action| action["state"].as_str().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn probes_each_kind_of_action() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+        let profile = temp_dir.path().join("profile");
+        std::fs::write(&profile, "# Existing\n\n# Nix\nexport NIX=1\n# End Nix\n")?;
+        let missing = temp_dir.path().join("missing");
+
+        let mut receipt = json!({ "actions": [
+            action(json!({ "action_name": "create_directory", "path": temp_dir.path() })),
+            action(json!({ "action_name": "create_directory", "path": missing })),
+            action(json!({ "action_name": "remove_directory", "path": missing })),
+            action(json!({ "action_name": "create_or_insert_into_file", "path": profile, "buf": "\n# Nix\nexport NIX=1\n# End Nix\n" })),
+            action(json!({ "action_name": "create_or_insert_into_file", "path": profile, "buf": "# Nix\nexport OTHER=1\n# End Nix\n" })),
+            action(json!({ "action_name": "create_user", "name": "root" })),
+            action(json!({ "action_name": "create_user", "name": "nix-installer-no-such-user" })),
+            action(json!({ "action_name": "create_group", "name": "nix-installer-no-such-group" })),
+            action(json!({ "action_name": "configure_init_service", "service_dest": profile, "socket_files": [{ "dest": missing }] })),
+            action(json!({ "action_name": "configure_init_service", "service_dest": null, "socket_files": [] })),
+            action(json!({ "action_name": "provision_apparmor", "profile_path": missing })),
+            action(json!({ "action_name": "systemctl_daemon_reload" })),
+            action(json!({ "action_name": "some_future_action" })),
+        ]});
+        mark_adopted(&mut receipt)?;
+
+        assert_eq!(
+            states(&receipt),
+            [
+                "Completed",
+                "Skipped",
+                "Completed",
+                "Completed",
+                "Skipped",
+                "Completed",
+                "Skipped",
+                "Skipped",
+                "Skipped",
+                "Completed",
+                "Skipped",
+                "Completed",
+                "Skipped",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn nesting_actions_follow_their_children() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+        let missing = temp_dir.path().join("missing");
+
+        let mut receipt = json!({ "actions": [
+            action(json!({
+                "action_name": "create_nix_tree",
+                "create_directories": [
+                    action(json!({ "action_name": "create_directory", "path": temp_dir.path() })),
+                    action(json!({ "action_name": "create_directory", "path": missing })),
+                ],
+            })),
+            action(json!({
+                "action_name": "configure_openrc_service",
+                "create_service": action(json!({ "action_name": "create_file", "path": missing })),
+            })),
+        ]});
+        mark_adopted(&mut receipt)?;
+
+        assert_eq!(states(&receipt), ["Completed", "Skipped"]);
+        assert_eq!(
+            receipt["actions"][0]["action"]["create_directories"][1]["state"],
+            "Skipped"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_user_ranges() {
+        assert_eq!(
+            build_user_range("nixbld", &[30001, 30002, 30003]),
+            Ok((30000, 3))
+        );
+        assert!(
+            build_user_range("nixbld", &[]).is_err_and(
+                |err| err.contains("`nixbld1`") && err.contains("--nix-build-user-prefix")
+            )
+        );
+        assert!(build_user_range("_nixbld", &[351, 352, 360])
+            .is_err_and(|err| err.contains("`_nixbld3` has UID 360 rather than 353")));
+    }
+
+    #[tokio::test]
+    async fn adopting_needs_the_build_group() -> Result<(), Box<dyn std::error::Error>> {
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_build_group_name = "nix-installer-no-such-group".to_string();
+        assert!(
+            adopt_settings(&mut settings).is_err_and(|err| err.contains("--nix-build-group-name"))
+        );
+
+        settings.nix_build_group_name = "root".to_string();
+        settings.nix_build_user_prefix = "nix-installer-no-such-user".to_string();
+        assert!(
+            adopt_settings(&mut settings).is_err_and(|err| err.contains("--nix-build-user-prefix"))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::{output, CommandExecute},
+    detect::{DetectReport, ProbeResult},
+};
+
+/**
+Report what the installer detects about this host, and the planner it would select
+
+Runs the same probes as `install` and `plan`, without changing anything.
+*/
+#[derive(Debug, Parser)]
+pub struct Detect {}
+
+#[async_trait::async_trait]
+impl CommandExecute for Detect {
+    #[tracing::instrument(level = "debug", skip_all, fields())]
+    async fn execute<T>(self, _feedback: T) -> eyre::Result<ExitCode>
+    where
+        T: crate::feedback::Feedback,
+    {
+        let report = crate::detect::run().await;
+
+        if output::is_json() {
+            output::report(&report)?;
+        } else {
+            print_report(&report);
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn print_report(report: &DetectReport) {
+    let DetectReport {
+        host,
+        linux,
+        nix,
+        planner,
+    } = report;
+
+    println!("{}", "Host".bold());
+    row("Target", &host.triple);
+    row("Architecture", &host.architecture);
+    row("Operating system", &host.operating_system);
+    row(
+        "Container",
+        host.container.as_deref().unwrap_or("none detected"),
+    );
+
+    if let Some(linux) = linux {
+        println!("{}", "Linux".bold());
+        match &linux.os_release {
+            Some(os_release) => {
+                row("os-release ID", &os_release.id);
+                row("os-release ID_LIKE", &os_release.id_like);
+                row("os-release PRETTY_NAME", &os_release.pretty_name);
+                row("os-release VERSION_ID", &os_release.version_id);
+            },
+            None => row("os-release", "unreadable"),
+        }
+        row(
+            "Init",
+            &match (linux.init, linux.start_daemon) {
+                (Some(init), Some(true)) => format!("{init} (daemon started)"),
+                (Some(init), _) => format!("{init} (daemon not started)"),
+                (None, _) => "unknown".to_string(),
+            },
+        );
+        row("systemd running", yes_no(linux.systemd_started));
+        row(
+            "OpenRC",
+            match (linux.openrc_installed, linux.openrc_started) {
+                (true, true) => "running",
+                (true, false) => "installed, not running",
+                (false, _) => "no",
+            },
+        );
+        row("SELinux", &probe(&linux.selinux));
+        row(
+            "AppArmor (restricting user namespaces)",
+            &probe(&linux.apparmor),
+        );
+        row(
+            "WSL",
+            match (linux.wsl1, linux.wsl2) {
+                (true, _) => "WSL1",
+                (false, true) => "WSL2",
+                (false, false) => "no",
+            },
+        );
+        row("ostree", yes_no(linux.ostree));
+        row("SteamOS", yes_no(linux.steamos));
+        if let Some(requires_bind_mount) = linux.steamos_requires_bind_mount {
+            row("SteamOS requires bind mount", yes_no(requires_bind_mount));
+        }
+    }
+
+    println!("{}", "Nix".bold());
+    row("`/nix` exists", yes_no(nix.nix_exists));
+    row("Receipt exists", yes_no(nix.receipt_exists));
+    row("`nix-env` runs", yes_no(nix.nix_env_runs));
+    row("NixOS", yes_no(nix.nixos));
+    if let Some(mount) = &nix.mount {
+        row(
+            "`/nix` mount",
+            &match (mount.mount_point, &mount.filesystem, &mount.source) {
+                (true, Some(filesystem), Some(source)) => format!("{filesystem} from {source}"),
+                (true, _, _) => "separate filesystem".to_string(),
+                (false, _, _) => "on the root filesystem".to_string(),
+            },
+        );
+    }
+
+    println!("{}", "Planner".bold());
+    row("Selected", planner.name.as_deref().unwrap_or("none"));
+    row("Because", &planner.reason);
+    let mut settings = planner.settings.iter().collect::<Vec<_>>();
+    settings.sort_by_key(|(key, _)| key.as_str());
+    for (key, value) in settings {
+        row(key, &value.to_string());
+    }
+}
+
+fn row(name: &str, value: &str) {
+    println!("  {}: {value}", name.dimmed());
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn probe(result: &ProbeResult) -> String {
+    match result {
+        ProbeResult::Absent => "no".to_string(),
+        ProbeResult::Present => "yes".to_string(),
+        ProbeResult::Unusable(reason) => format!("yes, but unusable: {reason}"),
+    }
+}
@@ -0,0 +1,113 @@
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::{output, CommandExecute},
+    history::{format_timestamp, ExitStatus, HistoryEntry, HISTORY_LOCATION},
+};
+
+/**
+List and show the recorded runs of the installer
+
+Each `install`, `uninstall`, `repair`, `adopt` and `split-receipt` is recorded in
+`/nix/var/nix-installer/history/`, with its arguments, how it exited and the receipt afterwards.
+*/
+#[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct History {
+    #[command(subcommand)]
+    command: Option<HistoryKind>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum HistoryKind {
+    /// List the recorded runs, oldest first
+    List,
+    /// Print a recorded run, including its receipt snapshot, as JSON
+    Show {
+        /// The ID of the run, as listed by `history list` (defaults to the latest)
+        id: Option<String>,
+    },
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for History {
+    #[tracing::instrument(level = "debug", skip_all, fields())]
+    async fn execute<T>(self, _feedback: T) -> eyre::Result<ExitCode>
+    where
+        T: crate::feedback::Feedback,
+    {
+        match self.command.unwrap_or(HistoryKind::List) {
+            HistoryKind::List => {
+                let entries = crate::history::entries()
+                    .await
+                    .wrap_err("Reading the history")?;
+                if output::is_json() {
+                    let entries = entries
+                        .into_iter()
+                        .map(|(id, entry)| ListedEntry { id, entry })
+                        .collect::<Vec<_>>();
+                    output::report(&entries)?;
+                    return Ok(ExitCode::SUCCESS);
+                }
+                if entries.is_empty() {
+                    println!("No runs are recorded in `{HISTORY_LOCATION}`.");
+                }
+                for (id, entry) in &entries {
+                    print_entry(id, entry);
+                }
+            },
+            HistoryKind::Show { id } => {
+                let entry = match id {
+                    Some(id) => crate::history::entry(&id)
+                        .await
+                        .wrap_err("Reading the history")?,
+                    None => crate::history::entries()
+                        .await
+                        .wrap_err("Reading the history")?
+                        .pop()
+                        .map(|(_id, entry)| entry),
+                };
+                let Some(entry) = entry else {
+                    output::failure(format!("No such run is recorded in `{HISTORY_LOCATION}`."));
+                    return Ok(ExitCode::FAILURE);
+                };
+                if output::is_json() {
+                    output::report(&entry)?;
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&entry)?);
+                }
+            },
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// A recorded run, as reported by `history list` with `--output json`
+#[derive(Debug, serde::Serialize)]
+struct ListedEntry {
+    id: String,
+    #[serde(flatten)]
+    entry: HistoryEntry,
+}
+
+fn print_entry(id: &str, entry: &HistoryEntry) {
+    let status = match &entry.exit_status {
+        ExitStatus::Success => entry.exit_status.to_string().green().to_string(),
+        _ => entry.exit_status.to_string().red().to_string(),
+    };
+    println!(
+        "{id}  {started}  {subcommand} ({version}, {duration}s): {status}",
+        started = format_timestamp(entry.started),
+        subcommand = entry.subcommand.bold(),
+        version = entry.version,
+        duration = entry.finished.saturating_sub(entry.started),
+    );
+    if !entry.args.is_empty() {
+        println!("    {}", entry.args.join(" ").dimmed());
+    }
+}
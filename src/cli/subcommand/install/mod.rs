@@ -10,21 +10,19 @@ use crate::{
     cli::{
         ensure_root,
         interaction::{self, PromptChoice},
-        signal_channel,
+        log_file, output, signal_channel,
         subcommand::split_receipt::{PHASE1_RECEIPT_LOCATION, PHASE2_RECEIPT_LOCATION},
         CommandExecute,
     },
     error::HasExpectedErrors,
-    plan::RECEIPT_LOCATION,
+    feedback::ActionPhase,
+    plan::{RECEIPT_LOCATION, RECEIPT_MIRROR_LOCATION},
     settings::CommonSettings,
     util::OnMissing,
-    BuiltinPlanner, InstallPlan, NixInstallerError,
+    BuiltinPlanner, FailureClass, InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
-use color_eyre::{
-    eyre::{eyre, WrapErr},
-    Section,
-};
+use color_eyre::eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
 
 const EXISTING_INCOMPATIBLE_PLAN_GUIDANCE: &str = "\
@@ -66,6 +64,19 @@ pub struct Install {
     )]
     pub explain: bool,
 
+    /// After installing, import a closure exported by `uninstall --export-closure` and install its packages into the default profile
+    #[clap(long, env = "NIX_INSTALLER_IMPORT_CLOSURE", global = true)]
+    pub import_closure: Option<PathBuf>,
+
+    /// Where to keep a copy of the receipt outside of `/nix`, for `uninstall` and `repair` to fall back to
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RECEIPT_MIRROR",
+        default_value = RECEIPT_MIRROR_LOCATION,
+        global = true
+    )]
+    pub receipt_mirror: PathBuf,
+
     /// A path to a non-default installer plan
     #[clap(env = "NIX_INSTALLER_PLAN")]
     pub plan: Option<PathBuf>,
@@ -87,10 +98,24 @@ impl CommandExecute for Install {
             planner: maybe_planner,
             settings,
             explain,
+            import_closure,
+            receipt_mirror,
         } = self;
 
         ensure_root()?;
 
+        if let Some(import_closure) = &import_closure {
+            if !import_closure.exists() {
+                return Ok(output::classified_failure(
+                    FailureClass::Precondition,
+                    format!(
+                        "The closure to import, `{}`, does not exist.",
+                        import_closure.display()
+                    ),
+                ));
+            }
+        }
+
         let existing_receipt: Option<InstallPlan> = match Path::new(RECEIPT_LOCATION).exists() {
             true => {
                 tracing::trace!("Reading existing receipt");
@@ -131,32 +156,28 @@ impl CommandExecute for Install {
             };
 
             if let Some(existing_receipt) = existing_receipt {
+                output::receipt(Some(Path::new(RECEIPT_LOCATION)));
+
                 if let Err(e) = existing_receipt.check_compatible() {
-                    eprintln!(
-                        "{}",
-                        format!("\
-                            {e}\n\
-                            \n\
-                            Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
-                            {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
-                        ").red()
-                        );
-                    return Ok(ExitCode::FAILURE);
+                    return Ok(output::classified_failure(FailureClass::Precondition, format!("\
+                        {e}\n\
+                        \n\
+                        Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
+                        {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
+                    ")));
                 }
 
                 if existing_receipt.planner.typetag_name() != planner.typetag_name() {
-                    eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
-                    return Ok(ExitCode::FAILURE);
+                    return Ok(output::classified_failure(FailureClass::Precondition, format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`")));
                 }
 
                 if existing_receipt.planner.settings().map_err(|e| eyre!(e))?
                     != planner.settings().map_err(|e| eyre!(e))?
                 {
-                    eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
-                    return Ok(ExitCode::FAILURE);
+                    return Ok(output::classified_failure(FailureClass::Precondition, format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`")));
                 }
 
-                eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
+                output::failure(format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working"));
                 return Ok(ExitCode::SUCCESS);
             }
 
@@ -168,8 +189,7 @@ impl CommandExecute for Install {
                 Err(err) => {
                     feedback.planning_failed(&err).await;
                     if let Some(expected) = err.expected() {
-                        eprintln!("{}", expected.red());
-                        return Ok(ExitCode::FAILURE);
+                        return Ok(output::expected_failure(&expected, &err));
                     }
                     return Err(err)?;
                 },
@@ -177,11 +197,11 @@ impl CommandExecute for Install {
         };
 
         feedback.planning_succeeded().await;
+        install_plan.receipt_mirror = Some(receipt_mirror);
 
         if let Err(err) = install_plan.pre_install_check().await {
             if let Some(expected) = err.expected() {
-                eprintln!("{}", expected.red());
-                return Ok(ExitCode::FAILURE);
+                return Ok(output::expected_failure(&expected, &err));
             }
             Err(err)?
         }
@@ -202,10 +222,9 @@ impl CommandExecute for Install {
                     PromptChoice::Yes => break,
                     PromptChoice::Explain => currently_explaining = true,
                     PromptChoice::No => {
-                        interaction::clean_exit_with_message(
+                        return Ok(output::cancelled(
                             "Okay, not continuing with the installation. Bye!",
-                        )
-                        .await
+                        ))
                     },
                 }
             }
@@ -213,8 +232,15 @@ impl CommandExecute for Install {
 
         let (tx, rx1) = signal_channel().await?;
 
-        match install_plan.install(feedback.clone(), rx1).await {
+        let res = install_plan.install(feedback.clone(), rx1).await;
+        output::changed_in(&install_plan, ActionPhase::Install);
+        if Path::new(RECEIPT_LOCATION).exists() {
+            output::receipt(Some(Path::new(RECEIPT_LOCATION)));
+        }
+
+        match res {
             Err(err) => {
+                let failure_class = err.failure_class();
                 // Attempt to copy self to the store if possible, but since the install failed, this might not work, that's ok.
                 copy_self_to_nix_dir().await.ok();
 
@@ -222,12 +248,12 @@ impl CommandExecute for Install {
                     let mut was_expected = false;
                     if let Some(expected) = err.expected() {
                         was_expected = true;
-                        eprintln!("{}", expected.red())
+                        output::expected_failure(&expected, &err);
                     }
 
                     let was_cancelled = matches!(err, NixInstallerError::Cancelled);
                     if was_cancelled {
-                        eprintln!("{}", err.red());
+                        output::error(&err);
                     }
 
                     if !was_expected && !was_cancelled {
@@ -236,6 +262,9 @@ impl CommandExecute for Install {
                     };
 
                     eprintln!("{}", "Installation failure, offering to revert...".red());
+                    if let Some(log) = log_file::path() {
+                        eprintln!("The full log of the installation is at `{}`", log.display());
+                    }
                     let mut currently_explaining = explain;
                     loop {
                         match interaction::prompt(
@@ -251,53 +280,40 @@ impl CommandExecute for Install {
                             PromptChoice::Yes => break,
                             PromptChoice::Explain => currently_explaining = true,
                             PromptChoice::No => {
-                                interaction::clean_exit_with_message(
-                                    "Okay, didn't do anything! Bye!",
-                                )
-                                .await
+                                return Ok(output::cancelled("Okay, didn't do anything! Bye!"))
                             },
                         }
                     }
                     let rx2 = tx.subscribe();
                     let res = install_plan.uninstall(feedback, rx2).await;
+                    output::changed_in(&install_plan, ActionPhase::Uninstall);
 
                     match res {
-                        Err(NixInstallerError::ActionRevert(errs)) => {
-                            let mut report = eyre!("Multiple errors");
-                            for err in errs {
-                                report = report.error(err);
-                            }
-                            return Err(report)?;
+                        Err(err @ NixInstallerError::ActionRevert(_)) => {
+                            return Err(eyre!(err).wrap_err("Reverting the partial install"));
                         },
                         Err(err) => {
                             if let Some(expected) = err.expected() {
-                                eprintln!("{}", expected.red());
-                                return Ok(ExitCode::FAILURE);
+                                return Ok(output::expected_failure(&expected, &err));
                             }
                             if matches!(err, NixInstallerError::Cancelled) {
-                                eprintln!("{}", err.red());
-                                return Ok(ExitCode::FAILURE);
+                                return Ok(output::error(&err));
                             }
                             return Err(err)?;
                         },
                         _ => {
-                            println!(
-                                "\
-                                {message}\n\
-                                ",
-                                message =
-                                    "Partial Nix install was uninstalled successfully!".bold(),
-                            );
+                            install_plan.remove_receipt_mirror().await?;
+                            output::receipt(None);
+                            output::success("Partial Nix install was uninstalled successfully!");
+                            return Ok(failure_class.into());
                         },
                     }
                 } else {
                     if let Some(expected) = err.expected() {
-                        eprintln!("{}", expected.red());
-                        return Ok(ExitCode::FAILURE);
+                        return Ok(output::expected_failure(&expected, &err));
                     }
                     if matches!(err, NixInstallerError::Cancelled) {
-                        eprintln!("{}", err.red());
-                        return Ok(ExitCode::FAILURE);
+                        return Ok(output::error(&err));
                     }
 
                     let error = eyre!(err).wrap_err("Install failure");
@@ -325,19 +341,41 @@ impl CommandExecute for Install {
                         .wrap_err_with(|| format!("Failed to remove uninstall phase 2 receipt at {PHASE2_RECEIPT_LOCATION}"))?;
                 }
 
-                println!(
-                    "\
-                    {success}\n\
-                    To get started using Nix, open a new shell or run `{shell_reminder}`\n\
-                    ",
-                    success = "Nix was installed successfully!".green().bold(),
-                    shell_reminder = match std::env::var("SHELL") {
-                        Ok(val) if val.contains("fish") =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish".bold(),
-                        Ok(_) | Err(_) =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh".bold(),
-                    },
-                );
+                if let Some(import_closure) = &import_closure {
+                    match crate::profile::closure::import_closure(import_closure).await {
+                        Ok(pkgs) => tracing::info!(
+                            "Imported `{}`, and installed {} packages into the default profile",
+                            import_closure.display(),
+                            pkgs.len()
+                        ),
+                        Err(err) => {
+                            output::failure(format!(
+                                "Nix was installed, but importing the closure `{}` failed: {err}",
+                                import_closure.display()
+                            ));
+                            return Ok(ExitCode::FAILURE);
+                        },
+                    }
+                }
+
+                if output::is_json() {
+                    output::success("Nix was installed successfully!");
+                } else {
+                    println!(
+                        "\
+                        {success}\n\
+                        To get started using Nix, open a new shell or run `{shell_reminder}`\n\
+                        ",
+                        success = "Nix was installed successfully!".green().bold(),
+                        shell_reminder = match std::env::var("SHELL") {
+                            Ok(val) if val.contains("fish") =>
+                                ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish"
+                                    .bold(),
+                            Ok(_) | Err(_) =>
+                                ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh".bold(),
+                        },
+                    );
+                }
             },
         }
 
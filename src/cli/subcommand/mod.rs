@@ -1,3 +1,6 @@
+mod adopt;
+mod detect;
+mod history;
 mod install;
 mod plan;
 mod repair;
@@ -5,6 +8,9 @@ mod self_test;
 mod split_receipt;
 mod uninstall;
 
+use adopt::Adopt;
+use detect::Detect;
+use history::History;
 use install::Install;
 use plan::Plan;
 use repair::Repair;
@@ -21,4 +27,57 @@ pub enum NixInstallerSubcommand {
     SelfTest(SelfTest),
     Plan(Plan),
     SplitReceipt(SplitReceipt),
+    Detect(Detect),
+    Adopt(Adopt),
+    History(History),
+}
+
+impl NixInstallerSubcommand {
+    /// The name of the subcommand, as given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            NixInstallerSubcommand::Install(_) => "install",
+            NixInstallerSubcommand::Repair(_) => "repair",
+            NixInstallerSubcommand::Uninstall(_) => "uninstall",
+            NixInstallerSubcommand::SelfTest(_) => "self-test",
+            NixInstallerSubcommand::Plan(_) => "plan",
+            NixInstallerSubcommand::SplitReceipt(_) => "split-receipt",
+            NixInstallerSubcommand::Detect(_) => "detect",
+            NixInstallerSubcommand::Adopt(_) => "adopt",
+            NixInstallerSubcommand::History(_) => "history",
+        }
+    }
+
+    /// The name to write the full log of runs under, for the subcommands which run as `root`
+    ///
+    /// Other subcommands only write a log when given `--log-file`, so running them as a user
+    /// leaves nothing behind.
+    pub fn log_name(&self) -> Option<&'static str> {
+        match self {
+            NixInstallerSubcommand::Install(_) => Some("install"),
+            NixInstallerSubcommand::Repair(_) => Some("repair"),
+            NixInstallerSubcommand::Uninstall(_) => Some("uninstall"),
+            NixInstallerSubcommand::Plan(_) => Some("plan"),
+            NixInstallerSubcommand::SplitReceipt(_) => Some("split-receipt"),
+            NixInstallerSubcommand::Adopt(_) => Some("adopt"),
+            NixInstallerSubcommand::SelfTest(_)
+            | NixInstallerSubcommand::Detect(_)
+            | NixInstallerSubcommand::History(_) => None,
+        }
+    }
+
+    /// The name to record runs under in the history, for the subcommands which change the system
+    pub(crate) fn history_name(&self) -> Option<&'static str> {
+        match self {
+            NixInstallerSubcommand::Install(_) => Some("install"),
+            NixInstallerSubcommand::Repair(_) => Some("repair"),
+            NixInstallerSubcommand::Uninstall(_) => Some("uninstall"),
+            NixInstallerSubcommand::SplitReceipt(_) => Some("split-receipt"),
+            NixInstallerSubcommand::Adopt(_) => Some("adopt"),
+            NixInstallerSubcommand::SelfTest(_)
+            | NixInstallerSubcommand::Plan(_)
+            | NixInstallerSubcommand::Detect(_)
+            | NixInstallerSubcommand::History(_) => None,
+        }
+    }
 }
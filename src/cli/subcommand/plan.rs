@@ -1,10 +1,16 @@
-use std::{path::PathBuf, process::ExitCode};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use crate::{cli::ensure_root, error::HasExpectedErrors, BuiltinPlanner};
+use crate::{
+    cli::{ensure_root, output},
+    error::HasExpectedErrors,
+    BuiltinPlanner,
+};
 use clap::Parser;
 
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
 
 use crate::cli::CommandExecute;
 
@@ -33,7 +39,10 @@ impl CommandExecute for Plan {
     where
         T: crate::feedback::Feedback,
     {
-        let Self { planner, output } = self;
+        let Self {
+            planner,
+            output: out_file,
+        } = self;
 
         ensure_root()?;
 
@@ -51,8 +60,7 @@ impl CommandExecute for Plan {
             Err(err) => {
                 feedback.planning_failed(&err).await;
                 if let Some(expected) = err.expected() {
-                    eprintln!("{}", expected.red());
-                    return Ok(ExitCode::FAILURE);
+                    return Ok(output::expected_failure(&expected, &err));
                 }
                 return Err(err)?;
             },
@@ -60,8 +68,14 @@ impl CommandExecute for Plan {
 
         feedback.planning_succeeded().await;
 
+        // The plan is part of the outcome, rather than a second document on stdout
+        if output::is_json() && out_file == Path::new("/dev/stdout") {
+            output::report(&install_plan)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+
         let json = serde_json::to_string_pretty(&install_plan)?;
-        tokio::fs::write(output, format!("{json}\n"))
+        tokio::fs::write(out_file, format!("{json}\n"))
             .await
             .wrap_err("Writing plan")?;
 
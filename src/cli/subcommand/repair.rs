@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io::IsTerminal as _;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::time::SystemTime;
 
 use clap::{ArgAction, Parser, Subcommand};
 use eyre::Context as _;
+use nix::unistd::{Gid, Group, Uid, User};
 use serde::{Deserialize, Serialize};
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
@@ -12,10 +16,13 @@ use crate::action::base::{AddUserToGroup, CreateGroup, CreateUser};
 use crate::action::common::{ConfigureShellProfile, CreateUsersAndGroups};
 use crate::action::{Action, ActionState, StatefulAction};
 use crate::cli::interaction::PromptChoice;
-use crate::cli::{ensure_root, CommandExecute};
-use crate::plan::RECEIPT_LOCATION;
+use crate::cli::{ensure_root, output, CommandExecute};
+use crate::feedback::ActionPhase;
+use crate::os::linux::UserDatabase;
+use crate::plan::{RECEIPT_LOCATION, RECEIPT_MIRROR_LOCATION};
 use crate::planner::{PlannerError, ShellProfileLocations};
-use crate::{execute_command, InstallPlan};
+use crate::settings::UserBackend;
+use crate::{execute_command, FailureClass, InstallPlan};
 
 /// The base UID that we temporarily move build users to while migrating macOS to the new range.
 const TEMP_USER_ID_BASE: u32 = 31000;
@@ -37,6 +44,15 @@ pub struct Repair {
     )]
     pub no_confirm: bool,
 
+    /// The copy of the receipt kept outside of `/nix`, read if the receipt is missing
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RECEIPT_MIRROR",
+        default_value = RECEIPT_MIRROR_LOCATION,
+        global = true
+    )]
+    pub receipt_mirror: PathBuf,
+
     #[command(subcommand)]
     command: Option<RepairKind>,
 }
@@ -107,6 +123,63 @@ pub enum RepairKind {
         )]
         move_existing_users: bool,
     },
+    /// Grow or shrink the number of Nix build users, or move them and their group to new IDs.
+    ///
+    /// Files under `/nix` owned by the previous IDs are given to the new ones, and the receipt is
+    /// updated to match.
+    BuildUsers {
+        /// The Nix build user prefix (user numbers will be postfixed)
+        #[cfg_attr(
+            feature = "cli",
+            clap(long, env = "NIX_INSTALLER_NIX_BUILD_USER_PREFIX", global = true)
+        )]
+        #[cfg_attr(
+            all(target_os = "macos", feature = "cli"),
+            clap(default_value = "_nixbld")
+        )]
+        #[cfg_attr(
+            all(target_os = "linux", feature = "cli"),
+            clap(default_value = "nixbld")
+        )]
+        nix_build_user_prefix: String,
+
+        /// The number of build users there should be (defaults to the current number)
+        #[cfg_attr(feature = "cli", clap(long, alias = "daemon-user-count"))]
+        nix_build_user_count: Option<u32>,
+
+        /// The build user UID base, user numbers are added to it (defaults to the current base)
+        #[cfg_attr(feature = "cli", clap(long))]
+        nix_build_user_id_base: Option<u32>,
+
+        /// The Nix build group name
+        #[cfg_attr(
+            feature = "cli",
+            clap(
+                long,
+                default_value = "nixbld",
+                env = "NIX_INSTALLER_NIX_BUILD_GROUP_NAME",
+                global = true
+            )
+        )]
+        nix_build_group_name: String,
+
+        /// The build group GID (defaults to the current GID)
+        #[cfg_attr(feature = "cli", clap(long))]
+        nix_build_group_id: Option<u32>,
+
+        /// How to modify the build users and group on Linux (`files` edits `/etc/passwd` and friends directly)
+        #[cfg_attr(
+            feature = "cli",
+            clap(
+                long,
+                value_enum,
+                default_value_t = UserBackend::Auto,
+                env = "NIX_INSTALLER_USER_BACKEND",
+                global = true
+            )
+        )]
+        user_backend: UserBackend,
+    },
 }
 
 impl Repair {
@@ -127,6 +200,7 @@ impl CommandExecute for Repair {
         ensure_root()?;
 
         let mut repair_actions = Vec::new();
+        let mut build_users_repair = None;
         let (prompt_before_repairing, brief_repair_summary) = match command {
             RepairKind::Hooks => (
                 false,
@@ -142,6 +216,7 @@ impl CommandExecute for Repair {
                     nix_build_user_prefix,
                     nix_build_user_count,
                     nix_build_group_name,
+                    &self.receipt_mirror,
                 )
                 .await?;
 
@@ -160,6 +235,46 @@ impl CommandExecute for Repair {
                 );
                 (!self.no_confirm, brief_summary)
             },
+            RepairKind::BuildUsers {
+                ref nix_build_user_prefix,
+                nix_build_user_count,
+                nix_build_user_id_base,
+                ref nix_build_group_name,
+                nix_build_group_id,
+                user_backend,
+            } => {
+                if !matches!(
+                    OperatingSystem::host(),
+                    OperatingSystem::MacOSX { .. }
+                        | OperatingSystem::Darwin
+                        | OperatingSystem::Linux
+                ) {
+                    return Err(color_eyre::eyre::eyre!(
+                        "The `build-users` repair command is only available on Linux and macOS"
+                    ));
+                }
+
+                let repair = BuildUsersRepair::plan(
+                    nix_build_user_prefix,
+                    nix_build_user_count,
+                    nix_build_user_id_base,
+                    nix_build_group_name,
+                    nix_build_group_id,
+                    user_backend,
+                    &self.receipt_mirror,
+                )
+                .await?;
+                if repair.is_noop() {
+                    tracing::info!(
+                        "Nothing to do! The build users and group are already as requested!"
+                    );
+                    return Ok(ExitCode::SUCCESS);
+                }
+
+                let brief_summary = repair.summary();
+                build_users_repair = Some(repair);
+                (!self.no_confirm, brief_summary)
+            },
         };
 
         if prompt_before_repairing {
@@ -173,10 +288,9 @@ impl CommandExecute for Repair {
                 {
                     PromptChoice::Yes => break,
                     PromptChoice::No => {
-                        crate::cli::interaction::clean_exit_with_message(
+                        return Ok(output::cancelled(
                             "Okay, not continuing with the repair. Bye!",
-                        )
-                        .await
+                        ))
                     },
                     PromptChoice::Explain => (),
                 }
@@ -185,22 +299,37 @@ impl CommandExecute for Repair {
             tracing::info!("{}", brief_repair_summary);
         }
 
-        // TODO(cole-h): if we add another repair command, make this whole thing more generic
         let updated_receipt = match command.clone() {
             RepairKind::Hooks => {
-                let reconfigure = ConfigureShellProfile::plan(ShellProfileLocations::default())
+                let locations = installed_shell_profile_locations(&self.receipt_mirror).await;
+                let mut reconfigure = ConfigureShellProfile::plan(locations)
                     .await
-                    .map_err(PlannerError::Action)?
-                    .boxed();
-                repair_actions.push(reconfigure);
+                    .map_err(PlannerError::Action)?;
+                // Repairs are not recorded in the receipt, so `uninstall` could never restore a backup
+                reconfigure.action.set_backup_root(None);
+
+                let missing_hooks = reconfigure.inner().missing_hooks();
+                if missing_hooks.is_empty() {
+                    tracing::info!("All shell profile hooks are in place");
+                } else {
+                    tracing::info!(
+                        "Restoring the Nix shell profile hooks in: {}",
+                        missing_hooks
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                    repair_actions.push(reconfigure.boxed());
+                }
 
                 match OperatingSystem::host() {
                     OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
-                        let reconfigure = crate::action::macos::ConfigureRemoteBuilding::plan()
+                        let mut reconfigure = crate::action::macos::ConfigureRemoteBuilding::plan()
                             .await
-                            .map_err(PlannerError::Action)?
-                            .boxed();
-                        repair_actions.push(reconfigure);
+                            .map_err(PlannerError::Action)?;
+                        reconfigure.action.set_backup_root(None);
+                        repair_actions.push(reconfigure.boxed());
                     },
                     _ => {
                         // Linux-specific hook repair actions, once we have them
@@ -237,6 +366,7 @@ impl CommandExecute for Repair {
                     &nix_build_user_prefix,
                     nix_build_user_count,
                     &nix_build_group_name,
+                    &self.receipt_mirror,
                 )
                 .await?;
 
@@ -359,6 +489,7 @@ impl CommandExecute for Repair {
                         group_gid,
                         format!("Nix build user {idx}"),
                         false,
+                        UserBackend::Auto,
                     )
                     .await?;
                     create_users.push(create_user);
@@ -386,6 +517,7 @@ impl CommandExecute for Repair {
                                     uid: action.uid,
                                     groupname: action.groupname.clone(),
                                     gid: action.gid,
+                                    backend: UserBackend::Auto,
                                 }),
                                 StatefulAction::completed(action),
                             )
@@ -419,14 +551,31 @@ impl CommandExecute for Repair {
 
                 maybe_updated_receipt
             },
+            RepairKind::BuildUsers { .. } => {
+                if !std::io::stdin().is_terminal() && !self.no_confirm {
+                    return Err(color_eyre::eyre::eyre!(
+                        "The `build-users` repair command should be run in an interactive terminal. If \
+                        you accept the risks of an unattended repair, pass `--no-confirm`."
+                    ));
+                }
+
+                let repair = build_users_repair
+                    .take()
+                    .expect("The build users repair is planned before prompting");
+                repair.execute().await?;
+                repair.updated_receipt().await?
+            },
         };
 
         for mut action in repair_actions {
             if let Err(err) = action.try_execute().await {
-                println!("{:#?}", err);
-                return Ok(ExitCode::FAILURE);
+                return Ok(output::classified_failure(
+                    FailureClass::Action,
+                    format!("{err:#?}"),
+                ));
             }
             action.state = ActionState::Completed;
+            output::changed(&action, ActionPhase::Install);
         }
 
         if let Some(updated_receipt) = updated_receipt {
@@ -434,12 +583,16 @@ impl CommandExecute for Repair {
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_millis();
 
-            let mut old_receipt = std::path::PathBuf::from(RECEIPT_LOCATION);
-            old_receipt.set_extension(format!("pre-repair.{timestamp_millis}.json"));
-            tokio::fs::copy(RECEIPT_LOCATION, &old_receipt).await?;
-            tracing::info!("Backed up pre-repair receipt to {}", old_receipt.display());
+            // The receipt may have been read from its mirror, if the one under `/nix` is missing
+            if Path::new(RECEIPT_LOCATION).exists() {
+                let mut old_receipt = std::path::PathBuf::from(RECEIPT_LOCATION);
+                old_receipt.set_extension(format!("pre-repair.{timestamp_millis}.json"));
+                tokio::fs::copy(RECEIPT_LOCATION, &old_receipt).await?;
+                tracing::info!("Backed up pre-repair receipt to {}", old_receipt.display());
+            }
 
             updated_receipt.write_receipt().await?;
+            output::receipt(Some(Path::new(RECEIPT_LOCATION)));
             tracing::info!("Wrote updated receipt");
         }
 
@@ -476,29 +629,81 @@ where
     Ok(gid)
 }
 
-#[tracing::instrument]
-async fn get_existing_receipt() -> Option<InstallPlan> {
-    match std::path::Path::new(RECEIPT_LOCATION).exists() {
-        true => {
-            tracing::debug!("Reading existing receipt");
-            let install_plan_string = tokio::fs::read_to_string(RECEIPT_LOCATION).await.ok();
-
-            match install_plan_string {
-                Some(s) => match serde_json::from_str::<InstallPlan>(s.as_str()) {
-                    Ok(plan) => {
-                        tracing::debug!(plan_version = %plan.version, "Able to parse receipt");
-                        Some(plan)
-                    },
-                    Err(e) => {
-                        tracing::debug!(?e);
-                        tracing::warn!("Could not parse receipt. Your receipt will not be updated to account for the new UIDs");
-                        None
-                    },
-                },
-                _ => None,
+/// The shell profile locations the install was planned with, as recorded in the receipt
+///
+/// Planners drop locations which can't be written (like `/usr/share/fish` on SteamOS and ostree)
+/// or use others (like Alpine's `/etc/profile.d`), so the defaults are only a last resort.
+async fn installed_shell_profile_locations(receipt_mirror: &Path) -> ShellProfileLocations {
+    let receipt = match crate::plan::read_receipt(Path::new(RECEIPT_LOCATION), receipt_mirror).await
+    {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            tracing::debug!(?e, "Could not read receipt");
+            None
+        },
+    };
+    let recorded = receipt
+        .and_then(|receipt| serde_json::from_str::<serde_json::Value>(&receipt.contents).ok())
+        .and_then(|receipt| shell_profile_locations_in(&receipt));
+    if let Some(locations) = recorded {
+        return locations;
+    }
+
+    tracing::debug!("No shell profile locations in the receipt, using those of this host");
+    match OperatingSystem::host() {
+        OperatingSystem::Linux if crate::planner::linux::detect_alpine() => {
+            ShellProfileLocations::alpine()
+        },
+        _ => ShellProfileLocations::default(),
+    }
+}
+
+/// The locations of the first `configure_shell_profile` action in `value`, a receipt
+fn shell_profile_locations_in(value: &serde_json::Value) -> Option<ShellProfileLocations> {
+    match value {
+        serde_json::Value::Object(map) => {
+            if map.get("action_name").and_then(serde_json::Value::as_str)
+                == Some(ConfigureShellProfile::action_tag().0)
+            {
+                if let Some(locations) = map
+                    .get("locations")
+                    .and_then(|locations| serde_json::from_value(locations.clone()).ok())
+                {
+                    return Some(locations);
+                }
             }
+            map.values().find_map(shell_profile_locations_in)
+        },
+        serde_json::Value::Array(values) => values.iter().find_map(shell_profile_locations_in),
+        _ => None,
+    }
+}
+
+#[tracing::instrument]
+async fn get_existing_receipt(receipt_mirror: &Path) -> Option<InstallPlan> {
+    let read_receipt =
+        match crate::plan::read_receipt(Path::new(RECEIPT_LOCATION), receipt_mirror).await {
+            Ok(read_receipt) => read_receipt?,
+            Err(e) => {
+                tracing::debug!(?e, "Could not read receipt");
+                return None;
+            },
+        };
+
+    tracing::debug!(
+        "Reading existing receipt at {}",
+        read_receipt.path.display()
+    );
+    match serde_json::from_str::<InstallPlan>(&read_receipt.contents) {
+        Ok(plan) => {
+            tracing::debug!(plan_version = %plan.version, "Able to parse receipt");
+            Some(plan)
+        },
+        Err(e) => {
+            tracing::debug!(?e);
+            tracing::warn!("Could not parse receipt. Your receipt will not be updated to account for the new UIDs");
+            None
         },
-        false => None,
     }
 }
 
@@ -556,6 +761,7 @@ fn find_users_and_groups(
 struct UsersAndGroupsMeta {
     user_prefix: String,
     user_count: u32,
+    user_id_base: Option<u32>,
     group_name: String,
     group_gid: Option<u32>,
     receipt_action_idx_create_group: Option<(InstallPlan, usize, StatefulAction<CreateGroup>)>,
@@ -565,8 +771,9 @@ async fn maybe_users_and_groups_from_receipt(
     nix_build_user_prefix: &str,
     nix_build_user_count: u32,
     nix_build_group_name: &str,
+    receipt_mirror: &Path,
 ) -> eyre::Result<UsersAndGroupsMeta> {
-    let existing_receipt = get_existing_receipt().await;
+    let existing_receipt = get_existing_receipt(receipt_mirror).await;
     let maybe_create_users_and_groups_idx_action = find_users_and_groups(existing_receipt)?;
 
     match maybe_create_users_and_groups_idx_action {
@@ -575,12 +782,14 @@ async fn maybe_users_and_groups_from_receipt(
 
             let user_prefix = action.nix_build_user_prefix;
             let user_count = action.nix_build_user_count;
+            let user_id_base = action.nix_build_user_id_base;
             let group_gid = action.nix_build_group_id;
             let group_name = action.nix_build_group_name;
 
             Ok(UsersAndGroupsMeta {
                 user_prefix,
                 user_count,
+                user_id_base: Some(user_id_base),
                 group_name,
                 group_gid: Some(group_gid),
                 receipt_action_idx_create_group: Some((
@@ -593,9 +802,783 @@ async fn maybe_users_and_groups_from_receipt(
         None => Ok(UsersAndGroupsMeta {
             user_prefix: nix_build_user_prefix.to_string(),
             user_count: nix_build_user_count,
+            user_id_base: None,
             group_name: nix_build_group_name.to_string(),
             group_gid: None,
             receipt_action_idx_create_group: None,
         }),
     }
 }
+
+/// The changes `repair build-users` makes to the build users and group
+struct BuildUsersRepair {
+    user_prefix: String,
+    group_name: String,
+    backend: UserBackend,
+    previous_user_count: u32,
+    user_count: u32,
+    previous_user_id_base: u32,
+    user_id_base: u32,
+    previous_group_id: u32,
+    group_id: u32,
+    /// Existing users (by number) whose UID or primary GID needs to change
+    moved_users: Vec<(u32, User)>,
+    /// Existing users (by number) which are beyond the new user count
+    removed_users: Vec<(u32, User)>,
+    /// User numbers which are within the new user count but don't exist
+    missing_users: Vec<u32>,
+    receipt: Option<(InstallPlan, usize)>,
+}
+
+impl BuildUsersRepair {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn plan(
+        nix_build_user_prefix: &str,
+        nix_build_user_count: Option<u32>,
+        nix_build_user_id_base: Option<u32>,
+        nix_build_group_name: &str,
+        nix_build_group_id: Option<u32>,
+        backend: UserBackend,
+        receipt_mirror: &Path,
+    ) -> eyre::Result<Self> {
+        let meta = maybe_users_and_groups_from_receipt(
+            nix_build_user_prefix,
+            0,
+            nix_build_group_name,
+            receipt_mirror,
+        )
+        .await?;
+        if meta.receipt_action_idx_create_group.is_none() {
+            tracing::warn!(
+                "Unable to find {} in receipt (receipt didn't exist or is unable to be parsed by \
+                this version of the installer). Your receipt at {RECEIPT_LOCATION} will not reflect \
+                the changed users and group.",
+                CreateUsersAndGroups::action_tag()
+            );
+        }
+
+        let group = Group::from_name(&meta.group_name)
+            .with_context(|| format!("Getting group `{}`", meta.group_name))?
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "The `{}` group does not exist, there are no build users to repair",
+                    meta.group_name
+                )
+            })?;
+        let previous_group_id = group.gid.as_raw();
+
+        // Users may have been created beyond the count in the receipt (or there may be no
+        // receipt), so keep looking until the first missing user past that count.
+        let mut existing_users = BTreeMap::new();
+        let mut user_idx = 1;
+        loop {
+            let name = format!("{}{user_idx}", meta.user_prefix);
+            match User::from_name(&name).with_context(|| format!("Getting user `{name}`"))? {
+                Some(user) => {
+                    existing_users.insert(user_idx, user);
+                },
+                None if user_idx > meta.user_count => break,
+                None => (),
+            }
+            user_idx += 1;
+        }
+
+        let build_user_names = existing_users
+            .values()
+            .map(|user| user.name.clone())
+            .collect::<Vec<_>>();
+        let repair = Self::from_existing(
+            meta,
+            existing_users,
+            previous_group_id,
+            nix_build_user_count,
+            nix_build_user_id_base,
+            nix_build_group_id,
+            backend,
+        )?;
+
+        if repair.group_id != previous_group_id {
+            if let Some(other) = Group::from_gid(Gid::from_raw(repair.group_id))
+                .with_context(|| format!("Getting group with GID {}", repair.group_id))?
+            {
+                return Err(eyre::eyre!(
+                    "GID {} is already taken by the `{}` group",
+                    repair.group_id,
+                    other.name
+                ));
+            }
+        }
+
+        for idx in 1..=repair.user_count {
+            let uid = repair.user_id_base + idx;
+            if let Some(other) = User::from_uid(Uid::from_raw(uid))
+                .with_context(|| format!("Getting user with UID {uid}"))?
+            {
+                if !build_user_names.contains(&other.name) {
+                    return Err(eyre::eyre!(
+                        "UID {uid} is already taken by the `{}` user, which is not a build user",
+                        other.name
+                    ));
+                }
+            }
+        }
+
+        Ok(repair)
+    }
+
+    /// Work out the changes from the build users (by number) and group which currently exist
+    fn from_existing(
+        meta: UsersAndGroupsMeta,
+        existing_users: BTreeMap<u32, User>,
+        previous_group_id: u32,
+        nix_build_user_count: Option<u32>,
+        nix_build_user_id_base: Option<u32>,
+        nix_build_group_id: Option<u32>,
+        backend: UserBackend,
+    ) -> eyre::Result<Self> {
+        let previous_user_count = existing_users
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or_default()
+            .max(meta.user_count);
+        let previous_user_id_base = meta
+            .user_id_base
+            .or_else(|| {
+                existing_users
+                    .iter()
+                    .next()
+                    .and_then(|(idx, user)| user.uid.as_raw().checked_sub(*idx))
+            })
+            .unwrap_or_else(crate::settings::default_nix_build_user_id_base);
+
+        let user_count = nix_build_user_count.unwrap_or(previous_user_count);
+        let user_id_base = nix_build_user_id_base.unwrap_or(previous_user_id_base);
+        let group_id = nix_build_group_id.unwrap_or(previous_group_id);
+
+        if user_id_base.checked_add(user_count).is_none() {
+            return Err(eyre::eyre!(
+                "A UID base of {user_id_base} with {user_count} users does not fit in a UID"
+            ));
+        }
+
+        let mut moved_users = Vec::new();
+        let mut missing_users = Vec::new();
+        for idx in 1..=user_count {
+            let uid = user_id_base + idx;
+            match existing_users.get(&idx) {
+                Some(user) if user.uid.as_raw() == uid && user.gid.as_raw() == group_id => (),
+                Some(user) => moved_users.push((idx, user.clone())),
+                None => missing_users.push(idx),
+            }
+        }
+        let removed_users = existing_users
+            .range(user_count.saturating_add(1)..)
+            .map(|(idx, user)| (*idx, user.clone()))
+            .collect();
+
+        Ok(Self {
+            user_prefix: meta.user_prefix,
+            group_name: meta.group_name,
+            backend,
+            previous_user_count,
+            user_count,
+            previous_user_id_base,
+            user_id_base,
+            previous_group_id,
+            group_id,
+            moved_users,
+            removed_users,
+            missing_users,
+            receipt: meta
+                .receipt_action_idx_create_group
+                .map(|(receipt, action_idx, _)| (receipt, action_idx)),
+        })
+    }
+
+    fn is_noop(&self) -> bool {
+        self.group_id == self.previous_group_id
+            && self.moved_users.is_empty()
+            && self.removed_users.is_empty()
+            && self.missing_users.is_empty()
+    }
+
+    fn summary(&self) -> String {
+        let mut changes = Vec::new();
+        if self.user_count != self.previous_user_count || !self.missing_users.is_empty() {
+            changes.push(format!(
+                "ensure there are {} {} users ({} to create, {} to delete)",
+                self.user_count,
+                self.user_prefix,
+                self.missing_users.len(),
+                self.removed_users.len()
+            ));
+        }
+        if !self.moved_users.is_empty() {
+            changes.push(format!(
+                "move {} {} users from the {}+ UID range to UIDs {}-{}",
+                self.moved_users.len(),
+                self.user_prefix,
+                self.previous_user_id_base + 1,
+                self.user_id_base + 1,
+                self.user_id_base + self.user_count
+            ));
+        }
+        if self.group_id != self.previous_group_id {
+            changes.push(format!(
+                "move the `{}` group from GID {} to GID {}",
+                self.group_name, self.previous_group_id, self.group_id
+            ));
+        }
+        format!(
+            "Will {}, give files in `/nix` owned by the previous IDs to the new ones, and {} update \
+            the receipt",
+            changes.join(", "),
+            if self.receipt.is_some() {
+                "WILL"
+            } else {
+                "WILL NOT"
+            }
+        )
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&self) -> eyre::Result<()> {
+        let mut uid_changes = HashMap::new();
+        let mut gid_changes = HashMap::new();
+
+        if self.group_id != self.previous_group_id {
+            tracing::info!(
+                "Moving group `{}` to GID {}",
+                self.group_name,
+                self.group_id
+            );
+            set_group_id(&self.group_name, self.group_id, self.backend).await?;
+            gid_changes.insert(self.previous_group_id, self.group_id);
+        }
+
+        // Deleting first frees up any UIDs the moved users need
+        for (idx, user) in &self.removed_users {
+            tracing::info!("Deleting user `{}`", user.name);
+            let mut create_user = CreateUser::plan(
+                user.name.clone(),
+                user.uid.as_raw(),
+                self.group_name.clone(),
+                user.gid.as_raw(),
+                format!("Nix build user {idx}"),
+                true,
+                self.backend,
+            )
+            .await?;
+            create_user.try_revert().await?;
+            // Nothing should be owned by a UID which a later user could take over, so hand what
+            // the deleted user owned to a remaining build user (if there is one)
+            if let Some(uid) = self.removed_users_heir() {
+                uid_changes.insert(user.uid.as_raw(), uid);
+            }
+        }
+
+        // NOTE: Like `repair sequoia`, if a user would take over the UID of another build user which
+        // hasn't moved yet, first move everyone to a temporary range which is entirely unused.
+        let target_uid_taken = self.moved_users.iter().any(|(idx, _)| {
+            self.moved_users.iter().any(|(other_idx, other)| {
+                other_idx != idx && other.uid.as_raw() == self.user_id_base + idx
+            })
+        });
+        if target_uid_taken {
+            let temp_user_id_base = self.free_user_id_base()?;
+            for (idx, user) in &self.moved_users {
+                set_user_ids(
+                    &user.name,
+                    temp_user_id_base + idx,
+                    user.gid.as_raw(),
+                    self.backend,
+                )
+                .await?;
+            }
+        }
+
+        for (idx, user) in &self.moved_users {
+            let uid = self.user_id_base + idx;
+            tracing::info!("Moving user `{}` to UID {uid}", user.name);
+            set_user_ids(&user.name, uid, self.group_id, self.backend).await?;
+            if user.uid.as_raw() != uid {
+                uid_changes.insert(user.uid.as_raw(), uid);
+            }
+        }
+
+        for idx in &self.missing_users {
+            let name = format!("{}{idx}", self.user_prefix);
+            let uid = self.user_id_base + idx;
+            let mut create_user = CreateUser::plan(
+                name.clone(),
+                uid,
+                self.group_name.clone(),
+                self.group_id,
+                format!("Nix build user {idx}"),
+                true,
+                self.backend,
+            )
+            .await?;
+            create_user.try_execute().await?;
+            let mut add_user_to_group = AddUserToGroup::plan(
+                name,
+                uid,
+                self.group_name.clone(),
+                self.group_id,
+                self.backend,
+            )
+            .await?;
+            add_user_to_group.try_execute().await?;
+        }
+
+        if !uid_changes.is_empty() || !gid_changes.is_empty() {
+            tracing::info!("Updating the ownership of files in `/nix`, this may take a while");
+            let changed = tokio::task::spawn_blocking(move || {
+                chown_changed_ids(Path::new("/nix"), &uid_changes, &gid_changes)
+            })
+            .await??;
+            tracing::info!("Updated the ownership of {changed} files in `/nix`");
+        }
+
+        Ok(())
+    }
+
+    /// The UID which receives the files of deleted build users, the first remaining build user
+    fn removed_users_heir(&self) -> Option<u32> {
+        (self.user_count > 0).then(|| self.user_id_base + 1)
+    }
+
+    /// A UID base, above every ID involved, where all the moved users fit without taking any UID
+    fn free_user_id_base(&self) -> eyre::Result<u32> {
+        let highest_idx = self
+            .moved_users
+            .iter()
+            .map(|(idx, _)| *idx)
+            .max()
+            .unwrap_or_default();
+        let mut base = self
+            .moved_users
+            .iter()
+            .map(|(_, user)| user.uid.as_raw())
+            .chain([TEMP_USER_ID_BASE, self.user_id_base + self.user_count])
+            .max()
+            .unwrap_or(TEMP_USER_ID_BASE);
+        loop {
+            let end = base
+                .checked_add(highest_idx)
+                .ok_or_else(|| eyre::eyre!("Could not find a free range of UIDs"))?;
+            let mut taken = None;
+            for uid in base + 1..=end {
+                if User::from_uid(Uid::from_raw(uid))?.is_some() {
+                    taken = Some(uid);
+                    break;
+                }
+            }
+            match taken {
+                Some(uid) => base = uid,
+                None => return Ok(base),
+            }
+        }
+    }
+
+    /// The receipt, with its `CreateUsersAndGroups` replaced by one describing the repaired users
+    async fn updated_receipt(self) -> eyre::Result<Option<InstallPlan>> {
+        let Some((mut receipt, action_idx)) = self.receipt else {
+            return Ok(None);
+        };
+
+        // NOTE(cole-h): Once we write the updated receipt, these steps will have been completed,
+        // so manually setting them to completed with StatefulAction::completed is fine.
+        let create_group =
+            CreateGroup::plan(self.group_name.clone(), self.group_id, self.backend)?.action;
+        let mut create_users = Vec::with_capacity(self.user_count as usize);
+        let mut add_users_to_groups = Vec::with_capacity(self.user_count as usize);
+        for idx in 1..=self.user_count {
+            let name = format!("{}{idx}", self.user_prefix);
+            let uid = self.user_id_base + idx;
+            let create_user = CreateUser::plan(
+                name.clone(),
+                uid,
+                self.group_name.clone(),
+                self.group_id,
+                format!("Nix build user {idx}"),
+                true,
+                self.backend,
+            )
+            .await?;
+            create_users.push(StatefulAction::completed(create_user.action));
+            add_users_to_groups.push(StatefulAction::completed(AddUserToGroup {
+                name,
+                uid,
+                groupname: self.group_name.clone(),
+                gid: self.group_id,
+                backend: self.backend,
+            }));
+        }
+
+        let create_users_and_groups = StatefulAction::completed(CreateUsersAndGroups {
+            nix_build_group_name: self.group_name,
+            nix_build_group_id: self.group_id,
+            nix_build_user_count: self.user_count,
+            nix_build_user_prefix: self.user_prefix,
+            nix_build_user_id_base: self.user_id_base,
+            create_group: StatefulAction::completed(create_group),
+            create_users,
+            add_users_to_groups,
+        });
+        receipt.actions[action_idx] = create_users_and_groups.boxed();
+
+        Ok(Some(receipt))
+    }
+}
+
+/// Change the UID and primary GID of an existing user
+async fn set_user_ids(name: &str, uid: u32, gid: u32, backend: UserBackend) -> eyre::Result<()> {
+    match OperatingSystem::host() {
+        OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+            for (key, value) in [("UniqueID", uid), ("PrimaryGroupID", gid)] {
+                execute_command(
+                    Command::new("/usr/bin/dscl")
+                        .process_group(0)
+                        // NOTE(cole-h): even though it says "create" it's really "create-or-update"
+                        .args([".", "-create", &format!("/Users/{name}"), key])
+                        .arg(value.to_string())
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await?;
+            }
+        },
+        _ => {
+            if backend.resolve(&[&["usermod"]]) == UserBackend::Files {
+                UserDatabase::system().set_user_ids(name, uid, gid).await?;
+            } else {
+                execute_command(
+                    Command::new("usermod")
+                        .process_group(0)
+                        .args(["--uid", &uid.to_string(), "--gid", &gid.to_string(), name])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Change the GID of an existing group
+async fn set_group_id(name: &str, gid: u32, backend: UserBackend) -> eyre::Result<()> {
+    match OperatingSystem::host() {
+        OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+            execute_command(
+                Command::new("/usr/bin/dscl")
+                    .process_group(0)
+                    .args([".", "-create", &format!("/Groups/{name}"), "PrimaryGroupID"])
+                    .arg(gid.to_string())
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await?;
+        },
+        _ => {
+            if backend.resolve(&[&["groupmod"]]) == UserBackend::Files {
+                UserDatabase::system().set_group_id(name, gid).await?;
+            } else {
+                execute_command(
+                    Command::new("groupmod")
+                        .process_group(0)
+                        .args(["--gid", &gid.to_string(), name])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Give everything under `root` owned by a changed UID or GID to its new ID, returning how many
+/// paths changed
+///
+/// Symlinks are not followed, and other filesystems mounted below `root` are not entered.
+fn chown_changed_ids(
+    root: &Path,
+    uid_changes: &HashMap<u32, u32>,
+    gid_changes: &HashMap<u32, u32>,
+) -> eyre::Result<usize> {
+    let root_metadata = std::fs::symlink_metadata(root)
+        .with_context(|| format!("Getting metadata of `{}`", root.display()))?;
+    let mut changed = 0;
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Getting metadata of `{}`", path.display()))?;
+        if metadata.dev() != root_metadata.dev() {
+            tracing::debug!("Not entering `{}`, a different filesystem", path.display());
+            continue;
+        }
+
+        let uid = uid_changes.get(&metadata.uid()).copied();
+        let gid = gid_changes.get(&metadata.gid()).copied();
+        if uid.is_some() || gid.is_some() {
+            tracing::trace!(?uid, ?gid, "Changing the ownership of `{}`", path.display());
+            std::os::unix::fs::lchown(&path, uid, gid)
+                .with_context(|| format!("Changing the ownership of `{}`", path.display()))?;
+            changed += 1;
+        }
+
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path)
+                .with_context(|| format!("Reading directory `{}`", path.display()))?
+            {
+                let entry =
+                    entry.with_context(|| format!("Reading directory `{}`", path.display()))?;
+                pending.push(entry.path());
+            }
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::os::unix::fs::MetadataExt as _;
+
+    use nix::unistd::{Gid, Uid, User};
+
+    use super::{
+        chown_changed_ids, shell_profile_locations_in, BuildUsersRepair, UsersAndGroupsMeta,
+    };
+    use crate::planner::ShellProfileLocations;
+    use crate::settings::UserBackend;
+
+    fn meta(user_count: u32, user_id_base: Option<u32>) -> UsersAndGroupsMeta {
+        UsersAndGroupsMeta {
+            user_prefix: "nixbld".to_string(),
+            user_count,
+            user_id_base,
+            group_name: "nixbld".to_string(),
+            group_gid: None,
+            receipt_action_idx_create_group: None,
+        }
+    }
+
+    fn build_users(count: u32, user_id_base: u32, gid: u32) -> BTreeMap<u32, User> {
+        let root = User::from_uid(Uid::from_raw(0)).unwrap().unwrap();
+        (1..=count)
+            .map(|idx| {
+                let user = User {
+                    name: format!("nixbld{idx}"),
+                    uid: Uid::from_raw(user_id_base + idx),
+                    gid: Gid::from_raw(gid),
+                    ..root.clone()
+                };
+                (idx, user)
+            })
+            .collect()
+    }
+
+    fn indexes(users: &[(u32, User)]) -> Vec<u32> {
+        users.iter().map(|(idx, _)| *idx).collect()
+    }
+
+    #[test]
+    fn unchanged_users_are_a_noop() -> eyre::Result<()> {
+        let repair = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            build_users(4, 300, 30000),
+            30000,
+            None,
+            None,
+            None,
+            UserBackend::Auto,
+        )?;
+        assert!(repair.is_noop());
+        assert_eq!(repair.user_count, 4);
+        assert_eq!(repair.user_id_base, 300);
+
+        let repair = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            build_users(4, 300, 30000),
+            30000,
+            Some(4),
+            Some(300),
+            Some(30000),
+            UserBackend::Auto,
+        )?;
+        assert!(repair.is_noop());
+        Ok(())
+    }
+
+    #[test]
+    fn fewer_users_removes_the_extra_ones() -> eyre::Result<()> {
+        let repair = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            build_users(4, 300, 30000),
+            30000,
+            Some(2),
+            None,
+            None,
+            UserBackend::Auto,
+        )?;
+        assert!(!repair.is_noop());
+        assert_eq!(indexes(&repair.removed_users), vec![3, 4]);
+        assert!(repair.moved_users.is_empty());
+        assert!(repair.missing_users.is_empty());
+        assert_eq!(repair.removed_users_heir(), Some(301));
+
+        let repair = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            build_users(4, 300, 30000),
+            30000,
+            Some(0),
+            None,
+            None,
+            UserBackend::Auto,
+        )?;
+        assert_eq!(indexes(&repair.removed_users), vec![1, 2, 3, 4]);
+        assert_eq!(repair.removed_users_heir(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn new_base_moves_users_and_creates_missing_ones() -> eyre::Result<()> {
+        let mut existing = build_users(4, 300, 30000);
+        existing.remove(&2);
+        let repair = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            existing,
+            30000,
+            Some(6),
+            Some(350),
+            None,
+            UserBackend::Auto,
+        )?;
+        assert!(!repair.is_noop());
+        assert_eq!(repair.previous_user_count, 4);
+        assert_eq!(repair.previous_user_id_base, 300);
+        assert_eq!(indexes(&repair.moved_users), vec![1, 3, 4]);
+        assert_eq!(repair.missing_users, vec![2, 5, 6]);
+        assert!(repair.removed_users.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn new_group_id_moves_every_user() -> eyre::Result<()> {
+        let repair = BuildUsersRepair::from_existing(
+            meta(3, Some(300)),
+            build_users(3, 300, 30000),
+            30000,
+            None,
+            None,
+            Some(31000),
+            UserBackend::Auto,
+        )?;
+        assert!(!repair.is_noop());
+        assert_eq!(repair.previous_group_id, 30000);
+        assert_eq!(repair.group_id, 31000);
+        assert_eq!(indexes(&repair.moved_users), vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn previous_layout_is_inferred_without_a_receipt() -> eyre::Result<()> {
+        // Users beyond the default count, with no receipt recording the base
+        let repair = BuildUsersRepair::from_existing(
+            meta(2, None),
+            build_users(5, 400, 30000),
+            30000,
+            None,
+            None,
+            None,
+            UserBackend::Auto,
+        )?;
+        assert!(repair.is_noop());
+        assert_eq!(repair.previous_user_count, 5);
+        assert_eq!(repair.previous_user_id_base, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn overflowing_uid_range_is_an_error() {
+        let result = BuildUsersRepair::from_existing(
+            meta(4, Some(300)),
+            build_users(4, 300, 30000),
+            30000,
+            Some(10),
+            Some(u32::MAX - 5),
+            None,
+            UserBackend::Auto,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn chown_changed_ids_only_touches_changed_owners() -> eyre::Result<()> {
+        if !Uid::effective().is_root() {
+            // Giving files to other users requires `root`
+            return Ok(());
+        }
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join("dir"))?;
+        std::fs::write(root.join("dir/moved"), "")?;
+        std::fs::write(root.join("dir/regrouped"), "")?;
+        std::fs::write(root.join("untouched"), "")?;
+        std::os::unix::fs::symlink("../untouched", root.join("dir/link"))?;
+        std::os::unix::fs::lchown(root.join("dir/moved"), Some(12301), Some(23001))?;
+        std::os::unix::fs::lchown(root.join("dir/regrouped"), Some(0), Some(23001))?;
+        std::os::unix::fs::lchown(root.join("dir/link"), Some(12301), Some(0))?;
+        std::os::unix::fs::lchown(root.join("untouched"), Some(12399), Some(0))?;
+
+        let uid_changes = HashMap::from([(12301, 12351)]);
+        let gid_changes = HashMap::from([(23001, 23002)]);
+        let changed = chown_changed_ids(root, &uid_changes, &gid_changes)?;
+        assert_eq!(changed, 3);
+
+        let owner = |path: &str| -> eyre::Result<(u32, u32)> {
+            let metadata = std::fs::symlink_metadata(root.join(path))?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        assert_eq!(owner("dir/moved")?, (12351, 23002));
+        assert_eq!(owner("dir/regrouped")?, (0, 23002));
+        assert_eq!(owner("dir/link")?, (12351, 0));
+        // Not followed through the symlink
+        assert_eq!(owner("untouched")?, (12399, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn shell_profile_locations_come_from_the_receipt() -> Result<(), Box<dyn std::error::Error>> {
+        // Like the steam_deck and ostree planners, which can't write to `/usr/share/fish`
+        let mut planned = ShellProfileLocations::alpine();
+        planned.fish.vendor_confd_prefixes.clear();
+        let receipt = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [
+                { "action": { "action_name": "create_directory", "path": "/nix" }, "state": "Completed" },
+                {
+                    "action": {
+                        "action_name": "configure_nix",
+                        "configure_shell_profile": {
+                            "action": {
+                                "action_name": "configure_shell_profile",
+                                "locations": serde_json::to_value(&planned)?,
+                                "create_directories": [],
+                                "create_or_insert_into_files": [],
+                            },
+                            "state": "Completed",
+                        },
+                    },
+                    "state": "Completed",
+                },
+            ],
+        });
+        assert_eq!(shell_profile_locations_in(&receipt), Some(planned));
+        assert_eq!(
+            shell_profile_locations_in(&serde_json::json!({ "actions": [] })),
+            None
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,37 @@
-use std::process::ExitCode;
+use std::{process::ExitCode, time::Duration};
 
-use clap::Parser;
+use clap::{ArgAction, Parser};
 
-use crate::{cli::CommandExecute, NixInstallerError};
+use crate::{
+    cli::{output, CommandExecute},
+    self_test::{Check, CheckStatus, SelfTestOptions, Shell},
+    FailureClass, NixInstallerError,
+};
 
 /// Run a self test of Nix to ensure that an install is working
 #[derive(Debug, Parser)]
-pub struct SelfTest {}
+pub struct SelfTest {
+    /// Only run the given check (may be repeated)
+    #[clap(long = "check", value_enum, action(ArgAction::Append))]
+    pub checks: Vec<Check>,
+
+    /// Only run per-shell checks in the given shell (may be repeated), instead of every discovered shell
+    #[clap(long = "shell", value_enum, action(ArgAction::Append))]
+    pub shells: Vec<Shell>,
+
+    /// The number of seconds each check may take before it fails
+    #[clap(long, env = "NIX_INSTALLER_SELF_TEST_TIMEOUT", default_value = "120")]
+    pub timeout: u64,
+
+    /// Print the results of each check as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_SELF_TEST_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+}
 
 #[async_trait::async_trait]
 impl CommandExecute for SelfTest {
@@ -15,17 +40,48 @@ impl CommandExecute for SelfTest {
     where
         T: crate::feedback::Feedback,
     {
-        crate::self_test::self_test()
-            .await
-            .map_err(NixInstallerError::SelfTest)?;
-
-        tracing::info!(
-            shells = ?crate::self_test::Shell::discover()
-                .iter()
-                .map(|v| v.executable())
-                .collect::<Vec<_>>(),
-            "Successfully tested Nix install in all discovered shells."
-        );
+        let Self {
+            checks,
+            shells,
+            timeout,
+            json,
+        } = self;
+
+        let options = SelfTestOptions::new(checks, shells, Duration::from_secs(timeout));
+        let report = crate::self_test::run(&options).await;
+
+        if output::is_json() {
+            // A failure is returned below, so the outcome has its explanation
+            output::report(&report)?;
+        } else if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(if report.is_success() {
+                ExitCode::SUCCESS
+            } else {
+                FailureClass::SelfTest.into()
+            });
+        }
+
+        for result in &report.results {
+            let name = match result.shell {
+                Some(shell) => format!("{} ({shell})", result.check),
+                None => result.check.to_string(),
+            };
+            match result.status {
+                CheckStatus::Passed => {
+                    tracing::info!(duration_millis = result.duration_millis, "Passed `{name}`")
+                },
+                CheckStatus::Skipped => tracing::info!(
+                    "Skipped `{name}`: {}",
+                    result.message.as_deref().unwrap_or_default()
+                ),
+                CheckStatus::Failed => (),
+            }
+        }
+
+        report.into_result().map_err(NixInstallerError::SelfTest)?;
+
+        tracing::info!("Successfully tested Nix install.");
         Ok(ExitCode::SUCCESS)
     }
 }
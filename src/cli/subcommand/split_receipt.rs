@@ -2,7 +2,8 @@ use std::{path::PathBuf, process::ExitCode, time::SystemTime};
 
 use crate::{
     action::{Action, ActionState, StatefulAction},
-    cli::{ensure_root, interaction::PromptChoice},
+    cli::{ensure_root, interaction::PromptChoice, output},
+    phase::TagFilter,
     plan::RECEIPT_LOCATION,
     InstallPlan,
 };
@@ -24,6 +25,9 @@ pub(crate) const PHASE2_RECEIPT_LOCATION: &str = "/nix/uninstall-phase2.json";
 /// location), it will clean up everything but the Nix store and allow you to reinstall with a
 /// newer version. If you run `/nix/nix-installer uninstall /nix/uninstall-phase2.json`, then it
 /// will complete the uninstall by cleaning up the Nix store.
+///
+/// With `--only` or `--except`, phase 1 is instead the actions selected by their tag (including
+/// nested ones), and phase 2 is everything else.
 #[derive(Debug, Parser)]
 pub struct SplitReceipt {
     #[clap(
@@ -40,6 +44,8 @@ pub struct SplitReceipt {
     pub phase1_output: PathBuf,
     #[clap(long, default_value = PHASE2_RECEIPT_LOCATION)]
     pub phase2_output: PathBuf,
+    #[clap(flatten)]
+    pub filter: TagFilter,
     // NOTE(cole-h): an escape hatch in case we somehow run into a case where the "receipt is
     // valid and we can actually parse it into structs" step does the wrong thing; hidden so
     // that users aren't tempted to use it themselves, but we can suggest it as a break-glass
@@ -65,21 +71,36 @@ impl CommandExecute for SplitReceipt {
         let backed_up_receipt_location = original_receipt_location
             .with_file_name(format!(".original-receipt.{timestamp_millis}.json"));
 
-        let brief_summary = format!("\n\
-               This will split your existing receipt at {receipt} into two phases (phase 1: {phase1}, phase 2: {phase2}) \
-               for uninstallation purposes, and move the existing receipt to a backup location at {backup_location} afterwards.\n\
-               Phase 1 will clean up everything {except} for the root of the Nix store.\n\
-               Phase 2 will then clean up the root of the Nix store and all its contents.\n\
-               If you are wanting to install with a newer version of nix-installer, \
-               you do not need to run phase 2 of the uninstallation.\n\
-               If you want a clean uninstallation, you should run phase 2 after phase 1.\
-               ",
-           receipt = self.receipt.display().bold(),
-           phase1 = self.phase1_output.display().bold(),
-           phase2 = self.phase2_output.display().bold(),
-           backup_location = backed_up_receipt_location.display().bold(),
-           except = "except".italic(),
-        );
+        let brief_summary = if self.filter.is_empty() {
+            format!("\n\
+                This will split your existing receipt at {receipt} into two phases (phase 1: {phase1}, phase 2: {phase2}) \
+                for uninstallation purposes, and move the existing receipt to a backup location at {backup_location} afterwards.\n\
+                Phase 1 will clean up everything {except} for the root of the Nix store.\n\
+                Phase 2 will then clean up the root of the Nix store and all its contents.\n\
+                If you are wanting to install with a newer version of nix-installer, \
+                you do not need to run phase 2 of the uninstallation.\n\
+                If you want a clean uninstallation, you should run phase 2 after phase 1.\
+                ",
+                receipt = self.receipt.display().bold(),
+                phase1 = self.phase1_output.display().bold(),
+                phase2 = self.phase2_output.display().bold(),
+                backup_location = backed_up_receipt_location.display().bold(),
+                except = "except".italic(),
+            )
+        } else {
+            format!("\n\
+                This will split your existing receipt at {receipt} into two phases (phase 1: {phase1}, phase 2: {phase2}) \
+                for uninstallation purposes, and move the existing receipt to a backup location at {backup_location} afterwards.\n\
+                Phase 1 will clean up the actions selected by {filter}.\n\
+                Phase 2 will then clean up everything else.\
+                ",
+                receipt = self.receipt.display().bold(),
+                phase1 = self.phase1_output.display().bold(),
+                phase2 = self.phase2_output.display().bold(),
+                backup_location = backed_up_receipt_location.display().bold(),
+                filter = describe_filter(&self.filter).bold(),
+            )
+        };
 
         if !self.no_confirm {
             loop {
@@ -88,10 +109,7 @@ impl CommandExecute for SplitReceipt {
                 {
                     PromptChoice::Yes => break,
                     PromptChoice::No => {
-                        crate::cli::interaction::clean_exit_with_message(
-                            "Okay, didn't do anything! Bye!",
-                        )
-                        .await
+                        return Ok(output::cancelled("Okay, didn't do anything! Bye!"))
                     },
                     PromptChoice::Explain => (),
                 }
@@ -104,7 +122,9 @@ impl CommandExecute for SplitReceipt {
             .await
             .wrap_err("Reading receipt")?;
 
-        if self.force_naive_json_method {
+        if !self.filter.is_empty() {
+            split_by_tags(&self, &install_receipt_string).await?;
+        } else if self.force_naive_json_method {
             two_phased_cannot_parse_receipt_perfectly(&self, &install_receipt_string).await?;
         } else {
             let maybe_compatible_plan =
@@ -134,22 +154,40 @@ impl CommandExecute for SplitReceipt {
             backed_up_receipt_location.display()
         );
 
-        println!(
-            "\
-            {success}\n\
-            ",
-            success = format!(
-                "Phase 1 and 2 uninstall receipts successfully written:\n\
-                Phase 1: {phase1}\n\
-                Phase 2: {phase2}\n\
-                You can now uninstall starting with:\n\
-                /nix/nix-installer uninstall {phase1}",
-                phase1 = self.phase1_output.display(),
-                phase2 = self.phase2_output.display()
-            )
-            .green()
-            .bold(),
-        );
+        // The mirror is a copy of the original receipt, so `uninstall` shouldn't fall back to it
+        let receipt_mirror = serde_json::from_str::<serde_json::Value>(&install_receipt_string)
+            .ok()
+            .and_then(|receipt| receipt["receipt_mirror"].as_str().map(PathBuf::from));
+        if let Some(receipt_mirror) = receipt_mirror {
+            match tokio::fs::remove_file(&receipt_mirror).await {
+                Ok(()) => {
+                    tracing::info!("Removed the receipt mirror at {}", receipt_mirror.display())
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => Err(e).wrap_err_with(|| {
+                    format!(
+                        "Removing the receipt mirror at {}",
+                        receipt_mirror.display()
+                    )
+                })?,
+            }
+        }
+
+        output::receipt(Some(&self.phase1_output));
+        output::report(&serde_json::json!({
+            "phase1": self.phase1_output,
+            "phase2": self.phase2_output,
+            "original_receipt": backed_up_receipt_location,
+        }))?;
+        output::success(format!(
+            "Phase 1 and 2 uninstall receipts successfully written:\n\
+            Phase 1: {phase1}\n\
+            Phase 2: {phase2}\n\
+            You can now uninstall starting with:\n\
+            /nix/nix-installer uninstall {phase1}",
+            phase1 = self.phase1_output.display(),
+            phase2 = self.phase2_output.display()
+        ));
 
         Ok(ExitCode::SUCCESS)
     }
@@ -168,6 +206,7 @@ async fn two_phased_can_parse_receipt_perfectly(
         version: phase1_plan.version.clone(),
         actions: Vec::new(),
         planner: phase1_plan.planner.clone(),
+        receipt_mirror: phase1_plan.receipt_mirror.clone(),
     };
 
     for action in phase1_plan.actions.iter_mut() {
@@ -462,6 +501,40 @@ async fn two_phased_cannot_parse_receipt_perfectly(
     Ok(())
 }
 
+/// Split the receipt by the tags of its actions, phase 1 being the selected actions
+async fn split_by_tags(uninstall_args: &SplitReceipt, receipt_str: &str) -> eyre::Result<()> {
+    tracing::debug!("Using the tag filters to split the receipt");
+
+    let receipt: serde_json::Value =
+        serde_json::from_str(receipt_str).context("Receipt was not opaquely parseable")?;
+    let selection = uninstall_args.filter.select(&receipt);
+    if selection.is_empty() {
+        return Err(eyre::eyre!(
+            "No actions in the receipt match {}",
+            describe_filter(&uninstall_args.filter)
+        ));
+    }
+
+    let phase1_plan = selection.selected_receipt(&receipt);
+    let phase2_plan = selection.remaining_receipt(&receipt);
+
+    crate::plan::write_receipt(&phase1_plan, &uninstall_args.phase1_output).await?;
+    crate::plan::write_receipt(&phase2_plan, &uninstall_args.phase2_output).await?;
+
+    Ok(())
+}
+
+fn describe_filter(filter: &TagFilter) -> String {
+    let mut parts = vec![];
+    if !filter.only.is_empty() {
+        parts.push(format!("`--only {}`", filter.only.join(",")));
+    }
+    if !filter.except.is_empty() {
+        parts.push(format!("`--except {}`", filter.except.join(",")));
+    }
+    parts.join(" and ")
+}
+
 fn roundtrip_to_extract_type<T: serde::de::DeserializeOwned>(
     action: &StatefulAction<Box<dyn Action>>,
 ) -> eyre::Result<StatefulAction<T>> {
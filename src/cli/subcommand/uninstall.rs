@@ -5,10 +5,15 @@ use std::{
 };
 
 use crate::{
-    cli::{ensure_root, interaction::PromptChoice, signal_channel},
+    backup::{collect_backups, reverted_originals},
+    cli::{ensure_root, interaction::PromptChoice, output, signal_channel},
     error::HasExpectedErrors,
-    plan::{current_version, RECEIPT_LOCATION},
-    InstallPlan, NixInstallerError,
+    feedback::ActionPhase,
+    inventory::{format_size, Inventory},
+    os::linux::processes::{processes_using_nix, terminate_processes},
+    phase::TagFilter,
+    plan::{current_version, RECEIPT_LOCATION, RECEIPT_MIRROR_LOCATION},
+    FailureClass, InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
 use color_eyre::eyre::{eyre, WrapErr};
@@ -38,6 +43,58 @@ pub struct Uninstall {
     )]
     pub explain: bool,
 
+    /// Restore the originals of modified files (such as `/etc/bashrc`) byte-for-byte from the backups taken during install, instead of removing the inserted text
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RESTORE_BACKUPS",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub restore_backups: bool,
+
+    #[clap(flatten)]
+    pub filter: TagFilter,
+
+    /// Describe the uninstall without making any changes
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_DRY_RUN",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub dry_run: bool,
+
+    /// With `--dry-run`, list the paths (with their sizes), users, groups, services and inserted lines which would be removed
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_INVENTORY",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        requires = "dry_run"
+    )]
+    pub inventory: bool,
+
+    /// Before reverting, export the closure of the default profile's packages to this file (in the format of `nix-store --export`), so `install --import-closure` can restore them
+    #[clap(long, env = "NIX_INSTALLER_EXPORT_CLOSURE")]
+    pub export_closure: Option<PathBuf>,
+
+    /// A GC root (or store path) to include in the exported closure, may be repeated
+    #[clap(long, requires = "export_closure")]
+    pub export_closure_root: Vec<PathBuf>,
+
+    /// Terminate processes holding files under `/nix` (other than the Nix daemon) instead of refusing to uninstall, on Linux only
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_KILL_PROCESSES",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub kill_processes: bool,
+
+    /// The copy of the receipt kept outside of `/nix`, read if the receipt is missing
+    #[clap(long, env = "NIX_INSTALLER_RECEIPT_MIRROR", default_value = RECEIPT_MIRROR_LOCATION)]
+    pub receipt_mirror: PathBuf,
+
     #[clap(default_value = RECEIPT_LOCATION)]
     pub receipt: PathBuf,
 }
@@ -52,7 +109,15 @@ impl CommandExecute for Uninstall {
         let Self {
             no_confirm,
             receipt,
+            receipt_mirror,
             explain,
+            restore_backups,
+            filter,
+            dry_run,
+            inventory,
+            export_closure,
+            export_closure_root,
+            kill_processes,
         } = self;
 
         ensure_root()?;
@@ -74,7 +139,7 @@ impl CommandExecute for Uninstall {
         // well, we have a problem, since the binary would delete itself.
         // Instead, detect if we're in that location, if so, move the binary and `execv` it.
         if let Ok(current_exe) = std::env::current_exe() {
-            if current_exe.as_path() == Path::new("/nix/nix-installer") {
+            if current_exe.as_path() == Path::new("/nix/nix-installer") && !dry_run {
                 tracing::debug!(
                     "Detected uninstall from `/nix/nix-installer`, moving executable and re-executing"
                 );
@@ -111,9 +176,25 @@ impl CommandExecute for Uninstall {
             }
         }
 
-        let install_receipt_string = tokio::fs::read_to_string(receipt)
+        let Some(read_receipt) = crate::plan::read_receipt(&receipt, &receipt_mirror)
             .await
-            .wrap_err("Reading receipt")?;
+            .wrap_err("Reading receipt")?
+        else {
+            return Ok(output::classified_failure(
+                FailureClass::Precondition,
+                format!(
+                    "No receipt was found at `{}`, or its mirror at `{}`.",
+                    receipt.display(),
+                    receipt_mirror.display()
+                ),
+            ));
+        };
+        // Phase receipts (like those of `split-receipt`) are not mirrored
+        let is_install_receipt =
+            receipt == Path::new(RECEIPT_LOCATION) || read_receipt.path == receipt_mirror;
+        let receipt = read_receipt.path;
+        let install_receipt_string = read_receipt.contents;
+        output::receipt(Some(&receipt));
 
         let mut plan: InstallPlan = match serde_json::from_str(&install_receipt_string) {
             Ok(plan) => plan,
@@ -143,26 +224,96 @@ impl CommandExecute for Uninstall {
 
         if let Err(e) = plan.check_compatible() {
             let version = plan.version;
-            eprintln!(
-                "{}",
-                format!("\
-                    {e}\n\
-                    \n\
-                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
-                    \n
-                    To uninstall, either run `/nix/nix-installer uninstall` or `curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v${version} | sh -s -- uninstall`\n\
-                    \n\
-                ").red()
-            );
-            return Ok(ExitCode::FAILURE);
+            let receipt = receipt.display();
+            return Ok(output::classified_failure(FailureClass::Precondition, format!("\
+                {e}\n\
+                \n\
+                Found existing plan in `{receipt}` which was created by a version incompatible `nix-installer`.\n\
+                \n
+                To uninstall, either run `/nix/nix-installer uninstall` or `curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v${version} | sh -s -- uninstall`\n\
+                \n\
+            ")));
         }
 
-        if let Err(err) = plan.pre_uninstall_check().await {
-            if let Some(expected) = err.expected() {
-                eprintln!("{}", expected.red());
-                return Ok(ExitCode::FAILURE);
+        // With a filter, only the selected actions are reverted, the rest are kept in the receipt
+        let partial = if filter.is_empty() {
+            None
+        } else {
+            let receipt_value = serde_json::to_value(&plan).wrap_err("Serializing receipt")?;
+            let selection = filter.select(&receipt_value);
+            if selection.is_empty() {
+                return Ok(output::classified_failure(
+                    FailureClass::Precondition,
+                    format!(
+                        "No actions in `{}` match the `--only`/`--except` filters, nothing to uninstall.",
+                        receipt.display()
+                    ),
+                ));
+            }
+            plan = serde_json::from_value(selection.selected_receipt(&receipt_value))
+                .wrap_err("Deserializing the selected actions")?;
+            Some((receipt_value, selection))
+        };
+
+        if dry_run {
+            if inventory {
+                let receipt_value = serde_json::to_value(&plan).wrap_err("Serializing receipt")?;
+                let inventory = Inventory::collect(&receipt_value).await;
+                if output::is_json() {
+                    output::report(&inventory)?;
+                } else {
+                    print_inventory(&inventory);
+                }
+            } else {
+                let description = plan
+                    .describe_uninstall(explain)
+                    .await
+                    .map_err(|e| eyre!(e))?;
+                if output::is_json() {
+                    output::report(&description)?;
+                } else {
+                    println!("{description}");
+                }
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        // With `--kill-processes`, the processes using `/nix` are only terminated once confirmed
+        if !kill_processes {
+            if let Err(err) = plan.pre_uninstall_check().await {
+                if let Some(expected) = err.expected() {
+                    return Ok(output::expected_failure(&expected, &err));
+                }
+                Err(err)?
+            }
+        }
+
+        // The backups live under `/nix`, so read them all before anything is reverted
+        let mut backups = vec![];
+        if restore_backups {
+            let receipt_value = serde_json::to_value(&plan).wrap_err("Serializing receipt")?;
+            let mut unreadable = vec![];
+            for backup in collect_backups(&receipt_value) {
+                let backup_path = backup.backup.clone();
+                let original = backup.original.clone();
+                match backup.load().await {
+                    Ok(loaded) => backups.push(loaded),
+                    Err(err) => unreadable.push(format!(
+                        "* `{}` (backup `{}`): {err}",
+                        original.display(),
+                        backup_path.display()
+                    )),
+                }
+            }
+            if !unreadable.is_empty() {
+                return Ok(output::classified_failure(
+                    FailureClass::Precondition,
+                    format!(
+                        "These backups could not be read, nothing was uninstalled:\n{}",
+                        unreadable.join("\n")
+                    ),
+                ));
             }
-            Err(err)?
         }
 
         if !no_confirm {
@@ -180,18 +331,134 @@ impl CommandExecute for Uninstall {
                     PromptChoice::Yes => break,
                     PromptChoice::Explain => currently_explaining = true,
                     PromptChoice::No => {
-                        interaction::clean_exit_with_message(
+                        return Ok(output::cancelled(
                             "Okay, not continuing with the uninstallation. Bye!",
-                        )
-                        .await
+                        ))
                     },
                 }
             }
         }
 
+        if kill_processes {
+            let processes = processes_using_nix().await;
+            if !processes.is_empty() {
+                let summary = format!(
+                    "These processes hold files under `/nix`, and will be terminated:\n{}\n",
+                    processes
+                        .iter()
+                        .map(|process| format!("* {process}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+                if no_confirm {
+                    tracing::info!("{summary}");
+                } else {
+                    loop {
+                        match interaction::prompt(&summary, PromptChoice::Yes, true).await? {
+                            PromptChoice::Yes => break,
+                            PromptChoice::Explain => (),
+                            PromptChoice::No => {
+                                return Ok(output::cancelled(
+                                    "Okay, not continuing with the uninstallation. Bye!",
+                                ))
+                            },
+                        }
+                    }
+                }
+                terminate_processes(&processes)
+                    .await
+                    .wrap_err("Terminating processes holding files under `/nix`")?;
+            }
+            if let Err(err) = plan.pre_uninstall_check().await {
+                if let Some(expected) = err.expected() {
+                    return Ok(output::expected_failure(&expected, &err));
+                }
+                Err(err)?
+            }
+        }
+
+        if let Some(export_closure) = &export_closure {
+            if export_closure.starts_with("/nix") {
+                return Ok(output::classified_failure(
+                    FailureClass::Precondition,
+                    format!(
+                        "The closure can't be exported to `{}`, which is removed by the uninstall.",
+                        export_closure.display()
+                    ),
+                ));
+            }
+            let exported =
+                crate::profile::closure::export_closure(&export_closure_root, export_closure)
+                    .await
+                    .wrap_err_with(|| {
+                        format!(
+                            "Exporting the closure to `{}`, nothing was uninstalled",
+                            export_closure.display()
+                        )
+                    })?;
+            tracing::info!(
+                "Exported {exported} store paths to `{}`",
+                export_closure.display()
+            );
+        }
+
         let (_tx, rx) = signal_channel().await?;
 
         let res = plan.uninstall(feedback, rx).await;
+        output::changed_in(&plan, ActionPhase::Uninstall);
+
+        let reverted = if backups.is_empty() {
+            Default::default()
+        } else {
+            reverted_originals(&serde_json::to_value(&plan).wrap_err("Serializing receipt")?)
+        };
+        let mut unrestored = vec![];
+        for backup in &backups {
+            let original = &backup.backup.original;
+            if !reverted.contains(original) {
+                tracing::warn!(
+                    "Not restoring `{}`, the action which changed it was not reverted",
+                    original.display()
+                );
+                continue;
+            }
+            match backup.restore().await {
+                Ok(()) => tracing::info!("Restored `{}` from backup", original.display()),
+                Err(err) => {
+                    tracing::error!("Could not restore `{}`: {err}", original.display());
+                    unrestored.push(format!("* `{}`: {err}", original.display()));
+                },
+            }
+        }
+
+        if let Some((mut receipt_value, selection)) = partial {
+            let reverted = serde_json::to_value(&plan).wrap_err("Serializing reverted actions")?;
+            selection.merge(&mut receipt_value, &reverted);
+            crate::plan::write_receipt(&receipt_value, &receipt).await?;
+            tracing::info!(
+                "Rewrote `{}` with the states of the reverted actions",
+                receipt.display()
+            );
+            if is_install_receipt {
+                if let Some(mirror) = plan.receipt_mirror.as_ref().filter(|m| **m != receipt) {
+                    if let Err(err) = crate::plan::write_receipt(&receipt_value, mirror).await {
+                        tracing::warn!(
+                            "Could not write the receipt mirror at `{}`: {err}",
+                            mirror.display()
+                        );
+                    }
+                }
+            }
+        } else if is_install_receipt {
+            if res.is_ok() {
+                plan.remove_receipt_mirror().await?;
+                output::receipt(None);
+            } else {
+                // `/nix` may already be gone, keep what remains to be reverted outside of it
+                plan.write_receipt_mirror().await;
+            }
+        }
+
         match res {
             Err(err @ NixInstallerError::ActionRevert(_)) => {
                 tracing::error!("Uninstallation complete, some errors encountered");
@@ -199,21 +466,116 @@ impl CommandExecute for Uninstall {
             },
             Err(err) => {
                 if let Some(expected) = err.expected() {
-                    println!("{}", expected.red());
-                    return Ok(ExitCode::FAILURE);
+                    return Ok(output::expected_failure(&expected, &err));
                 }
                 return Err(err)?;
             },
             _ => (),
         }
 
+        if !unrestored.is_empty() {
+            return Ok(output::classified_failure(
+                FailureClass::Revert,
+                format!(
+                    "Uninstallation complete, but these files were not restored from their backups:\n{}",
+                    unrestored.join("\n")
+                ),
+            ));
+        }
+
+        let success = if filter.is_empty() {
+            "Nix was uninstalled successfully!".to_string()
+        } else {
+            format!(
+                "The selected actions were reverted successfully, the rest remain in `{}`.",
+                receipt.display()
+            )
+        };
+        output::success(success);
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn print_inventory(inventory: &Inventory) {
+    let Inventory {
+        paths,
+        nix_store_size,
+        total_size,
+        users,
+        groups,
+        group_memberships,
+        services,
+        volumes,
+        inserted_lines,
+        nix_config_settings,
+    } = inventory;
+
+    println!(
+        "{}",
+        format!("Paths to remove ({}):", format_size(*total_size)).bold()
+    );
+    for path in paths {
         println!(
-            "\
-            {success}\n\
-            ",
-            success = "Nix was uninstalled successfully!".green().bold(),
+            "  {} ({}, {})",
+            path.path.display(),
+            path.kind,
+            format_size(path.size)
         );
+    }
+    if let Some(nix_store_size) = nix_store_size {
+        println!("  The Nix store alone is {}", format_size(*nix_store_size));
+    }
 
-        Ok(ExitCode::SUCCESS)
+    if !users.is_empty() {
+        println!("{}", "Users to delete:".bold());
+        for user in users {
+            match user.uid {
+                Some(uid) => println!("  {} (UID {uid})", user.name),
+                None => println!("  {}", user.name),
+            }
+        }
+    }
+    if !groups.is_empty() {
+        println!("{}", "Groups to delete:".bold());
+        for group in groups {
+            match group.gid {
+                Some(gid) => println!("  {} (GID {gid})", group.name),
+                None => println!("  {}", group.name),
+            }
+        }
+    }
+    if !group_memberships.is_empty() {
+        println!("{}", "Group memberships to remove:".bold());
+        for membership in group_memberships {
+            println!("  {} from {}", membership.user, membership.group);
+        }
+    }
+    if !services.is_empty() {
+        println!("{}", "Services to stop and remove:".bold());
+        for service in services {
+            println!("  {service}");
+        }
+    }
+    if !volumes.is_empty() {
+        println!("{}", "Volumes to delete:".bold());
+        for volume in volumes {
+            println!("  {volume}");
+        }
+    }
+    for (heading, files) in [
+        ("Lines to remove:", inserted_lines),
+        ("Nix settings to remove:", nix_config_settings),
+    ] {
+        if files.is_empty() {
+            continue;
+        }
+        println!("{}", heading.bold());
+        for file in files {
+            println!("  {}:", file.path.display());
+            for line in &file.lines {
+                println!("    {}", line.red());
+            }
+        }
     }
 }
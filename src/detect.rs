@@ -0,0 +1,343 @@
+/*! Probes of the host, and the [`BuiltinPlanner`] they select
+
+The probes are the same ones used to pick the default planner and its defaults (see
+[`BuiltinPlanner::detect`]), gathered into a [`DetectReport`] so a surprising choice can be
+explained.
+*/
+
+use std::{collections::HashMap, os::unix::fs::MetadataExt, path::Path};
+
+use target_lexicon::OperatingSystem;
+
+use crate::{
+    plan::RECEIPT_LOCATION,
+    planner::{
+        linux::{check_nix_not_already_installed, check_not_wsl1, detect_apparmor, detect_selinux},
+        ostree::detect_ostree,
+        steam_deck::{detect_requires_bind_mount, detect_steamos},
+        BuiltinPlanner,
+    },
+    settings::{
+        linux_detect_openrc, linux_detect_systemd_started, linux_detect_wsl2, InitSettings,
+        InitSystem,
+    },
+};
+
+/// Everything [`run`] found out about the host
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DetectReport {
+    pub host: HostReport,
+    /// `None` off Linux
+    pub linux: Option<LinuxReport>,
+    pub nix: NixReport,
+    pub planner: PlannerReport,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HostReport {
+    /// The target triple the installer was built for
+    pub triple: String,
+    pub architecture: String,
+    pub operating_system: String,
+    pub container: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LinuxReport {
+    /// The fields of `/etc/os-release`, `None` if it couldn't be read
+    pub os_release: Option<OsReleaseReport>,
+    /// The init system which would be configured by default, and if the daemon would be started
+    pub init: Option<InitSystem>,
+    pub start_daemon: Option<bool>,
+    pub systemd_started: bool,
+    pub openrc_installed: bool,
+    pub openrc_started: bool,
+    pub selinux: ProbeResult,
+    pub apparmor: ProbeResult,
+    pub wsl1: bool,
+    pub wsl2: bool,
+    pub ostree: bool,
+    pub steamos: bool,
+    /// Only probed on SteamOS
+    pub steamos_requires_bind_mount: Option<bool>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OsReleaseReport {
+    pub id: String,
+    pub id_like: String,
+    pub name: String,
+    pub pretty_name: String,
+    pub version_id: String,
+    pub version_codename: String,
+}
+
+/// The outcome of a probe which can find a feature but not the tools needed to support it
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeResult {
+    Absent,
+    Present,
+    /// Present, but unusable for the given reason
+    Unusable(String),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NixReport {
+    pub nix_exists: bool,
+    pub receipt_exists: bool,
+    /// If `nix-env` can be run
+    pub nix_env_runs: bool,
+    /// If running on NixOS
+    pub nixos: bool,
+    /// `None` if `/nix` doesn't exist
+    pub mount: Option<NixMountReport>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NixMountReport {
+    /// If `/nix` is on a different filesystem than `/`
+    pub mount_point: bool,
+    /// From `/proc/self/mountinfo`, on Linux
+    pub filesystem: Option<String>,
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PlannerReport {
+    /// The `typetag` name of the planner which would be selected, `None` if selection failed
+    pub name: Option<String>,
+    pub reason: String,
+    /// The settings the planner would use
+    pub settings: HashMap<String, serde_json::Value>,
+}
+
+/// Run every probe, then select the planner
+pub async fn run() -> DetectReport {
+    let host = HostReport {
+        triple: target_lexicon::HOST.to_string(),
+        architecture: target_lexicon::Architecture::host().to_string(),
+        operating_system: OperatingSystem::host().to_string(),
+        container: detect_container().await,
+    };
+
+    let linux = match OperatingSystem::host() {
+        OperatingSystem::Linux => Some(detect_linux().await),
+        _ => None,
+    };
+
+    let nix = NixReport {
+        nix_exists: Path::new("/nix").exists(),
+        receipt_exists: Path::new(RECEIPT_LOCATION).exists(),
+        nix_env_runs: check_nix_not_already_installed().await.is_err(),
+        nixos: Path::new("/etc/NIXOS").exists(),
+        mount: detect_nix_mount().await,
+    };
+
+    let planner = match BuiltinPlanner::detect().await {
+        Ok((planner, reason)) => {
+            let (settings, reason) = match planner.settings() {
+                Ok(settings) => (settings, reason),
+                Err(err) => (
+                    HashMap::new(),
+                    format!("{reason} (reading its settings failed: {err})"),
+                ),
+            };
+            PlannerReport {
+                name: Some(planner.typetag_name().to_string()),
+                reason,
+                settings,
+            }
+        },
+        Err(err) => PlannerReport {
+            name: None,
+            reason: format!("Selecting a planner failed: {err}"),
+            settings: HashMap::new(),
+        },
+    };
+
+    DetectReport {
+        host,
+        linux,
+        nix,
+        planner,
+    }
+}
+
+async fn detect_linux() -> LinuxReport {
+    let os_release = os_release::OsRelease::new()
+        .ok()
+        .map(|os_release| OsReleaseReport {
+            id: os_release.id,
+            id_like: os_release.id_like,
+            name: os_release.name,
+            pretty_name: os_release.pretty_name,
+            version_id: os_release.version_id,
+            version_codename: os_release.version_codename,
+        });
+    let (openrc_installed, openrc_started) = linux_detect_openrc();
+    let steamos = detect_steamos();
+    let steamos_requires_bind_mount = if steamos {
+        detect_requires_bind_mount().await.ok()
+    } else {
+        None
+    };
+
+    let init = InitSettings::default().await.ok();
+
+    LinuxReport {
+        os_release,
+        init: init.as_ref().map(|init| init.init),
+        start_daemon: init.as_ref().map(|init| init.start_daemon),
+        systemd_started: linux_detect_systemd_started().await,
+        openrc_installed,
+        openrc_started,
+        selinux: match detect_selinux().await {
+            Ok(true) => ProbeResult::Present,
+            Ok(false) => ProbeResult::Absent,
+            Err(err) => ProbeResult::Unusable(err.to_string()),
+        },
+        apparmor: match detect_apparmor().await {
+            Ok(true) => ProbeResult::Present,
+            Ok(false) => ProbeResult::Absent,
+            Err(err) => ProbeResult::Unusable(err.to_string()),
+        },
+        wsl1: check_not_wsl1().is_err(),
+        wsl2: linux_detect_wsl2().await,
+        ostree: detect_ostree(),
+        steamos,
+        steamos_requires_bind_mount,
+    }
+}
+
+/// The kind of container the installer runs in, if it could tell
+async fn detect_container() -> Option<String> {
+    let cgroup = tokio::fs::read_to_string("/proc/1/cgroup")
+        .await
+        .unwrap_or_default();
+    container_kind(
+        std::env::var("container").ok().as_deref(),
+        Path::new("/"),
+        &cgroup,
+    )
+}
+
+/// The kind of container, from the `container` environment variable, the marker files under `root`
+/// and the `cgroup` of PID 1
+fn container_kind(container_env: Option<&str>, root: &Path, cgroup: &str) -> Option<String> {
+    if let Some(container) = container_env.filter(|container| !container.is_empty()) {
+        return Some(container.to_string());
+    }
+    if root.join(".dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+    if root.join("run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+    ["docker", "kubepods", "lxc", "containerd"]
+        .into_iter()
+        .find(|runtime| cgroup.contains(runtime))
+        .map(String::from)
+}
+
+async fn detect_nix_mount() -> Option<NixMountReport> {
+    let nix = tokio::fs::metadata("/nix").await.ok()?;
+    let root = tokio::fs::metadata("/").await.ok()?;
+
+    let mountinfo = tokio::fs::read_to_string("/proc/self/mountinfo")
+        .await
+        .unwrap_or_default();
+    let (filesystem, source) = nix_mount_entry(&mountinfo).unwrap_or_default();
+
+    Some(NixMountReport {
+        mount_point: nix.dev() != root.dev(),
+        filesystem,
+        source,
+    })
+}
+
+/// The filesystem type and source of the last mount on `/nix` in `mountinfo`
+fn nix_mount_entry(mountinfo: &str) -> Option<(Option<String>, Option<String>)> {
+    // `ID PARENT MAJOR:MINOR ROOT MOUNT_POINT OPTIONS [OPTIONAL...] - FSTYPE SOURCE SUPER_OPTIONS`
+    mountinfo
+        .lines()
+        .rev()
+        .find(|line| line.split_whitespace().nth(4) == Some("/nix"))
+        .and_then(|line| line.split_once(" - "))
+        .map(|(_, rest)| {
+            let mut fields = rest.split_whitespace();
+            (
+                fields.next().map(String::from),
+                fields.next().map(String::from),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{container_kind, nix_mount_entry};
+
+    const MOUNTINFO: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+45 22 259:3 / /home rw,relatime shared:30 - btrfs /dev/nvme0n1p3 rw,subvol=/home
+46 22 259:3 /nix /nix rw,relatime shared:31 - btrfs /dev/nvme0n1p3 rw,subvol=/nix
+47 22 0:40 / /nixos rw,relatime shared:32 - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn finds_the_nix_mount() {
+        assert_eq!(
+            nix_mount_entry(MOUNTINFO),
+            Some((Some("btrfs".into()), Some("/dev/nvme0n1p3".into())))
+        );
+        // A bind mount (as on the Steam Deck) over an earlier mount wins
+        let bind_mounted =
+            format!("{MOUNTINFO}60 46 259:4 /nix /nix rw,relatime - ext4 /dev/nvme0n1p8 rw\n");
+        assert_eq!(
+            nix_mount_entry(&bind_mounted),
+            Some((Some("ext4".into()), Some("/dev/nvme0n1p8".into())))
+        );
+        // `/nix` on the root filesystem has no entry of its own
+        assert_eq!(
+            nix_mount_entry(MOUNTINFO.lines().next().unwrap_or_default()),
+            None
+        );
+        assert_eq!(nix_mount_entry(""), None);
+    }
+
+    #[test]
+    fn detects_containers() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        let host_cgroup = "0::/init.scope\n";
+
+        assert_eq!(container_kind(None, root, host_cgroup), None);
+        assert_eq!(
+            container_kind(Some("systemd-nspawn"), root, host_cgroup).as_deref(),
+            Some("systemd-nspawn")
+        );
+        assert_eq!(container_kind(Some(""), root, host_cgroup), None);
+        assert_eq!(
+            container_kind(None, root, "12:pids:/kubepods/besteffort/pod1234\n").as_deref(),
+            Some("kubepods")
+        );
+        assert_eq!(
+            container_kind(None, root, "0::/lxc.payload.alpine\n").as_deref(),
+            Some("lxc")
+        );
+
+        std::fs::create_dir(root.join("run"))?;
+        std::fs::write(root.join("run/.containerenv"), "")?;
+        assert_eq!(
+            container_kind(None, root, host_cgroup).as_deref(),
+            Some("podman")
+        );
+        std::fs::write(root.join(".dockerenv"), "")?;
+        assert_eq!(
+            container_kind(None, root, host_cgroup).as_deref(),
+            Some("docker")
+        );
+        Ok(())
+    }
+}
@@ -11,8 +11,8 @@ use detsys_ids_client::{Builder, Map, Recorder, Worker};
 use reqwest::Url;
 
 use crate::{
-    action::ActionError, planner::PlannerError, settings::InstallSettingsError, CertificateError,
-    NixInstallerError,
+    action::ActionError, error::HasErrorCode, planner::PlannerError,
+    settings::InstallSettingsError, CertificateError, NixInstallerError,
 };
 
 /// The static of an action attempt
@@ -40,6 +40,12 @@ pub struct Report {
     status: Status,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
     failure_chain: Option<Vec<String>>,
+    /// The stable codes of the error and of the errors it wraps, see `docs/error-codes.md`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failure_codes: Option<Vec<String>>,
+    /// The self-test checks which failed, as `check` or `check(shell)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failed_checks: Option<Vec<String>>,
 }
 
 impl Report {
@@ -48,41 +54,36 @@ impl Report {
             action,
             status,
             failure_chain: None,
+            failure_codes: None,
+            failed_checks: None,
         }
     }
 
-    fn set_failure_chain(mut self, err: &NixInstallerError) -> Self {
-        let mut failure_chain = vec![];
-        let diagnostic = err.diagnostic();
-        failure_chain.push(diagnostic);
-
-        let mut walker: &dyn std::error::Error = &err;
-        while let Some(source) = walker.source() {
-            if let Some(downcasted) = source.downcast_ref::<ActionError>() {
-                let downcasted_diagnostic = downcasted.kind().diagnostic();
-                failure_chain.push(downcasted_diagnostic);
-            }
-            if let Some(downcasted) = source.downcast_ref::<Box<ActionError>>() {
-                let downcasted_diagnostic = downcasted.kind().diagnostic();
-                failure_chain.push(downcasted_diagnostic);
-            }
-            if let Some(downcasted) = source.downcast_ref::<PlannerError>() {
-                let downcasted_diagnostic = downcasted.diagnostic();
-                failure_chain.push(downcasted_diagnostic);
-            }
-            if let Some(downcasted) = source.downcast_ref::<InstallSettingsError>() {
-                let downcasted_diagnostic = downcasted.diagnostic();
-                failure_chain.push(downcasted_diagnostic);
-            }
-            if let Some(downcasted) = source.downcast_ref::<DiagnosticError>() {
-                let downcasted_diagnostic = downcasted.diagnostic();
-                failure_chain.push(downcasted_diagnostic);
-            }
-
-            walker = source;
+    fn set_failed_checks(mut self, err: &NixInstallerError) -> Self {
+        if let NixInstallerError::SelfTest(errors) = err {
+            let failed_checks = errors
+                .iter()
+                .filter_map(|error| {
+                    let check = error.check()?;
+                    Some(match error.shell() {
+                        Some(shell) => format!("{check}({shell})"),
+                        None => check.to_string(),
+                    })
+                })
+                .collect();
+            self.failed_checks = Some(failed_checks);
         }
+        self
+    }
 
-        self.failure_chain = Some(failure_chain);
+    fn set_failure_chain(mut self, err: &NixInstallerError) -> Self {
+        self.failure_chain = Some(failure_chain(err));
+        self.failure_codes = Some(
+            err.error_codes()
+                .into_iter()
+                .map(ToString::to_string)
+                .collect(),
+        );
 
         self
     }
@@ -95,6 +96,41 @@ impl Report {
     }
 }
 
+/// The [`ErrorDiagnostic`] of `err` and of each of its sources which has one
+pub(crate) fn failure_chain(err: &NixInstallerError) -> Vec<String> {
+    let mut failure_chain = vec![];
+    let diagnostic = err.diagnostic();
+    failure_chain.push(diagnostic);
+
+    let mut walker: &dyn std::error::Error = &err;
+    while let Some(source) = walker.source() {
+        if let Some(downcasted) = source.downcast_ref::<ActionError>() {
+            let downcasted_diagnostic = downcasted.kind().diagnostic();
+            failure_chain.push(downcasted_diagnostic);
+        }
+        if let Some(downcasted) = source.downcast_ref::<Box<ActionError>>() {
+            let downcasted_diagnostic = downcasted.kind().diagnostic();
+            failure_chain.push(downcasted_diagnostic);
+        }
+        if let Some(downcasted) = source.downcast_ref::<PlannerError>() {
+            let downcasted_diagnostic = downcasted.diagnostic();
+            failure_chain.push(downcasted_diagnostic);
+        }
+        if let Some(downcasted) = source.downcast_ref::<InstallSettingsError>() {
+            let downcasted_diagnostic = downcasted.diagnostic();
+            failure_chain.push(downcasted_diagnostic);
+        }
+        if let Some(downcasted) = source.downcast_ref::<DiagnosticError>() {
+            let downcasted_diagnostic = downcasted.diagnostic();
+            failure_chain.push(downcasted_diagnostic);
+        }
+
+        walker = source;
+    }
+
+    failure_chain
+}
+
 pub async fn diagnostics(
     attribution: Option<String>,
     endpoint: Option<String>,
@@ -268,6 +304,7 @@ impl crate::feedback::Feedback for DiagnosticData {
                 "nix-installer:self-test-failure",
                 Report::new(Action::SelfTest, Status::Failure)
                     .set_failure_chain(error)
+                    .set_failed_checks(error)
                     .into_properties(),
             )
             .await
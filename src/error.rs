@@ -121,6 +121,123 @@ impl HasExpectedErrors for NixInstallerError {
     }
 }
 
+/// A stable code identifying an error variant, like `NI-PLAN-0007`
+///
+/// Codes are never reused or renumbered, they are listed in `docs/error-codes.md`.
+pub trait HasErrorCode {
+    /// The code of this error
+    fn error_code(&self) -> &'static str;
+
+    /// The code of this error, followed by the codes of the errors it wraps
+    fn error_codes(&self) -> Vec<&'static str> {
+        vec![self.error_code()]
+    }
+}
+
+impl HasErrorCode for NixInstallerError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::Action(_) => "NI-CORE-0001",
+            Self::SelfTest(_) => "NI-CORE-0002",
+            Self::ActionRevert(_) => "NI-CORE-0003",
+            Self::RecordingReceipt(..) => "NI-CORE-0004",
+            Self::CopyingSelf(_) => "NI-CORE-0005",
+            Self::SerializingReceipt(_) => "NI-CORE-0006",
+            Self::Cancelled => "NI-CORE-0007",
+            Self::SemVer(_) => "NI-CORE-0008",
+            Self::Planner(_) => "NI-CORE-0009",
+            Self::InstallSettings(_) => "NI-CORE-0010",
+            #[cfg(feature = "diagnostics")]
+            Self::Diagnostic(_) => "NI-CORE-0011",
+            Self::InvalidVersionRequirement(..) => "NI-CORE-0012",
+            Self::InvalidCurrentVersion(..) => "NI-CORE-0013",
+            Self::IncompatibleVersion { .. } => "NI-CORE-0014",
+        }
+    }
+
+    fn error_codes(&self) -> Vec<&'static str> {
+        let mut codes = vec![self.error_code()];
+        match self {
+            Self::Action(action_error) => codes.extend(action_error.kind().error_codes()),
+            Self::SelfTest(self_test_errors) => {
+                codes.extend(self_test_errors.iter().map(HasErrorCode::error_code))
+            },
+            Self::ActionRevert(action_errors) => codes.extend(
+                action_errors
+                    .iter()
+                    .flat_map(|action_error| action_error.kind().error_codes()),
+            ),
+            Self::Planner(planner_error) => codes.extend(planner_error.error_codes()),
+            _ => (),
+        }
+        codes
+    }
+}
+
+/// What kind of failure an error is, which decides the exit status of the CLI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    /// A failure without a more specific class
+    Other,
+    /// The system does not meet a requirement, nothing was changed
+    Precondition,
+    /// The user cancelled the run
+    Cancelled,
+    /// An action failed while installing
+    Action,
+    /// An action failed while reverting or uninstalling
+    Revert,
+    /// The self-test of the install failed
+    SelfTest,
+}
+
+impl FailureClass {
+    /// The process exit status for this class of failure
+    ///
+    /// `2` is left to `clap`, which uses it for invalid arguments.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            FailureClass::Other => 1,
+            FailureClass::Precondition => 3,
+            FailureClass::Cancelled => 4,
+            FailureClass::Action => 5,
+            FailureClass::Revert => 6,
+            FailureClass::SelfTest => 7,
+        }
+    }
+}
+
+impl From<FailureClass> for std::process::ExitCode {
+    fn from(class: FailureClass) -> Self {
+        std::process::ExitCode::from(class.exit_code())
+    }
+}
+
+impl NixInstallerError {
+    /// The class of this failure, see [`FailureClass`]
+    pub fn failure_class(&self) -> FailureClass {
+        match self {
+            NixInstallerError::Cancelled => FailureClass::Cancelled,
+            NixInstallerError::SelfTest(_) => FailureClass::SelfTest,
+            NixInstallerError::ActionRevert(_) => FailureClass::Revert,
+            _ if self.expected().is_some() => FailureClass::Precondition,
+            NixInstallerError::Action(_) | NixInstallerError::Planner(PlannerError::Action(_)) => {
+                FailureClass::Action
+            },
+            _ => FailureClass::Other,
+        }
+    }
+
+    /// The most specific code of this error, the one of the innermost error it wraps
+    pub fn most_specific_error_code(&self) -> &'static str {
+        self.error_codes()
+            .last()
+            .copied()
+            .unwrap_or_else(|| self.error_code())
+    }
+}
+
 #[cfg(feature = "diagnostics")]
 impl crate::diagnostics::ErrorDiagnostic for NixInstallerError {
     fn diagnostic(&self) -> String {
@@ -148,3 +265,68 @@ impl crate::diagnostics::ErrorDiagnostic for NixInstallerError {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{FailureClass, HasErrorCode, NixInstallerError};
+    use crate::{
+        action::{ActionError, ActionErrorKind, ActionTag},
+        planner::PlannerError,
+    };
+
+    const DOCUMENTED: &str = include_str!("../docs/error-codes.md");
+
+    #[test]
+    fn documented_codes_are_unique() {
+        let mut codes = DOCUMENTED
+            .lines()
+            .filter_map(|line| line.strip_prefix("| `NI-"))
+            .map(|line| line.split('`').next().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let documented = codes.len();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), documented);
+    }
+
+    #[test]
+    fn nested_codes_are_documented() {
+        let err = NixInstallerError::Planner(PlannerError::Action(ActionError::new(
+            ActionTag("create_directory"),
+            ActionErrorKind::Child(Box::new(ActionError::new(
+                ActionTag("create_file"),
+                ActionErrorKind::MalformedBinaryTarball,
+            ))),
+        )));
+        let codes = err.error_codes();
+        assert_eq!(
+            codes,
+            ["NI-CORE-0009", "NI-PLAN-0003", "NI-ACT-0003", "NI-ACT-0050"]
+        );
+        assert_eq!(err.most_specific_error_code(), "NI-ACT-0050");
+        assert_eq!(err.failure_class(), FailureClass::Action);
+        for code in codes {
+            assert!(
+                DOCUMENTED.contains(&format!("| `{code}` |")),
+                "`{code}` is not documented"
+            );
+        }
+    }
+
+    #[test]
+    fn failure_classes() {
+        assert_eq!(
+            NixInstallerError::Cancelled.failure_class(),
+            FailureClass::Cancelled
+        );
+        assert_eq!(
+            NixInstallerError::Planner(PlannerError::NixExists).failure_class(),
+            FailureClass::Precondition
+        );
+        assert_eq!(
+            NixInstallerError::ActionRevert(vec![]).failure_class(),
+            FailureClass::Revert
+        );
+        assert_eq!(FailureClass::Precondition.exit_code(), 3);
+    }
+}
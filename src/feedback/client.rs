@@ -104,6 +104,39 @@ impl Feedback for Client {
             Self::DiagnosticsData(d) => d.uninstall_succeeded().await,
         }
     }
+
+    async fn action_started(&mut self, progress: &super::ActionProgress) {
+        match self {
+            Self::DevNull(d) => d.action_started(progress).await,
+            #[cfg(feature = "diagnostics")]
+            Self::DiagnosticsData(d) => d.action_started(progress).await,
+        }
+    }
+
+    async fn action_completed(
+        &mut self,
+        progress: &super::ActionProgress,
+        duration: std::time::Duration,
+    ) {
+        match self {
+            Self::DevNull(d) => d.action_completed(progress, duration).await,
+            #[cfg(feature = "diagnostics")]
+            Self::DiagnosticsData(d) => d.action_completed(progress, duration).await,
+        }
+    }
+
+    async fn action_failed(
+        &mut self,
+        progress: &super::ActionProgress,
+        duration: std::time::Duration,
+        error: &crate::action::ActionError,
+    ) {
+        match self {
+            Self::DevNull(d) => d.action_failed(progress, duration, error).await,
+            #[cfg(feature = "diagnostics")]
+            Self::DiagnosticsData(d) => d.action_failed(progress, duration, error).await,
+        }
+    }
 }
 
 pub enum Worker {
@@ -39,6 +39,23 @@ impl Feedback for DevNull {
     async fn uninstall_failed(&mut self, _error: &crate::error::NixInstallerError) {}
 
     async fn uninstall_succeeded(&mut self) {}
+
+    async fn action_started(&mut self, _progress: &super::ActionProgress) {}
+
+    async fn action_completed(
+        &mut self,
+        _progress: &super::ActionProgress,
+        _duration: std::time::Duration,
+    ) {
+    }
+
+    async fn action_failed(
+        &mut self,
+        _progress: &super::ActionProgress,
+        _duration: std::time::Duration,
+        _error: &crate::action::ActionError,
+    ) {
+    }
 }
 
 pub struct DevNullWorker;
@@ -1,5 +1,38 @@
 pub mod client;
 pub mod devnull;
+pub mod progress;
+
+use std::time::Duration;
+
+use crate::action::{ActionError, ActionTag};
+
+/// Whether an action is being executed by an install or reverted by an uninstall
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionPhase {
+    Install,
+    Uninstall,
+}
+
+/// A top level action of an [`InstallPlan`](crate::InstallPlan) being executed or reverted
+#[derive(Debug, serde::Serialize)]
+pub struct ActionProgress {
+    pub phase: ActionPhase,
+    #[serde(serialize_with = "serialize_action_tag")]
+    pub tag: ActionTag,
+    pub synopsis: String,
+    /// The position of the action in the order it runs, starting from 1
+    pub index: usize,
+    /// How many top level actions the plan has
+    pub total: usize,
+}
+
+fn serialize_action_tag<S: serde::Serializer>(
+    tag: &ActionTag,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(tag.0)
+}
 
 pub trait Feedback: Clone + Send + Sync {
     fn set_planner(
@@ -43,6 +76,30 @@ pub trait Feedback: Clone + Send + Sync {
     ) -> impl std::future::Future<Output = ()> + Send;
 
     fn uninstall_succeeded(&mut self) -> impl std::future::Future<Output = ()> + Send;
+
+    fn action_started(
+        &mut self,
+        _progress: &ActionProgress,
+    ) -> impl std::future::Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn action_completed(
+        &mut self,
+        _progress: &ActionProgress,
+        _duration: Duration,
+    ) -> impl std::future::Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn action_failed(
+        &mut self,
+        _progress: &ActionProgress,
+        _duration: Duration,
+        _error: &ActionError,
+    ) -> impl std::future::Future<Output = ()> + Send {
+        async {}
+    }
 }
 
 pub trait FeedbackWorker {
@@ -0,0 +1,236 @@
+/*! Newline-delimited JSON progress events for wrappers like GUIs and CI
+
+[`Progress`] wraps another [`Feedback`] and, when given a sink, writes one JSON object per line
+for each action started, completed or failed, and for the outcome of planning, installing and
+uninstalling. Every event has an `event` name and a `timestamp` in milliseconds since the Unix
+epoch, action events also carry the fields of [`ActionProgress`].
+*/
+
+use std::{
+    fs::File,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use super::{ActionProgress, Feedback};
+use crate::action::ActionError;
+
+#[derive(Debug, serde::Serialize)]
+struct Event<'a> {
+    event: &'a str,
+    timestamp: u64,
+    #[serde(flatten)]
+    action: Option<&'a ActionProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> Event<'a> {
+    fn new(event: &'a str) -> Self {
+        Self {
+            event,
+            timestamp: crate::util::unix_millis(SystemTime::now()),
+            action: None,
+            duration_ms: None,
+            error: None,
+        }
+    }
+
+    fn action(mut self, progress: &'a ActionProgress) -> Self {
+        self.action = Some(progress);
+        self
+    }
+
+    fn duration(mut self, duration: Duration) -> Self {
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self
+    }
+
+    fn error(mut self, error: &dyn std::error::Error) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// A [`Feedback`] which reports to `inner` and writes progress events to a sink, if there is one
+#[derive(Clone)]
+pub struct Progress<F> {
+    inner: F,
+    sink: Option<Arc<Mutex<File>>>,
+}
+
+impl<F> Progress<F> {
+    pub fn new(inner: F, sink: Option<File>) -> Self {
+        Self {
+            inner,
+            sink: sink.map(|sink| Arc::new(Mutex::new(sink))),
+        }
+    }
+
+    fn emit(&self, event: Event<'_>) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::debug!(
+                    "Could not serialize progress event `{}`: {err}",
+                    event.event
+                );
+                return;
+            },
+        };
+        // A poisoned lock only means another write panicked, the file is still usable
+        let mut sink = sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = writeln!(sink, "{line}").and_then(|()| sink.flush()) {
+            tracing::debug!("Could not write progress event `{}`: {err}", event.event);
+        }
+    }
+}
+
+impl<F> Feedback for Progress<F>
+where
+    F: Feedback,
+{
+    async fn get_feature_ptr_payload<
+        T: serde::ser::Serialize + serde::de::DeserializeOwned + Send + std::fmt::Debug,
+    >(
+        &self,
+        name: impl Into<String> + core::marker::Send + std::fmt::Debug,
+    ) -> Option<T> {
+        self.inner.get_feature_ptr_payload(name).await
+    }
+
+    async fn set_planner(
+        &mut self,
+        planner: &crate::planner::BuiltinPlanner,
+    ) -> Result<(), crate::planner::PlannerError> {
+        self.inner.set_planner(planner).await
+    }
+
+    async fn planning_failed(&mut self, error: &crate::error::NixInstallerError) {
+        self.emit(Event::new("planning_failed").error(error));
+        self.inner.planning_failed(error).await
+    }
+
+    async fn planning_succeeded(&mut self) {
+        self.emit(Event::new("planning_succeeded"));
+        self.inner.planning_succeeded().await
+    }
+
+    async fn install_cancelled(&mut self) {
+        self.emit(Event::new("install_cancelled"));
+        self.inner.install_cancelled().await
+    }
+
+    async fn install_failed(&mut self, error: &crate::error::NixInstallerError) {
+        self.emit(Event::new("install_failed").error(error));
+        self.inner.install_failed(error).await
+    }
+
+    async fn self_test_failed(&mut self, error: &crate::error::NixInstallerError) {
+        self.emit(Event::new("self_test_failed").error(error));
+        self.inner.self_test_failed(error).await
+    }
+
+    async fn install_succeeded(&mut self) {
+        self.emit(Event::new("install_succeeded"));
+        self.inner.install_succeeded().await
+    }
+
+    async fn uninstall_cancelled(&mut self) {
+        self.emit(Event::new("uninstall_cancelled"));
+        self.inner.uninstall_cancelled().await
+    }
+
+    async fn uninstall_failed(&mut self, error: &crate::error::NixInstallerError) {
+        self.emit(Event::new("uninstall_failed").error(error));
+        self.inner.uninstall_failed(error).await
+    }
+
+    async fn uninstall_succeeded(&mut self) {
+        self.emit(Event::new("uninstall_succeeded"));
+        self.inner.uninstall_succeeded().await
+    }
+
+    async fn action_started(&mut self, progress: &ActionProgress) {
+        self.emit(Event::new("action_started").action(progress));
+        self.inner.action_started(progress).await
+    }
+
+    async fn action_completed(&mut self, progress: &ActionProgress, duration: Duration) {
+        self.emit(
+            Event::new("action_completed")
+                .action(progress)
+                .duration(duration),
+        );
+        self.inner.action_completed(progress, duration).await
+    }
+
+    async fn action_failed(
+        &mut self,
+        progress: &ActionProgress,
+        duration: Duration,
+        error: &ActionError,
+    ) {
+        self.emit(
+            Event::new("action_failed")
+                .action(progress)
+                .duration(duration)
+                .error(error),
+        );
+        self.inner.action_failed(progress, duration, error).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Read, time::Duration};
+
+    use super::Progress;
+    use crate::feedback::{devnull::DevNull, ActionPhase, ActionProgress, Feedback};
+
+    #[tokio::test]
+    async fn writes_one_event_per_line() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sink = tempfile::tempfile()?;
+        let mut progress = Progress::new(DevNull, Some(sink.try_clone()?));
+
+        let action = ActionProgress {
+            phase: ActionPhase::Install,
+            tag: "create_directory".into(),
+            synopsis: "Create directory `/nix`".into(),
+            index: 1,
+            total: 2,
+        };
+        progress.action_started(&action).await;
+        progress
+            .action_completed(&action, Duration::from_millis(1500))
+            .await;
+        progress.install_succeeded().await;
+
+        let mut written = String::new();
+        std::io::Seek::rewind(&mut sink)?;
+        sink.read_to_string(&mut written)?;
+        let events = written
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "action_started");
+        assert_eq!(events[0]["phase"], "install");
+        assert_eq!(events[0]["tag"], "create_directory");
+        assert_eq!(events[0]["index"], 1);
+        assert_eq!(events[0]["total"], 2);
+        assert!(events[0].get("duration_ms").is_none());
+        assert_eq!(events[1]["event"], "action_completed");
+        assert_eq!(events[1]["duration_ms"], 1500);
+        assert_eq!(events[2]["event"], "install_succeeded");
+        assert!(events[2].get("tag").is_none());
+        Ok(())
+    }
+}
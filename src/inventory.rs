@@ -0,0 +1,382 @@
+/*! The concrete objects an uninstall would remove
+
+`nix-installer uninstall --dry-run --inventory` resolves the actions of a receipt which would be
+reverted into an [`Inventory`]: the paths (with their sizes, including the whole Nix store), users,
+groups, services and volumes they created, and the lines and settings they inserted into existing
+files. Like [`collect_backups`](crate::backup::collect_backups), it works on the receipt's JSON, so
+nested actions are found without knowing their types.
+*/
+
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::action::ActionState;
+
+const NIX_STORE_LOCATION: &str = "/nix/store";
+
+/// Everything the actions of a receipt which would be reverted created or inserted
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Inventory {
+    pub paths: Vec<InventoryPath>,
+    /// The size of the Nix store, `None` if it won't be removed
+    pub nix_store_size: Option<u64>,
+    /// The size of the paths, not counting those inside another one twice
+    pub total_size: u64,
+    pub users: Vec<InventoryUser>,
+    pub groups: Vec<InventoryGroup>,
+    pub group_memberships: Vec<InventoryGroupMembership>,
+    pub services: Vec<String>,
+    pub volumes: Vec<String>,
+    pub inserted_lines: Vec<InventoryLines>,
+    pub nix_config_settings: Vec<InventoryLines>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InventoryPath {
+    pub path: PathBuf,
+    pub kind: PathKind,
+    /// The apparent size of the path, and everything in it, on disk now
+    pub size: u64,
+    /// The tag of the action which created it
+    pub action: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathKind {
+    File,
+    Directory,
+    Symlink,
+    /// Already gone
+    Missing,
+}
+
+impl std::fmt::Display for PathKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathKind::File => write!(f, "file"),
+            PathKind::Directory => write!(f, "directory"),
+            PathKind::Symlink => write!(f, "symlink"),
+            PathKind::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InventoryUser {
+    pub name: String,
+    pub uid: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InventoryGroup {
+    pub name: String,
+    pub gid: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InventoryGroupMembership {
+    pub user: String,
+    pub group: String,
+}
+
+/// Lines, or settings, to be removed from a file which is otherwise kept
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InventoryLines {
+    pub path: PathBuf,
+    pub lines: Vec<String>,
+}
+
+impl Inventory {
+    /// Resolve the actions of `receipt` (an [`InstallPlan`](crate::InstallPlan) as JSON) which
+    /// would be reverted, measuring the paths on disk
+    pub async fn collect(receipt: &Value) -> Self {
+        let mut inventory = Self::default();
+        let mut paths = vec![];
+        for action in receipt["actions"].as_array().into_iter().flatten() {
+            inventory.collect_from(action, &mut paths);
+        }
+
+        let store_removed = paths
+            .iter()
+            .any(|(path, _)| Path::new(NIX_STORE_LOCATION).starts_with(path));
+        let measured = tokio::task::spawn_blocking(move || measure(paths, store_removed))
+            .await
+            .unwrap_or_default();
+        let (paths, nix_store_size) = measured;
+
+        // Paths nested in another listed path are already counted in its size
+        let listed = paths
+            .iter()
+            .map(|path| path.path.clone())
+            .collect::<HashSet<_>>();
+        inventory.total_size = paths
+            .iter()
+            .filter(|path| {
+                !path
+                    .path
+                    .ancestors()
+                    .skip(1)
+                    .any(|ancestor| listed.contains(ancestor))
+            })
+            .map(|path| path.size)
+            .sum();
+        inventory.paths = paths;
+        inventory.nix_store_size = nix_store_size;
+        inventory
+    }
+
+    fn collect_from(&mut self, value: &Value, paths: &mut Vec<(PathBuf, String)>) {
+        match value {
+            Value::Object(map) => {
+                if let (Some(Value::Object(action)), Some(state)) =
+                    (map.get("action"), map.get("state"))
+                {
+                    let reverted = serde_json::from_value::<ActionState>(state.clone())
+                        .map(|state| {
+                            !matches!(state, ActionState::Uncompleted | ActionState::Skipped)
+                        })
+                        .unwrap_or(false);
+                    // Nothing nested in an action which won't be reverted is reverted either
+                    if !reverted {
+                        return;
+                    }
+                    if let Some(tag) = action.get("action_name").and_then(Value::as_str) {
+                        self.collect_action(tag, action, paths);
+                    }
+                }
+                for child in map.values() {
+                    self.collect_from(child, paths);
+                }
+            },
+            Value::Array(items) => {
+                for item in items {
+                    self.collect_from(item, paths);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn collect_action(
+        &mut self,
+        tag: &str,
+        action: &serde_json::Map<String, Value>,
+        paths: &mut Vec<(PathBuf, String)>,
+    ) {
+        let string = |field: &str| action.get(field).and_then(Value::as_str).map(String::from);
+        let mut push_path = |field: &str| {
+            if let Some(path) = string(field) {
+                paths.push((PathBuf::from(path), tag.to_string()));
+            }
+        };
+
+        match tag {
+            "create_directory" | "create_file" => push_path("path"),
+            "bootstrap_launchctl_service" => {
+                push_path("path");
+                self.services.extend(string("service"));
+            },
+            "create_volume_service" | "create_nix_hook_service" => push_path("path"),
+            "fetch_and_unpack_nix" => push_path("dest"),
+            "move_unpacked_nix" | "mount_unpacked_nix" => {
+                paths.push((PathBuf::from(NIX_STORE_LOCATION), tag.to_string()))
+            },
+            "provision_determinate_nixd" => push_path("binary_location"),
+            "provision_selinux" => push_path("policy_path"),
+            "configure_init_service" => {
+                push_path("service_dest");
+                self.services.extend(string("service_name"));
+                for socket in action
+                    .get("socket_files")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Some(dest) = socket.get("dest").and_then(Value::as_str) {
+                        paths.push((PathBuf::from(dest), tag.to_string()));
+                    }
+                    if let Some(name) = socket.get("name").and_then(Value::as_str) {
+                        self.services.push(name.to_string());
+                    }
+                }
+            },
+            "configure_openrc_service" => self.services.push(
+                crate::action::linux::configure_openrc_service::OPENRC_SERVICE_NAME.to_string(),
+            ),
+            "start_systemd_unit" => self.services.extend(string("unit")),
+            "kickstart_launchctl_service" => self.services.extend(string("service")),
+            "create_user" => self.users.push(InventoryUser {
+                name: string("name").unwrap_or_default(),
+                uid: action.get("uid").and_then(Value::as_u64),
+            }),
+            "create_group" => self.groups.push(InventoryGroup {
+                name: string("name").unwrap_or_default(),
+                gid: action.get("gid").and_then(Value::as_u64),
+            }),
+            "add_user_to_group" => self.group_memberships.push(InventoryGroupMembership {
+                user: string("name").unwrap_or_default(),
+                group: string("groupname").unwrap_or_default(),
+            }),
+            "create_apfs_volume" => self.volumes.extend(string("name")),
+            "create_or_insert_into_file" => {
+                if let Some(path) = string("path") {
+                    self.inserted_lines.push(InventoryLines {
+                        path: PathBuf::from(path),
+                        lines: string("buf")
+                            .unwrap_or_default()
+                            .lines()
+                            .filter(|line| !line.trim().is_empty())
+                            .map(String::from)
+                            .collect(),
+                    });
+                }
+            },
+            "create_or_merge_nix_config" => {
+                // Only the settings the install contributed are removed, older receipts lack them
+                let settings = ["contributed_nix_config", "pending_nix_config"]
+                    .iter()
+                    .find_map(|field| {
+                        action
+                            .get(*field)
+                            .and_then(|config| config.get("settings"))
+                            .and_then(Value::as_object)
+                    });
+                if let (Some(path), Some(settings)) = (string("path"), settings) {
+                    self.nix_config_settings.push(InventoryLines {
+                        path: PathBuf::from(path),
+                        lines: settings
+                            .iter()
+                            .map(|(name, value)| {
+                                let value = value
+                                    .as_str()
+                                    .map(String::from)
+                                    .unwrap_or_else(|| value.to_string());
+                                format!("{name} = {value}")
+                            })
+                            .collect(),
+                    });
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Measure each path, and the Nix store once if it is removed
+fn measure(
+    paths: Vec<(PathBuf, String)>,
+    store_removed: bool,
+) -> (Vec<InventoryPath>, Option<u64>) {
+    let store = Path::new(NIX_STORE_LOCATION);
+    let nix_store_size = store_removed.then(|| disk_usage(store, None));
+
+    let mut seen = HashSet::new();
+    let mut measured = vec![];
+    for (path, action) in paths {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let kind = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_symlink() => PathKind::Symlink,
+            Ok(metadata) if metadata.is_dir() => PathKind::Directory,
+            Ok(_) => PathKind::File,
+            Err(_) => PathKind::Missing,
+        };
+        let size = match (kind, nix_store_size) {
+            (PathKind::Missing, _) => 0,
+            (_, Some(store_size)) if path == store => store_size,
+            _ => disk_usage(&path, nix_store_size),
+        };
+        measured.push(InventoryPath {
+            path,
+            kind,
+            size,
+            action,
+        });
+    }
+    (measured, nix_store_size)
+}
+
+/// The apparent size of `path` and everything in it, counting hard links once
+///
+/// The Nix store is counted as `store_size` rather than walked again, if given.
+fn disk_usage(path: &Path, store_size: Option<u64>) -> u64 {
+    let store = Path::new(NIX_STORE_LOCATION);
+    let mut inodes = HashSet::new();
+    let mut size = 0;
+    let walker = walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|entry| store_size.is_none() || entry.path() != store);
+    for entry in walker.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if inodes.insert((metadata.dev(), metadata.ino())) {
+            size += metadata.len();
+        }
+    }
+    if let Some(store_size) = store_size {
+        if store.starts_with(path) && store != path {
+            size += store_size;
+        }
+    }
+    size
+}
+
+/// A size in bytes, in binary units
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_size, Inventory};
+
+    #[test]
+    fn collects_from_fixture_receipt() -> eyre::Result<()> {
+        let receipt: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/linux/linux.json"))?;
+        // Without measuring, which would walk the host's Nix store
+        let mut inventory = Inventory::default();
+        let mut paths = vec![];
+        for action in receipt["actions"].as_array().into_iter().flatten() {
+            inventory.collect_from(action, &mut paths);
+        }
+
+        assert!(inventory.users.iter().any(|user| user.name == "nixbld1"));
+        assert!(inventory.groups.iter().any(|group| group.name == "nixbld"));
+        assert!(paths
+            .iter()
+            .any(|(path, _)| path.as_os_str() == "/nix/store"));
+        assert!(inventory
+            .nix_config_settings
+            .iter()
+            .any(|config| config.path.as_os_str() == "/etc/nix/nix.conf"));
+        assert!(!inventory.inserted_lines.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -70,14 +70,19 @@ match plan.install(feedback::devnull::DevNull{}, None).await {
 */
 
 pub mod action;
+pub mod backup;
 #[cfg(feature = "cli")]
 pub mod cli;
+pub mod detect;
 #[cfg(feature = "diagnostics")]
 pub mod diagnostics;
 mod distribution;
 mod error;
 pub mod feedback;
+pub mod history;
+pub mod inventory;
 mod os;
+pub mod phase;
 mod plan;
 pub mod planner;
 mod profile;
@@ -87,7 +92,7 @@ mod util;
 
 use std::{ffi::OsStr, path::Path, process::Output};
 
-pub use error::NixInstallerError;
+pub use error::{FailureClass, HasErrorCode, NixInstallerError};
 pub use plan::InstallPlan;
 use planner::BuiltinPlanner;
 
@@ -0,0 +1,36 @@
+pub(crate) mod processes;
+pub(crate) mod user_database;
+
+pub(crate) use user_database::UserDatabase;
+
+/// If `command` in `PATH` is provided by busybox (as on Alpine), whose applets take different flags
+pub(crate) fn is_busybox_applet(command: &str) -> bool {
+    which::which(command).is_ok_and(|path| resolves_to_busybox(&path))
+}
+
+/// If `path` is (a link to) the busybox multi-call binary
+fn resolves_to_busybox(path: &std::path::Path) -> bool {
+    std::fs::canonicalize(path)
+        .is_ok_and(|path| path.file_name().is_some_and(|name| name == "busybox"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::resolves_to_busybox;
+
+    #[test]
+    fn detects_busybox_applets() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bin = temp_dir.path();
+        std::fs::write(bin.join("busybox"), "")?;
+        std::fs::write(bin.join("shadow-adduser"), "")?;
+        std::os::unix::fs::symlink("busybox", bin.join("adduser"))?;
+        std::os::unix::fs::symlink("shadow-adduser", bin.join("useradd"))?;
+
+        assert!(resolves_to_busybox(&bin.join("adduser")));
+        assert!(resolves_to_busybox(&bin.join("busybox")));
+        assert!(!resolves_to_busybox(&bin.join("useradd")));
+        assert!(!resolves_to_busybox(&bin.join("missing")));
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    sys::signal::{kill, Signal},
+    unistd::{Pid, Uid, User},
+};
+
+use crate::action::ActionErrorKind;
+
+/// Processes which are stopped by reverting their service, rather than terminated
+///
+/// These are executable names, `/proc/<pid>/comm` is truncated to 15 bytes so can't be used.
+const NIX_DAEMON_COMMANDS: &[&str] = &["nix-daemon", "determinate-nixd"];
+
+/// How long terminated processes are given to exit before they are killed
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A process with a file under `/nix` mapped, open or as its working directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixProcess {
+    pub pid: u32,
+    pub user: String,
+    pub command: String,
+    /// The first path found under `/nix` which the process holds
+    pub holding: PathBuf,
+}
+
+impl std::fmt::Display for NixProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{pid} ({user}): {command}, holding `{holding}`",
+            pid = self.pid,
+            user = self.user,
+            command = self.command,
+            holding = self.holding.display(),
+        )
+    }
+}
+
+/// Scan `/proc/*/maps`, `/proc/*/cwd` and `/proc/*/fd` for processes holding files under `/nix`
+///
+/// The Nix daemon, which the uninstall stops itself, this process and its ancestors are left out.
+pub(crate) async fn processes_using_nix() -> Vec<NixProcess> {
+    tokio::task::spawn_blocking(scan_proc)
+        .await
+        .unwrap_or_default()
+}
+
+fn scan_proc() -> Vec<NixProcess> {
+    let ignored = self_and_ancestors();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+
+    let mut processes = vec![];
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if ignored.contains(&pid) {
+            continue;
+        }
+        let proc_dir = entry.path();
+
+        let exe = std::fs::read_link(proc_dir.join("exe")).ok();
+        let cmdline = std::fs::read(proc_dir.join("cmdline")).unwrap_or_default();
+        if is_nix_daemon(exe.as_deref(), &cmdline) {
+            continue;
+        }
+
+        // Processes can exit while being scanned, anything unreadable is passed over
+        let Some(holding) = held_nix_path(&proc_dir) else {
+            continue;
+        };
+
+        let command = cmdline
+            .split(|byte| *byte == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let command = if command.is_empty() {
+            let comm = std::fs::read_to_string(proc_dir.join("comm")).unwrap_or_default();
+            format!("[{}]", comm.trim())
+        } else {
+            command
+        };
+
+        let user = std::fs::read_to_string(proc_dir.join("status"))
+            .ok()
+            .and_then(|status| status_field(&status, "Uid:"))
+            .map(|uid| match User::from_uid(Uid::from_raw(uid)) {
+                Ok(Some(user)) => user.name,
+                _ => uid.to_string(),
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+
+        processes.push(NixProcess {
+            pid,
+            user,
+            command,
+            holding,
+        });
+    }
+    processes.sort_by_key(|process| process.pid);
+    processes
+}
+
+/// Whether the process runs the Nix daemon, by the name of its executable or its first argument
+fn is_nix_daemon(exe: Option<&Path>, cmdline: &[u8]) -> bool {
+    let executable_name = |path: &Path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            // A replaced executable shows as `/nix/store/..-nix-2.24/bin/nix-daemon (deleted)`
+            .map(|name| name.trim_end_matches(" (deleted)").to_string())
+    };
+    let argv0 = cmdline
+        .split(|byte| *byte == 0)
+        .next()
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| PathBuf::from(String::from_utf8_lossy(arg).into_owned()));
+    exe.and_then(executable_name)
+        .into_iter()
+        .chain(argv0.as_deref().and_then(executable_name))
+        .any(|name| NIX_DAEMON_COMMANDS.contains(&name.as_str()))
+}
+
+fn held_nix_path(proc_dir: &Path) -> Option<PathBuf> {
+    let under_nix = |path: &Path| path.starts_with("/nix");
+
+    if let Ok(cwd) = std::fs::read_link(proc_dir.join("cwd")) {
+        if under_nix(&cwd) {
+            return Some(cwd);
+        }
+    }
+
+    if let Ok(fds) = std::fs::read_dir(proc_dir.join("fd")) {
+        for fd in fds.flatten() {
+            if let Ok(target) = std::fs::read_link(fd.path()) {
+                if under_nix(&target) {
+                    return Some(target);
+                }
+            }
+        }
+    }
+
+    let maps = std::fs::read_to_string(proc_dir.join("maps")).unwrap_or_default();
+    maps.lines()
+        .filter_map(mapped_path)
+        .find(|path| under_nix(path))
+}
+
+/// The path of a line of `/proc/<pid>/maps`, like `7f3c.. r-xp 00000000 00:1f 1234 /nix/store/..`
+fn mapped_path(line: &str) -> Option<PathBuf> {
+    let path = line.splitn(6, char::is_whitespace).nth(5)?.trim_start();
+    path.starts_with('/').then(|| PathBuf::from(path))
+}
+
+/// The first value of a field of `/proc/<pid>/status`, like the real UID of `Uid:`
+fn status_field(status: &str, field: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn self_and_ancestors() -> HashSet<u32> {
+    let mut pids = HashSet::new();
+    let mut pid = std::process::id();
+    while pid > 1 && pids.insert(pid) {
+        pid = std::fs::read_to_string(format!("/proc/{pid}/status"))
+            .ok()
+            .and_then(|status| status_field(&status, "PPid:"))
+            .unwrap_or(0);
+    }
+    pids
+}
+
+/// Send `SIGTERM` to the processes, then `SIGKILL` to those still running after a timeout
+pub(crate) async fn terminate_processes(processes: &[NixProcess]) -> Result<(), ActionErrorKind> {
+    if processes.is_empty() {
+        return Ok(());
+    }
+    signal_processes(Signal::SIGTERM, processes)?;
+
+    let start = std::time::Instant::now();
+    loop {
+        let running = processes
+            .iter()
+            .filter(|process| Path::new("/proc").join(process.pid.to_string()).exists())
+            .cloned()
+            .collect::<Vec<_>>();
+        if running.is_empty() {
+            return Ok(());
+        }
+        if start.elapsed() > TERMINATE_TIMEOUT {
+            tracing::debug!(
+                "{} processes still running after `SIGTERM`, killing them",
+                running.len()
+            );
+            return signal_processes(Signal::SIGKILL, &running);
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+fn signal_processes(signal: Signal, processes: &[NixProcess]) -> Result<(), ActionErrorKind> {
+    for process in processes {
+        match kill(Pid::from_raw(process.pid as i32), signal) {
+            // It exited since the scan
+            Ok(()) | Err(Errno::ESRCH) => (),
+            Err(err) => return Err(ActionErrorKind::Signal(process.pid, err)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{is_nix_daemon, mapped_path, signal_processes, status_field, NixProcess};
+
+    #[test]
+    fn parses_maps_lines() {
+        assert_eq!(
+            mapped_path("7f3c2a000000-7f3c2a022000 r--p 00000000 00:1f 1234    /nix/store/abc-glibc-2.39/lib/libc.so.6"),
+            Some(PathBuf::from("/nix/store/abc-glibc-2.39/lib/libc.so.6"))
+        );
+        assert_eq!(
+            mapped_path("7ffd1c3f0000-7ffd1c411000 rw-p 00000000 00:00 0          [stack]"),
+            None
+        );
+        assert_eq!(
+            mapped_path("7ffd1c3f0000-7ffd1c411000 rw-p 00000000 00:00 0"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_status_fields() {
+        let status = "Name:\tbash\nPPid:\t41\nUid:\t1000\t1000\t1000\t1000\n";
+        assert_eq!(status_field(status, "Uid:"), Some(1000));
+        assert_eq!(status_field(status, "PPid:"), Some(41));
+        assert_eq!(status_field(status, "Gid:"), None);
+    }
+
+    #[test]
+    fn recognizes_the_nix_daemon() {
+        let determinate_nixd = Path::new("/usr/local/bin/determinate-nixd");
+        assert!(is_nix_daemon(
+            Some(determinate_nixd),
+            b"determinate-nixd\0daemon\0"
+        ));
+        assert!(is_nix_daemon(
+            Some(Path::new(
+                "/nix/store/abc-nix-2.24.0/bin/nix-daemon (deleted)"
+            )),
+            b""
+        ));
+        // Without permission to read `exe`, the first argument is used
+        assert!(is_nix_daemon(
+            None,
+            b"/nix/var/nix/profiles/default/bin/nix-daemon\0--daemon\0"
+        ));
+        assert!(!is_nix_daemon(
+            Some(Path::new("/nix/store/abc-bash-5.2/bin/bash")),
+            b"bash\0-c\0nix-daemon\0"
+        ));
+        assert!(!is_nix_daemon(None, b""));
+    }
+
+    #[test]
+    fn signalling_exited_processes_succeeds() {
+        // Above any `/proc/sys/kernel/pid_max`, so no process has it
+        let pid = i32::MAX as u32;
+
+        let exited = NixProcess {
+            pid,
+            user: "root".to_string(),
+            command: "true".to_string(),
+            holding: PathBuf::from("/nix/store"),
+        };
+        assert!(signal_processes(nix::sys::signal::Signal::SIGTERM, &[exited]).is_ok());
+    }
+}
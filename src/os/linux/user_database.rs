@@ -0,0 +1,596 @@
+/*! Direct edits of `/etc/passwd`, `/etc/group`, `/etc/shadow` and `/etc/gshadow`
+
+Used to manage users and groups on systems which ship none of `useradd`/`adduser`,
+`groupadd`/`addgroup` or `gpasswd` (such as distroless or stripped container images).
+
+Edits follow the same conventions as shadow-utils so they can't race with `useradd` and friends:
+
+* The `lckpwdf(3)` lock (a `fcntl` write lock on `/etc/.pwd.lock`) is held for the whole edit.
+* Each database also gets a `<file>.lock` link to a file containing our PID, stale locks of
+  processes which no longer exist are removed.
+* The previous contents are kept at `<file>-`, the new contents are written to `<file>+` with the
+  mode and ownership of the original, then renamed over it.
+*/
+
+use std::{
+    fs::File,
+    io::Write,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg},
+    libc,
+    unistd::{chown, Gid, Uid},
+};
+
+use crate::action::ActionErrorKind;
+
+/// The same as the `LOCK_TIMEOUT` of `lckpwdf(3)`
+const LOCK_TIMEOUT: Duration = Duration::from_secs(15);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+const PASSWD: &str = "passwd";
+const SHADOW: &str = "shadow";
+const GROUP: &str = "group";
+const GSHADOW: &str = "gshadow";
+
+/// The user and group databases under a directory, usually `/etc`
+#[derive(Debug, Clone)]
+pub(crate) struct UserDatabase {
+    root: PathBuf,
+}
+
+impl UserDatabase {
+    pub(crate) fn system() -> Self {
+        Self::new("/etc")
+    }
+
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, database: &str) -> PathBuf {
+        self.root.join(database)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn create_group(&self, name: &str, gid: u32) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        self.edit(GROUP, true, |entries| {
+            if !entries.iter().any(|entry| entry_name(entry) == name) {
+                entries.push(format!("{name}:x:{gid}:"));
+            }
+        })
+        .await?;
+        self.edit(GSHADOW, false, |entries| {
+            if !entries.iter().any(|entry| entry_name(entry) == name) {
+                entries.push(format!("{name}:!::"));
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn delete_group(&self, name: &str) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        for (database, required) in [(GROUP, true), (GSHADOW, false)] {
+            self.edit(database, required, |entries| {
+                entries.retain(|entry| entry_name(entry) != name)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn create_user(
+        &self,
+        name: &str,
+        uid: u32,
+        gid: u32,
+        comment: &str,
+    ) -> Result<(), ActionErrorKind> {
+        // `:` and newlines would corrupt the database
+        let comment = comment.replace([':', '\n'], " ");
+        let days_since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs() / (60 * 60 * 24))
+            .unwrap_or_default();
+
+        let _lock = self.lock().await?;
+        self.edit(PASSWD, true, |entries| {
+            if !entries.iter().any(|entry| entry_name(entry) == name) {
+                entries.push(format!(
+                    "{name}:x:{uid}:{gid}:{comment}:/var/empty:/sbin/nologin"
+                ));
+            }
+        })
+        .await?;
+        self.edit(SHADOW, false, |entries| {
+            if !entries.iter().any(|entry| entry_name(entry) == name) {
+                entries.push(format!("{name}:!:{days_since_epoch}::::::"));
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Remove the user, and their membership of any groups, like `userdel`
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn delete_user(&self, name: &str) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        for (database, required) in [(PASSWD, true), (SHADOW, false)] {
+            self.edit(database, required, |entries| {
+                entries.retain(|entry| entry_name(entry) != name)
+            })
+            .await?;
+        }
+        for (database, required) in [(GROUP, true), (GSHADOW, false)] {
+            self.edit(database, required, |entries| {
+                for entry in entries.iter_mut() {
+                    *entry = without_member(entry, name);
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn add_user_to_group(
+        &self,
+        name: &str,
+        groupname: &str,
+    ) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        let mut found = false;
+        self.edit(GROUP, true, |entries| {
+            for entry in entries.iter_mut() {
+                if entry_name(entry) == groupname {
+                    found = true;
+                    *entry = with_member(entry, name);
+                }
+            }
+        })
+        .await?;
+        if !found {
+            return Err(ActionErrorKind::NoGroup(groupname.to_string()));
+        }
+        self.edit(GSHADOW, false, |entries| {
+            for entry in entries.iter_mut() {
+                if entry_name(entry) == groupname {
+                    *entry = with_member(entry, name);
+                }
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn remove_user_from_group(
+        &self,
+        name: &str,
+        groupname: &str,
+    ) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        for (database, required) in [(GROUP, true), (GSHADOW, false)] {
+            self.edit(database, required, |entries| {
+                for entry in entries.iter_mut() {
+                    if entry_name(entry) == groupname {
+                        *entry = without_member(entry, name);
+                    }
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Change the UID and primary GID of a user, like `usermod --uid --gid`
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn set_user_ids(
+        &self,
+        name: &str,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        let mut found = false;
+        self.edit(PASSWD, true, |entries| {
+            for entry in entries.iter_mut() {
+                if entry_name(entry) == name {
+                    found = true;
+                    *entry = with_fields(entry, &[(2, uid), (3, gid)]);
+                }
+            }
+        })
+        .await?;
+        if !found {
+            return Err(ActionErrorKind::NoUser(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Change the GID of a group, like `groupmod --gid`
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn set_group_id(&self, name: &str, gid: u32) -> Result<(), ActionErrorKind> {
+        let _lock = self.lock().await?;
+        let mut found = false;
+        self.edit(GROUP, true, |entries| {
+            for entry in entries.iter_mut() {
+                if entry_name(entry) == name {
+                    found = true;
+                    *entry = with_fields(entry, &[(2, gid)]);
+                }
+            }
+        })
+        .await?;
+        if !found {
+            return Err(ActionErrorKind::NoGroup(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Take the `lckpwdf(3)` lock, then the shadow-utils `.lock` file of each database
+    async fn lock(&self) -> Result<DatabaseLock, ActionErrorKind> {
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+
+        let pwd_lock_path = self.path(".pwd.lock");
+        let pwd_lock = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&pwd_lock_path)
+            .map_err(|e| ActionErrorKind::Open(pwd_lock_path.clone(), e))?;
+        let flock = libc::flock {
+            l_type: libc::F_WRLCK as libc::c_short,
+            l_whence: libc::SEEK_SET as libc::c_short,
+            l_start: 0,
+            l_len: 0,
+            l_pid: 0,
+        };
+        loop {
+            match fcntl(pwd_lock.as_raw_fd(), FcntlArg::F_SETLK(&flock)) {
+                Ok(_) => break,
+                Err(nix::errno::Errno::EAGAIN | nix::errno::Errno::EACCES)
+                    if Instant::now() < deadline =>
+                {
+                    tokio::time::sleep(LOCK_RETRY_INTERVAL).await
+                },
+                Err(nix::errno::Errno::EAGAIN | nix::errno::Errno::EACCES) => {
+                    return Err(ActionErrorKind::UserDatabaseLocked(pwd_lock_path))
+                },
+                Err(e) => return Err(ActionErrorKind::Open(pwd_lock_path, e.into())),
+            }
+        }
+
+        let mut lock = DatabaseLock {
+            _pwd_lock: pwd_lock,
+            lock_files: vec![],
+        };
+        for database in [PASSWD, SHADOW, GROUP, GSHADOW] {
+            let path = self.path(database);
+            if path.exists() {
+                lock.lock_files.push(lock_database(&path, deadline).await?);
+            }
+        }
+        Ok(lock)
+    }
+
+    /// Rewrite the entries of `database`, keeping comments and blank lines where they were
+    ///
+    /// Databases which aren't `required` (such as `/etc/gshadow`) are skipped if they don't exist.
+    async fn edit(
+        &self,
+        database: &str,
+        required: bool,
+        edit: impl FnOnce(&mut Vec<String>),
+    ) -> Result<(), ActionErrorKind> {
+        let path = self.path(database);
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => return Ok(()),
+            Err(e) => return Err(ActionErrorKind::GettingMetadata(path, e)),
+        };
+        let contents = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| ActionErrorKind::Read(path.clone(), e))?;
+
+        let mut entries = contents.lines().map(String::from).collect::<Vec<_>>();
+        edit(&mut entries);
+        let mut new_contents = entries.join("\n");
+        if !new_contents.is_empty() {
+            new_contents.push('\n');
+        }
+        if new_contents == contents {
+            return Ok(());
+        }
+
+        write_like(&with_suffix(&path, "-"), &contents, &metadata).await?;
+        let temp = with_suffix(&path, "+");
+        write_like(&temp, &new_contents, &metadata).await?;
+        tokio::fs::rename(&temp, &path)
+            .await
+            .map_err(|e| ActionErrorKind::Rename(temp.clone(), path.clone(), e))?;
+        tracing::trace!("Updated `{}`", path.display());
+        Ok(())
+    }
+}
+
+/// The locks taken by [`UserDatabase::lock`], released on drop
+struct DatabaseLock {
+    // Closing the file releases the `fcntl` lock
+    _pwd_lock: File,
+    lock_files: Vec<PathBuf>,
+}
+
+impl Drop for DatabaseLock {
+    fn drop(&mut self) {
+        for lock_file in self.lock_files.iter().rev() {
+            if let Err(e) = std::fs::remove_file(lock_file) {
+                tracing::warn!("Could not remove `{}`: {e}", lock_file.display());
+            }
+        }
+    }
+}
+
+/// Take the shadow-utils lock of `path` by linking a file containing our PID to `<path>.lock`
+async fn lock_database(path: &Path, deadline: Instant) -> Result<PathBuf, ActionErrorKind> {
+    let pid = std::process::id();
+    let lock_path = with_suffix(path, ".lock");
+    let pid_path = with_suffix(path, &format!(".{pid}"));
+
+    let mut pid_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&pid_path)
+        .map_err(|e| ActionErrorKind::Open(pid_path.clone(), e))?;
+    pid_file
+        .write_all(pid.to_string().as_bytes())
+        .map_err(|e| ActionErrorKind::Write(pid_path.clone(), e))?;
+    drop(pid_file);
+
+    let result = loop {
+        match std::fs::hard_link(&pid_path, &lock_path) {
+            Ok(()) => break Ok(lock_path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if lock_is_stale(&lock_path) {
+                    tracing::debug!("Removing stale lock `{}`", lock_path.display());
+                    if let Err(e) = std::fs::remove_file(&lock_path) {
+                        break Err(ActionErrorKind::Remove(lock_path, e));
+                    }
+                } else if Instant::now() < deadline {
+                    tokio::time::sleep(LOCK_RETRY_INTERVAL).await
+                } else {
+                    break Err(ActionErrorKind::UserDatabaseLocked(lock_path));
+                }
+            },
+            Err(e) => break Err(ActionErrorKind::Write(lock_path, e)),
+        }
+    };
+
+    if let Err(e) = std::fs::remove_file(&pid_path) {
+        tracing::warn!("Could not remove `{}`: {e}", pid_path.display());
+    }
+    result
+}
+
+/// A lock is stale if the process whose PID it contains no longer exists
+fn lock_is_stale(lock_path: &Path) -> bool {
+    let Ok(contents) = std::fs::read_to_string(lock_path) else {
+        return false;
+    };
+    match contents.trim().parse::<u32>() {
+        Ok(pid) => !Path::new("/proc").join(pid.to_string()).exists(),
+        // shadow-utils treats a lock it can't parse as stale
+        Err(_) => true,
+    }
+}
+
+/// Write `contents` to `path` with the mode and ownership of `like`
+async fn write_like(
+    path: &Path,
+    contents: &str,
+    like: &std::fs::Metadata,
+) -> Result<(), ActionErrorKind> {
+    let mode = like.permissions().mode() & 0o7777;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        // Never expose `/etc/shadow` more widely than the original, even briefly
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| ActionErrorKind::Open(path.to_path_buf(), e))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| ActionErrorKind::Write(path.to_path_buf(), e))?;
+    file.sync_all()
+        .map_err(|e| ActionErrorKind::Sync(path.to_path_buf(), e))?;
+    drop(file);
+
+    if Uid::effective().is_root() {
+        chown(
+            path,
+            Some(Uid::from_raw(like.uid())),
+            Some(Gid::from_raw(like.gid())),
+        )
+        .map_err(|e| ActionErrorKind::Chown(path.to_path_buf(), e))?;
+    }
+    tokio::fs::set_permissions(path, PermissionsExt::from_mode(mode))
+        .await
+        .map_err(|e| ActionErrorKind::SetPermissions(mode, path.to_path_buf(), e))?;
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn entry_name(entry: &str) -> &str {
+    entry.split(':').next().unwrap_or_default()
+}
+
+/// Replace the numeric fields (such as the UID and GID of a `passwd` entry) at the given indices
+fn with_fields(entry: &str, fields: &[(usize, u32)]) -> String {
+    let mut parts = entry.split(':').map(String::from).collect::<Vec<_>>();
+    for (index, value) in fields {
+        if let Some(part) = parts.get_mut(*index) {
+            *part = value.to_string();
+        }
+    }
+    parts.join(":")
+}
+
+/// The members of a `group` or `gshadow` entry are always its last field
+fn edit_members(entry: &str, edit: impl FnOnce(&mut Vec<String>)) -> String {
+    if entry.starts_with('#') || !entry.contains(':') {
+        return entry.to_string();
+    }
+    let (rest, members) = entry.rsplit_once(':').expect("Checked above");
+    let mut members = members
+        .split(',')
+        .filter(|member| !member.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    edit(&mut members);
+    format!("{rest}:{}", members.join(","))
+}
+
+fn with_member(entry: &str, name: &str) -> String {
+    edit_members(entry, |members| {
+        if !members.iter().any(|member| member == name) {
+            members.push(name.to_string());
+        }
+    })
+}
+
+fn without_member(entry: &str, name: &str) -> String {
+    edit_members(entry, |members| members.retain(|member| member != name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn creates_and_deletes_users_and_groups() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let database = UserDatabase::new(root.path());
+        std::fs::write(root.path().join(PASSWD), "root:x:0:0:root:/root:/bin/sh\n")?;
+        std::fs::write(root.path().join(SHADOW), "root:*:19000:0:99999:7:::\n")?;
+        std::fs::set_permissions(root.path().join(SHADOW), PermissionsExt::from_mode(0o640))?;
+        std::fs::write(root.path().join(GROUP), "root:x:0:\nwheel:x:10:root\n")?;
+
+        database.create_group("nixbld", 30000).await?;
+        database
+            .create_user("nixbld1", 30001, 30000, "Nix build user 1")
+            .await?;
+        database.add_user_to_group("nixbld1", "nixbld").await?;
+        database.add_user_to_group("nixbld1", "wheel").await?;
+        // Adding twice is a no-op
+        database.add_user_to_group("nixbld1", "nixbld").await?;
+
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(PASSWD))?,
+            "root:x:0:0:root:/root:/bin/sh\nnixbld1:x:30001:30000:Nix build user 1:/var/empty:/sbin/nologin\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(GROUP))?,
+            "root:x:0:\nwheel:x:10:root,nixbld1\nnixbld:x:30000:nixbld1\n"
+        );
+        let shadow = std::fs::read_to_string(root.path().join(SHADOW))?;
+        assert!(shadow.contains("\nnixbld1:!:"));
+        assert_eq!(
+            std::fs::metadata(root.path().join(SHADOW))?
+                .permissions()
+                .mode()
+                & 0o777,
+            0o640
+        );
+        // There was no `gshadow` to begin with, and there should be no leftover locks
+        assert!(!root.path().join(GSHADOW).exists());
+        assert!(!root.path().join("passwd.lock").exists());
+        assert!(!root.path().join("group.lock").exists());
+
+        database.delete_user("nixbld1").await?;
+        database.delete_group("nixbld").await?;
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(PASSWD))?,
+            "root:x:0:0:root:/root:/bin/sh\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(SHADOW))?,
+            "root:*:19000:0:99999:7:::\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(GROUP))?,
+            "root:x:0:\nwheel:x:10:root\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn moves_users_and_groups() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let database = UserDatabase::new(root.path());
+        std::fs::write(
+            root.path().join(PASSWD),
+            "root:x:0:0:root:/root:/bin/sh\nnixbld1:x:30001:30000:Nix build user 1:/var/empty:/sbin/nologin\n",
+        )?;
+        std::fs::write(
+            root.path().join(GROUP),
+            "root:x:0:\nnixbld:x:30000:nixbld1\n",
+        )?;
+
+        database.set_group_id("nixbld", 40000).await?;
+        database.set_user_ids("nixbld1", 40001, 40000).await?;
+
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(PASSWD))?,
+            "root:x:0:0:root:/root:/bin/sh\nnixbld1:x:40001:40000:Nix build user 1:/var/empty:/sbin/nologin\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(GROUP))?,
+            "root:x:0:\nnixbld:x:40000:nixbld1\n"
+        );
+        assert!(matches!(
+            database.set_user_ids("nixbld2", 40002, 40000).await,
+            Err(ActionErrorKind::NoUser(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_stale_locks() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let database = UserDatabase::new(root.path());
+        std::fs::write(root.path().join(GROUP), "root:x:0:\n")?;
+        // No process has PID `u32::MAX`
+        std::fs::write(root.path().join("group.lock"), u32::MAX.to_string())?;
+
+        database.create_group("nixbld", 30000).await?;
+
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(GROUP))?,
+            "root:x:0:\nnixbld:x:30000:\n"
+        );
+        assert!(!root.path().join("group.lock").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+/*! Splitting a receipt into phases by action tag
+
+A [`TagFilter`] selects the [`StatefulAction`](crate::action::StatefulAction)s of a receipt whose
+`action_name` matches, at any depth, so a part of an install (like the shell profile hooks or the
+daemon service) can be reverted on its own. `nix-installer uninstall --only`/`--except` revert the
+[`Selection`] and write the remaining receipt back, while `nix-installer split-receipt` writes the
+selection and the remainder as two phase receipts.
+
+The filter works on the receipt's JSON, so nested actions are found without knowing their types.
+Nested actions inside one action are visited by field name, not by the order they run in.
+*/
+
+use serde_json::Value;
+
+use crate::action::ActionState;
+
+/// Which actions of a receipt to act on, by their tag
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct TagFilter {
+    /// Only the actions with this tag (like `configure_shell_profile`), including nested ones
+    #[cfg_attr(feature = "cli", clap(long, value_delimiter = ','))]
+    pub only: Vec<String>,
+    /// Leave the actions with this tag alone, including nested ones
+    #[cfg_attr(feature = "cli", clap(long, value_delimiter = ','))]
+    pub except: Vec<String>,
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.except.is_empty()
+    }
+
+    /// Select the actions of `receipt` (an [`InstallPlan`](crate::InstallPlan) as JSON)
+    ///
+    /// The outermost actions matching `only` (or every top level action, if it is empty) are
+    /// selected, unless they match `except`. Actions matching `except` nested in a selected action
+    /// are excluded from it.
+    pub fn select(&self, receipt: &Value) -> Selection {
+        let mut candidates = vec![];
+        for (index, action) in receipt["actions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let pointer = format!("/actions/{index}");
+            if self.only.is_empty() {
+                candidates.push(pointer);
+            } else {
+                find_tagged(action, &pointer, &self.only, &mut candidates);
+            }
+        }
+
+        let mut selection = Selection::default();
+        for pointer in candidates {
+            let Some(action) = receipt.pointer(&pointer) else {
+                continue;
+            };
+            if action_tag(action).is_some_and(|tag| self.except.iter().any(|t| t == tag)) {
+                continue;
+            }
+            find_tagged_within(action, &pointer, &self.except, &mut selection.excluded);
+            selection.selected.push(pointer);
+        }
+        selection
+    }
+}
+
+/// Actions picked from a receipt by a [`TagFilter`], as JSON pointers into it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selection {
+    /// In receipt order, none nested in another
+    pub selected: Vec<String>,
+    /// Nested in the selected actions, to be left alone
+    pub excluded: Vec<String>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.selected.is_empty()
+    }
+
+    /// A receipt of only the selected actions, with the excluded ones marked skipped
+    pub fn selected_receipt(&self, receipt: &Value) -> Value {
+        let mut actions = vec![];
+        for pointer in &self.selected {
+            let mut action = receipt.pointer(pointer).cloned().unwrap_or(Value::Null);
+            for excluded in self.excluded_within(pointer) {
+                if let Some(Value::Object(excluded)) = action.pointer_mut(excluded) {
+                    excluded.insert("state".to_string(), state_value(ActionState::Skipped));
+                }
+            }
+            actions.push(action);
+        }
+
+        let mut selected_receipt = receipt.clone();
+        selected_receipt["actions"] = Value::Array(actions);
+        selected_receipt
+    }
+
+    /// Write the selected actions of `selected_receipt`, made by [`Selection::selected_receipt`],
+    /// back into `receipt`
+    ///
+    /// Excluded actions, and the state of the actions they are nested in, are kept from `receipt`
+    /// so a later uninstall still reaches them.
+    pub fn merge(&self, receipt: &mut Value, selected_receipt: &Value) {
+        let updated_actions = selected_receipt["actions"].as_array();
+        for (index, pointer) in self.selected.iter().enumerate() {
+            let Some(mut updated) = updated_actions
+                .and_then(|actions| actions.get(index))
+                .cloned()
+            else {
+                continue;
+            };
+            let Some(original) = receipt.pointer(pointer) else {
+                continue;
+            };
+
+            for excluded in self.excluded_within(pointer) {
+                if let (Some(slot), Some(kept)) =
+                    (updated.pointer_mut(excluded), original.pointer(excluded))
+                {
+                    *slot = kept.clone();
+                }
+                // Each action enclosing an excluded one must be reverted again to reach it
+                let mut enclosing = String::new();
+                for token in excluded.split('/').skip(1) {
+                    let state = original
+                        .pointer(&enclosing)
+                        .filter(|value| is_action(value))
+                        .and_then(|value| value.get("state"));
+                    if let (Some(state), Some(Value::Object(slot))) =
+                        (state, updated.pointer_mut(&enclosing))
+                    {
+                        slot.insert("state".to_string(), state.clone());
+                    }
+                    enclosing.push('/');
+                    enclosing.push_str(token);
+                }
+            }
+
+            if let Some(slot) = receipt.pointer_mut(pointer) {
+                *slot = updated;
+            }
+        }
+    }
+
+    /// The receipt with the selected actions marked skipped, leaving the rest to a later phase
+    pub fn remaining_receipt(&self, receipt: &Value) -> Value {
+        let mut skipped = self.selected_receipt(receipt);
+        mark_skipped(&mut skipped["actions"]);
+
+        let mut remaining = receipt.clone();
+        self.merge(&mut remaining, &skipped);
+        remaining
+    }
+
+    /// The excluded actions nested in the selected action at `pointer`, relative to it
+    fn excluded_within<'a>(&'a self, pointer: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.excluded.iter().filter_map(move |excluded| {
+            excluded
+                .strip_prefix(pointer)
+                .filter(|relative| relative.starts_with('/'))
+        })
+    }
+}
+
+/// Tags of the actions which remove the Nix store itself when reverted
+const NIX_STORE_TAGS: &[&str] = &[
+    "provision_nix",
+    "move_unpacked_nix",
+    "provision_determinate_nixd",
+];
+
+/// Whether uninstalling `receipt` (an [`InstallPlan`](crate::InstallPlan) as JSON) removes anything
+/// under `/nix`, by reverting the store itself or an action with a `path` there
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn reverts_nix_paths(receipt: &Value) -> bool {
+    receipt["actions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(action_reverts_nix_paths)
+}
+
+fn action_reverts_nix_paths(value: &Value) -> bool {
+    if is_action(value) {
+        let reverted = value["state"]
+            .as_str()
+            .is_some_and(|state| state == "Completed" || state == "Progress");
+        let action = &value["action"];
+        let under_nix = action["path"]
+            .as_str()
+            .is_some_and(|path| std::path::Path::new(path).starts_with("/nix"));
+        let is_store = action_tag(value).is_some_and(|tag| NIX_STORE_TAGS.contains(&tag));
+        if reverted && (under_nix || is_store) {
+            return true;
+        }
+    }
+    match value {
+        Value::Object(map) => map.values().any(action_reverts_nix_paths),
+        Value::Array(items) => items.iter().any(action_reverts_nix_paths),
+        _ => false,
+    }
+}
+
+/// If `value` is a serialized [`StatefulAction`](crate::action::StatefulAction)
+fn is_action(value: &Value) -> bool {
+    value.get("action").is_some_and(Value::is_object) && value.get("state").is_some()
+}
+
+fn action_tag(value: &Value) -> Option<&str> {
+    if !is_action(value) {
+        return None;
+    }
+    value["action"]["action_name"].as_str()
+}
+
+fn state_value(state: ActionState) -> Value {
+    serde_json::to_value(state).expect("ActionState should be trivially serializable to JSON")
+}
+
+/// Collect the outermost actions in `value` (itself included) with one of `tags`
+fn find_tagged(value: &Value, pointer: &str, tags: &[String], found: &mut Vec<String>) {
+    if action_tag(value).is_some_and(|tag| tags.iter().any(|t| t == tag)) {
+        found.push(pointer.to_string());
+        return;
+    }
+    find_tagged_within(value, pointer, tags, found)
+}
+
+/// Collect the outermost actions nested in `value` with one of `tags`
+fn find_tagged_within(value: &Value, pointer: &str, tags: &[String], found: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                find_tagged(child, &format!("{pointer}/{key}"), tags, found);
+            }
+        },
+        Value::Array(items) => {
+            for (index, child) in items.iter().enumerate() {
+                find_tagged(child, &format!("{pointer}/{index}"), tags, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn mark_skipped(value: &mut Value) {
+    if is_action(value) {
+        value["state"] = state_value(ActionState::Skipped);
+    }
+    match value {
+        Value::Object(map) => map.values_mut().for_each(mark_skipped),
+        Value::Array(items) => items.iter_mut().for_each(mark_skipped),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::{reverts_nix_paths, TagFilter};
+
+    fn action(name: &str, state: &str, children: Value) -> Value {
+        let mut action = json!({ "action_name": name });
+        if let Value::Object(children) = children {
+            action.as_object_mut().unwrap().extend(children);
+        }
+        json!({ "action": action, "state": state })
+    }
+
+    fn receipt() -> Value {
+        json!({
+            "version": "0.0.0",
+            "planner": { "planner": "linux" },
+            "actions": [
+                action("provision_nix", "Completed", json!({})),
+                action("configure_nix", "Completed", json!({
+                    "configure_shell_profile": action("configure_shell_profile", "Completed", json!({
+                        "create_or_insert_into_files": [
+                            action("create_or_insert_into_file", "Completed", json!({})),
+                        ],
+                    })),
+                    "place_nix_configuration": action("place_nix_configuration", "Completed", json!({})),
+                })),
+            ],
+        })
+    }
+
+    #[test]
+    fn only_selects_nested_actions() {
+        let receipt = receipt();
+        let filter = TagFilter {
+            only: vec!["configure_shell_profile".to_string()],
+            except: vec![],
+        };
+        let selection = filter.select(&receipt);
+        assert_eq!(
+            selection.selected,
+            vec!["/actions/1/action/configure_shell_profile"]
+        );
+
+        let selected = selection.selected_receipt(&receipt);
+        assert_eq!(
+            selected["actions"][0]["action"]["action_name"],
+            "configure_shell_profile"
+        );
+
+        let remaining = selection.remaining_receipt(&receipt);
+        let shell_profile = &remaining["actions"][1]["action"]["configure_shell_profile"];
+        assert_eq!(shell_profile["state"], "Skipped");
+        assert_eq!(
+            shell_profile["action"]["create_or_insert_into_files"][0]["state"],
+            "Skipped"
+        );
+        assert_eq!(remaining["actions"][1]["state"], "Completed");
+        assert_eq!(remaining["actions"][0]["state"], "Completed");
+    }
+
+    #[test]
+    fn except_keeps_excluded_actions_reachable() {
+        let mut receipt = receipt();
+        let filter = TagFilter {
+            only: vec![],
+            except: vec![
+                "provision_nix".to_string(),
+                "place_nix_configuration".to_string(),
+            ],
+        };
+        let selection = filter.select(&receipt);
+        assert_eq!(selection.selected, vec!["/actions/1"]);
+        assert_eq!(
+            selection.excluded,
+            vec!["/actions/1/action/place_nix_configuration"]
+        );
+
+        let mut selected = selection.selected_receipt(&receipt);
+        assert_eq!(
+            selected["actions"][0]["action"]["place_nix_configuration"]["state"],
+            "Skipped"
+        );
+
+        // As if reverted
+        selected["actions"][0]["state"] = json!("Uncompleted");
+        selected["actions"][0]["action"]["configure_shell_profile"]["state"] = json!("Uncompleted");
+        selection.merge(&mut receipt, &selected);
+
+        let configure_nix = &receipt["actions"][1];
+        assert_eq!(configure_nix["state"], "Completed");
+        assert_eq!(
+            configure_nix["action"]["configure_shell_profile"]["state"],
+            "Uncompleted"
+        );
+        assert_eq!(
+            configure_nix["action"]["place_nix_configuration"]["state"],
+            "Completed"
+        );
+    }
+
+    #[test]
+    fn only_store_reverting_selections_touch_nix() {
+        let receipt = receipt();
+        assert!(reverts_nix_paths(&receipt));
+
+        let filter = TagFilter {
+            only: vec!["configure_shell_profile".to_string()],
+            except: vec![],
+        };
+        let selected = filter.select(&receipt).selected_receipt(&receipt);
+        assert!(!reverts_nix_paths(&selected));
+
+        let nix_conf = json!({
+            "actions": [
+                action("create_directory", "Completed", json!({ "path": "/nix/var/nix" })),
+            ],
+        });
+        assert!(reverts_nix_paths(&nix_conf));
+        let skipped = json!({
+            "actions": [action("provision_nix", "Skipped", json!({}))],
+        });
+        assert!(!reverts_nix_paths(&skipped));
+    }
+}
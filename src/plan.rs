@@ -1,10 +1,12 @@
 use std::{
     path::{Path, PathBuf},
     str::FromStr,
+    time::Instant,
 };
 
 use crate::{
-    action::{Action, ActionDescription, StatefulAction},
+    action::{Action, ActionDescription, ActionTag, StatefulAction},
+    feedback::{ActionPhase, ActionProgress},
     planner::{BuiltinPlanner, Planner},
     NixInstallerError,
 };
@@ -13,6 +15,8 @@ use semver::{Version, VersionReq};
 use tokio::sync::broadcast::Receiver;
 
 pub const RECEIPT_LOCATION: &str = "/nix/receipt.json";
+/// Where a copy of the receipt is kept outside of `/nix`, in case `/nix` is damaged or partially removed
+pub const RECEIPT_MIRROR_LOCATION: &str = "/etc/nix-installer/receipt.json";
 
 /**
 A set of [`Action`]s, along with some metadata, which can be carried out to drive an install or
@@ -25,6 +29,10 @@ pub struct InstallPlan {
     pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
 
     pub(crate) planner: Box<dyn Planner>,
+
+    /// A copy of the receipt written alongside [`RECEIPT_LOCATION`], receipts from before it existed have none
+    #[serde(default)]
+    pub(crate) receipt_mirror: Option<PathBuf>,
 }
 
 impl InstallPlan {
@@ -38,6 +46,7 @@ impl InstallPlan {
             planner,
             actions,
             version: current_version()?,
+            receipt_mirror: Some(PathBuf::from(RECEIPT_MIRROR_LOCATION)),
         })
     }
 
@@ -55,12 +64,19 @@ impl InstallPlan {
             planner: planner.boxed(),
             actions,
             version: current_version()?,
+            receipt_mirror: Some(PathBuf::from(RECEIPT_MIRROR_LOCATION)),
         })
     }
 
     pub async fn pre_uninstall_check(&self) -> Result<(), NixInstallerError> {
         self.planner.platform_check().await?;
         self.planner.pre_uninstall_check().await?;
+        // Reverting only some actions, like the shell profile, leaves processes using `/nix` be.
+        // Processes are found through `/proc`, so macOS is not checked.
+        #[cfg(target_os = "linux")]
+        if crate::phase::reverts_nix_paths(&serde_json::to_value(self)?) {
+            crate::planner::linux::check_nix_not_in_use().await?;
+        }
         Ok(())
     }
 
@@ -158,11 +174,12 @@ impl InstallPlan {
 
         let Self { actions, .. } = self;
         let mut cancel_channel = cancel_channel.into();
+        let total = actions.len();
 
         // This is **deliberately sequential**.
         // Actions which are parallelizable are represented by "group actions" like CreateUsers
         // The plan itself represents the concept of the sequence of stages.
-        for action in actions {
+        for (index, action) in actions.iter_mut().enumerate() {
             if let Some(ref mut cancel_channel) = cancel_channel {
                 if cancel_channel.try_recv()
                     != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
@@ -178,7 +195,14 @@ impl InstallPlan {
             }
 
             tracing::info!("Step: {}", action.tracing_synopsis());
-            if let Err(err) = action.try_execute().await {
+            let progress = action_progress(action, ActionPhase::Install, index, total);
+            feedback.action_started(&progress).await;
+            let started = Instant::now();
+            let res = action.try_execute().await;
+            let duration = started.elapsed();
+            if let Err(err) = res {
+                feedback.action_failed(&progress, duration, &err).await;
+
                 if let Err(err) = self.write_receipt().await {
                     tracing::error!("Error saving receipt: {:?}", err);
                 }
@@ -189,6 +213,7 @@ impl InstallPlan {
 
                 return Err(err);
             }
+            feedback.action_completed(&progress, duration).await;
         }
 
         self.write_receipt().await?;
@@ -298,11 +323,12 @@ impl InstallPlan {
         let Self { actions, .. } = self;
         let mut cancel_channel = cancel_channel.into();
         let mut errors = vec![];
+        let total = actions.len();
 
         // This is **deliberately sequential**.
         // Actions which are parallelizable are represented by "group actions" like CreateUsers
         // The plan itself represents the concept of the sequence of stages.
-        for action in actions.iter_mut().rev() {
+        for (index, action) in actions.iter_mut().rev().enumerate() {
             if let Some(ref mut cancel_channel) = cancel_channel {
                 if cancel_channel.try_recv()
                     != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
@@ -318,8 +344,17 @@ impl InstallPlan {
             }
 
             tracing::info!("Revert: {}", action.tracing_synopsis());
-            if let Err(errs) = action.try_revert().await {
-                errors.push(errs);
+            let progress = action_progress(action, ActionPhase::Uninstall, index, total);
+            feedback.action_started(&progress).await;
+            let started = Instant::now();
+            let res = action.try_revert().await;
+            let duration = started.elapsed();
+            match res {
+                Ok(()) => feedback.action_completed(&progress, duration).await,
+                Err(errs) => {
+                    feedback.action_failed(&progress, duration, &errs).await;
+                    errors.push(errs);
+                },
             }
         }
 
@@ -351,9 +386,137 @@ impl InstallPlan {
     pub(crate) async fn write_receipt(&self) -> Result<(), NixInstallerError> {
         let install_receipt_path = PathBuf::from(RECEIPT_LOCATION);
         write_receipt(self, &install_receipt_path).await?;
+        self.write_receipt_mirror().await;
 
         Ok(())
     }
+
+    /// Write only the mirror of the receipt, for when `/nix` may already be (partially) removed
+    ///
+    /// Failing to write the mirror is not fatal, the receipt under `/nix` is authoritative.
+    pub(crate) async fn write_receipt_mirror(&self) {
+        let Some(receipt_mirror) = &self.receipt_mirror else {
+            return;
+        };
+        if let Err(err) = write_receipt(self, receipt_mirror).await {
+            tracing::warn!(
+                "Could not write the receipt mirror at `{}`: {err}",
+                receipt_mirror.display()
+            );
+        }
+    }
+
+    /// Remove the mirror of the receipt, once nothing remains to be uninstalled
+    pub(crate) async fn remove_receipt_mirror(&self) -> Result<(), NixInstallerError> {
+        let Some(receipt_mirror) = &self.receipt_mirror else {
+            return Ok(());
+        };
+        match tokio::fs::remove_file(receipt_mirror).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(NixInstallerError::RecordingReceipt(
+                    receipt_mirror.clone(),
+                    e,
+                ))
+            },
+        }
+        // Leave the directory if anything else (like a custom plan) is kept there
+        if let Some(parent) = receipt_mirror.parent() {
+            if let Ok(mut entries) = tokio::fs::read_dir(parent).await {
+                if let Ok(None) = entries.next_entry().await {
+                    let _ = tokio::fs::remove_dir(parent).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn action_progress(
+    action: &StatefulAction<Box<dyn Action>>,
+    phase: ActionPhase,
+    index: usize,
+    total: usize,
+) -> ActionProgress {
+    ActionProgress {
+        phase,
+        tag: ActionTag(action.inner_typetag_name()),
+        synopsis: action.tracing_synopsis(),
+        index: index + 1,
+        total,
+    }
+}
+
+/// A receipt read by [`read_receipt`]
+#[derive(Debug, Clone)]
+pub(crate) struct ReadReceipt {
+    /// Where the receipt was read from, the primary location or its mirror
+    pub(crate) path: PathBuf,
+    pub(crate) contents: String,
+}
+
+/// Read the receipt at `primary`, falling back to `mirror` if it is missing
+///
+/// When both exist but differ, a warning is logged and the primary is used.
+pub(crate) async fn read_receipt(
+    primary: &Path,
+    mirror: &Path,
+) -> std::io::Result<Option<ReadReceipt>> {
+    let mirror_contents = match tokio::fs::read_to_string(mirror).await {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::warn!(
+                "Could not read the receipt mirror at `{}`: {e}",
+                mirror.display()
+            );
+            None
+        },
+    };
+
+    match tokio::fs::read_to_string(primary).await {
+        Ok(contents) => {
+            if let Some(mirror_contents) = &mirror_contents {
+                if receipts_diverge(&contents, mirror_contents) {
+                    tracing::warn!(
+                        "The receipt at `{}` differs from its mirror at `{}`, using `{}`",
+                        primary.display(),
+                        mirror.display(),
+                        primary.display(),
+                    );
+                }
+            }
+            Ok(Some(ReadReceipt {
+                path: primary.to_path_buf(),
+                contents,
+            }))
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(mirror_contents.map(|contents| {
+            tracing::warn!(
+                "No receipt at `{}`, using its mirror at `{}`",
+                primary.display(),
+                mirror.display(),
+            );
+            ReadReceipt {
+                path: mirror.to_path_buf(),
+                contents,
+            }
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compare two receipts by their content, ignoring formatting
+fn receipts_diverge(primary: &str, mirror: &str) -> bool {
+    match (
+        serde_json::from_str::<serde_json::Value>(primary),
+        serde_json::from_str::<serde_json::Value>(mirror),
+    ) {
+        (Ok(primary), Ok(mirror)) => primary != mirror,
+        // An unparsable copy only matches if it is byte-for-byte the same
+        _ => primary != mirror,
+    }
 }
 
 pub(crate) async fn write_receipt(
@@ -368,9 +531,11 @@ pub(crate) async fn write_receipt(
     let self_json =
         serde_json::to_string_pretty(plan).map_err(NixInstallerError::SerializingReceipt)?;
 
-    tokio::fs::create_dir_all("/nix")
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(PathBuf::from("/nix"), e))?;
+    if let Some(parent) = install_receipt_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| NixInstallerError::RecordingReceipt(parent.to_path_buf(), e))?;
+    }
     tokio::fs::write(&install_receipt_path_tmp, format!("{self_json}\n"))
         .await
         .map_err(|e| NixInstallerError::RecordingReceipt(install_receipt_path_tmp.clone(), e))?;
@@ -421,4 +586,38 @@ mod test {
         assert!(maybe_plan.check_compatible().is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn read_receipt_falls_back_to_mirror() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+        let primary = temp_dir.path().join("nix/receipt.json");
+        let mirror = temp_dir.path().join("etc/nix-installer/receipt.json");
+
+        assert!(super::read_receipt(&primary, &mirror).await?.is_none());
+
+        super::write_receipt(&serde_json::json!({ "actions": [] }), &mirror).await?;
+        let read = super::read_receipt(&primary, &mirror)
+            .await?
+            .expect("The mirror should be read");
+        assert_eq!(read.path, mirror);
+
+        super::write_receipt(&serde_json::json!({ "actions": [] }), &primary).await?;
+        let read = super::read_receipt(&primary, &mirror)
+            .await?
+            .expect("The primary should be read");
+        assert_eq!(read.path, primary);
+        Ok(())
+    }
+
+    #[test]
+    fn receipts_diverge_ignores_formatting() {
+        assert!(!super::receipts_diverge(
+            "{\"actions\": []}",
+            "{\n  \"actions\": []\n}\n"
+        ));
+        assert!(super::receipts_diverge(
+            "{\"actions\": []}",
+            "{\"actions\": [1]}"
+        ));
+    }
 }
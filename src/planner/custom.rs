@@ -0,0 +1,482 @@
+/*! A [`Planner`] which plans the actions listed in a JSON or TOML document
+
+Each entry of `actions` names an action by its tag (as found in a receipt's `action_name`) in
+`action`, alongside its parameters:
+
+```json
+{
+  "actions": [
+    { "action": "create_directory", "path": "/nix", "mode": 493 },
+    { "action": "provision_nix" },
+    { "action": "create_users_and_groups" },
+    { "action": "configure_nix" },
+    { "action": "configure_init_service", "init": "Systemd", "start_daemon": true },
+    { "action": "remove_directory", "path": "/nix/temp-install-dir" }
+  ]
+}
+```
+
+The actions in [`PLANNED_ACTIONS`] are planned like the builtin planners plan them, taking
+only the parameters a planner would pass, and using the [`CommonSettings`] given to this planner
+where they need them. Any other registered action is read with every field of its receipt form.
+
+A plan file ending in `.toml` is read as TOML, with an `[[actions]]` table for each entry:
+
+```toml
+[[actions]]
+action = "create_directory"
+path = "/nix"
+mode = 493
+
+[[actions]]
+action = "provision_nix"
+```
+*/
+
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    action::{
+        base::{CreateDirectory, CreateFile, RemoveDirectory},
+        common::{
+            ConfigureDeterminateNixdInitService, ConfigureNix, ConfigureUpstreamInitService,
+            CreateUsersAndGroups, ProvisionDeterminateNixd, ProvisionNix,
+        },
+        linux::StartSystemdUnit,
+        ActionState, StatefulAction,
+    },
+    distribution::Distribution,
+    error::HasExpectedErrors,
+    planner::{Planner, PlannerError, ShellProfileLocations},
+    settings::{CommonSettings, InitSystem, InstallSettingsError},
+    Action, BuiltinPlanner,
+};
+
+pub const CUSTOM_PLAN_PATH: &str = "/etc/nix-installer/custom-plan.json";
+
+/// The actions which are planned from parameters, rather than read in their receipt form
+pub const PLANNED_ACTIONS: &[&str] = &[
+    "create_directory",
+    "create_file",
+    "remove_directory",
+    "provision_determinate_nixd",
+    "provision_nix",
+    "create_users_and_groups",
+    "configure_nix",
+    "configure_init_service",
+    "start_systemd_unit",
+];
+
+/// A planner which plans the actions listed in a JSON or TOML document
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct Custom {
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    pub settings: CommonSettings,
+    /// The JSON (or, ending in `.toml`, TOML) document listing the actions to plan
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_CUSTOM_PLAN", default_value = CUSTOM_PLAN_PATH)
+    )]
+    pub plan_file: PathBuf,
+}
+
+/// The document read by [`Custom`]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomPlan {
+    pub actions: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// The parameters of the actions in [`PLANNED_ACTIONS`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+enum PlannedAction {
+    CreateDirectory {
+        path: PathBuf,
+        #[serde(default)]
+        user: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        mode: Option<u32>,
+        #[serde(default)]
+        force_prune_on_revert: bool,
+    },
+    CreateFile {
+        path: PathBuf,
+        #[serde(default)]
+        user: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        mode: Option<u32>,
+        buf: String,
+        #[serde(default)]
+        force: bool,
+    },
+    RemoveDirectory {
+        path: PathBuf,
+    },
+    ProvisionDeterminateNixd {},
+    ProvisionNix {},
+    CreateUsersAndGroups {},
+    ConfigureNix {
+        #[serde(default)]
+        shell_profile_locations: Option<ShellProfileLocations>,
+    },
+    /// Configures the init service of the distribution selected by the [`CommonSettings`]
+    ConfigureInitService {
+        init: InitSystem,
+        #[serde(default = "default_true")]
+        start_daemon: bool,
+    },
+    StartSystemdUnit {
+        unit: String,
+        #[serde(default = "default_true")]
+        enable: bool,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Custom {
+    async fn read_plan(&self) -> Result<CustomPlan, CustomError> {
+        let contents = tokio::fs::read_to_string(&self.plan_file)
+            .await
+            .map_err(|e| CustomError::Read(self.plan_file.clone(), e))?;
+        if self
+            .plan_file
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            // Read into JSON values, so actions are deserialized the same way from either format
+            let document: serde_json::Value = toml::from_str(&contents)
+                .map_err(|e| CustomError::Toml(self.plan_file.clone(), e))?;
+            return serde_json::from_value(document)
+                .map_err(|e| CustomError::Parse(self.plan_file.clone(), e));
+        }
+        serde_json::from_str(&contents).map_err(|e| CustomError::Parse(self.plan_file.clone(), e))
+    }
+
+    async fn plan_action(
+        &self,
+        index: usize,
+        mut entry: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<StatefulAction<Box<dyn Action>>, PlannerError> {
+        let action = match entry.get("action") {
+            Some(serde_json::Value::String(action)) => action.clone(),
+            _ => return Err(CustomError::MissingAction { index }.into()),
+        };
+        let invalid = |e| CustomError::InvalidAction {
+            index,
+            action: action.clone(),
+            source: e,
+        };
+
+        if !PLANNED_ACTIONS.contains(&action.as_str()) {
+            entry.remove("action");
+            entry.insert(
+                "action_name".to_string(),
+                serde_json::Value::String(action.clone()),
+            );
+            let action: Box<dyn Action> =
+                serde_json::from_value(serde_json::Value::Object(entry)).map_err(invalid)?;
+            return Ok(StatefulAction {
+                action,
+                state: ActionState::Uncompleted,
+                timing: None,
+            });
+        }
+
+        let planned: PlannedAction =
+            serde_json::from_value(serde_json::Value::Object(entry)).map_err(invalid)?;
+        let planned = match planned {
+            PlannedAction::CreateDirectory {
+                path,
+                user,
+                group,
+                mode,
+                force_prune_on_revert,
+            } => CreateDirectory::plan(path, user, group, mode, force_prune_on_revert)
+                .await
+                .map(StatefulAction::boxed),
+            PlannedAction::CreateFile {
+                path,
+                user,
+                group,
+                mode,
+                buf,
+                force,
+            } => CreateFile::plan(path, user, group, mode, buf, force)
+                .await
+                .map(StatefulAction::boxed),
+            PlannedAction::RemoveDirectory { path } => {
+                RemoveDirectory::plan(path).await.map(StatefulAction::boxed)
+            },
+            PlannedAction::ProvisionDeterminateNixd {} => ProvisionDeterminateNixd::plan()
+                .await
+                .map(StatefulAction::boxed),
+            PlannedAction::ProvisionNix {} => ProvisionNix::plan(&self.settings)
+                .await
+                .map(StatefulAction::boxed),
+            PlannedAction::CreateUsersAndGroups {} => {
+                CreateUsersAndGroups::plan(self.settings.clone())
+                    .await
+                    .map(StatefulAction::boxed)
+            },
+            PlannedAction::ConfigureNix {
+                shell_profile_locations,
+            } => ConfigureNix::plan(shell_profile_locations.unwrap_or_default(), &self.settings)
+                .await
+                .map(StatefulAction::boxed),
+            PlannedAction::ConfigureInitService { init, start_daemon } => {
+                match self.settings.distribution() {
+                    Distribution::DeterminateNix => {
+                        ConfigureDeterminateNixdInitService::plan(init, start_daemon)
+                            .await
+                            .map(StatefulAction::boxed)
+                    },
+                    Distribution::Nix => ConfigureUpstreamInitService::plan(init, start_daemon)
+                        .await
+                        .map(StatefulAction::boxed),
+                }
+            },
+            PlannedAction::StartSystemdUnit { unit, enable } => {
+                StartSystemdUnit::plan(unit, enable)
+                    .await
+                    .map(StatefulAction::boxed)
+            },
+        };
+        planned.map_err(PlannerError::Action)
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "custom")]
+impl Planner for Custom {
+    async fn default() -> Result<Self, PlannerError> {
+        Ok(Self {
+            settings: CommonSettings::default().await?,
+            plan_file: CUSTOM_PLAN_PATH.into(),
+        })
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        let custom_plan = self.read_plan().await?;
+        if custom_plan.actions.is_empty() {
+            return Err(CustomError::Empty(self.plan_file.clone()).into());
+        }
+
+        let mut plan = Vec::with_capacity(custom_plan.actions.len());
+        for (index, entry) in custom_plan.actions.into_iter().enumerate() {
+            plan.push(self.plan_action(index, entry).await?);
+        }
+
+        Ok(plan)
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        let Self {
+            settings,
+            plan_file,
+        } = self;
+        let mut map = HashMap::default();
+
+        map.extend(settings.settings()?);
+        map.insert("plan_file".to_string(), serde_json::to_value(plan_file)?);
+
+        Ok(map)
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        let default = Self::default().await?.settings()?;
+        let configured = self.settings()?;
+
+        let mut settings: HashMap<String, serde_json::Value> = HashMap::new();
+        for (key, value) in configured.iter() {
+            if default.get(key) != Some(value) {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(settings)
+    }
+
+    async fn platform_check(&self) -> Result<(), PlannerError> {
+        use target_lexicon::OperatingSystem;
+        match target_lexicon::OperatingSystem::host() {
+            OperatingSystem::Linux | OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+                Ok(())
+            },
+            host_os => Err(PlannerError::IncompatibleOperatingSystem {
+                planner: self.typetag_name(),
+                host_os,
+            }),
+        }
+    }
+
+    async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
+        Ok(())
+    }
+
+    async fn pre_install_check(&self) -> Result<(), PlannerError> {
+        // Fail before anything is shown to be confirmed if the document is invalid
+        self.read_plan().await?;
+
+        Ok(())
+    }
+}
+
+impl From<Custom> for BuiltinPlanner {
+    fn from(val: Custom) -> Self {
+        BuiltinPlanner::Custom(val)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CustomError {
+    #[error("Reading the custom plan `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("The custom plan `{0}` is not of the form `{{\"actions\": [...]}}`")]
+    Parse(PathBuf, #[source] serde_json::Error),
+    #[error("The custom plan `{0}` is not valid TOML")]
+    Toml(PathBuf, #[source] toml::de::Error),
+    #[error("The custom plan `{0}` lists no actions")]
+    Empty(PathBuf),
+    #[error("Action {index} of the custom plan has no `action` naming it")]
+    MissingAction { index: usize },
+    #[error("Action {index} (`{action}`) of the custom plan is invalid: {source}")]
+    InvalidAction {
+        index: usize,
+        action: String,
+        source: serde_json::Error,
+    },
+}
+
+impl HasExpectedErrors for CustomError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            CustomError::Read(_, _) => None,
+            CustomError::Parse(_, _)
+            | CustomError::Toml(_, _)
+            | CustomError::Empty(_)
+            | CustomError::MissingAction { .. }
+            | CustomError::InvalidAction { .. } => Some(Box::new(self)),
+        }
+    }
+}
+
+impl From<CustomError> for PlannerError {
+    fn from(v: CustomError) -> PlannerError {
+        PlannerError::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn plans_listed_actions() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plan_file = temp_dir.path().join("custom-plan.json");
+        let target = temp_dir.path().join("example");
+        let document = serde_json::json!({
+            "actions": [
+                { "action": "create_directory", "path": temp_dir.path().join("dir"), "mode": 0o755 },
+                { "action": "create_file", "path": target, "mode": 0o644, "buf": "Example" },
+                { "action": "remove_directory", "path": temp_dir.path().join("scratch") },
+            ]
+        });
+        tokio::fs::write(&plan_file, serde_json::to_string(&document)?).await?;
+
+        let planner = Custom {
+            settings: CommonSettings::default().await?,
+            plan_file,
+        };
+        let plan = planner.plan().await?;
+        let tags = plan
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            ["create_directory", "create_file", "remove_directory"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_actions_and_parameters() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plan_file = temp_dir.path().join("custom-plan.json");
+        let planner = Custom {
+            settings: CommonSettings::default().await?,
+            plan_file: plan_file.clone(),
+        };
+
+        for action in [
+            serde_json::json!({ "action": "no_such_action" }),
+            serde_json::json!({ "action": "create_directory", "path": "/nix", "colour": "red" }),
+            serde_json::json!({ "path": "/nix" }),
+        ] {
+            let document = serde_json::json!({ "actions": [action] });
+            tokio::fs::write(&plan_file, serde_json::to_string(&document)?).await?;
+            let err = planner
+                .plan()
+                .await
+                .expect_err("Invalid action was planned");
+            assert!(err.expected().is_some(), "{err:?}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_toml_plans() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plan_file = temp_dir.path().join("custom-plan.toml");
+        let dir = temp_dir.path().join("dir");
+        tokio::fs::write(
+            &plan_file,
+            format!(
+                "[[actions]]\naction = \"create_directory\"\npath = {:?}\nmode = 493\n\n[[actions]]\naction = \"provision_nix\"\n",
+                dir.display().to_string()
+            ),
+        )
+        .await?;
+        let planner = Custom {
+            settings: CommonSettings::default().await?,
+            plan_file: plan_file.clone(),
+        };
+
+        let plan = planner.read_plan().await?;
+        let actions = plan
+            .actions
+            .iter()
+            .map(|entry| entry.get("action").and_then(serde_json::Value::as_str))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![Some("create_directory"), Some("provision_nix")]
+        );
+        assert_eq!(
+            plan.actions[0].get("mode"),
+            Some(&serde_json::Value::from(493))
+        );
+
+        tokio::fs::write(&plan_file, "[[actions]\n").await?;
+        match planner.read_plan().await {
+            Err(CustomError::Toml(path, _)) => assert_eq!(path, plan_file),
+            other => panic!("Expected the invalid TOML to be refused, got {other:?}"),
+        }
+        Ok(())
+    }
+}
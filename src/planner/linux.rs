@@ -1,4 +1,7 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use tokio::process::Command;
 use which::which;
@@ -12,8 +15,10 @@ use crate::{
             CreateUsersAndGroups, ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
+            provision_apparmor::APPARMOR_PROFILE_CONTENT,
             provision_selinux::{DETERMINATE_SELINUX_POLICY_PP_CONTENT, SELINUX_POLICY_PP_CONTENT},
-            ProvisionSelinux,
+            ConfigureOpenrcService, ConfigureRepairHooksService, ConfigureWslDaemon,
+            ProvisionAppArmor, ProvisionSelinux, VerifySelinuxLabels,
         },
         StatefulAction,
     },
@@ -24,7 +29,9 @@ use crate::{
     Action, BuiltinPlanner,
 };
 
+pub const FHS_SELINUX_POLICY_DIR: &str = "/usr/share/selinux/packages";
 pub const FHS_SELINUX_POLICY_PATH: &str = "/usr/share/selinux/packages/nix.pp";
+pub const APPARMOR_PROFILE_PATH: &str = "/etc/apparmor.d/nix";
 
 /// A planner for traditional, mutable Linux systems like Debian, RHEL, or Arch
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -34,6 +41,10 @@ pub struct Linux {
     pub settings: CommonSettings,
     #[cfg_attr(feature = "cli", clap(flatten))]
     pub init: InitSettings,
+    /// A site-specific SELinux policy to install instead of the builtin one, either a `.te` source (compiled along with a `.fc` of the same name, if any) or a compiled `.pp`
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_SELINUX_POLICY"))]
+    #[serde(default)]
+    pub selinux_policy: Option<PathBuf>,
 }
 
 #[async_trait::async_trait]
@@ -43,11 +54,13 @@ impl Planner for Linux {
         Ok(Self {
             settings: CommonSettings::default().await?,
             init: InitSettings::default().await?,
+            selinux_policy: None,
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
         let has_selinux = detect_selinux().await?;
+        let has_apparmor = detect_apparmor().await?;
 
         let mut plan = vec![];
 
@@ -79,25 +92,49 @@ impl Planner for Linux {
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
+        let shell_profile_locations = if detect_alpine() {
+            ShellProfileLocations::alpine()
+        } else {
+            ShellProfileLocations::default()
+        };
         plan.push(
-            ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
+            ConfigureNix::plan(shell_profile_locations, &self.settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
 
         if has_selinux {
+            let provision_selinux = match &self.selinux_policy {
+                Some(selinux_policy) => {
+                    ProvisionSelinux::plan_from_file(
+                        Path::new(FHS_SELINUX_POLICY_DIR),
+                        selinux_policy,
+                    )
+                    .await
+                },
+                None => {
+                    ProvisionSelinux::plan(
+                        FHS_SELINUX_POLICY_PATH.into(),
+                        match self.settings.distribution() {
+                            Distribution::DeterminateNix => DETERMINATE_SELINUX_POLICY_PP_CONTENT,
+                            Distribution::Nix => SELINUX_POLICY_PP_CONTENT,
+                        },
+                    )
+                    .await
+                },
+            };
+            plan.push(provision_selinux.map_err(PlannerError::Action)?.boxed());
+        } else if self.selinux_policy.is_some() {
+            tracing::warn!("Not installing the given SELinux policy, SELinux was not detected");
+        }
+
+        if has_apparmor {
             plan.push(
-                ProvisionSelinux::plan(
-                    FHS_SELINUX_POLICY_PATH.into(),
-                    match self.settings.distribution() {
-                        Distribution::DeterminateNix => DETERMINATE_SELINUX_POLICY_PP_CONTENT,
-                        Distribution::Nix => SELINUX_POLICY_PP_CONTENT,
-                    },
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ProvisionAppArmor::plan(APPARMOR_PROFILE_PATH.into(), APPARMOR_PROFILE_CONTENT)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
             );
         }
 
@@ -129,6 +166,48 @@ impl Planner for Linux {
                 );
             },
         }
+        let daemon_command = match self.settings.distribution() {
+            Distribution::DeterminateNix => "/usr/local/bin/determinate-nixd daemon",
+            Distribution::Nix => "/nix/var/nix/profiles/default/bin/nix-daemon",
+        };
+        match self.init.init {
+            InitSystem::Wsl => plan.push(
+                ConfigureWslDaemon::plan(daemon_command, self.init.start_daemon)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            ),
+            InitSystem::Openrc => plan.push(
+                ConfigureOpenrcService::plan(daemon_command, self.init.start_daemon)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            ),
+            InitSystem::None | InitSystem::Systemd | InitSystem::Launchd => (),
+        }
+        if has_selinux {
+            plan.push(
+                VerifySelinuxLabels::plan(self.init.start_daemon)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+        if self.settings.repair_hooks_on_boot && self.settings.modify_profile {
+            if self.init.init == InitSystem::Systemd {
+                plan.push(
+                    ConfigureRepairHooksService::plan(self.init.start_daemon)
+                        .await
+                        .map_err(PlannerError::Action)?
+                        .boxed(),
+                );
+            } else {
+                tracing::warn!(
+                    "Not configuring the shell profile repair service, it requires `--init systemd`"
+                );
+            }
+        }
+
         plan.push(
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
                 .await
@@ -140,11 +219,19 @@ impl Planner for Linux {
     }
 
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
-        let Self { settings, init } = self;
+        let Self {
+            settings,
+            init,
+            selinux_policy,
+        } = self;
         let mut map = HashMap::default();
 
         map.extend(settings.settings()?);
         map.extend(init.settings()?);
+        map.insert(
+            "selinux_policy".to_string(),
+            serde_json::to_value(selinux_policy)?,
+        );
 
         Ok(map)
     }
@@ -224,6 +311,11 @@ pub(crate) fn check_not_wsl1() -> Result<(), PlannerError> {
     Ok(())
 }
 
+/// If running on Alpine, going by the `ID` of `/etc/os-release`
+pub(crate) fn detect_alpine() -> bool {
+    os_release::OsRelease::new().is_ok_and(|os_release| os_release.id == "alpine")
+}
+
 pub(crate) async fn detect_selinux() -> Result<bool, PlannerError> {
     if Path::new("/sys/fs/selinux").exists() && which("sestatus").is_ok() {
         // We expect systems with SELinux to have the normal SELinux tools.
@@ -239,6 +331,37 @@ pub(crate) async fn detect_selinux() -> Result<bool, PlannerError> {
     }
 }
 
+/// If AppArmor is enabled and restricts unprivileged user namespaces (`kernel.apparmor_restrict_unprivileged_userns`)
+///
+/// Systems without the restriction are left alone, their AppArmor may also predate the `userns` rule.
+pub(crate) async fn detect_apparmor() -> Result<bool, PlannerError> {
+    let enabled = tokio::fs::read_to_string("/sys/module/apparmor/parameters/enabled")
+        .await
+        .ok();
+    let restrict_userns =
+        tokio::fs::read_to_string("/proc/sys/kernel/apparmor_restrict_unprivileged_userns")
+            .await
+            .ok();
+
+    if apparmor_restricts_userns(enabled.as_deref(), restrict_userns.as_deref()) {
+        // We expect systems with AppArmor to have the normal AppArmor tools.
+        if which("apparmor_parser").is_err() {
+            Err(PlannerError::AppArmorRequirements)
+        } else {
+            Ok(true)
+        }
+    } else {
+        Ok(false)
+    }
+}
+
+/// If the AppArmor module parameter `enabled` and the `kernel.apparmor_restrict_unprivileged_userns`
+/// sysctl (`None` where missing) show user namespaces are restricted
+fn apparmor_restricts_userns(enabled: Option<&str>, restrict_userns: Option<&str>) -> bool {
+    enabled.is_some_and(|enabled| enabled.trim() == "Y")
+        && restrict_userns.is_some_and(|restrict| restrict.trim() == "1")
+}
+
 pub(crate) async fn check_nix_not_already_installed() -> Result<(), PlannerError> {
     // For now, we don't try to repair the user's Nix install or anything special.
     if Command::new("nix-env")
@@ -254,6 +377,19 @@ pub(crate) async fn check_nix_not_already_installed() -> Result<(), PlannerError
     Ok(())
 }
 
+/// Refuse to uninstall while processes other than the Nix daemon hold files under `/nix`
+///
+/// Processes are found through `/proc`, so this is only checked on Linux.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) async fn check_nix_not_in_use() -> Result<(), PlannerError> {
+    let processes = crate::os::linux::processes::processes_using_nix().await;
+    if !processes.is_empty() {
+        return Err(LinuxErrorKind::NixInUse(processes).into());
+    }
+
+    Ok(())
+}
+
 pub(crate) fn check_systemd_active() -> Result<(), PlannerError> {
     if !Path::new("/run/systemd/system").exists() {
         if std::env::var("WSL_DISTRO_NAME").is_ok() {
@@ -284,11 +420,22 @@ pub enum LinuxErrorKind {
         \n\
         On WSL2, systemd is not enabled by default. Consider enabling it by adding it to your `/etc/wsl.conf` with `echo -e '[boot]\\nsystemd=true'` then restarting WSL2 with `wsl.exe --shutdown` and re-entering the WSL shell. For more information, see https://devblogs.microsoft.com/commandline/systemd-support-is-now-available-in-wsl/.\n\
         \n\
+        To run the Nix daemon without systemd, started by WSL from `/etc/wsl.conf`, consider passing `--init wsl`.\n\
+        \n\
         If it will be started later, consider passing `--no-start-daemon`.\n\
         \n\
         To use a `root`-only Nix install, consider passing `--init none`."
     )]
     Wsl2SystemdNotActive,
+    #[error(
+        "\
+        These processes hold files under `/nix`, uninstalling now could leave the Nix store half deleted:\n\
+        {}\n\
+        \n\
+        Exit them, or pass `--kill-processes` to terminate them before uninstalling.",
+        .0.iter().map(|process| format!("* {process}")).collect::<Vec<_>>().join("\n")
+    )]
+    NixInUse(Vec<crate::os::linux::processes::NixProcess>),
 }
 
 impl HasExpectedErrors for LinuxErrorKind {
@@ -296,6 +443,7 @@ impl HasExpectedErrors for LinuxErrorKind {
         match self {
             LinuxErrorKind::SystemdNotActive => Some(Box::new(self)),
             LinuxErrorKind::Wsl2SystemdNotActive => Some(Box::new(self)),
+            LinuxErrorKind::NixInUse(_) => Some(Box::new(self)),
         }
     }
 }
@@ -305,3 +453,20 @@ impl From<LinuxErrorKind> for PlannerError {
         PlannerError::Custom(Box::new(v))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::apparmor_restricts_userns;
+
+    #[test]
+    fn apparmor_restriction_needs_both_settings() {
+        assert!(apparmor_restricts_userns(Some("Y\n"), Some("1\n")));
+        // AppArmor enabled, but user namespaces aren't restricted (before Ubuntu 23.10)
+        assert!(!apparmor_restricts_userns(Some("Y\n"), Some("0\n")));
+        assert!(!apparmor_restricts_userns(Some("Y\n"), None));
+        // The sysctl outlives disabling AppArmor
+        assert!(!apparmor_restricts_userns(Some("N\n"), Some("1\n")));
+        assert!(!apparmor_restricts_userns(None, Some("1\n")));
+        assert!(!apparmor_restricts_userns(None, None));
+    }
+}
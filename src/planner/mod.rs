@@ -101,6 +101,7 @@ match plan.install(feedback::devnull::DevNull{}, None).await {
 ```
 
 */
+pub mod custom;
 pub mod linux;
 pub mod macos;
 pub mod ostree;
@@ -112,7 +113,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     action::{ActionError, StatefulAction},
-    error::HasExpectedErrors,
+    error::{HasErrorCode, HasExpectedErrors},
     settings::{CommonSettings, InstallSettingsError},
     Action, InstallPlan, NixInstallerError,
 };
@@ -170,49 +171,59 @@ pub enum BuiltinPlanner {
     #[cfg_attr(not(target_os = "macos"), clap(hide = true))]
     /// A planner for MacOS (Darwin) systems
     Macos(macos::Macos),
+    /// A planner which plans the actions listed in a JSON document
+    Custom(custom::Custom),
 }
 
 impl BuiltinPlanner {
     /// Heuristically determine the default planner for the target system
     pub async fn default() -> Result<Self, PlannerError> {
+        Ok(Self::detect().await?.0)
+    }
+
+    /// Like [`BuiltinPlanner::default`], along with why that planner was chosen
+    pub async fn detect() -> Result<(Self, String), PlannerError> {
         use target_lexicon::{Architecture, OperatingSystem};
         match (Architecture::host(), OperatingSystem::host()) {
             (Architecture::X86_64, OperatingSystem::Linux) => Self::detect_linux_distro().await,
-            (Architecture::X86_32(_), OperatingSystem::Linux) => {
-                Ok(Self::Linux(linux::Linux::default().await?))
-            },
-            (Architecture::Aarch64(_), OperatingSystem::Linux) => {
-                Ok(Self::Linux(linux::Linux::default().await?))
-            },
+            (Architecture::X86_32(_), OperatingSystem::Linux) => Ok((
+                Self::Linux(linux::Linux::default().await?),
+                "32 bit x86 Linux hosts always use the `linux` planner".to_string(),
+            )),
+            (Architecture::Aarch64(_), OperatingSystem::Linux) => Ok((
+                Self::Linux(linux::Linux::default().await?),
+                "aarch64 Linux hosts always use the `linux` planner".to_string(),
+            )),
             (Architecture::X86_64, OperatingSystem::MacOSX { .. })
-            | (Architecture::X86_64, OperatingSystem::Darwin) => {
-                Ok(Self::Macos(macos::Macos::default().await?))
-            },
-            (Architecture::Aarch64(_), OperatingSystem::MacOSX { .. })
-            | (Architecture::Aarch64(_), OperatingSystem::Darwin) => {
-                Ok(Self::Macos(macos::Macos::default().await?))
-            },
+            | (Architecture::X86_64, OperatingSystem::Darwin)
+            | (Architecture::Aarch64(_), OperatingSystem::MacOSX { .. })
+            | (Architecture::Aarch64(_), OperatingSystem::Darwin) => Ok((
+                Self::Macos(macos::Macos::default().await?),
+                "macOS hosts always use the `macos` planner".to_string(),
+            )),
             _ => Err(PlannerError::UnsupportedArchitecture(target_lexicon::HOST)),
         }
     }
 
-    async fn detect_linux_distro() -> Result<Self, PlannerError> {
-        let is_steam_deck =
-            os_release::OsRelease::new().is_ok_and(|os_release| os_release.id == "steamos");
-        if is_steam_deck {
-            return Ok(Self::SteamDeck(steam_deck::SteamDeck::default().await?));
+    async fn detect_linux_distro() -> Result<(Self, String), PlannerError> {
+        if steam_deck::detect_steamos() {
+            return Ok((
+                Self::SteamDeck(steam_deck::SteamDeck::default().await?),
+                "`/etc/os-release` has `ID=steamos`".to_string(),
+            ));
         }
 
-        let is_ostree = std::process::Command::new("ostree")
-            .arg("remote")
-            .arg("list")
-            .output()
-            .is_ok_and(|output| output.status.success());
-        if is_ostree {
-            return Ok(Self::Ostree(ostree::Ostree::default().await?));
+        if ostree::detect_ostree() {
+            return Ok((
+                Self::Ostree(ostree::Ostree::default().await?),
+                "`ostree remote list` succeeded".to_string(),
+            ));
         }
 
-        Ok(Self::Linux(linux::Linux::default().await?))
+        Ok((
+            Self::Linux(linux::Linux::default().await?),
+            "Neither SteamOS nor ostree were detected".to_string(),
+        ))
     }
 
     pub async fn from_common_settings(settings: CommonSettings) -> Result<Self, PlannerError> {
@@ -222,6 +233,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(inner) => inner.settings = settings,
             BuiltinPlanner::Ostree(inner) => inner.settings = settings,
             BuiltinPlanner::Macos(inner) => inner.settings = settings,
+            BuiltinPlanner::Custom(inner) => inner.settings = settings,
         }
         Ok(built)
     }
@@ -232,6 +244,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(inner) => &inner.settings,
             BuiltinPlanner::Ostree(inner) => &inner.settings,
             BuiltinPlanner::Macos(inner) => &inner.settings,
+            BuiltinPlanner::Custom(inner) => &inner.settings,
         }
     }
 
@@ -241,6 +254,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(inner) => &mut inner.settings,
             BuiltinPlanner::Ostree(inner) => &mut inner.settings,
             BuiltinPlanner::Macos(inner) => &mut inner.settings,
+            BuiltinPlanner::Custom(inner) => &mut inner.settings,
         }
     }
 
@@ -252,6 +266,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(inner) => inner.configured_settings().await,
             BuiltinPlanner::Ostree(inner) => inner.configured_settings().await,
             BuiltinPlanner::Macos(inner) => inner.configured_settings().await,
+            BuiltinPlanner::Custom(inner) => inner.configured_settings().await,
         }
     }
 
@@ -261,6 +276,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(planner) => InstallPlan::plan(planner).await,
             BuiltinPlanner::Ostree(planner) => InstallPlan::plan(planner).await,
             BuiltinPlanner::Macos(planner) => InstallPlan::plan(planner).await,
+            BuiltinPlanner::Custom(planner) => InstallPlan::plan(planner).await,
         }
     }
     pub fn boxed(self) -> Box<dyn Planner> {
@@ -269,6 +285,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(i) => i.boxed(),
             BuiltinPlanner::Ostree(i) => i.boxed(),
             BuiltinPlanner::Macos(i) => i.boxed(),
+            BuiltinPlanner::Custom(i) => i.boxed(),
         }
     }
 
@@ -278,6 +295,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(i) => i.typetag_name(),
             BuiltinPlanner::Ostree(i) => i.typetag_name(),
             BuiltinPlanner::Macos(i) => i.typetag_name(),
+            BuiltinPlanner::Custom(i) => i.typetag_name(),
         }
     }
 
@@ -287,6 +305,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::SteamDeck(i) => i.settings(),
             BuiltinPlanner::Ostree(i) => i.settings(),
             BuiltinPlanner::Macos(i) => i.settings(),
+            BuiltinPlanner::Custom(i) => i.settings(),
         }
     }
 }
@@ -296,6 +315,17 @@ pub struct ShellProfileLocations {
     pub fish: FishShellProfileLocations,
     pub bash: Vec<PathBuf>,
     pub zsh: Vec<PathBuf>,
+    #[serde(default)]
+    pub nushell: NushellProfileLocations,
+    /// Only written if `xonsh` is installed, or the file already exists
+    #[serde(default)]
+    pub xonsh: Vec<PathBuf>,
+    /// Only written if `elvish` is installed, or the file already exists
+    #[serde(default)]
+    pub elvish: Vec<PathBuf>,
+    /// Read by both `tcsh` and `csh`, only written if either is installed, or the file already exists
+    #[serde(default)]
+    pub csh: Vec<PathBuf>,
 }
 
 impl Default for ShellProfileLocations {
@@ -312,6 +342,22 @@ impl Default for ShellProfileLocations {
                 "/etc/zshrc".into(),
                 "/etc/zsh/zshrc".into(),
             ],
+            nushell: NushellProfileLocations::default(),
+            // https://xon.sh/xonshrc.html
+            xonsh: vec!["/etc/xonshrc".into()],
+            elvish: vec!["/etc/elvish/rc.elv".into()],
+            csh: vec!["/etc/csh.login".into()],
+        }
+    }
+}
+
+impl ShellProfileLocations {
+    /// Alpine's `/etc/profile` (read by busybox `ash` and login shells) sources `/etc/profile.d/*.sh`, its `bash` and `zsh` keep their configuration in directories
+    pub fn alpine() -> Self {
+        Self {
+            bash: vec!["/etc/profile.d/nix.sh".into(), "/etc/bash/bashrc".into()],
+            zsh: vec!["/etc/zsh/zshrc".into()],
+            ..Self::default()
         }
     }
 }
@@ -353,6 +399,32 @@ impl Default for FishShellProfileLocations {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct NushellProfileLocations {
+    /// Nushell cannot source POSIX shell scripts, treat it separate
+    pub vendor_autoload_suffix: PathBuf,
+    /**
+    Each of these are common values of `$XDG_DATA_DIRS` joined with `nushell`, under which
+    Nushell will look for the file named by `vendor_autoload_suffix`.
+
+    More info: <https://www.nushell.sh/book/configuration.html#configuration-overview>
+    */
+    pub vendor_autoload_prefixes: Vec<PathBuf>,
+}
+
+impl Default for NushellProfileLocations {
+    fn default() -> Self {
+        Self {
+            vendor_autoload_prefixes: vec![
+                "/usr/share/nushell/".into(),
+                "/usr/local/share/nushell/".into(),
+                "/opt/homebrew/share/nushell/".into(), // homebrew
+            ],
+            vendor_autoload_suffix: "vendor/autoload/nix.nu".into(),
+        }
+    }
+}
+
 /// An error originating from a [`Planner`]
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
@@ -392,6 +464,9 @@ pub enum PlannerError {
     /// A Linux SELinux related error
     #[error("Unable to install on an SELinux system without common SELinux tooling, the binaries `restorecon`, and `semodule` are required")]
     SelinuxRequirements,
+    /// A Linux AppArmor related error
+    #[error("Unable to install on an AppArmor system which restricts unprivileged user namespaces without common AppArmor tooling, the binary `apparmor_parser` is required")]
+    AppArmorRequirements,
     /// A UTF-8 related error
     #[error("UTF-8 error")]
     Utf8(#[from] FromUtf8Error),
@@ -400,7 +475,7 @@ pub enum PlannerError {
     Custom(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[error("NixOS already has Nix installed")]
     NixOs,
-    #[error("`nix` is already a valid command, so it is installed")]
+    #[error("`nix` is already a valid command, so it is installed. If it was installed without this installer, `nix-installer adopt` can record it so it can be uninstalled or repaired")]
     NixExists,
     #[error("WSL1 is not supported, please upgrade to WSL2: https://learn.microsoft.com/en-us/windows/wsl/install#upgrade-version-from-wsl-1-to-wsl-2")]
     Wsl1,
@@ -427,6 +502,7 @@ impl HasExpectedErrors for PlannerError {
             PlannerError::OsRelease(_) => None,
             PlannerError::Utf8(_) => None,
             PlannerError::SelinuxRequirements => Some(Box::new(self)),
+            PlannerError::AppArmorRequirements => Some(Box::new(self)),
             PlannerError::Custom(_e) => {
                 #[cfg(target_os = "linux")]
                 if let Some(err) = _e.downcast_ref::<linux::LinuxErrorKind>() {
@@ -436,6 +512,9 @@ impl HasExpectedErrors for PlannerError {
                 if let Some(err) = _e.downcast_ref::<macos::MacosError>() {
                     return err.expected();
                 }
+                if let Some(err) = _e.downcast_ref::<custom::CustomError>() {
+                    return err.expected();
+                }
                 None
             },
             this @ PlannerError::NixOs => Some(Box::new(this)),
@@ -448,6 +527,41 @@ impl HasExpectedErrors for PlannerError {
     }
 }
 
+impl HasErrorCode for PlannerError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            PlannerError::IncompatibleOperatingSystem { .. } => "NI-PLAN-0001",
+            PlannerError::UnsupportedArchitecture(_) => "NI-PLAN-0002",
+            PlannerError::Action(_) => "NI-PLAN-0003",
+            PlannerError::InstallSettings(_) => "NI-PLAN-0004",
+            PlannerError::OsRelease(_) => "NI-PLAN-0005",
+            PlannerError::Plist(_) => "NI-PLAN-0006",
+            PlannerError::Sysctl(_) => "NI-PLAN-0007",
+            PlannerError::RosettaDetected => "NI-PLAN-0008",
+            PlannerError::DeterminateNixUnavailable => "NI-PLAN-0009",
+            PlannerError::Ec2InstanceStoreRequiresDeterminateNix => "NI-PLAN-0010",
+            PlannerError::SelinuxRequirements => "NI-PLAN-0011",
+            PlannerError::AppArmorRequirements => "NI-PLAN-0012",
+            PlannerError::Utf8(_) => "NI-PLAN-0013",
+            PlannerError::Custom(_) => "NI-PLAN-0014",
+            PlannerError::NixOs => "NI-PLAN-0015",
+            PlannerError::NixExists => "NI-PLAN-0016",
+            PlannerError::Wsl1 => "NI-PLAN-0017",
+            PlannerError::Command(..) => "NI-PLAN-0018",
+            #[cfg(feature = "diagnostics")]
+            PlannerError::Diagnostic(_) => "NI-PLAN-0019",
+        }
+    }
+
+    fn error_codes(&self) -> Vec<&'static str> {
+        let mut codes = vec![self.error_code()];
+        if let PlannerError::Action(action_error) = self {
+            codes.extend(action_error.kind().error_codes());
+        }
+        codes
+    }
+}
+
 #[cfg(feature = "diagnostics")]
 impl crate::diagnostics::ErrorDiagnostic for PlannerError {
     fn diagnostic(&self) -> String {
@@ -455,3 +569,59 @@ impl crate::diagnostics::ErrorDiagnostic for PlannerError {
         static_str.to_string()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::ShellProfileLocations;
+
+    #[test]
+    fn default_profile_locations_of_other_shells() {
+        let locations = ShellProfileLocations::default();
+        assert_eq!(
+            locations
+                .nushell
+                .vendor_autoload_prefixes
+                .iter()
+                .map(|prefix| prefix.join(&locations.nushell.vendor_autoload_suffix))
+                .collect::<Vec<_>>(),
+            [
+                "/usr/share/nushell/vendor/autoload/nix.nu",
+                "/usr/local/share/nushell/vendor/autoload/nix.nu",
+                "/opt/homebrew/share/nushell/vendor/autoload/nix.nu",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(locations.xonsh, [PathBuf::from("/etc/xonshrc")]);
+        assert_eq!(locations.elvish, [PathBuf::from("/etc/elvish/rc.elv")]);
+        assert_eq!(locations.csh, [PathBuf::from("/etc/csh.login")]);
+
+        // Alpine only moves the bash and zsh profiles
+        let alpine = ShellProfileLocations::alpine();
+        assert_eq!(alpine.nushell, locations.nushell);
+        assert_eq!(alpine.xonsh, locations.xonsh);
+        assert_eq!(alpine.elvish, locations.elvish);
+        assert_eq!(alpine.csh, locations.csh);
+    }
+
+    #[test]
+    fn profile_locations_of_other_shells_are_optional() -> serde_json::Result<()> {
+        // Receipts written before these shells were supported don't list them
+        let locations: ShellProfileLocations = serde_json::from_value(serde_json::json!({
+            "fish": {
+                "confd_suffix": "conf.d/nix.fish",
+                "confd_prefixes": ["/etc/fish"],
+                "vendor_confd_suffix": "vendor_conf.d/nix.fish",
+                "vendor_confd_prefixes": [],
+            },
+            "bash": ["/etc/bashrc"],
+            "zsh": ["/etc/zshrc"],
+        }))?;
+        assert_eq!(locations.nushell, ShellProfileLocations::default().nushell);
+        assert!(locations.xonsh.is_empty());
+        assert!(locations.elvish.is_empty());
+        assert!(locations.csh.is_empty());
+        Ok(())
+    }
+}
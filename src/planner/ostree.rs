@@ -6,8 +6,10 @@ use crate::{
             CreateUsersAndGroups, ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
+            provision_apparmor::APPARMOR_PROFILE_CONTENT,
             provision_selinux::{DETERMINATE_SELINUX_POLICY_PP_CONTENT, SELINUX_POLICY_PP_CONTENT},
-            ProvisionSelinux, StartSystemdUnit, SystemctlDaemonReload,
+            ConfigureRepairHooksService, ProvisionAppArmor, ProvisionSelinux, StartSystemdUnit,
+            SystemctlDaemonReload, VerifySelinuxLabels,
         },
         StatefulAction,
     },
@@ -17,12 +19,15 @@ use crate::{
     settings::{CommonSettings, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use super::{
     linux::{
         check_nix_not_already_installed, check_not_nixos, check_not_wsl1, check_systemd_active,
-        detect_selinux,
+        detect_apparmor, detect_selinux, APPARMOR_PROFILE_PATH,
     },
     ShellProfileLocations,
 };
@@ -36,6 +41,10 @@ pub struct Ostree {
     persistence: PathBuf,
     #[cfg_attr(feature = "cli", clap(flatten))]
     pub settings: CommonSettings,
+    /// A site-specific SELinux policy to install instead of the builtin one, either a `.te` source (compiled along with a `.fc` of the same name, if any) or a compiled `.pp`
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_SELINUX_POLICY"))]
+    #[serde(default)]
+    pub selinux_policy: Option<PathBuf>,
 }
 
 #[async_trait::async_trait]
@@ -45,11 +54,13 @@ impl Planner for Ostree {
         Ok(Self {
             persistence: PathBuf::from("/var/home/nix"),
             settings: CommonSettings::default().await?,
+            selinux_policy: None,
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
         let has_selinux = detect_selinux().await?;
+        let has_apparmor = detect_apparmor().await?;
         let mut plan = vec![
             // Primarily for uninstall
             SystemctlDaemonReload::plan()
@@ -162,7 +173,7 @@ impl Planner for Ostree {
             .fish
             .vendor_confd_prefixes
             .iter()
-            .position(|v| *v == PathBuf::from("/usr/share/fish/"))
+            .position(|v| v == std::path::Path::new("/usr/share/fish/"))
         {
             shell_profile_locations
                 .fish
@@ -206,18 +217,38 @@ impl Planner for Ostree {
         );
 
         if has_selinux {
+            let provision_selinux = match &self.selinux_policy {
+                Some(selinux_policy) => {
+                    ProvisionSelinux::plan_from_file(
+                        Path::new("/etc/nix-installer/selinux/packages"),
+                        selinux_policy,
+                    )
+                    .await
+                },
+                None => {
+                    ProvisionSelinux::plan(
+                        "/etc/nix-installer/selinux/packages/nix.pp".into(),
+                        if self.settings.distribution() == Distribution::DeterminateNix {
+                            DETERMINATE_SELINUX_POLICY_PP_CONTENT
+                        } else {
+                            SELINUX_POLICY_PP_CONTENT
+                        },
+                    )
+                    .await
+                },
+            };
+            plan.push(provision_selinux.map_err(PlannerError::Action)?.boxed());
+        } else if self.selinux_policy.is_some() {
+            tracing::warn!("Not installing the given SELinux policy, SELinux was not detected");
+        }
+
+        // `/etc` stays writable on ostree systems, so the profile goes in the usual place
+        if has_apparmor {
             plan.push(
-                ProvisionSelinux::plan(
-                    "/etc/nix-installer/selinux/packages/nix.pp".into(),
-                    if self.settings.distribution() == Distribution::DeterminateNix {
-                        DETERMINATE_SELINUX_POLICY_PP_CONTENT
-                    } else {
-                        SELINUX_POLICY_PP_CONTENT
-                    },
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ProvisionAppArmor::plan(APPARMOR_PROFILE_PATH.into(), APPARMOR_PROFILE_CONTENT)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
             );
         }
 
@@ -248,6 +279,14 @@ impl Planner for Ostree {
                 );
             },
         }
+        if has_selinux {
+            plan.push(
+                VerifySelinuxLabels::plan(start_daemon)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
 
         plan.push(
             StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
@@ -255,6 +294,15 @@ impl Planner for Ostree {
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
+        if self.settings.repair_hooks_on_boot && self.settings.modify_profile {
+            plan.push(
+                ConfigureRepairHooksService::plan(start_daemon)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
         plan.push(
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
                 .await
@@ -275,6 +323,7 @@ impl Planner for Ostree {
         let Self {
             persistence,
             settings,
+            selinux_policy,
         } = self;
         let mut map = HashMap::default();
 
@@ -283,6 +332,10 @@ impl Planner for Ostree {
             "persistence".to_string(),
             serde_json::to_value(persistence)?,
         );
+        map.insert(
+            "selinux_policy".to_string(),
+            serde_json::to_value(selinux_policy)?,
+        );
 
         Ok(map)
     }
@@ -341,6 +394,15 @@ impl From<Ostree> for BuiltinPlanner {
     }
 }
 
+/// If the system is managed by ostree, going by whether `ostree remote list` succeeds
+pub(crate) fn detect_ostree() -> bool {
+    std::process::Command::new("ostree")
+        .arg("remote")
+        .arg("list")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum OstreeError {
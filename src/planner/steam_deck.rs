@@ -108,8 +108,8 @@ use crate::{
             ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
-            EnsureSteamosNixDirectory, RevertCleanSteamosNixOffload, StartSystemdUnit,
-            SystemctlDaemonReload,
+            ConfigureRepairHooksService, EnsureSteamosNixDirectory, RevertCleanSteamosNixOffload,
+            StartSystemdUnit, SystemctlDaemonReload,
         },
         Action, StatefulAction,
     },
@@ -122,6 +122,9 @@ use crate::{
 use super::ShellProfileLocations;
 
 /// A planner for the Valve Steam Deck running SteamOS
+///
+/// SteamOS enables neither SELinux nor AppArmor, so unlike the `linux` and `ostree` planners this
+/// one installs no SELinux policy (and has no `--selinux-policy`) and no AppArmor profile.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "cli", derive(clap::Parser))]
 pub struct SteamDeck {
@@ -327,7 +330,7 @@ impl Planner for SteamDeck {
             .fish
             .vendor_confd_prefixes
             .iter()
-            .position(|v| *v == PathBuf::from("/usr/share/fish/"))
+            .position(|v| v == std::path::Path::new("/usr/share/fish/"))
         {
             shell_profile_locations
                 .fish
@@ -375,6 +378,18 @@ impl Planner for SteamDeck {
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+        ]);
+
+        if self.settings.repair_hooks_on_boot && self.settings.modify_profile {
+            actions.push(
+                ConfigureRepairHooksService::plan(true)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
+        actions.append(&mut vec![
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
                 .await
                 .map_err(PlannerError::Action)?
@@ -472,6 +487,11 @@ pub enum SteamDeckError {
     NixMountSystemctlDaemonReloadRequired,
 }
 
+/// If running on SteamOS, going by the `ID` of `/etc/os-release`
+pub(crate) fn detect_steamos() -> bool {
+    os_release::OsRelease::new().is_ok_and(|os_release| os_release.id == "steamos")
+}
+
 pub(crate) async fn detect_requires_bind_mount() -> Result<bool, PlannerError> {
     let steamos_nix_mount_unit_path = "/usr/lib/systemd/system/nix.mount";
     let nix_mount_unit = tokio::fs::read_to_string(steamos_nix_mount_unit_path)
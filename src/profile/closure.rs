@@ -0,0 +1,433 @@
+/*! Exporting the default profile's closure before an uninstall, and importing it after an install */
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde_json::Value;
+use tokio::process::Command;
+
+pub(crate) const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
+/// Export the closure of the default profile's packages and of `extra_roots` to `dest`, in the
+/// format of `nix-store --export`, returning the number of store paths exported
+///
+/// `dest` is written even if there is nothing to export, so importing it always succeeds.
+pub(crate) async fn export_closure(
+    extra_roots: &[PathBuf],
+    dest: &Path,
+) -> Result<usize, super::Error> {
+    let nix_store = Path::new(DEFAULT_PROFILE).join("bin/nix-store");
+
+    let mut roots = profile_packages(&nix_store, Path::new(DEFAULT_PROFILE)).await?;
+    for root in extra_roots {
+        let resolved = root
+            .canonicalize()
+            .map_err(|e| super::Error::ReadClosure(root.clone(), e))?;
+        let root_store_path =
+            store_path_of(&resolved).ok_or_else(|| super::Error::NotInStore(root.clone()))?;
+        roots.push(root_store_path);
+    }
+
+    let closure = if roots.is_empty() {
+        vec![]
+    } else {
+        query(&nix_store, "--requisites", &roots).await?
+    };
+
+    let file =
+        std::fs::File::create(dest).map_err(|e| super::Error::WriteClosure(dest.into(), e))?;
+    let output = Command::new(&nix_store)
+        .process_group(0)
+        .arg("--export")
+        .args(&closure)
+        .env_remove("NIX_REMOTE")
+        .stdin(Stdio::null())
+        .stdout(file)
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| super::Error::StartNixCommand("nix-store --export".to_string(), e))?;
+    if !output.status.success() {
+        return Err(super::Error::NixCommand(
+            "nix-store --export".to_string(),
+            output,
+        ));
+    }
+
+    Ok(closure.len())
+}
+
+/// Import a closure written by [`export_closure`], then install its top level packages into the
+/// default profile, returning them
+///
+/// Packages named like one already in the default profile, such as `nix` and `nss-cacert`, are
+/// left out so the freshly installed versions are kept.
+pub(crate) async fn import_closure(src: &Path) -> Result<Vec<PathBuf>, super::Error> {
+    let nix_store = Path::new(DEFAULT_PROFILE).join("bin/nix-store");
+
+    let file = std::fs::File::open(src).map_err(|e| super::Error::ReadClosure(src.into(), e))?;
+    let output = Command::new(&nix_store)
+        .process_group(0)
+        .arg("--import")
+        .env_remove("NIX_REMOTE")
+        .stdin(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| super::Error::StartNixCommand("nix-store --import".to_string(), e))?;
+    if !output.status.success() {
+        return Err(super::Error::NixCommand(
+            "nix-store --import".to_string(),
+            output,
+        ));
+    }
+    let imported = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+    // The top level packages are those no other imported path refers to
+    let mut referenced = HashSet::new();
+    if !imported.is_empty() {
+        let nix = Path::new(DEFAULT_PROFILE).join("bin/nix");
+        for (path, references) in references(&nix, &imported).await? {
+            referenced.extend(
+                references
+                    .into_iter()
+                    .filter(|reference| *reference != path),
+            );
+        }
+    }
+
+    let profile = Path::new(DEFAULT_PROFILE)
+        .canonicalize()
+        .map_err(|e| super::Error::ReadClosure(PathBuf::from(DEFAULT_PROFILE), e))?;
+    let installed = query(&nix_store, "--references", &[profile]).await?;
+    let installed_names = installed
+        .iter()
+        .filter_map(|path| package_name(path))
+        .collect::<HashSet<_>>();
+
+    let pkgs = imported
+        .iter()
+        .filter(|path| !referenced.contains(*path))
+        .filter(|path| package_name(path).is_some_and(|name| !installed_names.contains(name)))
+        .cloned()
+        .collect::<Vec<_>>();
+    if pkgs.is_empty() {
+        return Ok(pkgs);
+    }
+
+    let nix_pkg = resolve_store_path(&Path::new(DEFAULT_PROFILE).join("bin/nix"))?;
+    let nss_ca_cert_pkg =
+        resolve_store_path(&Path::new(DEFAULT_PROFILE).join("etc/ssl/certs/ca-bundle.crt"))?;
+    super::Profile {
+        nix_store_path: &nix_pkg,
+        nss_ca_cert_path: &nss_ca_cert_pkg,
+        profile: Path::new(DEFAULT_PROFILE),
+        pkgs: &pkgs.iter().map(PathBuf::as_path).collect::<Vec<_>>(),
+    }
+    .install_packages(super::WriteToDefaultProfile::WriteToDefault)
+    .await?;
+
+    Ok(pkgs)
+}
+
+/// The store paths of the packages installed in `profile`
+///
+/// These are read from the manifest of `nix profile` or `nix-env`, as the profile itself also
+/// refers to the manifest of `nix-env`, which is not a package.
+async fn profile_packages(nix_store: &Path, profile: &Path) -> Result<Vec<PathBuf>, super::Error> {
+    let read = |manifest: PathBuf| async move {
+        match tokio::fs::read_to_string(&manifest).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(super::Error::ReadClosure(manifest, e)),
+        }
+    };
+    if let Some(manifest) = read(profile.join("manifest.json")).await? {
+        return Ok(manifest_json_store_paths(&manifest)?);
+    }
+    if let Some(manifest) = read(profile.join("manifest.nix")).await? {
+        return Ok(manifest_nix_store_paths(&manifest));
+    }
+
+    let profile = profile
+        .canonicalize()
+        .map_err(|e| super::Error::ReadClosure(profile.into(), e))?;
+    query(nix_store, "--references", &[profile]).await
+}
+
+/// The store paths of the elements of a `nix profile` `manifest.json`
+///
+/// `elements` is a list up to version 2 of the manifest, and a map of element names since.
+fn manifest_json_store_paths(manifest: &str) -> Result<Vec<PathBuf>, serde_json::Error> {
+    let manifest: Value = serde_json::from_str(manifest)?;
+    let elements: Vec<&Value> = match &manifest["elements"] {
+        Value::Array(elements) => elements.iter().collect(),
+        Value::Object(elements) => elements.values().collect(),
+        _ => vec![],
+    };
+    let mut store_paths = vec![];
+    for element in elements {
+        if element["active"] == Value::Bool(false) {
+            continue;
+        }
+        for store_path in element["storePaths"].as_array().into_iter().flatten() {
+            if let Some(store_path) = store_path.as_str() {
+                store_paths.push(PathBuf::from(store_path));
+            }
+        }
+    }
+    Ok(store_paths)
+}
+
+/// The store paths of the outputs in a `nix-env` `manifest.nix`, which assigns each as `outPath`
+fn manifest_nix_store_paths(manifest: &str) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    manifest
+        .split("outPath = \"")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('"'))
+        .map(|(store_path, _)| PathBuf::from(store_path))
+        .filter(|store_path| store_path.starts_with("/nix/store"))
+        .filter(|store_path| seen.insert(store_path.clone()))
+        .collect()
+}
+
+/// The references of each of `paths`, from one `nix path-info` instead of a query per path
+async fn references(
+    nix: &Path,
+    paths: &[PathBuf],
+) -> Result<HashMap<PathBuf, Vec<PathBuf>>, super::Error> {
+    let command = "nix path-info --json".to_string();
+    let output = Command::new(nix)
+        .process_group(0)
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--json",
+        ])
+        .args(paths)
+        .env_remove("NIX_REMOTE")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| super::Error::StartNixCommand(command.clone(), e))?;
+    if !output.status.success() {
+        return Err(super::Error::NixCommand(command, output));
+    }
+    Ok(path_info_references(&serde_json::from_slice(
+        &output.stdout,
+    )?))
+}
+
+/// The references of each path in the output of `nix path-info --json`
+///
+/// The output is a list of objects with a `path` before Nix 2.19, and a map keyed by path since.
+fn path_info_references(path_info: &Value) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let infos: Vec<(&str, &Value)> = match path_info {
+        Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((info["path"].as_str()?, info)))
+            .collect(),
+        Value::Object(infos) => infos
+            .iter()
+            .map(|(path, info)| (path.as_str(), info))
+            .collect(),
+        _ => vec![],
+    };
+    let store_path = |path: &str| Path::new("/nix/store").join(path);
+    infos
+        .into_iter()
+        .map(|(path, info)| {
+            let references = info["references"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(store_path)
+                .collect();
+            (store_path(path), references)
+        })
+        .collect()
+}
+
+async fn query(
+    nix_store: &Path,
+    flag: &str,
+    paths: &[PathBuf],
+) -> Result<Vec<PathBuf>, super::Error> {
+    let command = format!("nix-store --query {flag}");
+    let output = Command::new(nix_store)
+        .process_group(0)
+        .args(["--query", flag])
+        .args(paths)
+        .env_remove("NIX_REMOTE")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| super::Error::StartNixCommand(command.clone(), e))?;
+    if !output.status.success() {
+        return Err(super::Error::NixCommand(command, output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn resolve_store_path(path: &Path) -> Result<PathBuf, super::Error> {
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| super::Error::ReadClosure(path.into(), e))?;
+    store_path_of(&resolved).ok_or_else(|| super::Error::NotInStore(path.into()))
+}
+
+/// The store path containing `path`, like `/nix/store/<hash>-<name>`
+fn store_path_of(path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix("/nix/store").ok()?;
+    let name = relative.components().next()?;
+    Some(Path::new("/nix/store").join(name))
+}
+
+/// The name of the package at a store path, without its hash or version
+fn package_name(path: &Path) -> Option<&str> {
+    let (_hash, name_version) = path.file_name()?.to_str()?.split_once('-')?;
+    let version_start = name_version
+        .match_indices('-')
+        .find(|(index, _)| {
+            name_version[index + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_digit())
+        })
+        .map(|(index, _)| index);
+    Some(match version_start {
+        Some(index) => &name_version[..index],
+        None => name_version,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        manifest_json_store_paths, manifest_nix_store_paths, package_name, path_info_references,
+        store_path_of,
+    };
+
+    #[test]
+    fn package_names() {
+        let name = |path: &str| package_name(Path::new(path)).map(String::from);
+        assert_eq!(
+            name("/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-nix-2.24.10"),
+            Some("nix".into())
+        );
+        assert_eq!(
+            name("/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-nss-cacert-3.101"),
+            Some("nss-cacert".into())
+        );
+        assert_eq!(
+            name("/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-nix-direnv-3.0.6"),
+            Some("nix-direnv".into())
+        );
+        assert_eq!(
+            name("/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-hello"),
+            Some("hello".into())
+        );
+    }
+
+    #[test]
+    fn store_paths() {
+        assert_eq!(
+            store_path_of(Path::new(
+                "/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-nix-2.24.10/bin/nix"
+            )),
+            Some(Path::new("/nix/store/0n2zs6ggp6qyhbsnqgryg5d4gmhzbj4r-nix-2.24.10").into())
+        );
+        assert_eq!(store_path_of(Path::new("/etc/profile")), None);
+    }
+
+    #[test]
+    fn nix_profile_manifests() -> Result<(), serde_json::Error> {
+        let v2 = r#"{"version": 2, "elements": [
+            {"active": true, "storePaths": ["/nix/store/aaaa-hello-2.12"]},
+            {"active": false, "storePaths": ["/nix/store/bbbb-cowsay-3.7"]},
+            {"storePaths": ["/nix/store/cccc-jq-1.7-bin", "/nix/store/dddd-jq-1.7-man"]}
+        ]}"#;
+        assert_eq!(
+            manifest_json_store_paths(v2)?,
+            [
+                Path::new("/nix/store/aaaa-hello-2.12"),
+                Path::new("/nix/store/cccc-jq-1.7-bin"),
+                Path::new("/nix/store/dddd-jq-1.7-man"),
+            ]
+        );
+
+        let v3 = r#"{"version": 3, "elements": {
+            "hello": {"active": true, "storePaths": ["/nix/store/aaaa-hello-2.12"]}
+        }}"#;
+        assert_eq!(
+            manifest_json_store_paths(v3)?,
+            [Path::new("/nix/store/aaaa-hello-2.12")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn nix_env_manifests() {
+        let manifest = r#"[ { meta = { description = "A program"; }; name = "hello-2.12"; out = { outPath = "/nix/store/aaaa-hello-2.12"; }; outPath = "/nix/store/aaaa-hello-2.12"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { bin = { outPath = "/nix/store/cccc-jq-1.7-bin"; }; man = { outPath = "/nix/store/dddd-jq-1.7-man"; }; name = "jq-1.7"; outPath = "/nix/store/cccc-jq-1.7-bin"; outputs = [ "bin" "man" ]; type = "derivation"; } ]"#;
+        assert_eq!(
+            manifest_nix_store_paths(manifest),
+            [
+                Path::new("/nix/store/aaaa-hello-2.12"),
+                Path::new("/nix/store/cccc-jq-1.7-bin"),
+                Path::new("/nix/store/dddd-jq-1.7-man"),
+            ]
+        );
+    }
+
+    #[test]
+    fn path_info_formats() {
+        let expected = [
+            (
+                Path::new("/nix/store/aaaa-hello-2.12").to_path_buf(),
+                vec![
+                    Path::new("/nix/store/aaaa-hello-2.12").to_path_buf(),
+                    Path::new("/nix/store/eeee-glibc-2.40").to_path_buf(),
+                ],
+            ),
+            (
+                Path::new("/nix/store/eeee-glibc-2.40").to_path_buf(),
+                vec![],
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let list = serde_json::json!([
+            {"path": "/nix/store/aaaa-hello-2.12", "references": ["/nix/store/aaaa-hello-2.12", "/nix/store/eeee-glibc-2.40"]},
+            {"path": "/nix/store/eeee-glibc-2.40", "references": []},
+        ]);
+        assert_eq!(path_info_references(&list), expected);
+
+        let map = serde_json::json!({
+            "/nix/store/aaaa-hello-2.12": {"references": ["/nix/store/aaaa-hello-2.12", "/nix/store/eeee-glibc-2.40"]},
+            "/nix/store/eeee-glibc-2.40": {"references": []},
+        });
+        assert_eq!(path_info_references(&map), expected);
+
+        let store_relative = serde_json::json!({
+            "aaaa-hello-2.12": {"references": ["aaaa-hello-2.12", "eeee-glibc-2.40"]},
+            "eeee-glibc-2.40": {"references": []},
+        });
+        assert_eq!(path_info_references(&store_relative), expected);
+    }
+}
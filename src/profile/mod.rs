@@ -1,5 +1,6 @@
 use std::path::{Path, PathBuf};
 
+pub(crate) mod closure;
 pub(crate) mod nixenv;
 pub(crate) mod nixprofile;
 
@@ -31,6 +32,15 @@ pub enum Error {
 
     #[error("Deserializing the list of installed packages for the profile: {0}")]
     Deserialization(#[from] serde_json::Error),
+
+    #[error("Reading `{0}`: {1}")]
+    ReadClosure(PathBuf, std::io::Error),
+
+    #[error("Writing the closure to `{0}`: {1}")]
+    WriteClosure(PathBuf, std::io::Error),
+
+    #[error("`{0}` is not in the Nix store")]
+    NotInStore(PathBuf),
 }
 
 pub enum WriteToDefaultProfile {
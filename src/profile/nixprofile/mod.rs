@@ -87,7 +87,7 @@ impl NixProfile<'_> {
                 return Err(super::Error::PathConflict(pkg.to_path_buf(), intersection));
             }
 
-            all_new_paths.extend(candidates.into_iter());
+            all_new_paths.extend(candidates);
         }
 
         Ok(all_new_paths)
@@ -282,12 +282,6 @@ impl NixProfile<'_> {
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct PackageInfo {
-    #[serde(default)]
-    outputs: HashMap<String, PathBuf>,
-}
-
 fn collect_children<P: AsRef<std::path::Path>>(
     base_path: P,
 ) -> Result<HashSet<PathBuf>, std::io::Error> {
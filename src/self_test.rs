@@ -1,13 +1,40 @@
-use std::{process::Output, time::SystemTime};
+/*! A suite of named checks that an install is working
 
-use tokio::process::Command;
+Each [`Check`] runs once, or once per [`Shell`] for checks of the shell profile hooks. Checks run
+in parallel, each limited by [`SelfTestOptions::timeout`], and produce a [`SelfTestReport`] of
+per-check results.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    process::Output,
+    time::{Duration, Instant, SystemTime},
+};
+
+use nix_config_parser::NixConfig;
+use tokio::{process::Command, task::JoinSet};
 use which::which;
 
+use crate::planner::ShellProfileLocations;
+
+const NIX_BIN: &str = "/nix/var/nix/profiles/default/bin/nix";
+const DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+const NIX_CONF: &str = "/etc/nix/nix.conf";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SYSTEM: &str = "x86_64-linux";
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const SYSTEM: &str = "aarch64-linux";
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+const SYSTEM: &str = "aarch64-darwin";
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
 pub enum SelfTestError {
-    #[error("Shell `{shell}` failed self-test with command `{command}`, stderr:\n{}", String::from_utf8_lossy(&output.stderr))]
+    #[error("Shell `{shell}` failed self-test `{check}` with command `{command}`, stderr:\n{}", String::from_utf8_lossy(&output.stderr))]
     ShellFailed {
+        check: Check,
         shell: Shell,
         command: String,
         output: Output,
@@ -17,24 +44,89 @@ pub enum SelfTestError {
         command = .command,
     )]
     Command {
+        check: Check,
         shell: Shell,
         command: String,
         #[source]
         error: std::io::Error,
     },
+    #[error("Self-test `{check}` failed with command `{command}`, stderr:\n{}", String::from_utf8_lossy(&output.stderr))]
+    CheckFailed {
+        check: Check,
+        command: String,
+        output: Output,
+    },
+    /// Failed to execute command
+    #[error("Self-test `{check}` failed to execute command `{command}`")]
+    CheckCommand {
+        check: Check,
+        command: String,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Self-test `{check}` failed: {reason}")]
+    Check { check: Check, reason: String },
+    #[error("Self-test `{check}`{} timed out after {} seconds",
+        shell.map(|shell| format!(" in shell `{shell}`")).unwrap_or_default(),
+        timeout.as_secs(),
+    )]
+    TimedOut {
+        check: Check,
+        shell: Option<Shell>,
+        timeout: Duration,
+    },
     #[error(transparent)]
     SystemTime(#[from] std::time::SystemTimeError),
 }
 
+impl SelfTestError {
+    /// The check which failed, if known
+    pub fn check(&self) -> Option<Check> {
+        match self {
+            Self::ShellFailed { check, .. }
+            | Self::Command { check, .. }
+            | Self::CheckFailed { check, .. }
+            | Self::CheckCommand { check, .. }
+            | Self::Check { check, .. }
+            | Self::TimedOut { check, .. } => Some(*check),
+            Self::SystemTime(_) => None,
+        }
+    }
+
+    /// The shell the check failed in, if it was run in one
+    pub fn shell(&self) -> Option<Shell> {
+        match self {
+            Self::ShellFailed { shell, .. } | Self::Command { shell, .. } => Some(*shell),
+            Self::TimedOut { shell, .. } => *shell,
+            _ => None,
+        }
+    }
+}
+
+impl crate::error::HasErrorCode for SelfTestError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::ShellFailed { .. } => "NI-TEST-0001",
+            Self::Command { .. } => "NI-TEST-0002",
+            Self::CheckFailed { .. } => "NI-TEST-0003",
+            Self::CheckCommand { .. } => "NI-TEST-0004",
+            Self::Check { .. } => "NI-TEST-0005",
+            Self::TimedOut { .. } => "NI-TEST-0006",
+            Self::SystemTime(_) => "NI-TEST-0007",
+        }
+    }
+}
+
 #[cfg(feature = "diagnostics")]
 impl crate::diagnostics::ErrorDiagnostic for SelfTestError {
     fn diagnostic(&self) -> String {
         let static_str: &'static str = (self).into();
-        let context = match self {
-            Self::ShellFailed { shell, .. } => vec![shell.to_string()],
-            Self::Command { shell, .. } => vec![shell.to_string()],
-            Self::SystemTime(_) => vec![],
-        };
+        let context = self
+            .check()
+            .map(|check| check.to_string())
+            .into_iter()
+            .chain(self.shell().map(|shell| shell.to_string()))
+            .collect::<Vec<_>>();
         format!(
             "{}({})",
             static_str,
@@ -47,12 +139,224 @@ impl crate::diagnostics::ErrorDiagnostic for SelfTestError {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// A named self-test check
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Check {
+    /// The Nix daemon answers on its socket
+    DaemonSocket,
+    /// A path can be added to the store via the daemon
+    StoreWritable,
+    /// A derivation builds inside the sandbox
+    SandboxedBuild,
+    /// The first build user can run a process
+    BuildUser,
+    /// `/etc/nix/nix.conf` parses
+    NixConf,
+    /// A path can be substituted from a `file://` binary cache into the store via the daemon
+    FileSubstituter,
+    /// The SELinux labels of the store and daemon socket match the loaded policy
+    SelinuxLabels,
+    /// `nix` is on the `PATH` of login and interactive shells
+    ShellPath,
+    /// A derivation builds from each shell
+    ShellBuild,
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name: &'static str = self.into();
+        write!(f, "{name}")
+    }
+}
+
+impl Check {
+    pub fn all() -> &'static [Check] {
+        &[
+            Check::DaemonSocket,
+            Check::StoreWritable,
+            Check::SandboxedBuild,
+            Check::BuildUser,
+            Check::NixConf,
+            Check::FileSubstituter,
+            Check::SelinuxLabels,
+            Check::ShellPath,
+            Check::ShellBuild,
+        ]
+    }
+
+    /// Whether the check runs once per [`Shell`]
+    pub fn per_shell(&self) -> bool {
+        matches!(self, Check::ShellPath | Check::ShellBuild)
+    }
+
+    async fn run(&self) -> Result<CheckOutcome, SelfTestError> {
+        match self {
+            Check::DaemonSocket => check_daemon_socket().await,
+            Check::StoreWritable => check_store_writable().await,
+            Check::SandboxedBuild => check_sandboxed_build().await,
+            Check::BuildUser => check_build_user().await,
+            Check::NixConf => check_nix_conf().await,
+            Check::FileSubstituter => check_file_substituter().await,
+            Check::SelinuxLabels => check_selinux_labels().await,
+            Check::ShellPath | Check::ShellBuild => {
+                unreachable!("Shell checks are run via `Check::run_in_shell`")
+            },
+        }
+    }
+
+    async fn run_in_shell(&self, shell: Shell) -> Result<CheckOutcome, SelfTestError> {
+        match self {
+            Check::ShellPath => shell.check_path().await,
+            Check::ShellBuild => shell.self_test().await.map(|()| CheckOutcome::Passed),
+            _ => unreachable!("Only shell checks are run in a shell"),
+        }
+    }
+}
+
+enum CheckOutcome {
+    Passed,
+    Skipped(String),
+}
+
+/// The outcome of a [`Check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+/// The result of one [`Check`], in one [`Shell`] if it runs per shell
+#[derive(Debug, serde::Serialize)]
+pub struct CheckResult {
+    pub check: Check,
+    pub shell: Option<Shell>,
+    pub status: CheckStatus,
+    pub duration_millis: u128,
+    /// Why the check failed or was skipped
+    pub message: Option<String>,
+    #[serde(skip)]
+    pub error: Option<SelfTestError>,
+}
+
+/// The results of every [`Check`] run by [`run`]
+#[derive(Debug, serde::Serialize)]
+pub struct SelfTestReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    pub fn is_success(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| result.status != CheckStatus::Failed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results
+            .iter()
+            .filter(|result| result.status == CheckStatus::Failed)
+    }
+
+    pub fn into_result(self) -> Result<Self, Vec<SelfTestError>> {
+        if self.is_success() {
+            return Ok(self);
+        }
+        Err(self
+            .results
+            .into_iter()
+            .filter_map(|result| result.error)
+            .collect())
+    }
+}
+
+/// Which checks [`run`] runs, and how
+#[derive(Debug, Clone)]
+pub struct SelfTestOptions {
+    pub checks: Vec<Check>,
+    /// The shells to run per-shell checks in, `None` for all discovered shells
+    pub shells: Option<Vec<Shell>>,
+    pub timeout: Duration,
+}
+
+impl SelfTestOptions {
+    /// Run `checks`, or every check if empty, with per-shell checks in `shells`, or every
+    /// discovered shell if empty
+    pub fn new(mut checks: Vec<Check>, mut shells: Vec<Shell>, timeout: Duration) -> Self {
+        if checks.is_empty() {
+            checks = Check::all().to_vec();
+        }
+        checks.sort();
+        checks.dedup();
+        shells.sort();
+        shells.dedup();
+        Self {
+            checks,
+            shells: if shells.is_empty() {
+                None
+            } else {
+                Some(shells)
+            },
+            timeout,
+        }
+    }
+
+    /// Each check to run, and the shell to run it in, given the shells to use when none were selected
+    fn runs(&self, discovered: &[Shell]) -> Vec<(Check, Option<Shell>)> {
+        let shells = self.shells.as_deref().unwrap_or(discovered);
+        self.checks
+            .iter()
+            .flat_map(|check| {
+                if check.per_shell() {
+                    shells.iter().map(|shell| (*check, Some(*shell))).collect()
+                } else {
+                    vec![(*check, None)]
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for SelfTestOptions {
+    fn default() -> Self {
+        Self {
+            checks: Check::all().to_vec(),
+            shells: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
 pub enum Shell {
     Sh,
     Bash,
     Fish,
     Zsh,
+    #[cfg_attr(feature = "cli", value(name = "nu"))]
+    #[serde(rename = "nu")]
+    Nushell,
+    Xonsh,
+    Elvish,
+    Tcsh,
+    Csh,
 }
 
 impl std::fmt::Display for Shell {
@@ -61,9 +365,26 @@ impl std::fmt::Display for Shell {
     }
 }
 
+/// How a [`Shell`] is started
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShellMode {
+    Login,
+    Interactive,
+}
+
 impl Shell {
     pub fn all() -> &'static [Shell] {
-        &[Shell::Sh, Shell::Bash, Shell::Fish, Shell::Zsh]
+        &[
+            Shell::Sh,
+            Shell::Bash,
+            Shell::Fish,
+            Shell::Zsh,
+            Shell::Nushell,
+            Shell::Xonsh,
+            Shell::Elvish,
+            Shell::Tcsh,
+            Shell::Csh,
+        ]
     }
     pub fn executable(&self) -> &'static str {
         match &self {
@@ -71,65 +392,150 @@ impl Shell {
             Shell::Bash => "bash",
             Shell::Fish => "fish",
             Shell::Zsh => "zsh",
+            Shell::Nushell => "nu",
+            Shell::Xonsh => "xonsh",
+            Shell::Elvish => "elvish",
+            Shell::Tcsh => "tcsh",
+            Shell::Csh => "csh",
         }
     }
 
-    #[tracing::instrument(skip_all)]
-    pub async fn self_test(&self) -> Result<(), SelfTestError> {
-        let executable = self.executable();
-        let mut command = match &self {
+    /// The mode in which the Nix hooks of this shell are loaded most reliably
+    fn default_mode(&self) -> ShellMode {
+        match self {
+            Shell::Zsh | Shell::Fish => ShellMode::Interactive,
+            _ => ShellMode::Login,
+        }
+    }
+
+    /// Build a command running `script` in `mode`, or `None` if the Nix hooks aren't loaded in that mode
+    fn command(&self, mode: ShellMode, script: &str) -> Option<Command> {
+        let (args, prelude): (&[&str], String) = match (self, mode) {
             // On Mac, `bash -ic nix` won't work, but `bash -lc nix` will.
-            Shell::Sh | Shell::Bash => {
-                let mut command = Command::new(executable);
-                command.arg("-lc");
-                command
+            (Shell::Sh | Shell::Bash, ShellMode::Login) => (&["-lc"], String::new()),
+            (Shell::Bash, ShellMode::Interactive) if cfg!(target_os = "linux") => {
+                (&["-ic"], String::new())
             },
-            Shell::Zsh | Shell::Fish => {
-                let mut command = Command::new(executable);
-                command.arg("-ic");
-                command
+            (Shell::Sh | Shell::Bash, ShellMode::Interactive) => return None,
+            // Non-interactive zsh does not read `zshrc`
+            (Shell::Zsh, ShellMode::Login) => (&["-lic"], String::new()),
+            (Shell::Zsh | Shell::Fish, ShellMode::Interactive) => (&["-ic"], String::new()),
+            (Shell::Fish, ShellMode::Login) => (&["-lc"], String::new()),
+            // Vendor autoload files are only read when Nushell runs as a login shell.
+            (Shell::Nushell, ShellMode::Login) => (&["--login", "-c"], String::new()),
+            (Shell::Nushell, ShellMode::Interactive) => return None,
+            (Shell::Xonsh, ShellMode::Login) => {
+                (&["--login", "--interactive", "-c"], String::new())
             },
+            (Shell::Xonsh, ShellMode::Interactive) => (&["--interactive", "-c"], String::new()),
+            // Elvish only reads its rc file when interactive, which can't be combined with `-c`.
+            (Shell::Elvish, ShellMode::Login) => (
+                &["-c"],
+                format!("eval (slurp < {}); ", self.hooked_profile()?.display()),
+            ),
+            // `tcsh -l` can't be combined with any other flag, so read the login file by hand.
+            (Shell::Tcsh | Shell::Csh, ShellMode::Login) => (
+                &["-c"],
+                format!("source {}; ", self.hooked_profile()?.display()),
+            ),
+            (Shell::Elvish | Shell::Tcsh | Shell::Csh, ShellMode::Interactive) => return None,
         };
 
-        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-        const SYSTEM: &str = "x86_64-linux";
-        #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-        const SYSTEM: &str = "aarch64-linux";
-        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-        const SYSTEM: &str = "aarch64-darwin";
+        let mut command = Command::new(self.executable());
+        command
+            .args(args)
+            .arg(format!("{prelude}{script}"))
+            .stdin(std::process::Stdio::null())
+            .env("NIX_REMOTE", "daemon")
+            .kill_on_drop(true);
+        Some(command)
+    }
 
-        let timestamp_millis = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
+    /// The profile holding the Nix hook, for shells which can't be made to read it on their own
+    fn hooked_profile(&self) -> Option<PathBuf> {
+        let locations = ShellProfileLocations::default();
+        let candidates = match self {
+            Shell::Elvish => locations.elvish,
+            Shell::Tcsh | Shell::Csh => locations.csh,
+            _ => return None,
+        };
+        candidates
+            .iter()
+            .find(|candidate| candidate.exists())
+            .or(candidates.first())
+            .cloned()
+    }
 
-        command.arg(format!(
-            r#"exec nix build --option substitute false --option post-build-hook '' --no-link --expr 'derivation {{ name = "self-test-{executable}-{timestamp_millis}"; system = "{SYSTEM}"; builder = "/bin/sh"; args = ["-c" "echo hello > \$out"]; }}'"#
-        ));
+    async fn run(
+        &self,
+        check: Check,
+        mode: ShellMode,
+        script: &str,
+    ) -> Option<Result<(), SelfTestError>> {
+        let mut command = self.command(mode, script)?;
         let command_str = format!("{:?}", command.as_std());
 
         tracing::debug!(
             command = command_str,
-            "Testing Nix install via `{executable}`"
+            "Testing Nix install via `{}`",
+            self.executable()
         );
-        let output = command
-            .stdin(std::process::Stdio::null())
-            .env("NIX_REMOTE", "daemon")
-            .output()
-            .await
-            .map_err(|error| SelfTestError::Command {
-                shell: *self,
-                command: command_str.clone(),
-                error,
-            })?;
+        let output = match command.output().await {
+            Ok(output) => output,
+            Err(error) => {
+                return Some(Err(SelfTestError::Command {
+                    check,
+                    shell: *self,
+                    command: command_str,
+                    error,
+                }))
+            },
+        };
 
         if output.status.success() {
-            Ok(())
+            Some(Ok(()))
         } else {
-            Err(SelfTestError::ShellFailed {
+            Some(Err(SelfTestError::ShellFailed {
+                check,
                 shell: *self,
                 command: command_str,
                 output,
-            })
+            }))
+        }
+    }
+
+    /// Build a trivial derivation from this shell
+    #[tracing::instrument(skip_all)]
+    pub async fn self_test(&self) -> Result<(), SelfTestError> {
+        let executable = self.executable();
+        let timestamp_millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+
+        let script = format!(
+            r#"exec nix build --option substitute false --option post-build-hook '' --no-link --expr 'derivation {{ name = "self-test-{executable}-{timestamp_millis}"; system = "{SYSTEM}"; builder = "/bin/sh"; args = ["-c" "echo hello > \$out"]; }}'"#
+        );
+        self.run(Check::ShellBuild, self.default_mode(), &script)
+            .await
+            .unwrap_or(Ok(()))
+    }
+
+    /// Check `nix` can be found in both login and interactive sessions of this shell
+    #[tracing::instrument(skip_all)]
+    async fn check_path(&self) -> Result<CheckOutcome, SelfTestError> {
+        let mut tested = false;
+        for mode in [ShellMode::Login, ShellMode::Interactive] {
+            if let Some(result) = self.run(Check::ShellPath, mode, "exec nix --version").await {
+                result?;
+                tested = true;
+            }
+        }
+        if tested {
+            Ok(CheckOutcome::Passed)
+        } else {
+            Ok(CheckOutcome::Skipped(format!(
+                "`{self}` has no mode which loads the Nix hooks"
+            )))
         }
     }
 
@@ -137,6 +543,11 @@ impl Shell {
     pub fn discover() -> Vec<Shell> {
         let mut found_shells = vec![];
         for shell in Self::all() {
+            // `csh` is usually `tcsh` under another name, so don't test the same shell twice
+            if *shell == Shell::Csh && same_executable("csh", "tcsh") {
+                tracing::debug!("Skipping `csh`, it is `tcsh`");
+                continue;
+            }
             if which(shell.executable()).is_ok() {
                 tracing::debug!("Discovered `{shell}`");
                 found_shells.push(*shell)
@@ -146,22 +557,477 @@ impl Shell {
     }
 }
 
+/// If both commands are found in `PATH`, and are the same file
+fn same_executable(first: &str, second: &str) -> bool {
+    let canonical = |command| {
+        which(command)
+            .ok()
+            .and_then(|path| std::fs::canonicalize(path).ok())
+    };
+    match (canonical(first), canonical(second)) {
+        (Some(first), Some(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// A `nix` command talking to the daemon, which is killed if the check times out
+fn nix_command() -> Command {
+    let mut command = Command::new(NIX_BIN);
+    command
+        .args(["--extra-experimental-features", "nix-command"])
+        .stdin(std::process::Stdio::null())
+        .env("NIX_REMOTE", "daemon")
+        .kill_on_drop(true);
+    command
+}
+
+async fn run_check_command(check: Check, command: &mut Command) -> Result<Output, SelfTestError> {
+    let command_str = format!("{:?}", command.as_std());
+    tracing::debug!(command = command_str, "Running self-test `{check}`");
+    let output = command
+        .output()
+        .await
+        .map_err(|error| SelfTestError::CheckCommand {
+            check,
+            command: command_str.clone(),
+            error,
+        })?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(SelfTestError::CheckFailed {
+            check,
+            command: command_str,
+            output,
+        })
+    }
+}
+
+async fn check_daemon_socket() -> Result<CheckOutcome, SelfTestError> {
+    std::os::unix::net::UnixStream::connect(DAEMON_SOCKET).map_err(|e| SelfTestError::Check {
+        check: Check::DaemonSocket,
+        reason: format!("Could not connect to `{DAEMON_SOCKET}`: {e}"),
+    })?;
+    run_check_command(
+        Check::DaemonSocket,
+        nix_command().args(["store", "ping", "--store", "daemon"]),
+    )
+    .await?;
+    Ok(CheckOutcome::Passed)
+}
+
+/// Write a uniquely named file to add to a store
+fn self_test_file(check: Check) -> Result<tempfile::NamedTempFile, SelfTestError> {
+    let timestamp_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    let io_error = |e: std::io::Error| SelfTestError::Check {
+        check,
+        reason: format!("Could not write a temporary file: {e}"),
+    };
+    let file = tempfile::Builder::new()
+        .prefix("nix-installer-self-test")
+        .tempfile()
+        .map_err(io_error)?;
+    std::fs::write(file.path(), format!("{check} {timestamp_millis}\n")).map_err(io_error)?;
+    Ok(file)
+}
+
+async fn check_store_writable() -> Result<CheckOutcome, SelfTestError> {
+    let file = self_test_file(Check::StoreWritable)?;
+    run_check_command(
+        Check::StoreWritable,
+        nix_command()
+            .args(["store", "add-file", "--store", "daemon"])
+            .arg(file.path()),
+    )
+    .await?;
+    Ok(CheckOutcome::Passed)
+}
+
+async fn check_sandboxed_build() -> Result<CheckOutcome, SelfTestError> {
+    if cfg!(target_os = "macos") {
+        return Ok(CheckOutcome::Skipped(
+            "The sandbox is not enabled by default on macOS".to_string(),
+        ));
+    }
+    let timestamp_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    // The daemon socket is not visible from inside the sandbox
+    let expr = format!(
+        r#"derivation {{ name = "self-test-sandbox-{timestamp_millis}"; system = "{SYSTEM}"; builder = "/bin/sh"; args = ["-c" "if [ -e {DAEMON_SOCKET} ]; then exit 1; fi; echo hello > $out"]; }}"#
+    );
+    run_check_command(
+        Check::SandboxedBuild,
+        nix_command()
+            .args([
+                "build",
+                "--option",
+                "sandbox",
+                "true",
+                "--option",
+                "substitute",
+                "false",
+                "--option",
+                "post-build-hook",
+                "",
+                "--no-link",
+                "--expr",
+            ])
+            .arg(expr),
+    )
+    .await?;
+    Ok(CheckOutcome::Passed)
+}
+
+async fn check_build_user() -> Result<CheckOutcome, SelfTestError> {
+    let check_error = |reason: String| SelfTestError::Check {
+        check: Check::BuildUser,
+        reason,
+    };
+    let group_name = NixConfig::parse_file(Path::new(NIX_CONF))
+        .ok()
+        .and_then(|nix_config| nix_config.settings().get("build-users-group").cloned())
+        .unwrap_or_else(|| crate::settings::DEFAULT_NIX_BUILD_USER_GROUP_NAME.to_string());
+    let group = nix::unistd::Group::from_name(&group_name)
+        .map_err(|e| check_error(format!("Could not look up group `{group_name}`: {e}")))?
+        .ok_or_else(|| check_error(format!("The build group `{group_name}` does not exist")))?;
+    let user_name = group
+        .mem
+        .first()
+        .ok_or_else(|| check_error(format!("The build group `{group_name}` has no members")))?;
+    let user = nix::unistd::User::from_name(user_name)
+        .map_err(|e| check_error(format!("Could not look up user `{user_name}`: {e}")))?
+        .ok_or_else(|| check_error(format!("The build user `{user_name}` does not exist")))?;
+
+    if !nix::unistd::Uid::effective().is_root() {
+        return Ok(CheckOutcome::Skipped(format!(
+            "Running a process as `{user_name}` requires `root`"
+        )));
+    }
+    run_check_command(
+        Check::BuildUser,
+        Command::new("/bin/sh")
+            .args(["-c", "exit 0"])
+            .uid(user.uid.as_raw())
+            .gid(user.gid.as_raw())
+            .current_dir("/")
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true),
+    )
+    .await?;
+    Ok(CheckOutcome::Passed)
+}
+
+async fn check_nix_conf() -> Result<CheckOutcome, SelfTestError> {
+    NixConfig::parse_file(Path::new(NIX_CONF)).map_err(|e| SelfTestError::Check {
+        check: Check::NixConf,
+        reason: format!("Could not parse `{NIX_CONF}`: {e}"),
+    })?;
+    Ok(CheckOutcome::Passed)
+}
+
+async fn check_file_substituter() -> Result<CheckOutcome, SelfTestError> {
+    // Only trusted users may add paths without a signature by a trusted key
+    if !nix::unistd::Uid::effective().is_root() {
+        return Ok(CheckOutcome::Skipped(
+            "Substituting unsigned paths via the daemon requires `root`".to_string(),
+        ));
+    }
+    let cache = tempfile::Builder::new()
+        .prefix("nix-installer-self-test-cache")
+        .tempdir()
+        .map_err(|e| SelfTestError::Check {
+            check: Check::FileSubstituter,
+            reason: format!("Could not create a temporary directory: {e}"),
+        })?;
+    let store = format!("file://{}", cache.path().display());
+    let file = self_test_file(Check::FileSubstituter)?;
+
+    let output = run_check_command(
+        Check::FileSubstituter,
+        nix_command()
+            .args(["store", "add-file", "--store", &store])
+            .arg(file.path()),
+    )
+    .await?;
+    let store_path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    run_check_command(
+        Check::FileSubstituter,
+        nix_command().args([
+            "copy",
+            "--no-check-sigs",
+            "--from",
+            &store,
+            "--to",
+            "daemon",
+            &store_path,
+        ]),
+    )
+    .await?;
+    run_check_command(
+        Check::FileSubstituter,
+        nix_command().args(["path-info", "--store", "daemon", &store_path]),
+    )
+    .await?;
+    Ok(CheckOutcome::Passed)
+}
+
+async fn check_selinux_labels() -> Result<CheckOutcome, SelfTestError> {
+    if !Path::new("/sys/fs/selinux/enforce").exists() {
+        return Ok(CheckOutcome::Skipped("SELinux is not enabled".to_string()));
+    }
+    if which("matchpathcon").is_err() {
+        return Ok(CheckOutcome::Skipped(
+            "Could not find `matchpathcon` to look up the expected labels".to_string(),
+        ));
+    }
+    let check_error = |reason: String| SelfTestError::Check {
+        check: Check::SelinuxLabels,
+        reason,
+    };
+    for path in crate::action::linux::verify_selinux_labels::VERIFIED_PATHS {
+        let path = Path::new(path);
+        if !path.exists() {
+            return Err(check_error(format!(
+                "`{}` does not exist, so its SELinux label can not be checked",
+                path.display()
+            )));
+        }
+        crate::action::linux::provision_selinux::verify_label(path)
+            .await
+            .map_err(|e| check_error(e.to_string()))?;
+    }
+    Ok(CheckOutcome::Passed)
+}
+
+async fn timed_check(check: Check, shell: Option<Shell>, timeout: Duration) -> CheckResult {
+    match shell {
+        Some(shell) => timed(check, Some(shell), timeout, check.run_in_shell(shell)).await,
+        None => timed(check, None, timeout, check.run()).await,
+    }
+}
+
+/// Run `future`, the body of `check`, limited to `timeout`
+async fn timed(
+    check: Check,
+    shell: Option<Shell>,
+    timeout: Duration,
+    future: impl std::future::Future<Output = Result<CheckOutcome, SelfTestError>>,
+) -> CheckResult {
+    let start = Instant::now();
+    let result =
+        tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or(Err(SelfTestError::TimedOut {
+                check,
+                shell,
+                timeout,
+            }));
+    let duration_millis = start.elapsed().as_millis();
+
+    let (status, message, error) = match result {
+        Ok(CheckOutcome::Passed) => (CheckStatus::Passed, None, None),
+        Ok(CheckOutcome::Skipped(reason)) => (CheckStatus::Skipped, Some(reason), None),
+        Err(err) => (CheckStatus::Failed, Some(err.to_string()), Some(err)),
+    };
+    CheckResult {
+        check,
+        shell,
+        status,
+        duration_millis,
+        message,
+        error,
+    }
+}
+
+/// Run the selected checks in parallel
 #[tracing::instrument(skip_all)]
-pub async fn self_test() -> Result<(), Vec<SelfTestError>> {
-    let shells = Shell::discover();
+pub async fn run(options: &SelfTestOptions) -> SelfTestReport {
+    let discovered = match options.shells {
+        Some(_) => vec![],
+        None => Shell::discover(),
+    };
 
-    let mut failures = vec![];
+    let mut set = JoinSet::new();
+    for (check, shell) in options.runs(&discovered) {
+        let _abort_handle = set.spawn(timed_check(check, shell, options.timeout));
+    }
 
-    for shell in shells {
-        match shell.self_test().await {
-            Ok(()) => (),
-            Err(err) => failures.push(err),
+    let mut results = vec![];
+    while let Some(result) = set.join_next().await {
+        match result {
+            Ok(result) => results.push(result),
+            Err(err) => tracing::error!("Self-test task failed: {err}"),
         }
     }
+    results.sort_by_key(|result| (result.check, result.shell));
 
-    if failures.is_empty() {
+    SelfTestReport { results }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn self_test() -> Result<(), Vec<SelfTestError>> {
+    run(&SelfTestOptions::default()).await.into_result()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{
+        timed, Check, CheckOutcome, CheckStatus, SelfTestError, SelfTestOptions, SelfTestReport,
+        Shell, ShellMode,
+    };
+
+    #[test]
+    fn shells_read_the_profiles_hooked_by_the_installer() {
+        let last_arg = |shell: Shell| {
+            shell
+                .command(ShellMode::Login, "exec nix --version")
+                .and_then(|command| command.as_std().get_args().last().map(|arg| arg.to_owned()))
+        };
+        assert_eq!(
+            last_arg(Shell::Elvish).as_deref(),
+            Some("eval (slurp < /etc/elvish/rc.elv); exec nix --version".as_ref())
+        );
+        assert_eq!(
+            last_arg(Shell::Tcsh).as_deref(),
+            Some("source /etc/csh.login; exec nix --version".as_ref())
+        );
+        assert_eq!(last_arg(Shell::Csh), last_arg(Shell::Tcsh));
+        assert_eq!(
+            last_arg(Shell::Bash).as_deref(),
+            Some("exec nix --version".as_ref())
+        );
+        assert!(Shell::Csh
+            .command(ShellMode::Interactive, "exec nix --version")
+            .is_none());
+    }
+
+    #[test]
+    fn selects_every_check_by_default() {
+        let options = SelfTestOptions::new(vec![], vec![], Duration::from_secs(1));
+        assert_eq!(options.checks, Check::all());
+        assert_eq!(options.shells, None);
+
+        let runs = options.runs(&[Shell::Bash, Shell::Fish]);
+        assert!(runs.contains(&(Check::DaemonSocket, None)));
+        assert!(runs.contains(&(Check::ShellPath, Some(Shell::Bash))));
+        assert!(runs.contains(&(Check::ShellBuild, Some(Shell::Fish))));
+        assert!(!runs.contains(&(Check::ShellPath, None)));
+        assert_eq!(runs.len(), Check::all().len() - 2 + 2 * 2);
+    }
+
+    #[test]
+    fn filters_checks_and_shells() {
+        let options = SelfTestOptions::new(
+            vec![Check::ShellPath, Check::NixConf, Check::ShellPath],
+            vec![Shell::Zsh],
+            Duration::from_secs(1),
+        );
+        assert_eq!(options.checks, [Check::NixConf, Check::ShellPath]);
+        assert_eq!(options.shells, Some(vec![Shell::Zsh]));
+
+        // Selected shells are used even when others were discovered
+        assert_eq!(
+            options.runs(&[Shell::Bash]),
+            [(Check::NixConf, None), (Check::ShellPath, Some(Shell::Zsh))]
+        );
+    }
+
+    #[test]
+    fn per_shell_checks_without_shells_do_not_run() {
+        let options = SelfTestOptions::new(vec![Check::ShellBuild], vec![], Duration::from_secs(1));
+        assert!(options.runs(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn checks_time_out() {
+        let result = timed(
+            Check::StoreWritable,
+            None,
+            Duration::from_millis(10),
+            async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(CheckOutcome::Passed)
+            },
+        )
+        .await;
+        assert_eq!(result.status, CheckStatus::Failed);
+        assert!(matches!(
+            result.error,
+            Some(SelfTestError::TimedOut {
+                check: Check::StoreWritable,
+                shell: None,
+                ..
+            })
+        ));
+        assert_eq!(
+            result.message.as_deref(),
+            Some("Self-test `store-writable` timed out after 0 seconds")
+        );
+    }
+
+    #[tokio::test]
+    async fn report_shape() -> Result<(), Box<dyn std::error::Error>> {
+        let timeout = Duration::from_secs(1);
+        let results = vec![
+            timed(Check::NixConf, None, timeout, async {
+                Ok(CheckOutcome::Passed)
+            })
+            .await,
+            timed(Check::ShellPath, Some(Shell::Elvish), timeout, async {
+                Ok(CheckOutcome::Skipped("No hooks".to_string()))
+            })
+            .await,
+            timed(Check::BuildUser, None, timeout, async {
+                Err(SelfTestError::Check {
+                    check: Check::BuildUser,
+                    reason: "No build users".to_string(),
+                })
+            })
+            .await,
+        ];
+        let report = SelfTestReport { results };
+
+        assert!(!report.is_success());
+        assert_eq!(
+            report
+                .failures()
+                .map(|result| result.check)
+                .collect::<Vec<_>>(),
+            [Check::BuildUser]
+        );
+
+        let mut json = serde_json::to_value(&report)?;
+        for result in json["results"]
+            .as_array_mut()
+            .ok_or("No results")?
+            .iter_mut()
+        {
+            let duration = result
+                .as_object_mut()
+                .ok_or("Not an object")?
+                .remove("duration_millis");
+            assert!(duration.is_some_and(|duration| duration.is_u64()));
+        }
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "results": [
+                    { "check": "nix-conf", "shell": null, "status": "passed", "message": null },
+                    { "check": "shell-path", "shell": "elvish", "status": "skipped", "message": "No hooks" },
+                    { "check": "build-user", "shell": null, "status": "failed", "message": "Self-test `build-user` failed: No build users" },
+                ]
+            })
+        );
+
+        let errors = report.into_result().map(|_| ()).unwrap_err();
+        assert_eq!(errors.len(), 1);
         Ok(())
-    } else {
-        Err(failures)
     }
 }
@@ -21,6 +21,10 @@ pub enum InitSystem {
     None,
     Systemd,
     Launchd,
+    /// Start the daemon from the `[boot] command` of `/etc/wsl.conf` (WSL2 without systemd)
+    Wsl,
+    /// Start the daemon from an OpenRC service (Alpine, Gentoo)
+    Openrc,
 }
 
 impl std::fmt::Display for InitSystem {
@@ -29,6 +33,56 @@ impl std::fmt::Display for InitSystem {
             InitSystem::None => write!(f, "none"),
             InitSystem::Systemd => write!(f, "systemd"),
             InitSystem::Launchd => write!(f, "launchd"),
+            InitSystem::Wsl => write!(f, "wsl"),
+            InitSystem::Openrc => write!(f, "openrc"),
+        }
+    }
+}
+
+/// How users and groups are created on Linux
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum UserBackend {
+    /// Use the system tools if they're available, otherwise edit the databases directly
+    #[default]
+    Auto,
+    /// Use `useradd`/`adduser`, `groupadd`/`addgroup` and `gpasswd`/`addgroup`
+    Tools,
+    /// Edit `/etc/passwd`, `/etc/group`, `/etc/shadow` and `/etc/gshadow` directly
+    Files,
+}
+
+impl UserBackend {
+    /// Resolve [`UserBackend::Auto`] to [`UserBackend::Tools`] if, for each set of alternatives, one command is in `PATH`, otherwise to [`UserBackend::Files`]
+    pub fn resolve(self, commands: &[&[&str]]) -> Self {
+        match self {
+            UserBackend::Auto => {
+                let found = commands.iter().all(|alternatives| {
+                    alternatives
+                        .iter()
+                        .any(|command| which::which(command).is_ok())
+                });
+                if found {
+                    UserBackend::Tools
+                } else {
+                    tracing::debug!(
+                        "Could not find {commands:?} in `PATH`, editing the user database directly"
+                    );
+                    UserBackend::Files
+                }
+            },
+            backend => backend,
+        }
+    }
+}
+
+impl std::fmt::Display for UserBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserBackend::Auto => write!(f, "auto"),
+            UserBackend::Tools => write!(f, "tools"),
+            UserBackend::Files => write!(f, "files"),
         }
     }
 }
@@ -78,6 +132,20 @@ pub struct CommonSettings {
     )]
     pub modify_profile: bool,
 
+    /// Install a systemd timer which restores the shell profile hooks at boot if an OS upgrade removed them (Linux only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_REPAIR_HOOKS_ON_BOOT"
+        )
+    )]
+    #[serde(default)]
+    pub repair_hooks_on_boot: bool,
+
     /// The Nix build group name
     #[cfg_attr(
         feature = "cli",
@@ -145,6 +213,20 @@ pub struct CommonSettings {
     )]
     pub nix_build_user_id_base: u32,
 
+    /// How to create the build users and group on Linux (`files` edits `/etc/passwd` and friends directly)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_enum,
+            default_value_t = UserBackend::Auto,
+            env = "NIX_INSTALLER_USER_BACKEND",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub user_backend: UserBackend,
+
     /// The Nix package URL
     #[cfg_attr(
         feature = "cli",
@@ -242,11 +324,13 @@ impl CommonSettings {
             determinate_nix: false,
             prefer_upstream: false,
             modify_profile: true,
+            repair_hooks_on_boot: false,
             nix_build_group_name: String::from(crate::settings::DEFAULT_NIX_BUILD_USER_GROUP_NAME),
             nix_build_group_id: default_nix_build_group_id(),
             nix_build_user_id_base: default_nix_build_user_id_base(),
             nix_build_user_count: 32,
             nix_build_user_prefix: nix_build_user_prefix.to_string(),
+            user_backend: UserBackend::Auto,
             nix_package_url: None,
             proxy: Default::default(),
             extra_conf: Default::default(),
@@ -262,11 +346,13 @@ impl CommonSettings {
             determinate_nix,
             prefer_upstream,
             modify_profile,
+            repair_hooks_on_boot,
             nix_build_group_name,
             nix_build_group_id,
             nix_build_user_prefix,
             nix_build_user_id_base,
             nix_build_user_count,
+            user_backend,
             nix_package_url,
             proxy,
             extra_conf,
@@ -288,6 +374,10 @@ impl CommonSettings {
             "modify_profile".into(),
             serde_json::to_value(modify_profile)?,
         );
+        map.insert(
+            "repair_hooks_on_boot".into(),
+            serde_json::to_value(repair_hooks_on_boot)?,
+        );
         map.insert(
             "nix_build_group_name".into(),
             serde_json::to_value(nix_build_group_name)?,
@@ -308,6 +398,7 @@ impl CommonSettings {
             "nix_build_user_count".into(),
             serde_json::to_value(nix_build_user_count)?,
         );
+        map.insert("user_backend".into(), serde_json::to_value(user_backend)?);
         map.insert(
             "nix_package_url".into(),
             serde_json::to_value(nix_package_url)?,
@@ -333,7 +424,7 @@ impl CommonSettings {
     }
 }
 
-async fn linux_detect_systemd_started() -> bool {
+pub(crate) async fn linux_detect_systemd_started() -> bool {
     use std::process::Stdio;
 
     let mut started = false;
@@ -350,15 +441,74 @@ async fn linux_detect_systemd_started() -> bool {
             .unwrap_or(false)
     }
 
-    // TODO: Other inits
     started
 }
 
+/// If OpenRC is installed, and if it booted the system (it doesn't in most containers)
+pub(crate) fn linux_detect_openrc() -> (bool, bool) {
+    let installed = which::which("openrc-run").is_ok() && which::which("rc-update").is_ok();
+    let started = installed && std::path::Path::new("/run/openrc/softlevel").exists();
+    (installed, started)
+}
+
+/// Whichever of systemd or OpenRC is running, otherwise on WSL2 start the daemon from the WSL boot command
+async fn linux_detect_init() -> (InitSystem, bool) {
+    linux_choose_init(
+        linux_detect_systemd_started().await,
+        linux_detect_openrc(),
+        linux_detect_wsl2().await,
+    )
+}
+
+/// The init system `--init` defaults to on Linux, chosen like [`InitSettings::default`] does
+///
+/// This is computed whenever the arguments are parsed, so rather than asking `systemctl` it only
+/// checks whether systemd booted the system.
+#[cfg(all(target_os = "linux", feature = "cli"))]
+fn linux_default_init() -> InitSystem {
+    let systemd_booted = std::path::Path::new("/run/systemd/system").exists();
+    let wsl2 = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|osrelease| is_wsl2_osrelease(&osrelease))
+        .unwrap_or(false);
+    linux_choose_init(systemd_booted, linux_detect_openrc(), wsl2).0
+}
+
+/// The init system to configure, and whether to start the daemon, given what was detected
+fn linux_choose_init(
+    systemd_started: bool,
+    (openrc_installed, openrc_started): (bool, bool),
+    wsl2: bool,
+) -> (InitSystem, bool) {
+    if systemd_started {
+        (InitSystem::Systemd, true)
+    } else if openrc_started {
+        (InitSystem::Openrc, true)
+    } else if wsl2 {
+        (InitSystem::Wsl, true)
+    } else if openrc_installed {
+        (InitSystem::Openrc, false)
+    } else {
+        (InitSystem::Systemd, false)
+    }
+}
+
+/// If running under WSL2, whose kernels (unlike WSL1's) identify as `microsoft-standard`
+pub(crate) async fn linux_detect_wsl2() -> bool {
+    tokio::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .await
+        .map(|osrelease| is_wsl2_osrelease(&osrelease))
+        .unwrap_or(false)
+}
+
+fn is_wsl2_osrelease(osrelease: &str) -> bool {
+    osrelease.to_lowercase().contains("microsoft-standard")
+}
+
 #[serde_with::serde_as]
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[cfg_attr(feature = "cli", derive(clap::Parser))]
 pub struct InitSettings {
-    /// Which init system to configure (if `--init none` Nix will be root-only)
+    /// Which init system to configure (if `--init none` Nix will be root-only, `--init wsl` starts the daemon from `/etc/wsl.conf`, `--init openrc` adds an OpenRC service)
     #[cfg_attr(feature = "cli", clap(value_parser, long, env = "NIX_INSTALLER_INIT",))]
     #[cfg_attr(
         all(target_os = "macos", feature = "cli"),
@@ -366,7 +516,7 @@ pub struct InitSettings {
     )]
     #[cfg_attr(
         all(target_os = "linux", feature = "cli"),
-        clap(default_value_t = InitSystem::Systemd)
+        clap(default_value_t = linux_default_init())
     )]
     pub init: InitSystem,
 
@@ -390,15 +540,9 @@ impl InitSettings {
     pub async fn default() -> Result<Self, InstallSettingsError> {
         use target_lexicon::{Architecture, OperatingSystem};
         let (init, start_daemon) = match (Architecture::host(), OperatingSystem::host()) {
-            (Architecture::X86_64, OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
-            (Architecture::X86_32(_), OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
-            (Architecture::Aarch64(_), OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
+            (Architecture::X86_64, OperatingSystem::Linux) => linux_detect_init().await,
+            (Architecture::X86_32(_), OperatingSystem::Linux) => linux_detect_init().await,
+            (Architecture::Aarch64(_), OperatingSystem::Linux) => linux_detect_init().await,
             (Architecture::X86_64, OperatingSystem::MacOSX { .. })
             | (Architecture::X86_64, OperatingSystem::Darwin) => (InitSystem::Launchd, true),
             (Architecture::Aarch64(_), OperatingSystem::MacOSX { .. })
@@ -618,7 +762,10 @@ impl crate::diagnostics::ErrorDiagnostic for InstallSettingsError {
 
 #[cfg(test)]
 mod tests {
-    use super::{FromStr, PathBuf, Url, UrlOrPath, UrlOrPathOrString};
+    use super::{
+        is_wsl2_osrelease, linux_choose_init, FromStr, InitSystem, PathBuf, Url, UrlOrPath,
+        UrlOrPathOrString,
+    };
 
     #[test]
     fn url_or_path_or_string_parses() -> Result<(), Box<dyn std::error::Error>> {
@@ -659,4 +806,38 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn linux_init_is_chosen_from_what_is_running() {
+        // systemd wins, even on WSL2 with systemd enabled
+        assert_eq!(
+            linux_choose_init(true, (true, true), true),
+            (InitSystem::Systemd, true)
+        );
+        assert_eq!(
+            linux_choose_init(false, (true, true), true),
+            (InitSystem::Openrc, true)
+        );
+        assert_eq!(
+            linux_choose_init(false, (true, false), true),
+            (InitSystem::Wsl, true)
+        );
+        // OpenRC installed in a container where it didn't boot
+        assert_eq!(
+            linux_choose_init(false, (true, false), false),
+            (InitSystem::Openrc, false)
+        );
+        assert_eq!(
+            linux_choose_init(false, (false, false), false),
+            (InitSystem::Systemd, false)
+        );
+    }
+
+    #[test]
+    fn wsl2_is_detected_from_osrelease() {
+        assert!(is_wsl2_osrelease("5.15.153.1-microsoft-standard-WSL2\n"));
+        // WSL1 kernels identify as `Microsoft`
+        assert!(!is_wsl2_osrelease("4.4.0-19041-Microsoft\n"));
+        assert!(!is_wsl2_osrelease("6.8.0-45-generic\n"));
+    }
 }
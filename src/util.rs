@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{os::unix::fs::MetadataExt, path::Path, time::SystemTime};
 
 use crate::action::ActionErrorKind;
 
@@ -36,6 +36,8 @@ pub(crate) async fn remove_dir_all(path: &Path, on_missing: OnMissing) -> std::i
     }
 }
 
+/// Replace `destination` with `body` by renaming a temporary file over it, keeping the mode and
+/// ownership of the file it replaces
 pub(crate) async fn write_atomic(destination: &Path, body: &str) -> Result<(), ActionErrorKind> {
     let temp = destination.with_extension("tmp");
 
@@ -43,9 +45,67 @@ pub(crate) async fn write_atomic(destination: &Path, body: &str) -> Result<(), A
         .await
         .map_err(|e| ActionErrorKind::Write(temp.to_owned(), e))?;
 
+    match tokio::fs::metadata(destination).await {
+        Ok(metadata) => {
+            tokio::fs::set_permissions(&temp, metadata.permissions())
+                .await
+                .map_err(|e| {
+                    ActionErrorKind::SetPermissions(metadata.mode() & 0o7777, temp.clone(), e)
+                })?;
+            nix::unistd::chown(
+                &temp,
+                Some(nix::unistd::Uid::from_raw(metadata.uid())),
+                Some(nix::unistd::Gid::from_raw(metadata.gid())),
+            )
+            .map_err(|e| ActionErrorKind::Chown(temp.clone(), e))?;
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(ActionErrorKind::GettingMetadata(destination.into(), e)),
+    }
+
     tokio::fs::rename(&temp, &destination)
         .await
         .map_err(|e| ActionErrorKind::Rename(temp, destination.into(), e))?;
 
     Ok(())
 }
+
+/// Milliseconds since the Unix epoch, as receipts, the history and `--progress json` record times
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Like `1760789696123-install`, so names of runs of `subcommand` sort by when they started
+pub(crate) fn run_name(started: SystemTime, subcommand: &str) -> String {
+    format!("{}-{subcommand}", unix_millis(started))
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::write_atomic;
+
+    #[tokio::test]
+    async fn atomic_writes_keep_the_mode() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("nix.conf");
+        tokio::fs::write(&path, "old").await?;
+        tokio::fs::set_permissions(&path, PermissionsExt::from_mode(0o640)).await?;
+
+        write_atomic(&path, "new").await?;
+
+        assert_eq!(tokio::fs::read_to_string(&path).await?, "new");
+        let mode = tokio::fs::metadata(&path).await?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        Ok(())
+    }
+}